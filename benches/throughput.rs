@@ -0,0 +1,190 @@
+/*!
+ * @file benches/throughput.rs
+ * @brief Headless emulation throughput: frames and instructions per second
+ *
+ * Runs small ROMs through Frontend with the null backends, the way batch runs
+ * do, and prints wall-clock rates. `cargo bench` builds it with optimisations;
+ * `cargo bench --features jit` runs each ROM compiled as well. The "x256"
+ * runs step that many copies of a ROM in lockstep through VecEnv, on every
+ * core, the way RL training does. The draw runs time DXYN on its own, against
+ * the per-pixel [[u8; 64]; 32] framebuffer the Cpu used before rows were
+ * packed into words, as a baseline.
+ */
+use chip8_emulator::display::Display;
+use chip8_emulator::env::{Env, EnvConfig, VecEnv};
+use chip8_emulator::font::FONT_SET;
+use chip8_emulator::frontend::{Frontend, NullAudio, NullInput, NullVideo};
+#[cfg(feature = "jit")]
+use chip8_emulator::jit::JitMode;
+use chip8_emulator::{Cpu, Quirks, Timing};
+use std::time::Instant;
+
+const FRAMES: u64 = 20_000;
+const INSTRUCTIONS_PER_FRAME: u32 = 1000;
+const ENVS: usize = 256;
+const DRAWS: usize = 5_000_000;
+
+// Draws 15-row sprites across the screen without end
+const SPRITES: &[u8] = &[
+    0x60, 0x00, // LD V0, 0
+    0x61, 0x00, // LD V1, 0
+    0xA0, 0x00, // LD I, 0x000
+    0xD0, 0x1F, // DRW V0, V1, 15
+    0x70, 0x03, // ADD V0, 3
+    0x71, 0x01, // ADD V1, 1
+    0x12, 0x06, // JP 0x206
+];
+
+// Arithmetic and memory traffic, no drawing
+const ARITHMETIC: &[u8] = &[
+    0x60, 0x00, // LD V0, 0
+    0xA3, 0x00, // LD I, 0x300
+    0x70, 0x01, // ADD V0, 1
+    0x81, 0x04, // ADD V1, V0
+    0x82, 0x13, // XOR V2, V1
+    0xF2, 0x33, // LD B, V2
+    0xF2, 0x65, // LD V2, [I]
+    0x12, 0x04, // JP 0x204
+];
+
+// Register arithmetic only: the straight-line code the JIT compiles
+const REGISTERS: &[u8] = &[
+    0x60, 0x01, // LD V0, 1
+    0x70, 0x03, // ADD V0, 3
+    0x81, 0x04, // ADD V1, V0
+    0x82, 0x15, // SUB V2, V1
+    0x83, 0x23, // XOR V3, V2
+    0x83, 0x0E, // SHL V3
+    0xF3, 0x1E, // ADD I, V3
+    0x44, 0x00, // SNE V4, 0
+    0x12, 0x02, // JP 0x202
+];
+
+fn quirks() -> Quirks {
+    Quirks {
+        timing: Timing::Instructions {
+            per_frame: INSTRUCTIONS_PER_FRAME,
+        },
+        ..Quirks::default()
+    }
+}
+
+fn new_cpu(program: &[u8]) -> Cpu {
+    let mut cpu = Cpu::with_quirks(quirks());
+    cpu.load_program(program);
+    cpu
+}
+
+fn run(name: &str, mut cpu: Cpu) {
+    let mut frontend = Frontend::new(
+        Box::new(NullVideo),
+        Box::new(NullAudio),
+        Box::new(NullInput::with_frame_limit(FRAMES)),
+    );
+
+    let start = Instant::now();
+    while frontend.run_frame(&mut cpu) {}
+    let seconds = start.elapsed().as_secs_f64();
+
+    print_rates(name, frontend.frame() as f64 / seconds);
+}
+
+fn print_rates(name: &str, frames_per_second: f64) {
+    println!(
+        "{:<16} {:>10.0} frames/s {:>8.1} M instructions/s",
+        name,
+        frames_per_second,
+        frames_per_second * INSTRUCTIONS_PER_FRAME as f64 / 1e6
+    );
+}
+
+fn run_vec(name: &str, program: &[u8]) {
+    let envs = (0..ENVS)
+        .map(|_| Env::new(program, quirks(), EnvConfig::default()))
+        .collect();
+    let mut vec_env = VecEnv::new(envs);
+    let mut observations = vec![0; ENVS * vec_env.observation_size()];
+    let mut rewards = vec![0.0; ENVS];
+    let mut dones = vec![false; ENVS];
+    let actions = vec![0; ENVS];
+
+    vec_env.reset(0, &mut observations);
+    while vec_env.frames() < FRAMES {
+        vec_env.step(&actions, &mut observations, &mut rewards, &mut dones);
+    }
+    print_rates(name, vec_env.fps());
+}
+
+// The framebuffer before packed rows, one byte per pixel, kept as a baseline
+struct PixelDisplay {
+    pixels: [[u8; 64]; 32],
+}
+
+impl PixelDisplay {
+    fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
+        let mut collision = false;
+        for (i, row) in sprite.iter().enumerate() {
+            for j in 0..8 {
+                let pixel = (row >> (7 - j)) & 0x1;
+                let cell = &mut self.pixels[(y + i) % 32][(x + j) % 64];
+                collision |= pixel == 1 && *cell == 1;
+                *cell ^= pixel;
+            }
+        }
+        collision
+    }
+}
+
+// Draw the font's 5-row glyphs across the screen, wrapping at the edges;
+// returns sprites drawn per second
+fn time_draws(mut draw: impl FnMut(usize, usize, &[u8]) -> bool) -> f64 {
+    let mut collisions = 0;
+    let start = Instant::now();
+    for i in 0..DRAWS {
+        let glyph = &FONT_SET[i % 16 * 5..i % 16 * 5 + 5];
+        collisions += draw(i * 3 % 64, i * 7 % 32, glyph) as usize;
+    }
+    let seconds = start.elapsed().as_secs_f64();
+    std::hint::black_box(collisions);
+    DRAWS as f64 / seconds
+}
+
+fn run_draws() {
+    let mut pixels = PixelDisplay {
+        pixels: [[0; 64]; 32],
+    };
+    let baseline = time_draws(|x, y, sprite| pixels.draw_sprite(x, y, sprite));
+    let mut display = Display::new(64, 32);
+    let packed = time_draws(|x, y, sprite| display.draw_sprite(x, y, sprite, true));
+    println!(
+        "{:<16} {:>10.1} M sprites/s",
+        "draw per pixel",
+        baseline / 1e6
+    );
+    println!(
+        "{:<16} {:>10.1} M sprites/s {:>8.1}x",
+        "draw packed",
+        packed / 1e6,
+        packed / baseline
+    );
+}
+
+fn main() {
+    run_draws();
+    run("sprites", new_cpu(SPRITES));
+    run("arithmetic", new_cpu(ARITHMETIC));
+    run("registers", new_cpu(REGISTERS));
+    run_vec("sprites x256", SPRITES);
+    run_vec("registers x256", REGISTERS);
+
+    #[cfg(feature = "jit")]
+    for (name, program) in [
+        ("sprites", SPRITES),
+        ("arithmetic", ARITHMETIC),
+        ("registers", REGISTERS),
+    ] {
+        let mut cpu = new_cpu(program);
+        cpu.set_jit(JitMode::On);
+        run(&format!("{} jit", name), cpu);
+    }
+}
@@ -0,0 +1,328 @@
+/*!
+ * @file batch.rs
+ * @brief Many independent headless runs spread across threads, and reports of
+ *        how each one ended
+ *
+ * A Job is one ROM run for a number of frames with its quirks, a seed for
+ * CXKK and optionally an input script. run_batch hands jobs to worker threads
+ * as they become free and returns the outcomes in job order, so the JSON and
+ * CSV reports of two sweeps over a ROM collection can be diffed line by line.
+ * A ROM that can't be loaded, or that trips a bug in the emulator, is reported
+ * with an error rather than stopping the other runs.
+ */
+use crate::cpu::{Cpu, Fault};
+use crate::display::Display;
+use crate::frontend::{Frontend, InputSource, NullAudio, NullInput, NullVideo, ScriptedInput};
+use crate::json::Json;
+use crate::quirks::Quirks;
+use crate::script::Script;
+use crate::sha1::sha1_hex;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+
+/**
+ * @brief One run: a ROM, how to run it, and for how long
+ */
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub rom: String, // name in the report, usually the path
+    pub program: Vec<u8>,
+    pub quirks: Quirks,
+    pub seed: u64,
+    pub script: Option<(String, Script)>, // name in the report, script
+    pub frames: u64,                      // at most; a script can end the run earlier
+}
+
+/**
+ * @brief How a run ended
+ */
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub rom: String,
+    pub seed: u64,
+    pub script: Option<String>,
+    pub frames: u64,
+    pub cycles: u64, // see Cpu::cycles
+    pub fault: Option<Fault>,
+    pub failure: Option<String>, // why the script stopped the run, if it failed
+    pub error: Option<String>,   // why the run couldn't finish; nothing else is set then
+    pub state_hash: String,      // SHA-1 of the final save state
+    pub display: Display,
+}
+
+// The outcome of a job that couldn't run to the end
+fn error_outcome(job: &Job, error: String) -> Outcome {
+    let (width, height) = job.quirks.platform.display_size();
+    Outcome {
+        rom: job.rom.clone(),
+        seed: job.seed,
+        script: job.script.as_ref().map(|(name, _)| name.clone()),
+        frames: 0,
+        cycles: 0,
+        fault: None,
+        failure: None,
+        error: Some(error),
+        state_hash: String::new(),
+        display: Display::new(width, height),
+    }
+}
+
+pub fn run_job(job: &Job) -> Outcome {
+    let platform = job.quirks.platform;
+    if job.program.len() > platform.memory_size() - platform.load_address() {
+        return error_outcome(job, "program too large to fit in memory".to_string());
+    }
+
+    let mut cpu = Cpu::with_quirks(job.quirks);
+    cpu.seed_random(job.seed);
+    cpu.load_program(&job.program);
+
+    let input: Box<dyn InputSource> = match &job.script {
+        Some((_, script)) => Box::new(ScriptedInput::new(script.clone())),
+        None => Box::new(NullInput::new()),
+    };
+    let mut frontend = Frontend::new(Box::new(NullVideo), Box::new(NullAudio), input);
+    while frontend.frame() < job.frames && frontend.run_frame(&mut cpu) {}
+
+    Outcome {
+        rom: job.rom.clone(),
+        seed: job.seed,
+        script: job.script.as_ref().map(|(name, _)| name.clone()),
+        frames: frontend.frame(),
+        cycles: cpu.cycles(),
+        fault: cpu.fault(),
+        failure: frontend.input.failure().map(str::to_string),
+        error: None,
+        state_hash: sha1_hex(&cpu.save_state()),
+        display: cpu.display().clone(),
+    }
+}
+
+// Run every job on up to `threads` threads; outcomes come back in job order
+pub fn run_batch(jobs: &[Job], threads: usize) -> Vec<Outcome> {
+    let next = AtomicUsize::new(0);
+    let outcomes = Mutex::new(vec![None; jobs.len()]);
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, jobs.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(job) = jobs.get(index) else {
+                    break;
+                };
+                // A panic is a bug in the emulator, but it shouldn't take
+                // the rest of the sweep with it
+                let outcome = panic::catch_unwind(AssertUnwindSafe(|| run_job(job)))
+                    .unwrap_or_else(|panic| {
+                        let message = match panic.downcast_ref::<&str>() {
+                            Some(message) => message.to_string(),
+                            None => panic.downcast_ref::<String>().cloned().unwrap_or_default(),
+                        };
+                        error_outcome(job, format!("emulator panicked: {}", message))
+                    });
+                outcomes.lock().unwrap_or_else(PoisonError::into_inner)[index] = Some(outcome);
+            });
+        }
+    });
+    outcomes
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner)
+        .into_iter()
+        .map(|outcome| outcome.expect("every job runs"))
+        .collect()
+}
+
+// The display as one hex string per row, 4 pixels a digit, leftmost pixel in
+// the high bit; on a MegaChip display any palette index but 0 is lit
+pub fn framebuffer_rows(display: &Display) -> Vec<String> {
+    (0..display.height)
+        .map(|y| {
+            (0..display.width)
+                .step_by(4)
+                .map(|x| {
+                    let nibble = (x..(x + 4).min(display.width)).fold(0, |nibble, x| {
+                        let lit = match display.truecolor {
+                            Some(_) => display.index_at(x, y) != 0,
+                            None => display.get_pixel(x, y) != 0,
+                        };
+                        (nibble << 1) | lit as u32
+                    });
+                    char::from_digit(nibble, 16).unwrap()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn optional_string(value: &Option<String>) -> Json {
+    value.clone().map_or(Json::Null, Json::String)
+}
+
+// A JSON array with one object per outcome, on a line of its own
+pub fn json_report(outcomes: &[Outcome]) -> String {
+    let lines: Vec<String> = outcomes
+        .iter()
+        .map(|outcome| {
+            let object = Json::Object(vec![
+                ("rom".to_string(), Json::String(outcome.rom.clone())),
+                ("seed".to_string(), Json::Number(outcome.seed as f64)),
+                ("script".to_string(), optional_string(&outcome.script)),
+                ("frames".to_string(), Json::Number(outcome.frames as f64)),
+                ("cycles".to_string(), Json::Number(outcome.cycles as f64)),
+                (
+                    "fault".to_string(),
+                    optional_string(&outcome.fault.map(|fault| fault.to_string())),
+                ),
+                ("failure".to_string(), optional_string(&outcome.failure)),
+                ("error".to_string(), optional_string(&outcome.error)),
+                (
+                    "state_hash".to_string(),
+                    Json::String(outcome.state_hash.clone()),
+                ),
+                (
+                    "framebuffer".to_string(),
+                    Json::Array(
+                        framebuffer_rows(&outcome.display)
+                            .into_iter()
+                            .map(Json::String)
+                            .collect(),
+                    ),
+                ),
+            ]);
+            format!("  {}", object)
+        })
+        .collect();
+    format!("[\n{}\n]\n", lines.join(",\n"))
+}
+
+// Quoted only when it has to be, as RFC 4180 has it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// A header line and a line per outcome; the framebuffer's rows are separated by spaces
+pub fn csv_report(outcomes: &[Outcome]) -> String {
+    let mut report =
+        String::from("rom,seed,script,frames,cycles,fault,failure,error,state_hash,framebuffer\n");
+    for outcome in outcomes {
+        let fields = [
+            outcome.rom.clone(),
+            outcome.seed.to_string(),
+            outcome.script.clone().unwrap_or_default(),
+            outcome.frames.to_string(),
+            outcome.cycles.to_string(),
+            outcome
+                .fault
+                .map(|fault| fault.to_string())
+                .unwrap_or_default(),
+            outcome.failure.clone().unwrap_or_default(),
+            outcome.error.clone().unwrap_or_default(),
+            outcome.state_hash.clone(),
+            framebuffer_rows(&outcome.display).join(" "),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        report.push_str(&fields.join(","));
+        report.push('\n');
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Random numbers into V0 - V3 every frame, drawn as they come
+    const RANDOM: [u8; 12] = [
+        0xC0, 0xFF, // loop: RND V0, 0xFF
+        0xC1, 0xFF, // RND V1, 0xFF
+        0xF0, 0x29, // LD F, V0
+        0xD0, 0x15, // DRW V0, V1, 5
+        0xF3, 0x55, // LD [I], V3
+        0x12, 0x00, // JP loop
+    ];
+
+    fn job(rom: &str, program: &[u8], seed: u64, frames: u64) -> Job {
+        Job {
+            rom: rom.to_string(),
+            program: program.to_vec(),
+            quirks: Quirks::default(),
+            seed,
+            script: None,
+            frames,
+        }
+    }
+
+    fn summary(outcomes: &[Outcome]) -> Vec<(String, u64, u64, String)> {
+        outcomes
+            .iter()
+            .map(|outcome| {
+                (
+                    outcome.rom.clone(),
+                    outcome.seed,
+                    outcome.frames,
+                    outcome.state_hash.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn outcomes_in_job_order() {
+        let jobs: Vec<Job> = (0..12)
+            .map(|i| job(&format!("rom{}", i), &RANDOM, i % 3, 10 + 7 * i))
+            .collect();
+        let outcomes = run_batch(&jobs, 1);
+        for (job, outcome) in jobs.iter().zip(&outcomes) {
+            assert_eq!((&outcome.rom, outcome.frames), (&job.rom, job.frames));
+            assert_eq!(outcome.error, None);
+        }
+        for threads in [2, 5, 32] {
+            assert_eq!(summary(&run_batch(&jobs, threads)), summary(&outcomes));
+        }
+    }
+
+    #[test]
+    fn seeds_make_runs_repeatable() {
+        let first = run_job(&job("random", &RANDOM, 7, 30));
+        let again = run_job(&job("random", &RANDOM, 7, 30));
+        let other = run_job(&job("random", &RANDOM, 8, 30));
+        assert_eq!(first.state_hash, again.state_hash);
+        assert_eq!(
+            framebuffer_rows(&first.display),
+            framebuffer_rows(&again.display)
+        );
+        assert_ne!(first.state_hash, other.state_hash);
+    }
+
+    #[test]
+    fn oversized_rom_is_an_error() {
+        let jobs = [job("big", &[0; 4096], 0, 10), job("fine", &RANDOM, 0, 10)];
+        let outcomes = run_batch(&jobs, 2);
+        assert_eq!(
+            outcomes[0].error.as_deref(),
+            Some("program too large to fit in memory")
+        );
+        assert_eq!(outcomes[1].error, None);
+        assert_eq!(outcomes[1].frames, 10);
+    }
+
+    #[test]
+    fn reports_escape_fields() {
+        let mut outcome = run_job(&job("a,\"b\".ch8", &RANDOM, 0, 1));
+        outcome.failure = Some("line 1: v3 is 0x1\nand more".to_string());
+        let csv = csv_report(&[outcome.clone()]);
+        let line = csv.lines().nth(1).unwrap();
+        assert!(line.starts_with("\"a,\"\"b\"\".ch8\",0,,1,"), "{}", line);
+        assert!(csv.contains(",\"line 1: v3 is 0x1\nand more\","));
+
+        let json = json_report(&[outcome]);
+        assert!(json.contains(r#""rom":"a,\"b\".ch8""#), "{}", json);
+        assert!(json.contains(r#""failure":"line 1: v3 is 0x1\nand more""#));
+        assert!(json.contains(r#""error":null"#));
+    }
+}
@@ -0,0 +1,499 @@
+/*!
+ * @file chip8-debugger.rs
+ * @brief Interactive line-based debugger: step, breakpoints, register and memory dumps,
+ *        a memory search for finding a game's counters, and cheats
+ */
+use chip8_emulator::cheats::{Cheat, Cheats, Effect};
+use chip8_emulator::memory_search::{Filter, MemorySearch};
+use chip8_emulator::patch::apply_patch;
+use chip8_emulator::script::{Operand, ParseError};
+use chip8_emulator::symbols::Symbols;
+use chip8_emulator::{decode_for, Cpu, Platform, Quirks};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::{env, fs, process};
+
+const HELP: &str = "commands:
+    s, step [n]         execute n instructions (default 1)
+    c, continue [n]     run until a breakpoint, at most n instructions (default 100000)
+    f, frame [n]        run n frames (default 1), stopping at breakpoints
+    b, break <addr>     toggle a breakpoint at a hex address
+    r, regs             print registers, timers and the call stack
+    bt, backtrace       print active calls, innermost first
+    m, mem <addr> [n]   dump n bytes of memory from a hex address (default 64)
+    d, dis [addr] [n]   disassemble n instructions from a hex address (default pc, 10)
+    k, key <k>          toggle key k (hex) held down
+    v, screen           draw the display
+    save <file>         write a save state
+    load <file>         restore a save state
+    search start        start a search with all of memory and V0-VF as candidates
+    search <filter>     keep the candidates that are changed, unchanged, increased,
+                        decreased or = <n> since the last search command
+    search list [n]     print up to n candidates and their values (default 20)
+    name <name> [loc]   name a location (v3, mem[0x2F0], bcd[0x2F0]; default: the
+                        one search candidate left) in the ROM's symbol file
+    unname <name>       remove a name from the symbol file
+    symbols             print the named locations and their values
+    cheat               list the ROM's cheats
+    cheat <name>        turn a cheat on or off
+    cheat <name> <effect>
+                        add a cheat to the ROM's cheat file and turn it on; effects
+                        are freeze mem[0x2F3] = 3, set v5 = 16, replace 0x2A4 = 0x1300
+    uncheat <name>      turn a cheat off and remove it from the cheat file
+    q, quit             exit";
+
+struct Debugger {
+    cpu: Cpu,
+    keypad: [bool; 16],
+    breakpoints: BTreeSet<usize>,
+    search: Option<MemorySearch>,
+    symbols: Symbols,
+    symbols_path: Option<PathBuf>, // None without a config directory
+    cheats: Cheats,
+    cheats_path: Option<PathBuf>,
+}
+
+// The per-ROM file at `path`, or an empty one if there is none yet
+fn load_user_file<T: Default>(
+    path: &Option<PathBuf>,
+    parse: impl Fn(&str) -> Result<T, ParseError>,
+) -> T {
+    let Some(path) = path else {
+        return T::default();
+    };
+    match fs::read_to_string(path) {
+        Ok(source) => parse(&source).unwrap_or_else(|err| {
+            eprintln!("ignoring {}: {}", path.display(), err);
+            T::default()
+        }),
+        Err(_) => T::default(),
+    }
+}
+
+fn save_user_file(path: &Option<PathBuf>, contents: String) {
+    let Some(path) = path else {
+        return println!("no config directory; changes last until exit");
+    };
+    let written = match path.parent() {
+        Some(dir) => fs::create_dir_all(dir),
+        None => Ok(()),
+    }
+    .and_then(|()| fs::write(path, contents));
+    if let Err(err) = written {
+        println!("cannot write {}: {}", path.display(), err);
+    }
+}
+
+fn parse_hex(arg: &str) -> Option<usize> {
+    usize::from_str_radix(arg.trim_start_matches("0x"), 16).ok()
+}
+
+impl Debugger {
+    // Returns false if the Cpu is halted on a fault
+    fn step(&mut self) -> bool {
+        let output_state = self.cpu.cycle(self.keypad);
+        output_state.fault.is_none()
+    }
+
+    fn run(&mut self, limit: usize, stop_at_breakpoints: bool) {
+        for i in 0..limit {
+            if stop_at_breakpoints
+                && i > 0
+                && self.breakpoints.contains(&self.cpu.program_counter())
+            {
+                println!("breakpoint at {:03X}", self.cpu.program_counter());
+                break;
+            }
+            if !self.step() {
+                break;
+            }
+        }
+        self.print_stop();
+    }
+
+    fn run_frames(&mut self, frames: usize) {
+        let mut frame = 0;
+        let mut first = true;
+        while frame < frames {
+            if !first && self.breakpoints.contains(&self.cpu.program_counter()) {
+                println!(
+                    "breakpoint at {:03X} in frame {}",
+                    self.cpu.program_counter(),
+                    frame + 1
+                );
+                break;
+            }
+            first = false;
+            let output_state = self.cpu.cycle(self.keypad);
+            if output_state.fault.is_some() {
+                break;
+            }
+            frame += output_state.vblank as usize;
+        }
+        self.print_stop();
+    }
+
+    // Where a run stopped, and why if it was a fault
+    fn print_stop(&self) {
+        if let Some(fault) = self.cpu.fault() {
+            println!("fault: {}", fault);
+            self.print_backtrace();
+        }
+        self.print_current();
+    }
+
+    // Innermost call first: "#0 caller -> callee"
+    fn print_backtrace(&self) {
+        let backtrace = self.cpu.backtrace();
+        if backtrace.is_empty() {
+            println!("(no calls)");
+        }
+        for (depth, frame) in backtrace.iter().enumerate() {
+            println!("#{} {:03X} -> {:03X}", depth, frame.caller, frame.callee);
+        }
+    }
+
+    fn print_current(&self) {
+        let pc = self.cpu.program_counter();
+        println!(
+            "{:03X}: {:04X}  {}",
+            pc,
+            self.cpu.fetch_opcode(),
+            self.cpu.current_instruction()
+        );
+    }
+
+    fn print_registers(&self) {
+        for (i, value) in self.cpu.v_registers().iter().enumerate() {
+            print!("V{:X}={:02X} ", i, value);
+            if i % 8 == 7 {
+                println!();
+            }
+        }
+        println!(
+            "I={:03X} PC={:03X} DT={:02X} ST={:02X}{}",
+            self.cpu.index_register(),
+            self.cpu.program_counter(),
+            self.cpu.delay_timer(),
+            self.cpu.sound_timer(),
+            if self.cpu.keypad_waiting() {
+                " (waiting for key)"
+            } else {
+                ""
+            }
+        );
+        let stack: Vec<String> = self
+            .cpu
+            .stack()
+            .iter()
+            .map(|frame| format!("{:03X}", frame.return_address()))
+            .collect();
+        println!("stack: [{}]", stack.join(" "));
+    }
+
+    fn dump_memory(&self, start: usize, len: usize) {
+        let memory = self.cpu.memory();
+        let end = (start + len).min(memory.len());
+        for line in (start..end).step_by(16) {
+            let bytes: Vec<String> = memory[line..(line + 16).min(end)]
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            println!("{:03X}: {}", line, bytes.join(" "));
+        }
+    }
+
+    fn disassemble(&self, start: usize, count: usize) {
+        let memory = self.cpu.memory();
+        for addr in (start..memory.len() - 1).step_by(2).take(count) {
+            let opcode = ((memory[addr] as u16) << 8) | memory[addr + 1] as u16;
+            let marker = if addr == self.cpu.program_counter() {
+                ">"
+            } else {
+                " "
+            };
+            let instruction = decode_for(self.cpu.quirks().platform, opcode);
+            println!("{}{:03X}: {:04X}  {}", marker, addr, opcode, instruction);
+        }
+    }
+
+    fn search(&mut self, words: &[&str]) {
+        let list = |search: &MemorySearch, symbols: &Symbols, limit: usize| {
+            for (location, value) in search.candidates().take(limit) {
+                let name = symbols
+                    .iter()
+                    .find(|&(_, operand)| operand == location)
+                    .map_or(String::new(), |(name, _)| format!("  ({})", name));
+                println!("{} = {}{}", location, value, name);
+            }
+            if search.len() > limit {
+                println!("... and {} more", search.len() - limit);
+            }
+        };
+
+        match (words, &mut self.search) {
+            (["start"], _) => {
+                let search = MemorySearch::start(&self.cpu);
+                println!("{} candidates", search.len());
+                self.search = Some(search);
+            }
+            (["list", rest @ ..], Some(search)) => {
+                let limit = rest.first().and_then(|arg| arg.parse().ok()).unwrap_or(20);
+                list(search, &self.symbols, limit);
+            }
+            ([], Some(search)) => println!("{} candidates", search.len()),
+            (filter, Some(search)) => match Filter::parse(filter) {
+                Some(filter) => {
+                    search.filter(&self.cpu, filter);
+                    println!("{} candidates {}", search.len(), filter);
+                    if search.len() <= 10 {
+                        list(search, &self.symbols, 10);
+                    }
+                }
+                None => println!(
+                    "usage: search <start|changed|unchanged|increased|decreased|= n|list [n]>"
+                ),
+            },
+            (_, None) => println!("no search; start one with `search start`"),
+        }
+    }
+
+    fn name(&mut self, name: &str, location: Option<&str>) {
+        let operand = match (location, &self.search) {
+            (Some(word), _) => match Operand::parse(word) {
+                Ok(operand) => operand,
+                Err(message) => return println!("{}", message),
+            },
+            (None, Some(search)) if search.len() == 1 => search.candidates().next().unwrap().0,
+            (None, Some(search)) => {
+                return println!(
+                    "{} search candidates; name one with `name {} <loc>`",
+                    search.len(),
+                    name
+                )
+            }
+            (None, None) => return println!("usage: name <name> <loc>"),
+        };
+        self.symbols.insert(name, operand);
+        println!("{} is {}", name, operand);
+        save_user_file(&self.symbols_path, self.symbols.to_string());
+    }
+
+    fn cheat(&mut self, words: &[&str]) {
+        match words {
+            [] if self.cheats.is_empty() => println!("(no cheats)"),
+            [] => {
+                for cheat in self.cheats.iter() {
+                    let state = if cheat.enabled { "on" } else { "off" };
+                    println!("{} {} ({})", cheat.name, cheat.effect, state);
+                }
+            }
+            [name] => match self.cheats.toggle(name) {
+                Some(enabled) => {
+                    println!("{} {}", name, if enabled { "on" } else { "off" });
+                    self.cpu.set_cheats(&self.cheats.enabled());
+                    save_user_file(&self.cheats_path, self.cheats.to_string());
+                }
+                None => println!("no cheat is named {}", name),
+            },
+            [name, effect @ ..] => match Effect::parse(effect) {
+                Ok(effect) => {
+                    self.cheats.insert(Cheat {
+                        name: name.to_string(),
+                        effect,
+                        enabled: true,
+                    });
+                    println!("{} {} (on)", name, effect);
+                    self.cpu.set_cheats(&self.cheats.enabled());
+                    save_user_file(&self.cheats_path, self.cheats.to_string());
+                }
+                Err(message) => println!("{}", message),
+            },
+        }
+    }
+
+    fn print_symbols(&self) {
+        if self.symbols.is_empty() {
+            println!("(no names)");
+        }
+        for (name, operand) in self.symbols.iter() {
+            println!("{} {} = {}", name, operand, operand.read(&self.cpu));
+        }
+    }
+
+    // Returns false when the debugger should exit
+    fn execute(&mut self, line: &str) -> bool {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let count = |index: usize, default: usize| {
+            words
+                .get(index)
+                .and_then(|arg| arg.parse().ok())
+                .unwrap_or(default)
+        };
+
+        match words.first().copied() {
+            None => {}
+            Some("s") | Some("step") => self.run(count(1, 1), false),
+            Some("c") | Some("continue") => self.run(count(1, 100_000), true),
+            Some("f") | Some("frame") => self.run_frames(count(1, 1)),
+            Some("b") | Some("break") => match words.get(1).and_then(|arg| parse_hex(arg)) {
+                Some(addr) if self.breakpoints.remove(&addr) => {
+                    println!("breakpoint {:03X} removed", addr)
+                }
+                Some(addr) => {
+                    self.breakpoints.insert(addr);
+                    println!("breakpoint {:03X} set", addr);
+                }
+                None => println!("usage: break <addr>"),
+            },
+            Some("r") | Some("regs") => self.print_registers(),
+            Some("bt") | Some("backtrace") => self.print_backtrace(),
+            Some("m") | Some("mem") => match words.get(1).and_then(|arg| parse_hex(arg)) {
+                Some(addr) => self.dump_memory(addr, count(2, 64)),
+                None => println!("usage: mem <addr> [n]"),
+            },
+            Some("d") | Some("dis") => {
+                let start = words
+                    .get(1)
+                    .and_then(|arg| parse_hex(arg))
+                    .unwrap_or(self.cpu.program_counter());
+                self.disassemble(start, count(2, 10));
+            }
+            Some("k") | Some("key") => match words.get(1).and_then(|arg| parse_hex(arg)) {
+                Some(key) if key < 16 => {
+                    self.keypad[key] = !self.keypad[key];
+                    println!(
+                        "key {:X} {}",
+                        key,
+                        if self.keypad[key] { "down" } else { "up" }
+                    );
+                }
+                _ => println!("usage: key <0-F>"),
+            },
+            Some("v") | Some("screen") => {
+                self.cpu.display().render();
+                println!();
+            }
+            Some("save") => match words.get(1) {
+                Some(path) => match fs::write(path, self.cpu.save_state()) {
+                    Ok(()) => println!("saved {}", path),
+                    Err(err) => println!("cannot write {}: {}", path, err),
+                },
+                None => println!("usage: save <file>"),
+            },
+            Some("load") => match words.get(1) {
+                Some(path) => match fs::read(path) {
+                    Ok(data) => match self.cpu.load_state(&data) {
+                        Ok(()) => {
+                            // The state says which cheats were on
+                            self.cheats.match_active(&self.cpu.cheats());
+                            self.print_current();
+                        }
+                        Err(err) => println!("cannot load {}: {}", path, err),
+                    },
+                    Err(err) => println!("cannot read {}: {}", path, err),
+                },
+                None => println!("usage: load <file>"),
+            },
+            Some("search") => self.search(&words[1..]),
+            Some("name") => match words[1..] {
+                [name] => self.name(name, None),
+                [name, location] => self.name(name, Some(location)),
+                _ => println!("usage: name <name> [loc]"),
+            },
+            Some("unname") => match words.get(1) {
+                Some(name) if self.symbols.remove(name).is_some() => {
+                    save_user_file(&self.symbols_path, self.symbols.to_string())
+                }
+                Some(name) => println!("no location is named {}", name),
+                None => println!("usage: unname <name>"),
+            },
+            Some("symbols") => self.print_symbols(),
+            Some("cheat") => self.cheat(&words[1..]),
+            Some("uncheat") => match words.get(1) {
+                Some(name) if self.cheats.remove(name).is_some() => {
+                    self.cpu.set_cheats(&self.cheats.enabled());
+                    save_user_file(&self.cheats_path, self.cheats.to_string());
+                }
+                Some(name) => println!("no cheat is named {}", name),
+                None => println!("usage: uncheat <name>"),
+            },
+            Some("q") | Some("quit") => return false,
+            Some(_) => println!("{}", HELP),
+        }
+        true
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (rom_path, patch_paths) = match args.as_slice() {
+        [rom_path, rest @ ..]
+            if rest.len() % 2 == 0 && rest.iter().step_by(2).all(|arg| arg == "--patch") =>
+        {
+            (rom_path, rest.iter().skip(1).step_by(2))
+        }
+        _ => {
+            eprintln!("usage: chip8-debugger <rom> [--patch <file>]...");
+            process::exit(2);
+        }
+    };
+    let read = |path: &String| {
+        fs::read(path).unwrap_or_else(|err| {
+            eprintln!("cannot read {}: {}", path, err);
+            process::exit(1);
+        })
+    };
+    let mut program = read(rom_path);
+    // IPS or BPS patches, applied in order before loading
+    for path in patch_paths {
+        program = apply_patch(&program, &read(path)).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        });
+    }
+
+    let mut cpu = Cpu::with_quirks(Quirks {
+        platform: Platform::detect(&program),
+        ..Quirks::default()
+    });
+    cpu.load_program(&program);
+    // Stepping and breakpoints need every instruction to run on its own
+    cpu.set_idle_skip(false);
+
+    let symbols_path = Symbols::path(&program);
+    let symbols = load_user_file(&symbols_path, Symbols::parse);
+    if !symbols.is_empty() {
+        println!("{} named locations; `symbols` lists them", symbols.len());
+    }
+    let cheats_path = Cheats::path(&program);
+    let cheats = load_user_file(&cheats_path, Cheats::parse);
+    cpu.set_cheats(&cheats.enabled());
+    if !cheats.is_empty() {
+        println!("{} cheats; `cheat` lists them", cheats.len());
+    }
+
+    let mut debugger = Debugger {
+        cpu,
+        keypad: [false; 16],
+        breakpoints: BTreeSet::new(),
+        search: None,
+        symbols,
+        symbols_path,
+        cheats,
+        cheats_path,
+    };
+
+    debugger.print_current();
+    let stdin = io::stdin();
+    loop {
+        print!("(chip8) ");
+        io::stdout().flush().ok();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 || !debugger.execute(&line) {
+            break;
+        }
+    }
+}
@@ -0,0 +1,501 @@
+/*!
+ * @file chip8-tools.rs
+ * @brief Offline ROM tools built on the emulator library
+ */
+use chip8_emulator::batch::{csv_report, json_report, run_batch, Job};
+use chip8_emulator::detect::detect;
+use chip8_emulator::patch::{apply_patch, create_patch, PatchFormat};
+use chip8_emulator::recompile::recompile;
+use chip8_emulator::script::Script;
+use chip8_emulator::{
+    disassemble, Cpu, Display, IndexIncrement, KeyWait, MemoryLayout, Platform, Quirks,
+    RomDatabase, Timing, Vip, PROGRAM_START, VIP_STACK_DEPTH,
+};
+use std::io::{self, Write};
+use std::path::Path;
+use std::{env, fs, process, thread};
+
+const USAGE: &str = "usage: chip8-tools <command> [args]
+
+commands:
+    disasm <rom>    print a listing of every opcode in the ROM
+    detect <rom>    guess the CHIP-8 variant the ROM was written for from
+                    the opcodes it uses, and the quirks it would run with
+    vip <interpreter> <rom> [--monitor <file>] [--frames <n>]
+                    run the ROM on a dump of the original VIP interpreter
+                    and print the screen after n frames (default 600)
+    crosscheck <interpreter> <rom> [--monitor <file>] [--frames <n>]
+                    run the ROM both on the VIP interpreter and on Cpu with
+                    VIP quirks, and compare screens and V registers
+    recompile <rom> [--platform chip8|hires] [--cycles <n>]
+              [--key-wait release|press] [--stack-depth <n>|unlimited]
+              [-o <file>]
+                    translate the ROM into a standalone Rust module, one
+                    function per basic block, written to stdout or <file>
+    batch <rom>... [--frames <n>] [--seeds <n>] [--script <file>]...
+          [--threads <n>] [--format json|csv] [-o <file>]
+                    run every ROM headless for n frames (default 600), once
+                    per seed from 0 to n-1 (default 1) and per script, on all
+                    cores; report frames, cycles, faults, a hash of the final
+                    state and the final screen of each run. Quirks come from
+                    the ROM database, or detection as with chip8-emulator
+    patch create <original> <modified> [--format ips|bps] [-o <file>]
+                    write a patch that turns the original ROM into the
+                    modified one; BPS (the default unless <file> ends in
+                    .ips) also checks the ROM it is applied to
+    patch apply <rom> <patch>... [-o <file>]
+                    apply IPS or BPS patches in order, as --patch does";
+
+const DEFAULT_VIP_FRAMES: u64 = 600;
+const DEFAULT_BATCH_FRAMES: u64 = 600;
+
+fn read_rom(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| {
+        eprintln!("cannot read {}: {}", path, err);
+        process::exit(1);
+    })
+}
+
+fn disasm(args: &[String]) {
+    let [rom_path] = args else {
+        usage_error();
+    };
+    let program = read_rom(rom_path);
+
+    for (addr, opcode, instruction) in disassemble(&program, PROGRAM_START) {
+        println!("{:03X}: {:04X}  {}", addr, opcode, instruction);
+    }
+}
+
+fn detect_platform(args: &[String]) {
+    let [rom_path] = args else {
+        usage_error();
+    };
+    let detection = detect(&read_rom(rom_path));
+
+    println!(
+        "platform: {} ({:.0}% confidence)",
+        detection.family.name(),
+        detection.confidence * 100.0
+    );
+    let quirks = detection.quirks();
+    if let Some(warning) = detection.warning() {
+        println!("warning: {}", warning);
+    }
+    if detection.hires_header {
+        println!("hires header: 1260 at 0x200");
+    }
+    for evidence in &detection.evidence {
+        println!(
+            "{:03X}: {:04X}  {:<10} {}",
+            evidence.addr,
+            evidence.opcode,
+            evidence.family.name(),
+            if evidence.reachable { "code" } else { "data?" }
+        );
+    }
+    println!("quirks: {:?}", quirks);
+}
+
+fn usage_error() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+/**
+ * @brief Arguments shared by the commands that run a ROM on the VIP
+ */
+struct VipArgs {
+    interpreter: Vec<u8>,
+    monitor: Option<Vec<u8>>,
+    program: Vec<u8>,
+    frames: u64,
+}
+
+fn parse_vip_args(args: &[String]) -> VipArgs {
+    let mut paths = Vec::new();
+    let mut monitor = None;
+    let mut frames = DEFAULT_VIP_FRAMES;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--monitor" => monitor = Some(read_rom(args.next().unwrap_or_else(|| usage_error()))),
+            "--frames" => {
+                frames = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage_error())
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let [interpreter_path, rom_path] = paths[..] else {
+        usage_error();
+    };
+
+    let interpreter = read_rom(interpreter_path);
+    if interpreter.len() > PROGRAM_START {
+        eprintln!(
+            "{}: interpreter image is larger than {} bytes",
+            interpreter_path, PROGRAM_START
+        );
+        process::exit(1);
+    }
+    VipArgs {
+        interpreter,
+        monitor,
+        program: read_rom(rom_path),
+        frames,
+    }
+}
+
+fn run_vip(args: &VipArgs) -> Vip {
+    let mut vip = Vip::new(&args.interpreter);
+    if let Some(monitor) = &args.monitor {
+        vip = vip.with_monitor(monitor);
+    }
+    vip.load_program(&args.program);
+    for _ in 0..args.frames {
+        vip.run_frame([false; 16]);
+    }
+    vip
+}
+
+fn print_screen(screen: &Display) {
+    for row in screen.rows() {
+        let line: String = row
+            .map(|pixel| if pixel == 1 { '#' } else { '.' })
+            .collect();
+        println!("{}", line);
+    }
+}
+
+fn vip(args: &[String]) {
+    let args = parse_vip_args(args);
+    let vip = run_vip(&args);
+    print_screen(vip.display());
+}
+
+fn crosscheck(args: &[String]) {
+    let args = parse_vip_args(args);
+    let vip = run_vip(&args);
+
+    let mut cpu = Cpu::with_quirks(Quirks {
+        memory_layout: MemoryLayout::Vip,
+        stack_depth: Some(VIP_STACK_DEPTH),
+        timing: Timing::Vip,
+        display_wait: true,
+        machine_code: true,
+        shift_vy: true,
+        index_increment: IndexIncrement::ByXPlusOne,
+        clip_sprites: true,
+        logic_resets_vf: true,
+        ..Quirks::default()
+    });
+    cpu.load_program(&args.program);
+    let mut frames = 0;
+    while frames < args.frames && cpu.fault().is_none() {
+        if cpu.cycle([false; 16]).vblank {
+            frames += 1;
+        }
+    }
+
+    let mut differences = 0;
+    if let Some(fault) = cpu.fault() {
+        println!("cpu: fault: {}", fault);
+        differences += 1;
+    }
+    let (vip_v, cpu_v) = (vip.v_registers(), cpu.v_registers());
+    for x in 0..vip_v.len() {
+        if vip_v[x] != cpu_v[x] {
+            println!("V{:X}: vip {:02X}, cpu {:02X}", x, vip_v[x], cpu_v[x]);
+            differences += 1;
+        }
+    }
+    if vip.display() != cpu.display() {
+        println!("screens differ\nvip:");
+        print_screen(vip.display());
+        println!("cpu:");
+        print_screen(cpu.display());
+        differences += 1;
+    }
+
+    if differences > 0 {
+        process::exit(1);
+    }
+    println!(
+        "ok: screens and V registers match after {} frames",
+        args.frames
+    );
+}
+
+fn recompile_rom(args: &[String]) {
+    let mut rom_path = None;
+    let mut output = None;
+    let mut platform = None;
+    let mut quirks = Quirks::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error());
+        match arg.as_str() {
+            "--platform" => {
+                platform = Some(Platform::from_name(value()).unwrap_or_else(|| usage_error()))
+            }
+            "--cycles" => {
+                let per_frame = value().parse().unwrap_or_else(|_| usage_error());
+                quirks.timing = Timing::Instructions { per_frame };
+            }
+            "--key-wait" => {
+                quirks.key_wait = match value().as_str() {
+                    "release" => KeyWait::PressAndRelease,
+                    "press" => KeyWait::Press,
+                    _ => usage_error(),
+                }
+            }
+            "--stack-depth" => {
+                quirks.stack_depth = match value().as_str() {
+                    "unlimited" => None,
+                    depth => Some(depth.parse().unwrap_or_else(|_| usage_error())),
+                }
+            }
+            "-o" => output = Some(value()),
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => usage_error(),
+        }
+    }
+    let Some(rom_path) = rom_path else {
+        usage_error();
+    };
+    let program = read_rom(rom_path);
+    quirks.platform = platform.unwrap_or_else(|| Platform::detect(&program));
+
+    let name = Path::new(rom_path)
+        .file_name()
+        .map_or(rom_path.as_str(), |name| name.to_str().unwrap_or(rom_path));
+    let recompiled = recompile(&program, &quirks, name).unwrap_or_else(|err| {
+        eprintln!("{}: {}", rom_path, err);
+        process::exit(1);
+    });
+    match output {
+        Some(path) => fs::write(path, &recompiled.source).unwrap_or_else(|err| {
+            eprintln!("cannot write {}: {}", path, err);
+            process::exit(1);
+        }),
+        None => print!("{}", recompiled.source),
+    }
+    eprintln!(
+        "{} blocks, {} instructions; {} computed jumps left to the interpreter",
+        recompiled.blocks, recompiled.instructions, recompiled.computed_jumps
+    );
+}
+
+// Quirks for a ROM the way chip8-emulator picks them without options: the
+// bundled and per-user ROM databases first, then detection. Whatever can't be
+// honoured is reported against `rom_path`.
+fn rom_quirks(database: &RomDatabase, rom_path: &str, program: &[u8]) -> Quirks {
+    if let Some(entry) = database.lookup(program) {
+        let settings = database.settings(entry, program);
+        for note in settings.notes.iter() {
+            eprintln!("{}: ROM database: {}", rom_path, note);
+        }
+        return settings.quirks;
+    }
+    let detection = detect(program);
+    if let Some(warning) = detection.warning() {
+        eprintln!("{}: warning: {}", rom_path, warning);
+    }
+    detection.quirks()
+}
+
+fn batch(args: &[String]) {
+    let mut rom_paths = Vec::new();
+    let mut script_paths = Vec::new();
+    let mut frames = DEFAULT_BATCH_FRAMES;
+    let mut seeds = 1;
+    let mut threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+    let mut csv = false;
+    let mut output = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error());
+        match arg.as_str() {
+            "--frames" => frames = value().parse().unwrap_or_else(|_| usage_error()),
+            "--seeds" => seeds = value().parse().unwrap_or_else(|_| usage_error()),
+            "--threads" => threads = value().parse().unwrap_or_else(|_| usage_error()),
+            "--script" => script_paths.push(value()),
+            "--format" => {
+                csv = match value().as_str() {
+                    "json" => false,
+                    "csv" => true,
+                    _ => usage_error(),
+                }
+            }
+            "-o" => output = Some(value()),
+            _ if arg.starts_with("--") => usage_error(),
+            _ => rom_paths.push(arg),
+        }
+    }
+    if rom_paths.is_empty() {
+        usage_error();
+    }
+
+    let scripts: Vec<Option<(String, Script)>> = if script_paths.is_empty() {
+        vec![None]
+    } else {
+        script_paths
+            .iter()
+            .map(|path| {
+                let source = fs::read_to_string(path).unwrap_or_else(|err| {
+                    eprintln!("cannot read {}: {}", path, err);
+                    process::exit(1);
+                });
+                let script = Script::parse(&source).unwrap_or_else(|err| {
+                    eprintln!("{}: {}", path, err);
+                    process::exit(2);
+                });
+                Some((path.to_string(), script))
+            })
+            .collect()
+    };
+
+    let mut database = RomDatabase::bundled();
+    if let Some(dir) = RomDatabase::user_dir() {
+        if let Err(err) = database.load_dir(&dir) {
+            eprintln!("ROM database: {}", err);
+            process::exit(1);
+        }
+    }
+    let mut jobs = Vec::new();
+    for rom_path in rom_paths {
+        let program = read_rom(rom_path);
+        let quirks = rom_quirks(&database, rom_path, &program);
+        for seed in 0..seeds {
+            for script in &scripts {
+                jobs.push(Job {
+                    rom: rom_path.to_string(),
+                    program: program.clone(),
+                    quirks,
+                    seed,
+                    script: script.clone(),
+                    frames,
+                });
+            }
+        }
+    }
+
+    let outcomes = run_batch(&jobs, threads);
+    let report = if csv {
+        csv_report(&outcomes)
+    } else {
+        json_report(&outcomes)
+    };
+    match output {
+        Some(path) => fs::write(path, report).unwrap_or_else(|err| {
+            eprintln!("cannot write {}: {}", path, err);
+            process::exit(1);
+        }),
+        None => print!("{}", report),
+    }
+    let faults = outcomes
+        .iter()
+        .filter(|outcome| outcome.fault.is_some())
+        .count();
+    let failures = outcomes
+        .iter()
+        .filter(|outcome| outcome.failure.is_some())
+        .count();
+    let errors = outcomes
+        .iter()
+        .filter(|outcome| outcome.error.is_some())
+        .count();
+    eprintln!(
+        "{} runs: {} faulted, {} failed their script, {} could not run",
+        outcomes.len(),
+        faults,
+        failures,
+        errors
+    );
+}
+
+// Binary output to `path`, or stdout without one
+fn write_output(path: Option<&String>, data: &[u8]) {
+    let written = match path {
+        Some(path) => fs::write(path, data),
+        None => io::stdout().write_all(data),
+    };
+    if let Err(err) = written {
+        eprintln!(
+            "cannot write {}: {}",
+            path.map_or("stdout", |path| path),
+            err
+        );
+        process::exit(1);
+    }
+}
+
+fn patch(args: &[String]) {
+    let mut paths = Vec::new();
+    let mut format = None;
+    let mut output = None;
+
+    let mut args = args.iter();
+    let command = args.next();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error());
+        match arg.as_str() {
+            "--format" => {
+                format = Some(PatchFormat::from_name(value()).unwrap_or_else(|| usage_error()))
+            }
+            "-o" => output = Some(value()),
+            _ if arg.starts_with("--") => usage_error(),
+            _ => paths.push(arg),
+        }
+    }
+
+    match (command.map(String::as_str), paths.as_slice()) {
+        (Some("create"), [original, modified]) => {
+            let format = format.unwrap_or(match output {
+                Some(path) if path.ends_with(".ips") => PatchFormat::Ips,
+                _ => PatchFormat::Bps,
+            });
+            let patch = create_patch(format, &read_rom(original), &read_rom(modified))
+                .unwrap_or_else(|err| {
+                    eprintln!("{}: {}", modified, err);
+                    process::exit(1);
+                });
+            write_output(output, &patch);
+            eprintln!("{} byte patch", patch.len());
+        }
+        (Some("apply"), [rom_path, patch_paths @ ..])
+            if !patch_paths.is_empty() && format.is_none() =>
+        {
+            let mut program = read_rom(rom_path);
+            for path in patch_paths {
+                program = apply_patch(&program, &read_rom(path)).unwrap_or_else(|err| {
+                    eprintln!("{}: {}", path, err);
+                    process::exit(1);
+                });
+            }
+            write_output(output, &program);
+        }
+        _ => usage_error(),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("disasm") => disasm(&args[1..]),
+        Some("detect") => detect_platform(&args[1..]),
+        Some("vip") => vip(&args[1..]),
+        Some("crosscheck") => crosscheck(&args[1..]),
+        Some("recompile") => recompile_rom(&args[1..]),
+        Some("batch") => batch(&args[1..]),
+        Some("patch") => patch(&args[1..]),
+        _ => usage_error(),
+    }
+}
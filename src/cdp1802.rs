@@ -0,0 +1,405 @@
+/*!
+ * @file cdp1802.rs
+ * @brief RCA CDP1802 CPU core, the processor of the COSMAC VIP
+ *
+ * Implements the full 1802 instruction set. Memory and I/O go through the
+ * Bus trait so the same core serves both the VIP system (vip.rs) and 0NNN
+ * machine-code calls made from the high-level Cpu.
+ */
+
+/**
+ * @brief Everything the 1802 can see outside itself
+ */
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+
+    // INP 1-7
+    fn input(&mut self, _port: u8) -> u8 {
+        0
+    }
+
+    // OUT 1-7
+    fn output(&mut self, _port: u8, _value: u8) {}
+
+    // External flag lines EF1-EF4; true when asserted
+    fn flag(&self, _line: u8) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cdp1802 {
+    pub r: [u16; 16], // scratchpad registers R0 - RF
+    pub p: u8,        // which R is the program counter
+    pub x: u8,        // which R is the data pointer
+    pub d: u8,        // accumulator
+    pub df: bool,     // carry / not borrow
+    pub t: u8,        // X and P saved by an interrupt or MARK
+    pub ie: bool,     // interrupt enable
+    pub q: bool,      // Q output flip-flop (the VIP's tone generator)
+    pub idle: bool,   // IDL executed, waiting for an interrupt or DMA
+}
+
+impl Default for Cdp1802 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cdp1802 {
+    // State after a hardware reset: P, X and R0 cleared, interrupts enabled
+    pub fn new() -> Self {
+        Cdp1802 {
+            r: [0; 16],
+            p: 0,
+            x: 0,
+            d: 0,
+            df: false,
+            t: 0,
+            ie: true,
+            q: false,
+            idle: false,
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.r[self.p as usize]
+    }
+
+    fn fetch(&mut self, bus: &mut impl Bus) -> u8 {
+        let pc = self.p as usize;
+        let byte = bus.read(self.r[pc]);
+        self.r[pc] = self.r[pc].wrapping_add(1);
+        byte
+    }
+
+    fn rx(&self) -> u16 {
+        self.r[self.x as usize]
+    }
+
+    fn inc_rx(&mut self) {
+        let x = self.x as usize;
+        self.r[x] = self.r[x].wrapping_add(1);
+    }
+
+    // D = a + b + carry, DF = carry out
+    fn add(&mut self, a: u8, b: u8, carry: bool) {
+        let sum = a as u16 + b as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    // D = a - b - borrow, DF = no borrow
+    fn subtract(&mut self, a: u8, b: u8, borrow: bool) {
+        let difference = a as i16 - b as i16 - borrow as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+
+    fn short_branch(&mut self, bus: &mut impl Bus, condition: bool) {
+        let pc = self.p as usize;
+        if condition {
+            let target = bus.read(self.r[pc]);
+            self.r[pc] = (self.r[pc] & 0xFF00) | target as u16;
+        } else {
+            self.r[pc] = self.r[pc].wrapping_add(1);
+        }
+    }
+
+    fn long_branch(&mut self, bus: &mut impl Bus, condition: bool) {
+        let pc = self.p as usize;
+        if condition {
+            let high = bus.read(self.r[pc]) as u16;
+            let low = bus.read(self.r[pc].wrapping_add(1)) as u16;
+            self.r[pc] = (high << 8) | low;
+        } else {
+            self.r[pc] = self.r[pc].wrapping_add(2);
+        }
+    }
+
+    fn long_skip(&mut self, condition: bool) {
+        if condition {
+            let pc = self.p as usize;
+            self.r[pc] = self.r[pc].wrapping_add(2);
+        }
+    }
+
+    // Take an interrupt if enabled: save X and P in T, continue at R1 with X = 2
+    pub fn interrupt(&mut self) -> bool {
+        if !self.ie {
+            return false;
+        }
+        self.t = (self.x << 4) | self.p;
+        self.p = 1;
+        self.x = 2;
+        self.ie = false;
+        self.idle = false;
+        true
+    }
+
+    // One DMA-out cycle: the byte at R0 goes to the device and R0 advances
+    pub fn dma_out(&mut self, bus: &mut impl Bus) -> u8 {
+        let byte = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        byte
+    }
+
+    // Execute one instruction and return the machine cycles it took
+    // (8 clocks each). An idle CPU burns one cycle per call.
+    pub fn step(&mut self, bus: &mut impl Bus) -> u32 {
+        if self.idle {
+            return 1;
+        }
+
+        let opcode = self.fetch(bus);
+        let n = (opcode & 0x0F) as usize;
+        match opcode >> 4 {
+            0x0 if n == 0 => self.idle = true,            // IDL
+            0x0 => self.d = bus.read(self.r[n]),          // LDN
+            0x1 => self.r[n] = self.r[n].wrapping_add(1), // INC
+            0x2 => self.r[n] = self.r[n].wrapping_sub(1), // DEC
+            0x3 => {
+                let condition = match n {
+                    0x0 => true,                        // BR
+                    0x1 => self.q,                      // BQ
+                    0x2 => self.d == 0,                 // BZ
+                    0x3 => self.df,                     // BDF
+                    0x4..=0x7 => bus.flag(n as u8 - 3), // B1 - B4
+                    0x8 => false,                       // SKP (NBR)
+                    0x9 => !self.q,                     // BNQ
+                    0xA => self.d != 0,                 // BNZ
+                    0xB => !self.df,                    // BNF
+                    _ => !bus.flag(n as u8 - 0xB),      // BN1 - BN4
+                };
+                self.short_branch(bus, condition);
+            }
+            0x4 => {
+                // LDA
+                self.d = bus.read(self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1);
+            }
+            0x5 => bus.write(self.r[n], self.d), // STR
+            0x6 => match n {
+                0x0 => self.inc_rx(), // IRX
+                0x1..=0x7 => {
+                    // OUT
+                    let value = bus.read(self.rx());
+                    bus.output(n as u8, value);
+                    self.inc_rx();
+                }
+                0x8 => {} // undefined on the 1802
+                _ => {
+                    // INP
+                    let value = bus.input(n as u8 - 8);
+                    bus.write(self.rx(), value);
+                    self.d = value;
+                }
+            },
+            0x7 => match n {
+                0x0 | 0x1 => {
+                    // RET / DIS
+                    let value = bus.read(self.rx());
+                    self.inc_rx();
+                    self.x = value >> 4;
+                    self.p = value & 0x0F;
+                    self.ie = n == 0;
+                }
+                0x2 => {
+                    // LDXA
+                    self.d = bus.read(self.rx());
+                    self.inc_rx();
+                }
+                0x3 => {
+                    // STXD
+                    bus.write(self.rx(), self.d);
+                    let x = self.x as usize;
+                    self.r[x] = self.r[x].wrapping_sub(1);
+                }
+                0x4 => {
+                    // ADC
+                    let m = bus.read(self.rx());
+                    self.add(m, self.d, self.df);
+                }
+                0x5 => {
+                    // SDB
+                    let m = bus.read(self.rx());
+                    self.subtract(m, self.d, !self.df);
+                }
+                0x6 => {
+                    // SHRC
+                    let carry = self.d & 1 == 1;
+                    self.d = (self.d >> 1) | ((self.df as u8) << 7);
+                    self.df = carry;
+                }
+                0x7 => {
+                    // SMB
+                    let m = bus.read(self.rx());
+                    self.subtract(self.d, m, !self.df);
+                }
+                0x8 => bus.write(self.rx(), self.t), // SAV
+                0x9 => {
+                    // MARK
+                    self.t = (self.x << 4) | self.p;
+                    bus.write(self.r[2], self.t);
+                    self.x = self.p;
+                    self.r[2] = self.r[2].wrapping_sub(1);
+                }
+                0xA => self.q = false, // REQ
+                0xB => self.q = true,  // SEQ
+                0xC => {
+                    // ADCI
+                    let m = self.fetch(bus);
+                    self.add(m, self.d, self.df);
+                }
+                0xD => {
+                    // SDBI
+                    let m = self.fetch(bus);
+                    self.subtract(m, self.d, !self.df);
+                }
+                0xE => {
+                    // SHLC
+                    let carry = self.d & 0x80 != 0;
+                    self.d = (self.d << 1) | self.df as u8;
+                    self.df = carry;
+                }
+                _ => {
+                    // SMBI
+                    let m = self.fetch(bus);
+                    self.subtract(self.d, m, !self.df);
+                }
+            },
+            0x8 => self.d = self.r[n] as u8,        // GLO
+            0x9 => self.d = (self.r[n] >> 8) as u8, // GHI
+            0xA => self.r[n] = (self.r[n] & 0xFF00) | self.d as u16, // PLO
+            0xB => self.r[n] = (self.r[n] & 0x00FF) | ((self.d as u16) << 8), // PHI
+            0xC => {
+                match n {
+                    0x0 => self.long_branch(bus, true),        // LBR
+                    0x1 => self.long_branch(bus, self.q),      // LBQ
+                    0x2 => self.long_branch(bus, self.d == 0), // LBZ
+                    0x3 => self.long_branch(bus, self.df),     // LBDF
+                    0x4 => {}                                  // NOP
+                    0x5 => self.long_skip(!self.q),            // LSNQ
+                    0x6 => self.long_skip(self.d != 0),        // LSNZ
+                    0x7 => self.long_skip(!self.df),           // LSNF
+                    0x8 => self.long_skip(true),               // LSKP
+                    0x9 => self.long_branch(bus, !self.q),     // LBNQ
+                    0xA => self.long_branch(bus, self.d != 0), // LBNZ
+                    0xB => self.long_branch(bus, !self.df),    // LBNF
+                    0xC => self.long_skip(self.ie),            // LSIE
+                    0xD => self.long_skip(self.q),             // LSQ
+                    0xE => self.long_skip(self.d == 0),        // LSZ
+                    _ => self.long_skip(self.df),              // LSDF
+                }
+                // Long branches and skips take an extra machine cycle
+                return 3;
+            }
+            0xD => self.p = n as u8, // SEP
+            0xE => self.x = n as u8, // SEX
+            _ => {
+                // F0 - F7 operate on M(R(X)), F8 - FF on the immediate byte;
+                // SHR and SHL (F6, FE) have no operand, so SHL fetches nothing
+                let m = match n {
+                    0x0..=0x7 | 0xE => bus.read(self.rx()),
+                    _ => self.fetch(bus),
+                };
+                match n & 0x7 {
+                    0x0 => self.d = m,                      // LDX / LDI
+                    0x1 => self.d |= m,                     // OR / ORI
+                    0x2 => self.d &= m,                     // AND / ANI
+                    0x3 => self.d ^= m,                     // XOR / XRI
+                    0x4 => self.add(m, self.d, false),      // ADD / ADI
+                    0x5 => self.subtract(m, self.d, false), // SD / SDI
+                    0x6 if n == 0x6 => {
+                        // SHR
+                        self.df = self.d & 1 == 1;
+                        self.d >>= 1;
+                    }
+                    0x6 => {
+                        // SHL
+                        self.df = self.d & 0x80 != 0;
+                        self.d <<= 1;
+                    }
+                    _ => self.subtract(self.d, m, false), // SM / SMI
+                }
+            }
+        }
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Ram([u8; 0x100]);
+
+    impl Bus for Ram {
+        fn read(&mut self, addr: u16) -> u8 {
+            self.0[addr as usize & 0xFF]
+        }
+
+        fn write(&mut self, addr: u16, value: u8) {
+            self.0[addr as usize & 0xFF] = value;
+        }
+    }
+
+    // Run `code` from 0 with R(X) = R1 pointing at `m` in 0x80, and D = `d`;
+    // returns the core after one instruction
+    fn run(code: &[u8], d: u8, m: u8) -> Cdp1802 {
+        let mut ram = Ram([0; 0x100]);
+        ram.0[..code.len()].copy_from_slice(code);
+        ram.0[0x80] = m;
+        let mut cpu = Cdp1802::new();
+        cpu.x = 1;
+        cpu.r[1] = 0x80;
+        cpu.d = d;
+        cpu.step(&mut ram);
+        cpu
+    }
+
+    #[test]
+    fn f_group_operands_and_results() {
+        // (opcode, D after, DF after) with D = 0x81, M(R(X)) = 0x0F and an immediate of 0x0F
+        let cases = [
+            (0xF0, 0x0F, false), // LDX
+            (0xF1, 0x8F, false), // OR
+            (0xF2, 0x01, false), // AND
+            (0xF3, 0x8E, false), // XOR
+            (0xF4, 0x90, false), // ADD
+            (0xF5, 0x8E, false), // SD: 0x0F - 0x81 borrows
+            (0xF6, 0x40, true),  // SHR
+            (0xF7, 0x72, true),  // SM
+            (0xF8, 0x0F, false), // LDI
+            (0xF9, 0x8F, false), // ORI
+            (0xFA, 0x01, false), // ANI
+            (0xFB, 0x8E, false), // XRI
+            (0xFC, 0x90, false), // ADI
+            (0xFD, 0x8E, false), // SDI
+            (0xFE, 0x02, true),  // SHL
+            (0xFF, 0x72, true),  // SMI
+        ];
+        for (opcode, d, df) in cases {
+            let cpu = run(&[opcode, 0x0F], 0x81, 0x0F);
+            let immediate = opcode >= 0xF8 && opcode != 0xFE;
+            assert_eq!(cpu.d, d, "D after {:02X}", opcode);
+            assert_eq!(cpu.df, df, "DF after {:02X}", opcode);
+            assert_eq!(cpu.r[0], 1 + immediate as u16, "R(P) after {:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn shl_does_not_swallow_the_next_opcode() {
+        // LDI 0x81; SHL; LDI 7
+        let mut ram = Ram([0; 0x100]);
+        ram.0[..5].copy_from_slice(&[0xF8, 0x81, 0xFE, 0xF8, 0x07]);
+        let mut cpu = Cdp1802::new();
+        for _ in 0..3 {
+            cpu.step(&mut ram);
+        }
+        assert_eq!(cpu.d, 0x07);
+        assert_eq!(cpu.r[0], 5);
+    }
+}
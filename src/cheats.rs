@@ -0,0 +1,400 @@
+/*!
+ * @file cheats.rs
+ * @brief Cheat codes: memory held at a value, registers set every frame and
+ *        opcodes replaced, kept in a per-ROM cheat file
+ *
+ * One cheat per line: a name, what it does, and `off` at the end if it starts
+ * out disabled. Numbers are decimal or 0x-prefixed hex; `#` starts a comment:
+ *
+ * ```text
+ * lives      freeze mem[0x2F3] = 3      # written back between instructions
+ * speed      set v5 = 0x10              # at the start of every frame
+ * no-deaths  replace 0x2A4 = 0x1300 off # the opcode at 0x2A4 becomes 1300
+ * ```
+ *
+ * The file only lists the cheats; Cpu::set_cheats turns the enabled ones on.
+ * A save state records which were on when it was taken, and an input movie
+ * (movie.rs) the cheats a run started with and when each was toggled.
+ * Each ROM has its own file in the user config directory, keyed by SHA-1
+ * like its symbols.
+ */
+use crate::romdb::config_dir;
+use crate::script::{parse_number, parse_operand, Operand, ParseError};
+use crate::sha1::sha1_hex;
+use std::fmt;
+use std::path::PathBuf;
+
+/**
+ * @brief What an enabled cheat does to the Cpu
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    // memory[addr] goes back to `value` before every instruction
+    Freeze { addr: usize, value: u8 },
+    // VX is set to `value` at the start of every frame
+    SetRegister { x: usize, value: u8 },
+    // The opcode at addr is `opcode` for as long as the cheat is on
+    Replace { addr: usize, opcode: u16 },
+}
+
+fn parse_value(word: &str, max: usize) -> Result<usize, String> {
+    match parse_number(word)? {
+        value if value <= max => Ok(value),
+        value => Err(format!("{} is more than {:#x}", value, max)),
+    }
+}
+
+impl Effect {
+    // `freeze mem[A] = N`, `set vX = N` or `replace A = NNNN`, split into words
+    pub fn parse(words: &[&str]) -> Result<Effect, String> {
+        match *words {
+            ["freeze", target, "=", value] => match parse_operand(target)? {
+                Operand::Mem(addr) => Ok(Effect::Freeze {
+                    addr,
+                    value: parse_value(value, 0xFF)? as u8,
+                }),
+                _ => Err(format!("can only freeze mem[addr], not `{}`", target)),
+            },
+            ["set", target, "=", value] => match parse_operand(target)? {
+                Operand::V(x) => Ok(Effect::SetRegister {
+                    x,
+                    value: parse_value(value, 0xFF)? as u8,
+                }),
+                _ => Err(format!("can only set a register, not `{}`", target)),
+            },
+            ["replace", addr, "=", opcode] => Ok(Effect::Replace {
+                addr: parse_value(addr, 0xFF_FFFF)?, // MegaChip's 16 MB
+                opcode: parse_value(opcode, 0xFFFF)? as u16,
+            }),
+            _ => Err(format!("unknown cheat `{}`", words.join(" "))),
+        }
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Effect::Freeze { addr, value } => {
+                write!(f, "freeze {} = {:#04x}", Operand::Mem(addr), value)
+            }
+            Effect::SetRegister { x, value } => {
+                write!(f, "set {} = {:#04x}", Operand::V(x), value)
+            }
+            Effect::Replace { addr, opcode } => {
+                write!(f, "replace {:#05x} = {:#06x}", addr, opcode)
+            }
+        }
+    }
+}
+
+/**
+ * @brief A named effect, and whether it is on
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub name: String,
+    pub effect: Effect,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    // A line of a cheat file without its comment, split into words
+    pub fn parse(words: &[&str]) -> Result<Cheat, String> {
+        let Some((name, rest)) = words.split_first() else {
+            return Err("missing cheat name".to_string());
+        };
+        let (rest, enabled) = match rest {
+            [rest @ .., "off"] => (rest, false),
+            rest => (rest, true),
+        };
+        Ok(Cheat {
+            name: name.to_string(),
+            effect: Effect::parse(rest)?,
+            enabled,
+        })
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.effect)?;
+        if !self.enabled {
+            write!(f, " off")?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * @brief A ROM's cheats, in the order of its cheat file
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(source: &str) -> Result<Cheats, ParseError> {
+        let mut cheats = Cheats::new();
+        for (index, line) in source.lines().enumerate() {
+            let code = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = code.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            let cheat = Cheat::parse(&words).map_err(|message| ParseError {
+                line: index + 1,
+                message,
+            })?;
+            cheats.insert(cheat);
+        }
+        Ok(cheats)
+    }
+
+    // cheats/<SHA-1 of the ROM>.cht in the user config directory
+    pub fn path(program: &[u8]) -> Option<PathBuf> {
+        Some(
+            config_dir()?
+                .join("cheats")
+                .join(format!("{}.cht", sha1_hex(program))),
+        )
+    }
+
+    // Add `cheat`, replacing one with the same name
+    pub fn insert(&mut self, cheat: Cheat) {
+        match self
+            .cheats
+            .iter_mut()
+            .find(|known| known.name == cheat.name)
+        {
+            Some(known) => *known = cheat,
+            None => self.cheats.push(cheat),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Cheat> {
+        let index = self.cheats.iter().position(|cheat| cheat.name == name)?;
+        Some(self.cheats.remove(index))
+    }
+
+    // Flip a cheat on or off; returns whether it is now on, None if there is no such cheat
+    pub fn toggle(&mut self, name: &str) -> Option<bool> {
+        let cheat = self.cheats.iter_mut().find(|cheat| cheat.name == name)?;
+        cheat.enabled = !cheat.enabled;
+        Some(cheat.enabled)
+    }
+
+    // Turn on exactly the cheats whose effects are in `active`, e.g. the ones
+    // a save state had on (Cpu::cheats)
+    pub fn match_active(&mut self, active: &[Effect]) {
+        for cheat in &mut self.cheats {
+            cheat.enabled = active.contains(&cheat.effect);
+        }
+    }
+
+    // What to pass to Cpu::set_cheats
+    pub fn enabled(&self) -> Vec<Effect> {
+        self.cheats
+            .iter()
+            .filter(|cheat| cheat.enabled)
+            .map(|cheat| cheat.effect)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cheats.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> + '_ {
+        self.cheats.iter()
+    }
+}
+
+// In the file format parse reads
+impl fmt::Display for Cheats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for cheat in &self.cheats {
+            writeln!(f, "{}", cheat)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::timing::Timing;
+
+    const FILE: &str = "\
+# lives and speed
+lives      freeze mem[0x2F3] = 3      # written back between instructions
+
+speed      set vA = 0x10
+no-deaths  replace 0x2A4 = 0x1300 off
+";
+
+    #[test]
+    fn parse_file() {
+        let cheats = Cheats::parse(FILE).unwrap();
+        assert_eq!(cheats.len(), 3);
+        assert_eq!(
+            cheats.enabled(),
+            [
+                Effect::Freeze {
+                    addr: 0x2F3,
+                    value: 3
+                },
+                Effect::SetRegister { x: 10, value: 0x10 },
+            ]
+        );
+        let replace = cheats.iter().last().unwrap();
+        assert_eq!(replace.name, "no-deaths");
+        assert!(!replace.enabled);
+        assert_eq!(
+            replace.effect,
+            Effect::Replace {
+                addr: 0x2A4,
+                opcode: 0x1300
+            }
+        );
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let cheats = Cheats::parse(FILE).unwrap();
+        let written = cheats.to_string();
+        assert_eq!(
+            written.lines().next(),
+            Some("lives freeze mem[0x2f3] = 0x03")
+        );
+        assert_eq!(Cheats::parse(&written).unwrap(), cheats);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |source: &str| Cheats::parse(source).unwrap_err();
+        assert_eq!(error("a freeze v1 = 3").line, 1);
+        assert_eq!(error("\na set mem[0x300] = 3").line, 2);
+        assert!(error("a freeze mem[0x300] = 256")
+            .message
+            .contains("more than 0xff"));
+        assert!(error("a replace 0x300 = 0x10000")
+            .message
+            .contains("more than 0xffff"));
+        assert!(error("a teleport").message.contains("unknown cheat"));
+    }
+
+    #[test]
+    fn insert_toggle_and_match() {
+        let mut cheats = Cheats::parse(FILE).unwrap();
+        cheats.insert(Cheat {
+            name: "speed".to_string(),
+            effect: Effect::SetRegister { x: 10, value: 0x20 },
+            enabled: true,
+        });
+        assert_eq!(cheats.len(), 3);
+        assert_eq!(cheats.toggle("lives"), Some(false));
+        assert_eq!(cheats.toggle("nothing"), None);
+        assert_eq!(
+            cheats.enabled(),
+            [Effect::SetRegister { x: 10, value: 0x20 }]
+        );
+
+        let replace = Effect::Replace {
+            addr: 0x2A4,
+            opcode: 0x1300,
+        };
+        cheats.match_active(&[replace]);
+        assert_eq!(cheats.enabled(), [replace]);
+        assert!(cheats.remove("no-deaths").is_some());
+        assert!(cheats.enabled().is_empty());
+    }
+
+    // LD I, 0x300; loop: ADD V0, 1; LD [I], V0; LD V0, [I]; JP loop
+    const COUNTER: [u8; 10] = [0xA3, 0x00, 0x70, 0x01, 0xF0, 0x55, 0xF0, 0x65, 0x12, 0x02];
+
+    fn counter() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.set_idle_skip(false);
+        cpu.load_program(&COUNTER);
+        cpu
+    }
+
+    #[test]
+    fn freeze_between_instructions() {
+        let mut cpu = counter();
+        cpu.set_cheats(&[Effect::Freeze {
+            addr: 0x300,
+            value: 3,
+        }]);
+        for _ in 0..40 {
+            cpu.cycle([false; 16]);
+            assert_eq!(cpu.memory()[0x300], 3);
+            if cpu.program_counter() == 0x208 {
+                assert_eq!(cpu.v_registers()[0], 3);
+            }
+        }
+    }
+
+    #[test]
+    fn set_register_every_frame() {
+        let mut cpu = counter();
+        cpu.set_timing(Timing::Instructions { per_frame: 10 });
+        cpu.set_cheats(&[Effect::SetRegister { x: 5, value: 0x10 }]);
+        assert_eq!(cpu.v_registers()[5], 0x10);
+        cpu.set_cheats(&[Effect::SetRegister { x: 0, value: 0x80 }]);
+        for _ in 0..3 {
+            let mut changed = false;
+            while !cpu.cycle([false; 16]).vblank {
+                changed |= cpu.v_registers()[0] != 0x80;
+            }
+            assert!(changed);
+            assert_eq!(cpu.v_registers()[0], 0x80);
+        }
+    }
+
+    #[test]
+    fn replace_and_restore() {
+        let mut cpu = counter();
+        let replace = Effect::Replace {
+            addr: 0x202,
+            opcode: 0x7002,
+        };
+        cpu.set_cheats(&[replace]);
+        assert_eq!(&cpu.memory()[0x202..0x204], [0x70, 0x02]);
+        assert_eq!(cpu.cheats(), [replace]);
+        cpu.set_cheats(&[]);
+        assert_eq!(&cpu.memory()[0x202..0x204], [0x70, 0x01]);
+        assert!(cpu.cheats().is_empty());
+    }
+
+    // A frozen byte the program keeps overwriting, with the jit on
+    #[cfg(feature = "jit")]
+    #[test]
+    fn freeze_with_the_jit() {
+        use crate::jit::JitMode;
+
+        let mut cpu = counter();
+        cpu.set_timing(Timing::Instructions { per_frame: 100 });
+        cpu.set_jit(JitMode::Differential);
+        cpu.set_cheats(&[Effect::Freeze {
+            addr: 0x300,
+            value: 3,
+        }]);
+        for _ in 0..1000 {
+            cpu.cycle([false; 16]);
+            assert_eq!(cpu.memory()[0x300], 3);
+            assert!(cpu.v_registers()[0] <= 4);
+        }
+        assert_eq!(cpu.jit_stats().map(|stats| stats.instructions), Some(0));
+    }
+}
@@ -1,34 +1,127 @@
-use rand;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
+use crate::cdp1802::{Bus, Cdp1802};
+use crate::cheats::Effect;
+use crate::decode_cache::DecodeCache;
+use crate::display::{Color, Display, BACKGROUND_COLORS};
 use crate::font;
+use crate::instruction::{self, Instruction};
+#[cfg(feature = "jit")]
+use crate::jit::{Jit, JitMode, JitStats, Registers};
+use crate::megachip::{
+    BlendMode, DigitisedSound, MegaChip, FONT_COLOR, MEGACHIP_HEIGHT, MEGACHIP_WIDTH,
+};
+use crate::platform::{Platform, HIRES_CLEAR_SCREEN};
+use crate::quirks::{IndexIncrement, KeyWait, MemoryLayout, Quirks};
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::stack::{Stack, StackError};
+use crate::timing::Timing;
 use font::FONT_SET;
+use std::fmt;
+use std::ops::Range;
 
-const MEMORY_SIZE: usize = 4096;
+pub const MEMORY_SIZE: usize = 4096;
 
-const DISPLAY_HEIGHT: usize = 32;
-const DISPLAY_WIDTH: usize = 64;
+pub const DISPLAY_HEIGHT: usize = 32;
+pub const DISPLAY_WIDTH: usize = 64;
 
-const REGISTER_COUNT: usize = 16;
-const STACK_SIZE: usize = 16;
+pub const REGISTER_COUNT: usize = 16;
 const OPCODE_SIZE: usize = 2;
 
-const PROGRAM_START: usize = 0x200;
+pub const PROGRAM_START: usize = 0x200;
+
+// COSMAC VIP memory map, used with MemoryLayout::Vip. A taller display (hires)
+// takes more of the top of memory and moves everything below it down to match.
+pub const VIP_STACK_START: usize = 0xEA0;
+pub const VIP_DISPLAY_START: usize = 0xF00;
+// V0 - VF as the VIP interpreter keeps them, where 0NNN routines expect them
+pub const VIP_VARIABLES_START: usize = 0xEF0;
+// Top of the 1802 data stack (R2) handed to 0NNN routines
+const VIP_DATA_STACK_TOP: usize = 0xECF;
+
+// A machine-code routine that runs this long is assumed to never return
+const MACHINE_CODE_CYCLE_LIMIT: u32 = 10_000_000;
+
+const SAVE_STATE_VERSION: u8 = 10;
 
 pub struct Cpu {
-    memory: [u8; MEMORY_SIZE],
+    memory: Vec<u8>, // Quirks::platform's memory_size bytes, at least MEMORY_SIZE
     v_registers: [u8; REGISTER_COUNT], // V0 - VF
     index_register: usize,
     program_counter: usize,
-    stack: [usize; 16],
-    stack_pointer: usize,
+    stack: Stack<CallFrame>,
     delay_timer: u8,
     sound_timer: u8,
     keypad: [bool; 16],
+    keypad2: [bool; 16], // CHIP-8X second keypad
     keypad_waiting: bool,
     keypad_register: usize,
-    display: [[u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    keypad_pressed: Option<usize>, // key that went down during FX0A, awaiting release
+    display: Display,
     display_changed: bool,
+    frame_cycles: u32, // cost used up in the current frame, see timing.rs
+    vblank: bool,
+    quirks: Quirks,
+    fault: Option<Fault>,
+    megachip: Option<MegaChip>, // Some on Platform::MegaChip
+    idle_skip: bool,            // see skip_idle_loop
+    idle_cycles_skipped: u64,
+    cycles: u64,          // cost charged since power on
+    rng: StdRng,          // CXKK
+    decoded: DecodeCache, // memory, decoded; kept in step by every write
+    cheats: Vec<ActiveCheat>,
+    #[cfg(feature = "jit")]
+    jit_mode: JitMode,
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>, // Some unless jit_mode is Off
+}
+
+/**
+ * @brief A cheat that is on, see cheats.rs
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ActiveCheat {
+    effect: Effect,
+    replaced: Option<[u8; 2]>, // what Effect::Replace last wrote over
+}
+
+/**
+ * @brief One entry of the call stack: the 2NNN at `caller` jumped to `callee`
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallFrame {
+    pub caller: usize,
+    pub callee: usize,
+}
+
+impl CallFrame {
+    pub fn return_address(&self) -> usize {
+        self.caller + OPCODE_SIZE
+    }
+}
+
+/**
+ * @brief Why the Cpu stopped; `pc` is the address of the faulting instruction
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Stack { pc: usize, error: StackError },
+    // The 0NNN at `pc` called machine code at `addr` that never gave control back
+    MachineCode { pc: usize, addr: usize },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Stack { pc, error } => write!(f, "{} at {:#05x}", error, pc),
+            Fault::MachineCode { pc, addr } => write!(
+                f,
+                "machine code at {:#05x} did not return, called at {:#05x}",
+                addr, pc
+            ),
+        }
+    }
 }
 
 /**
@@ -38,12 +131,20 @@ enum PcInstructions {
     Next,
     Skip,
     Jump(usize),
+    Fault(Fault),
 }
 
-pub struct OutputState {
-    display: [[u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
-    display_changed: bool,
-    beep: bool,
+/**
+ * @brief Everything a frontend needs after a cycle. The display is borrowed
+ * from the Cpu rather than copied; clone it to keep a frame past the next cycle.
+ */
+pub struct OutputState<'a> {
+    pub display: &'a Display,
+    pub display_changed: bool,
+    pub beep: bool,
+    pub fault: Option<Fault>,
+    // This cycle finished a 60 Hz frame and the timers ticked
+    pub vblank: bool,
 }
 
 impl PcInstructions {
@@ -57,10 +158,20 @@ impl PcInstructions {
     }
 }
 
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Cpu {
     pub fn new() -> Self {
+        Self::with_quirks(Quirks::default())
+    }
+
+    pub fn with_quirks(quirks: Quirks) -> Self {
         // Load Font Set
-        let mut memory: [u8; MEMORY_SIZE] = [0; MEMORY_SIZE];
+        let mut memory = vec![0; quirks.platform.memory_size()];
         for (i, &byte) in FONT_SET.iter().enumerate() {
             memory[i] = byte;
         }
@@ -70,107 +181,490 @@ impl Cpu {
             memory,
             v_registers: [0; REGISTER_COUNT], // V0 - VF init to 0
             index_register: 0,
-            program_counter: PROGRAM_START as usize,
-            stack: [0; STACK_SIZE],
-            stack_pointer: 0,
+            program_counter: quirks.platform.entry_point(),
+            stack: match quirks.stack_depth {
+                Some(depth) => Stack::with_capacity(depth),
+                None => Stack::new(),
+            },
             delay_timer: 0,
             sound_timer: 0,
             keypad: [false; 16],
+            keypad2: [false; 16],
             keypad_waiting: false,
             keypad_register: 0,
-            display: [[0; DISPLAY_WIDTH]; DISPLAY_HEIGHT], // 64x32 display init to 0 (clear)
+            keypad_pressed: None,
+            display: quirks.platform.new_display(), // init to 0 (clear)
             display_changed: false,
+            frame_cycles: 0,
+            vblank: false,
+            megachip: (quirks.platform == Platform::MegaChip).then(MegaChip::new),
+            decoded: DecodeCache::new(quirks.platform),
+            quirks,
+            fault: None,
+            idle_skip: true,
+            idle_cycles_skipped: 0,
+            cycles: 0,
+            rng: StdRng::from_entropy(),
+            cheats: Vec::new(),
+            #[cfg(feature = "jit")]
+            jit_mode: JitMode::Off,
+            #[cfg(feature = "jit")]
+            jit: None,
         }
     }
 
     pub fn load_program(&mut self, program: &[u8]) {
+        let start = self.quirks.platform.load_address();
         for (i, &byte) in program.iter().enumerate() {
-            if i >= MEMORY_SIZE - PROGRAM_START {
+            if i >= self.memory.len() - start {
                 panic!("Program too large to fit in memory");
             }
 
-            self.memory[PROGRAM_START + i] = byte;
+            self.memory[start + i] = byte;
+        }
+        self.memory_written(start..start + program.len());
+    }
+
+    // State of the CHIP-8X second keypad, read by EXF2 / EXF5
+    pub fn set_second_keypad(&mut self, keypad: [bool; 16]) {
+        self.keypad2 = keypad;
+    }
+
+    // Turn fast-forwarding through delay timer polling loops on or off (on by
+    // default). The results are the same either way, but with it on a single
+    // cycle can run many instructions, which a debugger stepping one
+    // instruction at a time doesn't want.
+    pub fn set_idle_skip(&mut self, enabled: bool) {
+        self.idle_skip = enabled;
+    }
+
+    // Make CXKK repeatable: the same seed gives the same random numbers. Until
+    // this is called they come from a seed drawn from the OS.
+    pub fn seed_random(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    // Turn on exactly the cheats in `effects`; see cheats.rs. Replaced opcodes
+    // come back as the cheats that replaced them go off.
+    pub fn set_cheats(&mut self, effects: &[Effect]) {
+        // Latest first, so overlapping replacements unwind in order
+        for index in (0..self.cheats.len()).rev() {
+            if effects.contains(&self.cheats[index].effect) {
+                continue;
+            }
+            let cheat = self.cheats.remove(index);
+            if let (Effect::Replace { addr, .. }, Some([high, low])) =
+                (cheat.effect, cheat.replaced)
+            {
+                self.poke(addr, high);
+                self.poke(addr + 1, low);
+            }
+        }
+        for &effect in effects {
+            if !self.cheats.iter().any(|cheat| cheat.effect == effect) {
+                self.cheats.push(ActiveCheat {
+                    effect,
+                    replaced: None,
+                });
+            }
         }
+        self.apply_cheats(true);
+    }
+
+    // The cheats that are on, in the order they came on
+    pub fn cheats(&self) -> Vec<Effect> {
+        self.cheats.iter().map(|cheat| cheat.effect).collect()
+    }
+
+    // Run hot straight-line code compiled to native code, see jit.rs. Off by
+    // default; like idle skipping, a cycle can then run many instructions.
+    #[cfg(feature = "jit")]
+    pub fn set_jit(&mut self, mode: JitMode) {
+        self.jit_mode = mode;
+        self.jit = match mode {
+            JitMode::Off => None,
+            _ => self.jit.take().or_else(|| Some(Jit::new(&self.quirks))),
+        };
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn jit_stats(&self) -> Option<JitStats> {
+        self.jit.as_ref().map(Jit::stats)
+    }
+
+    // Change the cost model between frames, e.g. instructions per frame at runtime
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.quirks.timing = timing;
+    }
+
+    /*
+     * Read-only accessors, used by the debugger and tools
+     */
+
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
     }
 
-    fn fetch_opcode(&self) -> u16 {
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn v_registers(&self) -> &[u8; REGISTER_COUNT] {
+        &self.v_registers
+    }
+
+    pub fn index_register(&self) -> usize {
+        self.index_register
+    }
+
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+
+    pub fn stack(&self) -> &Stack<CallFrame> {
+        &self.stack
+    }
+
+    // Active calls, innermost first
+    pub fn backtrace(&self) -> Vec<CallFrame> {
+        self.stack.iter().rev().copied().collect()
+    }
+
+    // Set once the Cpu has stopped on an error; cycle does nothing afterwards
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    // Instructions not executed because skip_idle_loop fast-forwarded over them
+    pub fn idle_cycles_skipped(&self) -> u64 {
+        self.idle_cycles_skipped
+    }
+
+    // Cost charged since power on, in Quirks::timing's units: instructions, or
+    // VIP machine cycles. Idle loops skipped over count as if they had run.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // The display being drawn on; in MegaChip mode frontends see the frame
+    // 00E0 last presented instead (OutputState::display)
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn megachip(&self) -> Option<&MegaChip> {
+        self.megachip.as_ref()
+    }
+
+    // The digitised sound samples played during the last frame, and their rate
+    pub fn digitised_sound(&self) -> Option<(u32, &[u8])> {
+        let sound = self.megachip.as_ref()?.sound.as_ref()?;
+        let (start, end) = sound.frame;
+        Some((sound.rate, &self.memory[start..end]))
+    }
+
+    pub fn keypad_waiting(&self) -> bool {
+        self.keypad_waiting
+    }
+
+    // Decode the instruction the program counter points to, without executing it
+    pub fn current_instruction(&self) -> Instruction {
+        instruction::decode_for(self.quirks.platform, self.fetch_opcode())
+    }
+
+    pub fn fetch_opcode(&self) -> u16 {
         //each opcode is 2 bytes long, PC points to the first one
-        let first_byte = self.memory[self.program_counter as usize] as u16;
-        let second_byte = self.memory[(self.program_counter + 1) as usize] as u16;
+        let first_byte = self.memory[self.program_counter] as u16;
+        let second_byte = self.memory[(self.program_counter + 1) % self.memory.len()] as u16;
 
         // return the two bytes as a single opcode of 2 words
-        return (first_byte << 8) | second_byte;
+        (first_byte << 8) | second_byte
     }
 
-    fn exec_opcode(&mut self, opcode: u16) -> PcInstructions {
-        // nibbles = HEX Digits of the opcode
-        let nibbles = (
-            (opcode & 0xF000) >> 12 as u8,
-            (opcode & 0x0F00) >> 8 as u8,
-            (opcode & 0x00F0) >> 4 as u8,
-            (opcode & 0x000F) as u8,
-        );
+    fn exec_opcode(&mut self, instruction: Instruction) -> PcInstructions {
+        // match to instruction, if no match,go to next byte in the program
+        match instruction {
+            Instruction::Cls => self.op_00e0(),
+            Instruction::Ret => self.op_00ee(),
+            Instruction::Jp { nnn } => self.op_1nnn(nnn),
+            Instruction::Call { nnn } => self.op_2nnn(nnn),
+            Instruction::SeImm { x, kk } => self.op_3xkk(x, kk),
+            Instruction::SneImm { x, kk } => self.op_4xkk(x, kk),
+            Instruction::SeReg { x, y } => self.op_5xy0(x, y),
+            Instruction::LdImm { x, kk } => self.op_6xkk(x, kk),
+            Instruction::AddImm { x, kk } => self.op_7xkk(x, kk),
+            Instruction::LdReg { x, y } => self.op_8xy0(x, y),
+            Instruction::Or { x, y } => self.op_8xy1(x, y),
+            Instruction::And { x, y } => self.op_8xy2(x, y),
+            Instruction::Xor { x, y } => self.op_8xy3(x, y),
+            Instruction::AddReg { x, y } => self.op_8xy4(x, y),
+            Instruction::Sub { x, y } => self.op_8xy5(x, y),
+            Instruction::Shr { x, y } => self.op_8xy6(x, y),
+            Instruction::Subn { x, y } => self.op_8xy7(x, y),
+            Instruction::Shl { x, y } => self.op_8xye(x, y),
+            Instruction::SneReg { x, y } => self.op_9xy0(x, y),
+            Instruction::LdI { nnn } => self.op_annn(nnn),
+            Instruction::JpV0 { nnn } => self.op_bnnn(nnn),
+            Instruction::Rnd { x, kk } => self.op_cxkk(x, kk),
+            Instruction::Drw { x, y, n } => self.op_dxyn(x, y, n),
+            Instruction::Skp { x } => self.op_ex9e(x),
+            Instruction::Sknp { x } => self.op_exa1(x),
+            Instruction::LdVxDt { x } => self.op_fx07(x),
+            Instruction::LdVxK { x } => self.op_fx0a(x),
+            Instruction::LdDtVx { x } => self.op_fx15(x),
+            Instruction::LdStVx { x } => self.op_fx18(x),
+            Instruction::AddIVx { x } => self.op_fx1e(x),
+            Instruction::LdFVx { x } => self.op_fx29(x),
+            Instruction::LdBVx { x } => self.op_fx33(x),
+            Instruction::LdIVx { x } => self.op_fx55(x),
+            Instruction::LdVxI { x } => self.op_fx65(x),
+            Instruction::Sys { nnn } => self.op_0nnn(nnn),
+            Instruction::Col { x, y, n } => self.op_bxyn(x, y, n),
+            Instruction::Bgc => self.op_02a0(),
+            Instruction::Skp2 { x } => self.op_exf2(x),
+            Instruction::Sknp2 { x } => self.op_exf5(x),
+            Instruction::MegaOff => self.op_0010(),
+            Instruction::MegaOn => self.op_0011(),
+            Instruction::LdIHi { nn } => self.op_01nn(nn),
+            Instruction::LdPal { nn } => self.op_02nn(nn),
+            Instruction::SprW { nn } => self.op_03nn(nn),
+            Instruction::SprH { nn } => self.op_04nn(nn),
+            Instruction::Alpha { nn } => self.op_05nn(nn),
+            Instruction::DigiSnd { n } => self.op_060n(n),
+            Instruction::StopSnd => self.op_0700(),
+            Instruction::BMode { nn } => self.op_08nn(nn),
+            Instruction::Unknown(_) => PcInstructions::Next,
+        }
+    }
 
-        // break apart parameters o the instruction
-        let nnn = (opcode & 0x0FFF) as usize;
-        let kk = (opcode & 0x00FF) as u8;
-        let x = nibbles.1 as usize;
-        let y = nibbles.2 as usize;
-        let n = nibbles.3 as usize;
+    /*
+     * MEMORY LAYOUT - with MemoryLayout::Vip the stack and display are mirrored
+     * into memory, so writes from either side are seen by the other
+     */
 
-        // match to instruction, if no match,go to next byte in the program
-        let pc_change = match nibbles {
-            (0x00, 0x00, 0x0e, 0x00) => self.op_00e0(),
-            (0x00, 0x00, 0x0e, 0x0e) => self.op_00ee(),
-            (0x01, _, _, _) => self.op_1nnn(nnn),
-            (0x02, _, _, _) => self.op_2nnn(nnn),
-            (0x03, _, _, _) => self.op_3xkk(x, kk),
-            (0x04, _, _, _) => self.op_4xkk(x, kk),
-            (0x05, _, _, 0x00) => self.op_5xy0(x, y),
-            (0x06, _, _, _) => self.op_6xkk(x, kk),
-            (0x07, _, _, _) => self.op_7xkk(x, kk),
-            (0x08, _, _, 0x00) => self.op_8xy0(x, y),
-            (0x08, _, _, 0x01) => self.op_8xy1(x, y),
-            (0x08, _, _, 0x02) => self.op_8xy2(x, y),
-            (0x08, _, _, 0x03) => self.op_8xy3(x, y),
-            (0x08, _, _, 0x04) => self.op_8xy4(x, y),
-            (0x08, _, _, 0x05) => self.op_8xy5(x, y),
-            (0x08, _, _, 0x06) => self.op_8x06(x),
-            (0x08, _, _, 0x07) => self.op_8xy7(x, y),
-            (0x08, _, _, 0x0e) => self.op_8x0e(x),
-            (0x09, _, _, 0x00) => self.op_9xy0(x, y),
-            (0x0a, _, _, _) => self.op_annn(nnn),
-            (0x0b, _, _, _) => self.op_bnnn(nnn),
-            (0x0c, _, _, _) => self.op_cxkk(x, kk),
-            (0x0d, _, _, _) => self.op_dxyn(x, y, n),
-            (0x0e, _, 0x09, 0x0e) => self.op_ex9e(x),
-            (0x0e, _, 0x0a, 0x01) => self.op_exa1(x),
-            (0x0f, _, 0x00, 0x07) => self.op_fx07(x),
-            (0x0f, _, 0x00, 0x0a) => self.op_fx0a(x),
-            (0x0f, _, 0x01, 0x05) => self.op_fx15(x),
-            (0x0f, _, 0x01, 0x08) => self.op_fx18(x),
-            (0x0f, _, 0x01, 0x0e) => self.op_fx1e(x),
-            (0x0f, _, 0x02, 0x09) => self.op_fx29(x),
-            (0x0f, _, 0x03, 0x03) => self.op_fx33(x),
-            (0x0f, _, 0x05, 0x05) => self.op_fx55(x),
-            (0x0f, _, 0x06, 0x05) => self.op_fx65(x),
-            _ => PcInstructions::Next,
-        };
+    // Start of the display in memory: 0xF00 for 64x32, 0xE00 for 64x64
+    fn vip_display_start(&self) -> usize {
+        let (width, height) = self.quirks.platform.display_size();
+        MEMORY_SIZE - width * height / 8
+    }
+
+    // Whether the display has a copy in memory; MegaChip mode's never does
+    fn mirrors_display(&self) -> bool {
+        self.quirks.memory_layout == MemoryLayout::Vip && self.display.truecolor.is_none()
+    }
+
+    // How far the stack and variables sit below their 64x32 addresses
+    fn vip_offset(&self) -> usize {
+        VIP_DISPLAY_START - self.vip_display_start()
+    }
+
+    fn vip_variables_start(&self) -> usize {
+        VIP_VARIABLES_START - self.vip_offset()
+    }
+
+    // Write one byte of memory, keeping the display in step with its copy in memory
+    fn write_memory(&mut self, addr: usize, value: u8) {
+        self.memory[addr] = value;
+        self.memory_written(addr..addr + 1);
+
+        let display_start = self.vip_display_start();
+        if self.mirrors_display() && addr >= display_start && addr < MEMORY_SIZE {
+            let offset = addr - display_start;
+            let bytes_per_row = self.display.width / 8;
+            let (y, x) = (offset / bytes_per_row, (offset % bytes_per_row) * 8);
+            for bit in 0..8 {
+                self.display.set_pixel(x + bit, y, (value >> (7 - bit)) & 1);
+            }
+            self.display_changed = true;
+        }
+    }
+
+    // write_memory, unless `addr` is past the end of memory or already holds `value`
+    fn poke(&mut self, addr: usize, value: u8) {
+        if self.memory.get(addr).is_some_and(|&byte| byte != value) {
+            self.write_memory(addr, value);
+        }
+    }
+
+    // Whether any cheat holds memory at a value
+    #[cfg(feature = "jit")]
+    fn freezing(&self) -> bool {
+        self.cheats
+            .iter()
+            .any(|cheat| matches!(cheat.effect, Effect::Freeze { .. }))
+    }
+
+    // Put the cheats' values back wherever the program changed them; registers
+    // only at the start of a frame
+    fn apply_cheats(&mut self, frame_start: bool) {
+        for index in 0..self.cheats.len() {
+            match self.cheats[index].effect {
+                Effect::Freeze { addr, value } => self.poke(addr, value),
+                Effect::SetRegister { x, value } => {
+                    if frame_start {
+                        self.v_registers[x] = value;
+                    }
+                }
+                Effect::Replace { addr, opcode } => {
+                    let Some(&[high, low]) = self.memory.get(addr..addr + 2) else {
+                        continue;
+                    };
+                    if u16::from_be_bytes([high, low]) != opcode {
+                        self.cheats[index].replaced = Some([high, low]);
+                        let [high, low] = opcode.to_be_bytes();
+                        self.poke(addr, high);
+                        self.poke(addr + 1, low);
+                    }
+                }
+            }
+        }
+    }
+
+    // Keep everything derived from memory in step after memory[range] changed
+    fn memory_written(&mut self, range: Range<usize>) {
+        if range.len() == 1 {
+            self.decoded.write(&self.memory, range.start);
+        } else {
+            self.decoded.write_range(&self.memory, range.clone());
+        }
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.invalidate(range);
+        }
+    }
+
+    // Copy the display into memory after the interpreter drew on it
+    fn store_display(&mut self) {
+        if !self.mirrors_display() {
+            return;
+        }
+        let display_start = self.vip_display_start();
+        self.display
+            .to_bytes(&mut self.memory[display_start..MEMORY_SIZE]);
+        self.memory_written(display_start..MEMORY_SIZE);
+    }
+
+    // Copy the display back from memory after something other than the
+    // interpreter (an 0NNN routine) may have written it
+    fn load_display(&mut self) {
+        if !self.mirrors_display() {
+            return;
+        }
+        let display_start = self.vip_display_start();
+        self.display
+            .load_bytes(&self.memory[display_start..MEMORY_SIZE]);
+        self.display_changed = true;
+    }
+
+    // Address of a stack slot in VIP memory; wraps around the 4 KB address space
+    fn vip_stack_slot(&self, depth: usize) -> usize {
+        (VIP_STACK_START - self.vip_offset() + 2 * depth) % MEMORY_SIZE
+    }
 
-        pc_change
+    fn push_call(&mut self, frame: CallFrame) -> Result<(), StackError> {
+        let depth = self.stack.length();
+        self.stack.push(frame)?;
+
+        if self.quirks.memory_layout == MemoryLayout::Vip {
+            let slot = self.vip_stack_slot(depth);
+            let addr = frame.return_address();
+            self.write_memory(slot, (addr >> 8) as u8);
+            self.write_memory((slot + 1) % MEMORY_SIZE, addr as u8);
+        }
+        Ok(())
     }
 
-    /**
+    // Returns the address to resume at. With the VIP layout this is read back
+    // from memory, so a program that overwrote its stack returns where it wrote.
+    fn pop_call(&mut self) -> Result<usize, StackError> {
+        let frame = self.stack.pop()?;
+
+        if self.quirks.memory_layout == MemoryLayout::Vip {
+            let slot = self.vip_stack_slot(self.stack.length());
+            let addr = ((self.memory[slot] as usize) << 8)
+                | self.memory[(slot + 1) % MEMORY_SIZE] as usize;
+            return Ok(addr & 0xFFF);
+        }
+        Ok(frame.return_address())
+    }
+
+    /*
      * OPCODES - Instruction Implementations
      */
 
+    // SYS addr: Call the 1802 machine-code routine at nnn (with Quirks::machine_code).
+    // Registers are set up the way the VIP interpreter leaves them: R3 is the
+    // program counter, R5 the CHIP-8 PC, R6 points at V0 - VF in memory, RA holds I,
+    // RB the display page and R2 a data stack. The routine returns with SEP R4 (D4);
+    // V0 - VF, I and the CHIP-8 PC are read back from where it left them.
+    // On CHIP-8 hires, 0230 is the patched interpreter's clear screen.
+    fn op_0nnn(&mut self, nnn: usize) -> PcInstructions {
+        if self.quirks.platform == Platform::Chip8Hires && nnn == HIRES_CLEAR_SCREEN {
+            return self.op_00e0();
+        }
+        if !self.quirks.machine_code {
+            return PcInstructions::Next;
+        }
+
+        let variables = self.vip_variables_start();
+        self.memory[variables..variables + REGISTER_COUNT].copy_from_slice(&self.v_registers);
+        self.memory_written(variables..variables + REGISTER_COUNT);
+
+        let mut processor = Cdp1802::new();
+        processor.ie = false;
+        processor.p = 3;
+        processor.x = 2;
+        processor.r[2] = (VIP_DATA_STACK_TOP - self.vip_offset()) as u16;
+        processor.r[3] = nnn as u16;
+        processor.r[5] = (self.program_counter + OPCODE_SIZE) as u16;
+        processor.r[6] = variables as u16;
+        processor.r[0xA] = self.index_register as u16;
+        processor.r[0xB] = self.vip_display_start() as u16;
+
+        let mut bus = MachineCodeBus {
+            memory: &mut self.memory,
+            keypad: self.keypad,
+            key_latch: 0,
+            written: None,
+        };
+        let mut machine_cycles = 0;
+        while processor.p != 4 && machine_cycles < MACHINE_CODE_CYCLE_LIMIT {
+            machine_cycles += processor.step(&mut bus);
+        }
+        if let Some(written) = bus.written {
+            self.memory_written(written);
+        }
+        if processor.p != 4 {
+            return PcInstructions::Fault(Fault::MachineCode {
+                pc: self.program_counter,
+                addr: nnn,
+            });
+        }
+
+        self.v_registers
+            .copy_from_slice(&self.memory[variables..variables + REGISTER_COUNT]);
+        self.index_register = processor.r[0xA] as usize % MEMORY_SIZE;
+        self.load_display();
+        self.spend_cycles(self.quirks.timing.machine_code_cost(machine_cycles));
+        PcInstructions::Jump(processor.r[5] as usize % MEMORY_SIZE)
+    }
+
     // CLS: Clear the display.
+    // In MegaChip mode the finished frame is presented first.
     fn op_00e0(&mut self) -> PcInstructions {
-        for y in 0..DISPLAY_HEIGHT {
-            for x in 0..DISPLAY_WIDTH {
-                self.display[y][x] = 0;
-            }
+        if let Some(megachip) = self.megachip.as_mut().filter(|megachip| megachip.enabled) {
+            megachip.frame = self.display.clone();
         }
+        self.display.clear();
+        self.store_display();
         self.display_changed = true;
         PcInstructions::Next
     }
@@ -178,22 +672,35 @@ impl Cpu {
     // RET: Return from a subroutine.
     // The interpreter sets the program counter to the address at the top of the stack, then subtracts 1 from the stack pointer.
     fn op_00ee(&mut self) -> PcInstructions {
-        self.stack_pointer -= 1;
-        PcInstructions::Jump(self.stack[self.stack_pointer])
+        match self.pop_call() {
+            Ok(addr) => PcInstructions::Jump(addr),
+            Err(error) => PcInstructions::Fault(Fault::Stack {
+                pc: self.program_counter,
+                error,
+            }),
+        }
     }
 
     // JP addr: Jump to location nnn.
     // The interpreter sets the program counter to nnn.
     fn op_1nnn(&mut self, nnn: usize) -> PcInstructions {
-        PcInstructions::Jump(nnn.into())
+        PcInstructions::Jump(nnn)
     }
 
     // CALL addr: Call subroutine at nnn.
     // The interpreter pushes the current PC to the stack. The PC is then set to nnn.
     fn op_2nnn(&mut self, nnn: usize) -> PcInstructions {
-        self.stack[self.stack_pointer] = self.program_counter + (OPCODE_SIZE);
-        self.stack_pointer += 1;
-        PcInstructions::Jump(nnn.into())
+        let frame = CallFrame {
+            caller: self.program_counter,
+            callee: nnn,
+        };
+        match self.push_call(frame) {
+            Ok(()) => PcInstructions::Jump(nnn),
+            Err(error) => PcInstructions::Fault(Fault::Stack {
+                pc: self.program_counter,
+                error,
+            }),
+        }
     }
 
     // SE Vx, byte: Skip next instruction if registers[x] = kk.
@@ -256,6 +763,7 @@ impl Cpu {
     // then stores the result in registers[x].
     fn op_8xy1(&mut self, x: usize, y: usize) -> PcInstructions {
         self.v_registers[x] |= self.v_registers[y];
+        self.reset_flag_after_logic();
         PcInstructions::Next
     }
 
@@ -264,6 +772,7 @@ impl Cpu {
     // then stores the result in registers[x].
     fn op_8xy2(&mut self, x: usize, y: usize) -> PcInstructions {
         self.v_registers[x] &= self.v_registers[y];
+        self.reset_flag_after_logic();
         PcInstructions::Next
     }
 
@@ -272,12 +781,28 @@ impl Cpu {
     // then stores the result in registers[x].
     fn op_8xy3(&mut self, x: usize, y: usize) -> PcInstructions {
         self.v_registers[x] ^= self.v_registers[y];
+        self.reset_flag_after_logic();
         PcInstructions::Next
     }
 
+    // Logic quirk: the VIP's 8XY1 - 8XY3 leave VF at 0
+    fn reset_flag_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.v_registers[0xF] = 0;
+        }
+    }
+
+    // The register 8XY6 and 8XYE shift: VX itself, or VY with the shift quirk
+    fn shift_source(&self, x: usize, y: usize) -> usize {
+        if self.quirks.shift_vy {
+            y
+        } else {
+            x
+        }
+    }
+
     // ADD Vx, Vy: Set registers[x] = registers[x] + registers[y], set VF = carry.
     // The values of registers[x] and registers[y] are added together.
-
     fn op_8xy4(&mut self, x: usize, y: usize) -> PcInstructions {
         // If the result is greater than 8 bits (i.e., > 255,) registers[x] is set to the lowest 8 bits of the result,
         // and VF is set to 1, otherwise 0.
@@ -305,10 +830,12 @@ impl Cpu {
 
     // SHR Vx {, Vy}: Set registers[x] = registers[x] SHR 1. (Shift Right)
     // If the least-significant bit of registers[x] is 1, then VF is set to 1, otherwise 0.
-    // Then registers[x] is divided by 2.
-    fn op_8x06(&mut self, x: usize) -> PcInstructions {
-        self.v_registers[0xF] = self.v_registers[x] & 0x1;
-        self.v_registers[x] >>= 1;
+    // Then registers[x] is divided by 2. With the shift quirk registers[y] is
+    // shifted instead, and the result stored in registers[x].
+    fn op_8xy6(&mut self, x: usize, y: usize) -> PcInstructions {
+        let source = self.shift_source(x, y);
+        self.v_registers[0xF] = self.v_registers[source] & 0x1;
+        self.v_registers[x] = self.v_registers[source] >> 1;
 
         PcInstructions::Next
     }
@@ -329,10 +856,12 @@ impl Cpu {
 
     // SHL Vx {, Vy}: Set registers[x] = registers[x] SHL 1. (Shift Left)
     // If the most-significant bit of registers[x] is 1, then VF is set to 1, otherwise to 0.
-    // Then registers[x] is multiplied by 2.
-    fn op_8x0e(&mut self, x: usize) -> PcInstructions {
-        self.v_registers[0xF] = self.v_registers[x] >> 7;
-        self.v_registers[x] <<= 1;
+    // Then registers[x] is multiplied by 2. With the shift quirk registers[y]
+    // is shifted instead, as for SHR.
+    fn op_8xye(&mut self, x: usize, y: usize) -> PcInstructions {
+        let source = self.shift_source(x, y);
+        self.v_registers[0xF] = self.v_registers[source] >> 7;
+        self.v_registers[x] = self.v_registers[source] << 1;
 
         PcInstructions::Next
     }
@@ -356,18 +885,19 @@ impl Cpu {
     }
 
     // JP V0, addr: Jump to location nnn + registers[0].
-    // The program counter is set to nnn plus the value of registers[0].
+    // The program counter is set to nnn plus the value of registers[0]; with
+    // the jump quirk, plus the register named by nnn's top nibble (BXNN).
     fn op_bnnn(&mut self, nnn: usize) -> PcInstructions {
-        let addr = nnn + self.v_registers[0] as usize;
-        PcInstructions::Jump(addr.into())
+        let x = if self.quirks.jump_vx { nnn >> 8 } else { 0 };
+        let addr = nnn + self.v_registers[x] as usize;
+        PcInstructions::Jump(addr)
     }
 
     // RND Vx, byte: Set registers[x] = random byte AND kk.
     // The interpreter generates a random number from 0 to 255, which is then ANDed with the value kk.
     // The results are stored in registers[x].
     fn op_cxkk(&mut self, x: usize, kk: u8) -> PcInstructions {
-        let mut rng = rand::thread_rng();
-        self.v_registers[x] = rng.gen::<u8>() & kk;
+        self.v_registers[x] = self.rng.gen::<u8>() & kk;
         PcInstructions::Next
     }
 
@@ -378,19 +908,26 @@ impl Cpu {
     // If this causes any pixels to be erased, VF is set to 1, otherwise
     // it is set to 0. If the sprite is positioned so part of it is outside
     // the coordinates of the display, it wraps around to the opposite side
-    // of the screen.
+    // of the screen, or with the clip quirk is cut off at the edge.
     fn op_dxyn(&mut self, x: usize, y: usize, n: usize) -> PcInstructions {
-        self.v_registers[0x0f] = 0;
-        for byte in 0..n {
-            let y = (self.v_registers[y] as usize + byte) % DISPLAY_HEIGHT;
-            for bit in 0..8 {
-                let x = (self.v_registers[x] as usize + bit) % DISPLAY_WIDTH;
-                let color = (self.memory[self.index_register + byte] >> (7 - bit)) & 1;
-                self.v_registers[0x0f] |= color & self.display[y][x];
-                self.display[y][x] ^= color;
-            }
+        if self
+            .megachip
+            .as_ref()
+            .is_some_and(|megachip| megachip.enabled)
+        {
+            return self.op_dxyn_megachip(x, y, n);
         }
+        let mut sprite = [0; 16];
+        for (row, byte) in sprite.iter_mut().take(n).enumerate() {
+            *byte = self.memory[(self.index_register + row) % self.memory.len()];
+        }
+        let (x, y) = (self.v_registers[x] as usize, self.v_registers[y] as usize);
+        let collision = self
+            .display
+            .draw_sprite(x, y, &sprite[..n], !self.quirks.clip_sprites);
+        self.v_registers[0x0f] = collision as u8;
 
+        self.store_display();
         self.display_changed = true;
         PcInstructions::Next
     }
@@ -418,9 +955,11 @@ impl Cpu {
 
     // LD Vx, K: Wait for a key press, store the value of the key in registers[x].
     // All execution stops until a key is pressed, then the value of that key is stored in registers[x].
+    // Timers keep counting down while waiting; see poll_key_wait for when the wait ends.
     fn op_fx0a(&mut self, x: usize) -> PcInstructions {
         self.keypad_waiting = true;
         self.keypad_register = x;
+        self.keypad_pressed = None;
         PcInstructions::Next
     }
 
@@ -459,9 +998,10 @@ impl Cpu {
     // the tens digit at location I+1, and the ones digit at location I+2.
     fn op_fx33(&mut self, x: usize) -> PcInstructions {
         let value = self.v_registers[x];
-        self.memory[self.index_register as usize] = value / 100;
-        self.memory[self.index_register as usize + 1] = (value / 10) % 10;
-        self.memory[self.index_register as usize + 2] = (value % 100) % 10;
+        let digits = [value / 100, (value / 10) % 10, (value % 100) % 10];
+        for (i, digit) in digits.into_iter().enumerate() {
+            self.write_memory(self.index_address(i), digit);
+        }
         PcInstructions::Next
     }
 
@@ -469,8 +1009,9 @@ impl Cpu {
     // The interpreter copies the values of registers V0 through registers[x] into memory, starting at the address in Index Register.
     fn op_fx55(&mut self, x: usize) -> PcInstructions {
         for i in 0..=x {
-            self.memory[self.index_register as usize + i] = self.v_registers[i];
+            self.write_memory(self.index_address(i), self.v_registers[i]);
         }
+        self.increment_index(x);
         PcInstructions::Next
     }
 
@@ -478,37 +1019,284 @@ impl Cpu {
     // The interpreter reads values from memory starting at location I into registers V0 through registers[x].
     fn op_fx65(&mut self, x: usize) -> PcInstructions {
         for i in 0..=x {
-            self.v_registers[i] = self.memory[self.index_register as usize + i];
+            self.v_registers[i] = self.memory[self.index_address(i)];
         }
+        self.increment_index(x);
         PcInstructions::Next
     }
 
-    // MAIN LOOP
-    pub fn cycle(&mut self, keypad: [bool; 16]) -> OutputState {
-        self.keypad = keypad;
-        self.display_changed = false;
+    // I + offset, wrapped around the end of memory like the sprites DXYN reads;
+    // the index quirk can move I past it
+    fn index_address(&self, offset: usize) -> usize {
+        (self.index_register + offset) % self.memory.len()
+    }
 
-        if self.keypad_waiting {
-            for i in 0..keypad.len() {
-                if keypad[i] {
-                    self.keypad_waiting = false;
-                    self.v_registers[self.keypad_register] = i as u8;
-                    break;
+    // Move I on after FX55 / FX65 stored or loaded V0 - VX, per the quirk
+    fn increment_index(&mut self, x: usize) {
+        self.index_register += match self.quirks.index_increment {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::ByX => x,
+            IndexIncrement::ByXPlusOne => x + 1,
+        };
+    }
+
+    /*
+     * CHIP-8X - colour and second keypad opcodes
+     */
+
+    // BXY0: Colour 8x4 zones with the colour in registers[y].
+    // The low nibbles of registers[x] and registers[x + 1] give the left column
+    // and top row of zones, the high nibbles how many more columns and rows follow.
+    // BXYN: Colour n rows of the 8x1 cells starting at pixel (registers[x], registers[x + 1]).
+    fn op_bxyn(&mut self, x: usize, y: usize, n: usize) -> PcInstructions {
+        let horizontal = self.v_registers[x] as usize;
+        let vertical = self.v_registers[(x + 1) % REGISTER_COUNT] as usize;
+        let color = Color::from_code(self.v_registers[y]);
+
+        if n == 0 {
+            let (left, columns) = (horizontal & 0x0F, horizontal >> 4);
+            let (top, rows) = (vertical & 0x0F, vertical >> 4);
+            for zone_y in top..=top + rows {
+                for zone_x in left..=left + columns {
+                    for line in 0..4 {
+                        self.display
+                            .set_cell_color(zone_x * 8, zone_y * 4 + line, color);
+                    }
                 }
             }
         } else {
-            // Fetch Opcode
-            let opcode = self.fetch_opcode();
+            for row in 0..n {
+                self.display
+                    .set_cell_color(horizontal, vertical + row, color);
+            }
+        }
+        self.display_changed = true;
+        PcInstructions::Next
+    }
+
+    // BGC: Step the background colour through blue, black, green and red.
+    fn op_02a0(&mut self) -> PcInstructions {
+        if let Some(colors) = &mut self.display.colors {
+            colors.background = (colors.background + 1) % BACKGROUND_COLORS.len();
+        }
+        self.display_changed = true;
+        PcInstructions::Next
+    }
+
+    // SKP2 Vx: Skip next instruction if key registers[x] is down on the second keypad.
+    fn op_exf2(&mut self, x: usize) -> PcInstructions {
+        PcInstructions::skip_if(self.keypad2[self.v_registers[x] as usize & 0x0F])
+    }
+
+    // SKNP2 Vx: Skip next instruction if key registers[x] is up on the second keypad.
+    fn op_exf5(&mut self, x: usize) -> PcInstructions {
+        PcInstructions::skip_if(!self.keypad2[self.v_registers[x] as usize & 0x0F])
+    }
+
+    /*
+     * MEGACHIP - 256x192 colour mode, palette, blending and digitised sound
+     */
+
+    // MEGAOFF: Back to the 64x32 monochrome display.
+    fn op_0010(&mut self) -> PcInstructions {
+        if let Some(megachip) = &mut self.megachip {
+            megachip.enabled = false;
+            self.display = self.quirks.platform.new_display();
+            self.display_changed = true;
+        }
+        PcInstructions::Next
+    }
 
-            // Run Opcode instruction
-            let pc_instruction = self.exec_opcode(opcode);
+    // MEGAON: Switch to the 256x192 colour display, blank.
+    fn op_0011(&mut self) -> PcInstructions {
+        if let Some(megachip) = &mut self.megachip {
+            megachip.enabled = true;
+            self.display = Display::with_truecolor(MEGACHIP_WIDTH, MEGACHIP_HEIGHT);
+            megachip.frame = self.display.clone();
+            self.display_changed = true;
+        }
+        PcInstructions::Next
+    }
+
+    // LDHI I, nnnnnn: Set I = nn << 16 | the 16 bits of the next word, and skip that word.
+    fn op_01nn(&mut self, nn: u8) -> PcInstructions {
+        let low = self.program_counter + OPCODE_SIZE;
+        let len = self.memory.len();
+        self.index_register = ((nn as usize) << 16)
+            | ((self.memory[low % len] as usize) << 8)
+            | self.memory[(low + 1) % len] as usize;
+        PcInstructions::Jump(self.program_counter + 2 * OPCODE_SIZE)
+    }
+
+    // LDPAL nn: Load nn ARGB colours, 4 bytes each starting at I, into palette entries 1 - nn.
+    fn op_02nn(&mut self, nn: u8) -> PcInstructions {
+        if let Some(megachip) = &mut self.megachip {
+            for entry in 0..nn as usize {
+                let addr = self.index_register + 4 * entry;
+                let argb = (0..4).fold(0, |argb, i| {
+                    (argb << 8) | self.memory.get(addr + i).copied().unwrap_or(0) as u32
+                });
+                megachip.palette[entry + 1] = argb;
+            }
+        }
+        PcInstructions::Next
+    }
+
+    // SPRW nn: Set the sprite width to nn pixels; 0 means 256.
+    fn op_03nn(&mut self, nn: u8) -> PcInstructions {
+        if let Some(megachip) = &mut self.megachip {
+            megachip.sprite_width = if nn == 0 { 256 } else { nn as usize };
+        }
+        PcInstructions::Next
+    }
 
-            // Update Program Counter
-            match pc_instruction {
-                PcInstructions::Next => self.program_counter += OPCODE_SIZE,
-                PcInstructions::Skip => self.program_counter += 2 * OPCODE_SIZE,
-                PcInstructions::Jump(addr) => self.program_counter = addr,
+    // SPRH nn: Set the sprite height to nn pixels; 0 means 256.
+    fn op_04nn(&mut self, nn: u8) -> PcInstructions {
+        if let Some(megachip) = &mut self.megachip {
+            megachip.sprite_height = if nn == 0 { 256 } else { nn as usize };
+        }
+        PcInstructions::Next
+    }
+
+    // ALPHA nn: Set the opacity of the whole screen, for fades.
+    fn op_05nn(&mut self, nn: u8) -> PcInstructions {
+        if let Some(megachip) = &mut self.megachip {
+            for display in [&mut self.display, &mut megachip.frame] {
+                if let Some(truecolor) = &mut display.truecolor {
+                    truecolor.alpha = nn;
+                }
             }
+            self.display_changed = true;
+        }
+        PcInstructions::Next
+    }
+
+    // DIGISND n: Play the digitised sound at I, looping if n is 0 and once otherwise.
+    fn op_060n(&mut self, n: usize) -> PcInstructions {
+        if let Some(megachip) = &mut self.megachip {
+            megachip.sound = DigitisedSound::from_memory(&self.memory, self.index_register, n == 0);
+        }
+        PcInstructions::Next
+    }
+
+    // STOPSND: Stop the digitised sound.
+    fn op_0700(&mut self) -> PcInstructions {
+        if let Some(megachip) = &mut self.megachip {
+            megachip.sound = None;
+        }
+        PcInstructions::Next
+    }
+
+    // BMODE nn: Set how sprites blend with the screen: normal, 25%, 50%, add or multiply.
+    fn op_08nn(&mut self, nn: u8) -> PcInstructions {
+        if let Some(megachip) = &mut self.megachip {
+            if let Some(blend) = BlendMode::from_code(nn) {
+                megachip.blend = blend;
+            }
+        }
+        PcInstructions::Next
+    }
+
+    // DRW Vx, Vy, n in MegaChip mode: Draw a sprite_width x sprite_height sprite of
+    // palette indices from I at (registers[x], registers[y]), blending each colour
+    // onto the screen. Index 0 is transparent. With I in the font below 0x200 the
+    // sprite is 8 x n bits, drawn in palette entry 255. Sprites are clipped at the
+    // screen edges; VF is set if a pixel lands on one already drawn.
+    fn op_dxyn_megachip(&mut self, x: usize, y: usize, n: usize) -> PcInstructions {
+        let Some(megachip) = &self.megachip else {
+            return PcInstructions::Next;
+        };
+        let (left, top) = (self.v_registers[x] as usize, self.v_registers[y] as usize);
+        let font = self.index_register < PROGRAM_START;
+        let (width, height) = if font {
+            (8, n)
+        } else {
+            (megachip.sprite_width, megachip.sprite_height)
+        };
+
+        let mut collision = 0;
+        for row in 0..height {
+            let screen_y = top + row;
+            if screen_y >= self.display.height {
+                break;
+            }
+            for column in 0..width {
+                let screen_x = left + column;
+                if screen_x >= self.display.width {
+                    break;
+                }
+                let index = if font {
+                    let bits = self.memory[self.index_register + row];
+                    if (bits >> (7 - column)) & 1 == 1 {
+                        FONT_COLOR
+                    } else {
+                        0
+                    }
+                } else {
+                    let addr = self.index_register + row * width + column;
+                    self.memory.get(addr).copied().unwrap_or(0)
+                };
+                if index == 0 {
+                    continue;
+                }
+
+                if self.display.index_at(screen_x, screen_y) != 0 {
+                    collision = 1;
+                }
+                let color = megachip.palette[index as usize] & 0x00FF_FFFF;
+                let under = self.display.truecolor_at(screen_x, screen_y);
+                self.display.set_index(
+                    screen_x,
+                    screen_y,
+                    index,
+                    megachip.blend.blend(color, under),
+                );
+            }
+        }
+
+        // Nothing new is shown until 00E0 presents the frame
+        self.v_registers[0x0f] = collision;
+        PcInstructions::Next
+    }
+
+    // Called every cycle while FX0A is waiting. Only keys that go down during the
+    // wait count, so a key still held from an earlier prompt doesn't answer this one.
+    fn poll_key_wait(&mut self, previous_keypad: [bool; 16]) {
+        let key = match self.keypad_pressed {
+            Some(key) => {
+                if self.keypad[key] {
+                    return;
+                }
+                key
+            }
+            None => {
+                let pressed =
+                    (0..self.keypad.len()).find(|&key| self.keypad[key] && !previous_keypad[key]);
+                match (pressed, self.quirks.key_wait) {
+                    (None, _) => return,
+                    (Some(key), KeyWait::Press) => key,
+                    (Some(key), KeyWait::PressAndRelease) => {
+                        self.keypad_pressed = Some(key);
+                        return;
+                    }
+                }
+            }
+        };
+
+        self.keypad_waiting = false;
+        self.keypad_pressed = None;
+        self.v_registers[self.keypad_register] = key as u8;
+    }
+
+    // Charge `cost` against the current frame; when the frame's budget is used
+    // up, the timers count down once, as they would on the 60 Hz interrupt
+    fn spend_cycles(&mut self, cost: u32) {
+        let budget = self.quirks.timing.budget();
+        self.frame_cycles += cost;
+        self.cycles += cost as u64;
+        while self.frame_cycles >= budget {
+            self.frame_cycles -= budget;
+            self.vblank = true;
 
             // Update Timers
             if self.delay_timer > 0 {
@@ -518,15 +1306,968 @@ impl Cpu {
             if self.sound_timer > 0 {
                 self.sound_timer -= 1;
             }
+
+            if let Some(megachip) = &mut self.megachip {
+                if megachip
+                    .sound
+                    .as_mut()
+                    .is_some_and(|sound| !sound.advance())
+                {
+                    megachip.sound = None;
+                }
+            }
+        }
+    }
+
+    // Many ROMs wait for the delay timer with
+    //     A: LD Vx, DT / SE Vx, kk / JP A
+    // Until the frame ends DT can't change, so while DT != kk every pass of the
+    // loop does the same thing: set Vx to DT and use up some of the frame.
+    // Charge all the passes that end before the frame does at once; the last
+    // pass runs normally, so the frame ends in exactly the state it would have.
+    fn skip_idle_loop(&mut self) {
+        let pc = self.program_counter;
+        if !matches!(
+            self.decoded.get(&self.memory, pc),
+            Instruction::LdVxDt { .. }
+        ) {
+            return;
+        }
+        let loop_body = [pc, pc + OPCODE_SIZE, pc + 2 * OPCODE_SIZE]
+            .map(|addr| self.decoded.get(&self.memory, addr % self.memory.len()));
+        let x = match loop_body {
+            [Instruction::LdVxDt { x }, Instruction::SeImm { x: se_x, kk }, Instruction::Jp { nnn }]
+                if se_x == x && kk != self.delay_timer && nnn == pc =>
+            {
+                x
+            }
+            _ => return,
+        };
+
+        let mut v_registers = self.v_registers;
+        v_registers[x] = self.delay_timer;
+        let timing = self.quirks.timing;
+        let pass_cost: u32 = loop_body
+            .iter()
+            .map(|&instruction| timing.cost(instruction, &v_registers, false))
+            .sum();
+        let remaining = timing.budget().saturating_sub(self.frame_cycles);
+        let passes = remaining.saturating_sub(1) / pass_cost;
+        if passes > 0 {
+            self.v_registers = v_registers;
+            self.frame_cycles += passes * pass_cost;
+            self.cycles += (passes * pass_cost) as u64;
+            self.idle_cycles_skipped += passes as u64 * loop_body.len() as u64;
+        }
+    }
+
+    // MAIN LOOP
+    pub fn cycle(&mut self, keypad: [bool; 16]) -> OutputState<'_> {
+        let previous_keypad = self.keypad;
+        self.keypad = keypad;
+        self.display_changed = false;
+        self.vblank = false;
+
+        if self.fault.is_some() {
+            // Halted: leave everything, timers included, as it was at the fault
+            return self.output_state();
+        }
+
+        if self.keypad_waiting {
+            self.poll_key_wait(previous_keypad);
+            self.spend_cycles(self.quirks.timing.key_wait_cost());
+        } else {
+            if self.idle_skip {
+                self.skip_idle_loop();
+            }
+
+            // A frozen byte is written back between instructions, which a
+            // compiled block would only see at its end
+            #[cfg(feature = "jit")]
+            let compiled = !self.freezing() && self.run_block();
+            #[cfg(not(feature = "jit"))]
+            let compiled = false;
+            if !compiled {
+                self.step();
+            }
         }
 
-        let display = self.display.clone();
+        // Between this instruction and the next; a frame starts after vblank
+        if !self.cheats.is_empty() && self.fault.is_none() {
+            self.apply_cheats(self.vblank);
+        }
+        self.output_state()
+    }
+
+    // Run the instruction at the program counter through the interpreter
+    fn step(&mut self) {
+        // Fetch Opcode, decoded ahead of time
+        let instruction = self.decoded.get(&self.memory, self.program_counter);
+
+        // Display wait quirk: DXYN only draws at the start of a frame, so
+        // stall for the rest of this one
+        if self.quirks.display_wait
+            && self.frame_cycles > 0
+            && matches!(instruction, Instruction::Drw { .. })
+        {
+            let budget = self.quirks.timing.budget();
+            self.spend_cycles(budget - self.frame_cycles);
+            return;
+        }
+
+        match self.execute(instruction) {
+            Ok(cost) => self.spend_cycles(cost),
+            Err(fault) => self.fault = Some(fault),
+        }
+    }
+
+    // Run `instruction` and move the program counter on; returns what it cost
+    fn execute(&mut self, instruction: Instruction) -> Result<u32, Fault> {
+        // Run Opcode instruction
+        let v_registers = self.v_registers;
+        let pc_instruction = self.exec_opcode(instruction);
+        let skipped = matches!(pc_instruction, PcInstructions::Skip);
+
+        // Update Program Counter, wrapping around the end of memory
+        let next = match pc_instruction {
+            PcInstructions::Next => self.program_counter + OPCODE_SIZE,
+            PcInstructions::Skip => self.program_counter + 2 * OPCODE_SIZE,
+            PcInstructions::Jump(addr) => addr,
+            PcInstructions::Fault(fault) => return Err(fault),
+        };
+        self.program_counter = next % self.memory.len();
+
+        Ok(self.quirks.timing.cost(instruction, &v_registers, skipped))
+    }
+
+    #[cfg(feature = "jit")]
+    fn registers(&self) -> Registers {
+        Registers {
+            v: self.v_registers,
+            index: self.index_register as u64,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+        }
+    }
 
+    // Run the compiled block at the program counter, if there is one that ends
+    // inside this frame. In JitMode::Differential the interpreter then runs the
+    // same instructions from the same state, and the two have to agree.
+    #[cfg(feature = "jit")]
+    fn run_block(&mut self) -> bool {
+        let remaining = self
+            .quirks
+            .timing
+            .budget()
+            .saturating_sub(self.frame_cycles);
+        let mut registers = self.registers();
+        let Some(jit) = &mut self.jit else {
+            return false;
+        };
+        let Some(ran) = jit.run(
+            &self.memory,
+            self.program_counter,
+            self.quirks.timing,
+            remaining,
+            &mut registers,
+        ) else {
+            return false;
+        };
+        let next = ran.next % self.memory.len();
+
+        if self.jit_mode == JitMode::Differential {
+            let mut cost = 0;
+            for _ in 0..ran.instructions {
+                let instruction = self.decoded.get(&self.memory, self.program_counter);
+                cost += self
+                    .execute(instruction)
+                    .expect("compiled instructions don't fault");
+            }
+            assert_eq!(
+                (registers, next, ran.cost),
+                (self.registers(), self.program_counter, cost),
+                "compiled block at {:#05x} disagrees with the interpreter \
+                 (left compiled, right interpreted)",
+                ran.start
+            );
+        } else {
+            self.v_registers = registers.v;
+            self.index_register = registers.index as usize;
+            self.delay_timer = registers.delay_timer;
+            self.sound_timer = registers.sound_timer;
+            self.program_counter = next;
+        }
+        self.spend_cycles(ran.cost);
+        true
+    }
+
+    fn output_state(&self) -> OutputState<'_> {
         // Render Display
+        let display = match &self.megachip {
+            Some(megachip) if megachip.enabled => &megachip.frame,
+            _ => &self.display,
+        };
+        let digitised = self
+            .megachip
+            .as_ref()
+            .is_some_and(|megachip| megachip.sound.is_some());
         OutputState {
-            display: display,
+            display,
             display_changed: self.display_changed,
-            beep: self.sound_timer > 0,
+            beep: self.sound_timer > 0 || digitised,
+            fault: self.fault,
+            vblank: self.vblank,
+        }
+    }
+
+    /*
+     * SAVE STATES
+     */
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(SAVE_STATE_VERSION);
+        state.u32(self.memory.len() as u32);
+        state.bytes(&self.memory);
+        state.bytes(&self.v_registers);
+        state.u32(self.index_register as u32);
+        state.u16(self.program_counter as u16);
+        state.u16(self.stack.length() as u16);
+        for frame in self.stack.iter() {
+            state.u16(frame.caller as u16);
+            state.u16(frame.callee as u16);
+        }
+        state.u8(self.delay_timer);
+        state.u8(self.sound_timer);
+        for &pressed in self.keypad.iter() {
+            state.bool(pressed);
         }
+        state.bool(self.keypad_waiting);
+        state.u8(self.keypad_register as u8);
+        state.u8(self.keypad_pressed.map_or(0xFF, |key| key as u8));
+        state.u8(match self.quirks.platform {
+            Platform::Chip8 => 0,
+            Platform::Chip8Hires => 1,
+            Platform::Chip8X => 2,
+            Platform::MegaChip => 3,
+        });
+        state.bool(
+            self.megachip
+                .as_ref()
+                .is_some_and(|megachip| megachip.enabled),
+        );
+        write_display(&mut state, &self.display);
+        state.u8(match self.quirks.key_wait {
+            KeyWait::PressAndRelease => 0,
+            KeyWait::Press => 1,
+        });
+        state.u8(match self.quirks.memory_layout {
+            MemoryLayout::Separate => 0,
+            MemoryLayout::Vip => 1,
+        });
+        state.u16(
+            self.quirks
+                .stack_depth
+                .map_or(u16::MAX, |depth| depth as u16),
+        );
+        match self.quirks.timing {
+            Timing::Instructions { per_frame } => {
+                state.u8(0);
+                state.u32(per_frame);
+            }
+            Timing::Vip => {
+                state.u8(1);
+                state.u32(0);
+            }
+        }
+        state.bool(self.quirks.display_wait);
+        state.u32(self.frame_cycles);
+        state.bool(self.quirks.machine_code);
+        state.bool(self.quirks.shift_vy);
+        state.u8(match self.quirks.index_increment {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::ByX => 1,
+            IndexIncrement::ByXPlusOne => 2,
+        });
+        state.bool(self.quirks.clip_sprites);
+        state.bool(self.quirks.jump_vx);
+        state.bool(self.quirks.logic_resets_vf);
+        if let Some(megachip) = &self.megachip {
+            for &color in megachip.palette.iter() {
+                state.u32(color);
+            }
+            state.u16(megachip.sprite_width as u16);
+            state.u16(megachip.sprite_height as u16);
+            state.u8(megachip.blend.code());
+            state.bool(megachip.sound.is_some());
+            if let Some(sound) = &megachip.sound {
+                state.u32(sound.start as u32);
+                state.u32(sound.length as u32);
+                state.u32(sound.rate);
+                state.bool(sound.looping);
+                state.u64(sound.elapsed);
+                state.u32(sound.frame.0 as u32);
+                state.u32(sound.frame.1 as u32);
+            }
+            write_display(&mut state, &megachip.frame);
+        }
+        state.u16(self.cheats.len() as u16);
+        for cheat in &self.cheats {
+            match cheat.effect {
+                Effect::Freeze { addr, value } => {
+                    state.u8(0);
+                    state.u32(addr as u32);
+                    state.u16(value as u16);
+                }
+                Effect::SetRegister { x, value } => {
+                    state.u8(1);
+                    state.u32(x as u32);
+                    state.u16(value as u16);
+                }
+                Effect::Replace { addr, opcode } => {
+                    state.u8(2);
+                    state.u32(addr as u32);
+                    state.u16(opcode);
+                }
+            }
+            state.bool(cheat.replaced.is_some());
+            state.bytes(&cheat.replaced.unwrap_or_default());
+        }
+        state.finish()
+    }
+
+    // Restores a state produced by save_state. On error the Cpu is left untouched.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data)?;
+        if state.version != SAVE_STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(state.version));
+        }
+
+        let mut cpu = Cpu::new();
+        let memory_size = state.u32()? as usize;
+        cpu.memory = state.bytes(memory_size)?.to_vec();
+        cpu.v_registers
+            .copy_from_slice(state.bytes(REGISTER_COUNT)?);
+        cpu.index_register = state.u32()? as usize;
+        cpu.program_counter = state.u16()? as usize;
+        if cpu.program_counter >= cpu.memory.len() {
+            return Err(StateError::InvalidValue("program counter"));
+        }
+        let mut frames = Vec::new();
+        for _ in 0..state.u16()? {
+            let caller = state.u16()? as usize;
+            let callee = state.u16()? as usize;
+            frames.push(CallFrame { caller, callee });
+        }
+        cpu.delay_timer = state.u8()?;
+        cpu.sound_timer = state.u8()?;
+        for pressed in cpu.keypad.iter_mut() {
+            *pressed = state.bool()?;
+        }
+        cpu.keypad_waiting = state.bool()?;
+        cpu.keypad_register = state.u8()? as usize;
+        if cpu.keypad_register >= REGISTER_COUNT {
+            return Err(StateError::InvalidValue("key wait register"));
+        }
+        cpu.keypad_pressed = match state.u8()? {
+            0xFF => None,
+            key if key < 16 => Some(key as usize),
+            _ => return Err(StateError::InvalidValue("key wait key")),
+        };
+        cpu.quirks.platform = match state.u8()? {
+            0 => Platform::Chip8,
+            1 => Platform::Chip8Hires,
+            2 => Platform::Chip8X,
+            3 => Platform::MegaChip,
+            _ => return Err(StateError::InvalidValue("platform")),
+        };
+        if cpu.memory.len() != cpu.quirks.platform.memory_size() {
+            return Err(StateError::InvalidValue("memory size"));
+        }
+        cpu.decoded = DecodeCache::new(cpu.quirks.platform);
+        cpu.megachip = (cpu.quirks.platform == Platform::MegaChip).then(MegaChip::new);
+        cpu.display = match (&mut cpu.megachip, state.bool()?) {
+            (Some(megachip), true) => {
+                megachip.enabled = true;
+                Display::with_truecolor(MEGACHIP_WIDTH, MEGACHIP_HEIGHT)
+            }
+            (None, true) => return Err(StateError::InvalidValue("megachip mode")),
+            (_, false) => cpu.quirks.platform.new_display(),
+        };
+        read_display(&mut state, &mut cpu.display)?;
+        cpu.quirks.key_wait = match state.u8()? {
+            0 => KeyWait::PressAndRelease,
+            1 => KeyWait::Press,
+            _ => return Err(StateError::InvalidValue("key wait mode")),
+        };
+        cpu.quirks.memory_layout = match state.u8()? {
+            0 => MemoryLayout::Separate,
+            1 => MemoryLayout::Vip,
+            _ => return Err(StateError::InvalidValue("memory layout")),
+        };
+        cpu.quirks.stack_depth = match state.u16()? {
+            u16::MAX => None,
+            depth => Some(depth as usize),
+        };
+        cpu.stack = match cpu.quirks.stack_depth {
+            Some(depth) => Stack::with_capacity(depth),
+            None => Stack::new(),
+        };
+        cpu.quirks.timing = match (state.u8()?, state.u32()?) {
+            (0, per_frame) => Timing::Instructions { per_frame },
+            (1, _) => Timing::Vip,
+            _ => return Err(StateError::InvalidValue("timing")),
+        };
+        cpu.quirks.display_wait = state.bool()?;
+        cpu.frame_cycles = state.u32()?;
+        cpu.quirks.machine_code = state.bool()?;
+        cpu.quirks.shift_vy = state.bool()?;
+        cpu.quirks.index_increment = match state.u8()? {
+            0 => IndexIncrement::Unchanged,
+            1 => IndexIncrement::ByX,
+            2 => IndexIncrement::ByXPlusOne,
+            _ => return Err(StateError::InvalidValue("index increment")),
+        };
+        cpu.quirks.clip_sprites = state.bool()?;
+        cpu.quirks.jump_vx = state.bool()?;
+        cpu.quirks.logic_resets_vf = state.bool()?;
+        if let Some(megachip) = &mut cpu.megachip {
+            for color in megachip.palette.iter_mut() {
+                *color = state.u32()?;
+            }
+            megachip.sprite_width = state.u16()? as usize;
+            megachip.sprite_height = state.u16()? as usize;
+            megachip.blend =
+                BlendMode::from_code(state.u8()?).ok_or(StateError::InvalidValue("blend mode"))?;
+            if state.bool()? {
+                let sound = DigitisedSound {
+                    start: state.u32()? as usize,
+                    length: state.u32()? as usize,
+                    rate: state.u32()?,
+                    looping: state.bool()?,
+                    elapsed: state.u64()?,
+                    frame: (state.u32()? as usize, state.u32()? as usize),
+                };
+                let memory_size = cpu.memory.len();
+                if sound.start + sound.length > memory_size
+                    || sound.frame.0 > sound.frame.1
+                    || sound.frame.1 > memory_size
+                {
+                    return Err(StateError::InvalidValue("digitised sound"));
+                }
+                megachip.sound = Some(sound);
+            }
+            read_display(&mut state, &mut megachip.frame)?;
+        }
+        for _ in 0..state.u16()? {
+            let (kind, target, value) = (state.u8()?, state.u32()? as usize, state.u16()?);
+            let effect = match kind {
+                0 if value <= 0xFF => Effect::Freeze {
+                    addr: target,
+                    value: value as u8,
+                },
+                1 if target < REGISTER_COUNT && value <= 0xFF => Effect::SetRegister {
+                    x: target,
+                    value: value as u8,
+                },
+                2 => Effect::Replace {
+                    addr: target,
+                    opcode: value,
+                },
+                _ => return Err(StateError::InvalidValue("cheat")),
+            };
+            let has_replaced = state.bool()?;
+            let bytes = state.bytes(2)?;
+            cpu.cheats.push(ActiveCheat {
+                effect,
+                replaced: has_replaced.then(|| [bytes[0], bytes[1]]),
+            });
+        }
+        for frame in frames {
+            cpu.stack
+                .push(frame)
+                .map_err(|_| StateError::InvalidValue("stack depth"))?;
+        }
+
+        // Host-side settings aren't part of the emulated state
+        cpu.idle_skip = self.idle_skip;
+        cpu.idle_cycles_skipped = self.idle_cycles_skipped;
+        cpu.cycles = self.cycles;
+        cpu.rng = self.rng.clone();
+        #[cfg(feature = "jit")]
+        {
+            cpu.jit_mode = self.jit_mode;
+            cpu.jit = self.jit.take().map(|mut jit| {
+                if !jit.compiled_for(&cpu.quirks) {
+                    return Jit::new(&cpu.quirks);
+                }
+                jit.revalidate(&cpu.memory);
+                jit
+            });
+        }
+        *self = cpu;
+        Ok(())
+    }
+}
+
+// Pixels and any colour layers of a display, in the shape it already has. One
+// byte per pixel: the palette index on a truecolor display, 0 or 1 otherwise.
+fn write_display(state: &mut StateWriter, display: &Display) {
+    for y in 0..display.height {
+        for x in 0..display.width {
+            state.u8(match display.truecolor {
+                Some(_) => display.index_at(x, y),
+                None => display.get_pixel(x, y),
+            });
+        }
+    }
+    if let Some(colors) = &display.colors {
+        state.u8(colors.background as u8);
+        for color in colors.cells.iter() {
+            state.u8(color.code());
+        }
+    }
+    if let Some(truecolor) = &display.truecolor {
+        for &pixel in truecolor.pixels.iter() {
+            state.u32(pixel);
+        }
+        state.u8(truecolor.alpha);
+    }
+}
+
+fn read_display(state: &mut StateReader, display: &mut Display) -> Result<(), StateError> {
+    let width = display.width;
+    let pixels = state.bytes(width * display.height)?;
+    for (i, &pixel) in pixels.iter().enumerate() {
+        let (x, y) = (i % width, i / width);
+        match display.truecolor {
+            Some(_) => display.set_index(x, y, pixel, 0),
+            None => display.set_pixel(x, y, pixel),
+        }
+    }
+    if let Some(colors) = &mut display.colors {
+        colors.background = state.u8()? as usize;
+        if colors.background >= BACKGROUND_COLORS.len() {
+            return Err(StateError::InvalidValue("background colour"));
+        }
+        for color in colors.cells.iter_mut() {
+            *color = Color::from_code(state.u8()?);
+        }
+    }
+    if let Some(truecolor) = &mut display.truecolor {
+        for pixel in truecolor.pixels.iter_mut() {
+            *pixel = state.u32()?;
+        }
+        truecolor.alpha = state.u8()?;
+    }
+    Ok(())
+}
+
+/**
+ * @brief What an 0NNN routine sees: the Cpu's memory and the VIP keypad latch
+ */
+struct MachineCodeBus<'a> {
+    memory: &'a mut [u8],
+    keypad: [bool; 16],
+    key_latch: usize,
+    written: Option<Range<usize>>, // addresses the routine wrote, for the decode cache
+}
+
+impl Bus for MachineCodeBus<'_> {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.memory[addr as usize % MEMORY_SIZE]
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        let addr = addr as usize % MEMORY_SIZE;
+        self.memory[addr] = value;
+        self.written = Some(match self.written.take() {
+            Some(written) => written.start.min(addr)..written.end.max(addr + 1),
+            None => addr..addr + 1,
+        });
+    }
+
+    // OUT 2 selects the key EF3 reports on
+    fn output(&mut self, port: u8, value: u8) {
+        if port == 2 {
+            self.key_latch = (value & 0x0F) as usize;
+        }
+    }
+
+    fn flag(&self, line: u8) -> bool {
+        line == 3 && self.keypad[self.key_latch]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::DEFAULT_FOREGROUND;
+    use crate::platform::HIRES_ENTRY_POINT;
+
+    // A Cpu with `quirks` after running the first `steps` instructions of `program`
+    fn run(quirks: Quirks, program: &[u8], steps: usize) -> Cpu {
+        let mut cpu = Cpu::with_quirks(quirks);
+        cpu.set_idle_skip(false);
+        cpu.load_program(program);
+        for _ in 0..steps {
+            cpu.cycle([false; 16]);
+        }
+        assert_eq!(cpu.fault(), None);
+        cpu
+    }
+
+    #[test]
+    fn idle_skip_changes_nothing() {
+        let program = [
+            0x60, 0x1E, // LD V0, 30
+            0xF0, 0x15, // LD DT, V0
+            0xF1, 0x07, // loop: LD V1, DT
+            0x31, 0x00, // SE V1, 0
+            0x12, 0x04, // JP loop
+            0x72, 0x01, // ADD V2, 1
+            0x12, 0x00, // JP 0x200
+        ];
+        for timing in [Timing::Instructions { per_frame: 100 }, Timing::Vip] {
+            let [mut skipping, mut stepping] = [true, false].map(|idle_skip| {
+                let mut cpu = Cpu::with_quirks(Quirks {
+                    timing,
+                    ..Quirks::default()
+                });
+                cpu.set_idle_skip(idle_skip);
+                cpu.load_program(&program);
+                cpu
+            });
+            for frame in 0..100 {
+                for cpu in [&mut skipping, &mut stepping] {
+                    while !cpu.cycle([false; 16]).vblank {}
+                }
+                assert!(
+                    skipping.save_state() == stepping.save_state(),
+                    "frame {frame}"
+                );
+                assert_eq!(skipping.cycles(), stepping.cycles());
+                assert_eq!(skipping.delay_timer(), stepping.delay_timer());
+                assert_eq!(skipping.sound_timer(), stepping.sound_timer());
+            }
+            assert!(skipping.idle_cycles_skipped() > 0);
+            assert_eq!(stepping.idle_cycles_skipped(), 0);
+            assert!(skipping.v_registers()[2] >= 3);
+        }
+    }
+
+    #[test]
+    fn hires_geometry_and_clear() {
+        let mut program = vec![0x12, 0x60]; // JP 0x260 over the interpreter patch
+        program.resize(0x60, 0);
+        program.extend_from_slice(&[
+            0x61, 0x3B, // LD V1, 59
+            0xD0, 0x15, // DRW V0, V1, 5: the 0 glyph on the bottom five rows
+            0x02, 0x30, // clear all 64 rows
+            0x12, 0x66, // JP 0x266
+        ]);
+        let hires = Quirks {
+            platform: Platform::Chip8Hires,
+            ..Quirks::default()
+        };
+        let cpu = run(hires, &program, 0);
+        assert_eq!(cpu.program_counter(), HIRES_ENTRY_POINT);
+        assert_eq!((cpu.display().width, cpu.display().height), (64, 64));
+
+        let cpu = run(hires, &program, 2);
+        assert_eq!(cpu.display().get_pixel(0, 59), 1);
+        assert_eq!(cpu.display().get_pixel(0, 63), 1);
+        let cpu = run(hires, &program, 3);
+        assert!(cpu.display().rows().flatten().all(|pixel| pixel == 0));
+        assert_eq!(cpu.program_counter(), 0x266);
+    }
+
+    fn chip8x() -> Quirks {
+        Quirks {
+            platform: Platform::Chip8X,
+            ..Quirks::default()
+        }
+    }
+
+    // Foreground colour of the 8x1 cell holding pixel (x, y)
+    fn cell(cpu: &Cpu, x: usize, y: usize) -> Color {
+        let colors = cpu.display().colors.as_ref().expect("CHIP-8X has colours");
+        colors.cells[y * 8 + x / 8]
+    }
+
+    #[test]
+    fn chip8x_colour_zones() {
+        let program = [
+            0x60, 0x12, // LD V0, 0x12: zones 2 and 3 across
+            0x61, 0x01, // LD V1, 0x01: zone 1 down
+            0x62, 0x04, // LD V2, 4 (green)
+            0xB0, 0x20, // COL V0, V2, 0
+        ];
+        let cpu = run(chip8x(), &program, 4);
+        for y in 0..32 {
+            for x in (0..64).step_by(8) {
+                let zone = (16..32).contains(&x) && (4..8).contains(&y);
+                let expected = if zone {
+                    Color::Green
+                } else {
+                    DEFAULT_FOREGROUND
+                };
+                assert_eq!(cell(&cpu, x, y), expected, "({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn chip8x_colour_cells() {
+        let program = [
+            0x60, 0x2A, // LD V0, 42
+            0x61, 0x0A, // LD V1, 10
+            0x62, 0x06, // LD V2, 6 (aqua)
+            0xB0, 0x23, // COL V0, V2, 3
+        ];
+        let cpu = run(chip8x(), &program, 4);
+        for y in 9..14 {
+            let expected = if (10..13).contains(&y) {
+                Color::Aqua
+            } else {
+                DEFAULT_FOREGROUND
+            };
+            assert_eq!(cell(&cpu, 40, y), expected, "row {y}");
+            assert_eq!(cell(&cpu, 48, y), DEFAULT_FOREGROUND);
+        }
+    }
+
+    #[test]
+    fn chip8x_background() {
+        // BGC, three times
+        let program = [0x02, 0xA0, 0x02, 0xA0, 0x02, 0xA0];
+        for (steps, color) in [(0, Color::Blue), (1, Color::Black), (3, Color::Red)] {
+            let cpu = run(chip8x(), &program, steps);
+            assert_eq!(cpu.display().color_at(0, 0), color);
+        }
+    }
+
+    #[test]
+    fn chip8x_second_keypad() {
+        let program = [
+            0x60, 0x05, // LD V0, 5
+            0xE0, 0xF2, // SKP2 V0
+            0x61, 0x01, // LD V1, 1
+            0xE0, 0xF5, // SKNP2 V0
+            0x62, 0x01, // LD V2, 1
+        ];
+        let mut key5 = [false; 16];
+        key5[5] = true;
+        for (second, ran) in [(key5, [0, 1]), ([false; 16], [1, 0])] {
+            let mut cpu = Cpu::with_quirks(chip8x());
+            cpu.set_idle_skip(false);
+            cpu.load_program(&program);
+            cpu.set_second_keypad(second);
+            // The first keypad's 5 doesn't count
+            while cpu.program_counter() < 0x30A {
+                cpu.cycle(key5);
+            }
+            assert_eq!(cpu.v_registers()[1..3], ran);
+        }
+    }
+
+    #[test]
+    fn shift_quirk() {
+        // V1 = 0x81, V2 = 0x03; V1 >>= 1 or V1 = V2 >> 1; V3 = V1; the same for <<
+        let program = [0x61, 0x81, 0x62, 0x03, 0x81, 0x26, 0x83, 0x10, 0x81, 0x2E];
+        let cpu = run(Quirks::default(), &program, 5);
+        assert_eq!(cpu.v_registers()[3], 0x40);
+        assert_eq!(cpu.v_registers()[1], 0x80);
+        assert_eq!(cpu.v_registers()[0xF], 0);
+        let vip = Quirks {
+            shift_vy: true,
+            ..Quirks::default()
+        };
+        let cpu = run(vip, &program, 5);
+        assert_eq!(cpu.v_registers()[3], 0x01);
+        assert_eq!(cpu.v_registers()[1], 0x06);
+        assert_eq!(cpu.v_registers()[0xF], 0);
+    }
+
+    #[test]
+    fn index_increment_quirk() {
+        // I = 0x300; store V0 - V2; I = 0x300; load V0 - V3
+        let program = [0xA3, 0x00, 0xF2, 0x55, 0xA3, 0x00, 0xF3, 0x65];
+        for (index_increment, store, load) in [
+            (IndexIncrement::Unchanged, 0x300, 0x300),
+            (IndexIncrement::ByX, 0x302, 0x303),
+            (IndexIncrement::ByXPlusOne, 0x303, 0x304),
+        ] {
+            let quirks = Quirks {
+                index_increment,
+                ..Quirks::default()
+            };
+            assert_eq!(run(quirks, &program, 2).index_register(), store);
+            assert_eq!(run(quirks, &program, 4).index_register(), load);
+        }
+    }
+
+    #[test]
+    fn index_wraps_around_memory() {
+        // I = 0xFFE; V0 - V3 = 1 - 4; store V0 - V3; load V0 - V3; BCD of V0
+        let program = [
+            0xAF, 0xFE, 0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0x63, 0x04, 0xF3, 0x55, 0xF3, 0x65,
+            0xF0, 0x33,
+        ];
+        let quirks = Quirks {
+            index_increment: IndexIncrement::ByXPlusOne,
+            ..Quirks::default()
+        };
+        let cpu = run(quirks, &program, 6);
+        assert_eq!(cpu.memory()[0xFFE..], [1, 2]);
+        assert_eq!(cpu.memory()[..2], [3, 4]);
+        assert_eq!(cpu.index_register(), 0x1002);
+        let cpu = run(quirks, &program, 8);
+        assert_eq!(cpu.fault(), None);
+        assert_eq!(cpu.index_register(), 0x1006);
+        assert_eq!(cpu.v_registers()[..4], cpu.memory()[2..6]);
+    }
+
+    #[test]
+    fn clip_quirk() {
+        // Draw the 0 glyph at (62, 30), half off the right and bottom edges
+        let program = [0x60, 0x3E, 0x61, 0x1E, 0xA0, 0x00, 0xD0, 0x15];
+        let cpu = run(Quirks::default(), &program, 4);
+        assert_eq!(cpu.display().get_pixel(1, 0), 1);
+        assert_eq!(cpu.display().get_pixel(62, 30), 1);
+        let clip = Quirks {
+            clip_sprites: true,
+            ..Quirks::default()
+        };
+        let cpu = run(clip, &program, 4);
+        assert_eq!(cpu.display().get_pixel(1, 0), 0);
+        assert_eq!(cpu.display().get_pixel(62, 30), 1);
+        assert_eq!(
+            (0..64).map(|x| cpu.display().get_pixel(x, 0)).sum::<u8>(),
+            0
+        );
+    }
+
+    #[test]
+    fn jump_quirk() {
+        // V0 = 2, V3 = 4; B310
+        let program = [0x60, 0x02, 0x63, 0x04, 0xB3, 0x10];
+        assert_eq!(run(Quirks::default(), &program, 3).program_counter(), 0x312);
+        let schip = Quirks {
+            jump_vx: true,
+            ..Quirks::default()
+        };
+        assert_eq!(run(schip, &program, 3).program_counter(), 0x314);
+    }
+
+    #[test]
+    fn logic_quirk() {
+        // VF = 1; V0 |= V1
+        let program = [0x6F, 0x01, 0x80, 0x11];
+        assert_eq!(run(Quirks::default(), &program, 2).v_registers()[0xF], 1);
+        let vip = Quirks {
+            logic_resets_vf: true,
+            ..Quirks::default()
+        };
+        assert_eq!(run(vip, &program, 2).v_registers()[0xF], 0);
+    }
+
+    #[test]
+    fn save_state_keeps_quirks() {
+        let quirks = Quirks {
+            shift_vy: true,
+            index_increment: IndexIncrement::ByX,
+            clip_sprites: true,
+            jump_vx: true,
+            logic_resets_vf: true,
+            ..Quirks::default()
+        };
+        let saved = run(quirks, &[0x60, 0x01], 1).save_state();
+        let mut cpu = Cpu::new();
+        cpu.load_state(&saved).unwrap();
+        assert_eq!(*cpu.quirks(), quirks);
+        assert_eq!(cpu.save_state(), saved);
+    }
+
+    fn megachip() -> Quirks {
+        Quirks {
+            platform: Platform::MegaChip,
+            ..Quirks::default()
+        }
+    }
+
+    // MEGAON, load two palette entries, then draw a 2x1 sprite of entries 1 and 2 at
+    // (4, 4). `extra` runs after that, with a sprite of entries 2 and 1 at 0x22A.
+    fn megachip_program(extra: &[u8]) -> Vec<u8> {
+        let mut program = vec![
+            0x00, 0x11, // MEGAON
+            0x01, 0x00, 0x02, 0x20, // LDHI I, 0x000220
+            0x02, 0x02, // LDPAL 2
+            0x03, 0x02, // SPRW 2
+            0x04, 0x01, // SPRH 1
+            0xA2, 0x28, // LD I, 0x228
+            0x60, 0x04, // LD V0, 4
+            0xD0, 0x01, // DRW V0, V0, 1
+        ];
+        program.extend_from_slice(extra);
+        program.resize(0x20, 0);
+        program.extend_from_slice(&[0xFF, 0x10, 0x20, 0x30, 0xFF, 0x40, 0x50, 0x60]);
+        program.extend_from_slice(&[0x01, 0x02, 0x02, 0x01]);
+        program
+    }
+
+    #[test]
+    fn megachip_palette() {
+        let cpu = run(megachip(), &megachip_program(&[]), 3);
+        let palette = &cpu.megachip().unwrap().palette;
+        assert_eq!(palette[1], 0xFF10_2030);
+        assert_eq!(palette[2], 0xFF40_5060);
+        assert_eq!(palette[3], 0);
+
+        let cpu = run(megachip(), &megachip_program(&[]), 8);
+        let display = cpu.display();
+        assert_eq!((display.width, display.height), (256, 192));
+        assert_eq!(display.index_at(4, 4), 1);
+        assert_eq!(display.truecolor_at(4, 4), 0x10_2030);
+        assert_eq!(display.index_at(5, 4), 2);
+        assert_eq!(display.truecolor_at(5, 4), 0x40_5060);
+        assert_eq!(display.index_at(6, 4), 0);
+        assert_eq!(cpu.v_registers()[0xF], 0);
+    }
+
+    #[test]
+    fn megachip_blending() {
+        // The second sprite lands on the first, blended as set by BMODE
+        for (mode, expected) in [
+            (0, 0x40_5060),
+            (1, 0x1C_2C3C),
+            (2, 0x28_3848),
+            (3, 0x50_7090),
+            (4, 0x04_0A12),
+        ] {
+            let extra = [
+                0x08, mode, // BMODE
+                0xA2, 0x2A, // LD I, 0x22A
+                0xD0, 0x01, // DRW V0, V0, 1
+            ];
+            let cpu = run(megachip(), &megachip_program(&extra), 11);
+            assert_eq!(cpu.display().index_at(4, 4), 2, "mode {mode}");
+            assert_eq!(cpu.display().truecolor_at(4, 4), expected, "mode {mode}");
+            assert_eq!(cpu.v_registers()[0xF], 1, "mode {mode}");
+        }
+    }
+
+    #[test]
+    fn megachip_clear_presents_frame() {
+        let cpu = run(megachip(), &megachip_program(&[0x00, 0xE0]), 8);
+        let frame = &cpu.megachip().unwrap().frame;
+        assert_eq!(frame.index_at(4, 4), 0);
+        assert_eq!(cpu.output_state().display.index_at(4, 4), 0);
+
+        let cpu = run(megachip(), &megachip_program(&[0x00, 0xE0]), 9);
+        let frame = &cpu.megachip().unwrap().frame;
+        assert_eq!(frame.truecolor_at(4, 4), 0x10_2030);
+        assert_eq!(frame.truecolor_at(5, 4), 0x40_5060);
+        assert_eq!(cpu.display().index_at(4, 4), 0);
+        assert_eq!(cpu.output_state().display.truecolor_at(4, 4), 0x10_2030);
     }
 }
@@ -0,0 +1,193 @@
+/*!
+ * @file decode_cache.rs
+ * @brief Pre-decoded instructions for every address, so cycle doesn't decode
+ *        the same opcode again each time it runs
+ *
+ * Memory is decoded a page at a time, the first time an instruction on the
+ * page is needed. There is an entry per byte address, since CHIP-8 code can
+ * start at odd addresses. Every write to memory has to be reported: a single
+ * byte re-decodes the two opcodes that include it, and larger writes drop the
+ * pages they touch.
+ */
+use crate::instruction::{decode_for, Instruction};
+use crate::platform::Platform;
+use std::ops::Range;
+
+const PAGE_BITS: usize = 8;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+
+#[derive(Debug, Clone)]
+pub struct DecodeCache {
+    platform: Platform,
+    pages: Vec<Option<Box<[Instruction]>>>, // grown as pages are first used
+}
+
+// Opcode at `addr`, wrapping around the end of memory
+fn opcode_at(memory: &[u8], addr: usize) -> u16 {
+    ((memory[addr % memory.len()] as u16) << 8) | memory[(addr + 1) % memory.len()] as u16
+}
+
+impl DecodeCache {
+    pub fn new(platform: Platform) -> Self {
+        DecodeCache {
+            platform,
+            pages: Vec::new(),
+        }
+    }
+
+    // The instruction at `addr`, as decode_for would decode it from `memory`
+    pub fn get(&mut self, memory: &[u8], addr: usize) -> Instruction {
+        let page = addr >> PAGE_BITS;
+        if page >= self.pages.len() {
+            self.pages.resize_with(page + 1, || None);
+        }
+        let platform = self.platform;
+        let entries = self.pages[page].get_or_insert_with(|| {
+            let start = page << PAGE_BITS;
+            (start..start + PAGE_SIZE)
+                .map(|addr| decode_for(platform, opcode_at(memory, addr)))
+                .collect()
+        });
+        entries[addr & (PAGE_SIZE - 1)]
+    }
+
+    // memory[addr] was just written
+    pub fn write(&mut self, memory: &[u8], addr: usize) {
+        let previous = (addr + memory.len() - 1) % memory.len();
+        for addr in [previous, addr] {
+            if let Some(Some(entries)) = self.pages.get_mut(addr >> PAGE_BITS) {
+                entries[addr & (PAGE_SIZE - 1)] =
+                    decode_for(self.platform, opcode_at(memory, addr));
+            }
+        }
+    }
+
+    // memory[range] was just written
+    pub fn write_range(&mut self, memory: &[u8], range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        // The opcode starting just before the range includes its first byte
+        let first = (range.start + memory.len() - 1) % memory.len();
+        let pages = (range.start >> PAGE_BITS)..=((range.end - 1) >> PAGE_BITS);
+        for page in pages.chain([first >> PAGE_BITS]) {
+            if let Some(entries) = self.pages.get_mut(page) {
+                *entries = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cheats::Effect;
+    use crate::cpu::Cpu;
+    use crate::quirks::{MemoryLayout, Quirks};
+
+    fn run(cpu: &mut Cpu, steps: usize) {
+        for _ in 0..steps {
+            cpu.cycle([false; 16]);
+        }
+        assert_eq!(cpu.fault(), None);
+    }
+
+    fn load(quirks: Quirks, program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::with_quirks(quirks);
+        cpu.set_idle_skip(false);
+        cpu.load_program(program);
+        cpu
+    }
+
+    #[test]
+    fn writes_redecode() {
+        let mut memory = vec![0; 0x1000];
+        let mut cache = DecodeCache::new(Platform::Chip8);
+        assert_eq!(cache.get(&memory, 0x2FF), Instruction::Sys { nnn: 0 });
+        // The opcode at 0x2FF straddles two pages
+        memory[0x300] = 0x10;
+        cache.write(&memory, 0x300);
+        assert_eq!(cache.get(&memory, 0x2FF), Instruction::Sys { nnn: 0x10 });
+        memory[0x2FF] = 0x61;
+        memory[0x300] = 0x23;
+        cache.write_range(&memory, 0x2FF..0x301);
+        assert_eq!(
+            cache.get(&memory, 0x2FF),
+            Instruction::LdImm { x: 1, kk: 0x23 }
+        );
+        assert_eq!(cache.get(&memory, 0x2FE), Instruction::Sys { nnn: 0x61 });
+    }
+
+    #[test]
+    fn fx55_rewrites_the_next_instruction() {
+        let program = [
+            0xA2, 0x08, // LD I, 0x208
+            0x60, 0x62, // LD V0, 0x62
+            0x61, 0x42, // LD V1, 0x42
+            0xF1, 0x55, // LD [I], V1: 0x208 becomes LD V2, 0x42
+            0x00, 0x00,
+        ];
+        let mut cpu = load(Quirks::default(), &program);
+        run(&mut cpu, 5);
+        assert_eq!(cpu.v_registers()[2], 0x42);
+    }
+
+    #[test]
+    fn fx33_rewrites_the_next_instruction() {
+        let program = [
+            0xA2, 0x09, // LD I, 0x209
+            0x60, 0xFF, // LD V0, 255
+            0x00, 0x00, 0xF0, 0x33, // LD B, V0: 0x208 becomes LD V1, 2
+            0x61, 0x00,
+        ];
+        let mut cpu = load(Quirks::default(), &program);
+        run(&mut cpu, 5);
+        assert_eq!(cpu.v_registers()[1], 2);
+    }
+
+    #[test]
+    fn vip_display_drawn_into_code() {
+        let vip = Quirks {
+            memory_layout: MemoryLayout::Vip,
+            ..Quirks::default()
+        };
+        let program = [
+            0xA2, 0x20, // LD I, 0x220
+            0x60, 0x08, // LD V0, 8
+            0x61, 0x00, // LD V1, 0
+            0xD0, 0x11, // DRW V0, V1, 1: 0xF00 becomes RET
+            0x2F, 0x00, // CALL 0xF00
+            0xA2, 0x21, // LD I, 0x221
+            0x60, 0x00, // LD V0, 0
+            0xD0, 0x11, // DRW V0, V1, 1: 0xF00 becomes LD V5, 0xEE
+            0x2F, 0x00, // CALL 0xF00
+        ];
+        let mut program = program.to_vec();
+        program.resize(0x20, 0);
+        program.extend_from_slice(&[0xEE, 0x65]);
+        let mut cpu = load(vip, &program);
+        run(&mut cpu, 6);
+        assert_eq!(cpu.program_counter(), 0x20A);
+        run(&mut cpu, 5);
+        assert_eq!(cpu.v_registers()[5], 0xEE);
+    }
+
+    #[test]
+    fn replace_cheat_runs_the_new_opcode() {
+        let program = [
+            0x60, 0x01, // LD V0, 1
+            0x12, 0x00, // JP 0x200
+        ];
+        let mut cpu = load(Quirks::default(), &program);
+        run(&mut cpu, 2);
+        cpu.set_cheats(&[Effect::Replace {
+            addr: 0x200,
+            opcode: 0x6042,
+        }]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.v_registers()[0], 0x42);
+        cpu.set_cheats(&[]);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.v_registers()[0], 1);
+    }
+}
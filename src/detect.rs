@@ -0,0 +1,337 @@
+/*!
+ * @file detect.rs
+ * @brief Guess which CHIP-8 variant a ROM was written for from the opcodes it uses
+ *
+ * Two passes over the ROM: a walk of the code reachable from the entry point
+ * (following jumps, calls and skips) and a plain disassembly of every word.
+ * Variant-only opcodes found by the walk are strong evidence; ones found only
+ * by the disassembly may be sprite or other data and count half as much, and
+ * never outweigh a variant the walk found.
+ */
+use crate::cpu::PROGRAM_START;
+use crate::instruction::{decode, disassemble, Instruction};
+use crate::platform::{Platform, HIRES_CLEAR_SCREEN, HIRES_ENTRY_POINT, HIRES_HEADER};
+use crate::quirks::{IndexIncrement, MemoryLayout, Quirks, VIP_STACK_DEPTH};
+use crate::timing::Timing;
+use std::collections::HashSet;
+
+// Instructions per frame for the later, faster interpreters
+const SUPERCHIP_TICKRATE: u32 = 30;
+const XOCHIP_TICKRATE: u32 = 100;
+
+// Evidence weights
+const REACHABLE_WEIGHT: u32 = 2;
+const DATA_WEIGHT: u32 = 1;
+const HIRES_HEADER_CONFIDENCE: f64 = 0.9;
+
+/**
+ * @brief The variants a ROM can be recognised as
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Family {
+    Chip8,
+    Chip8Hires,
+    // CHIP-8 that calls 1802 machine code through 0NNN
+    HybridVip,
+    SuperChip,
+    XoChip,
+}
+
+impl Family {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Family::Chip8 => "chip8",
+            Family::Chip8Hires => "hires",
+            Family::HybridVip => "hybrid-vip",
+            Family::SuperChip => "superchip",
+            Family::XoChip => "xochip",
+        }
+    }
+
+    // Whether the Cpu implements the variant; the others run as CHIP-8
+    pub fn supported(&self) -> bool {
+        !matches!(self, Family::SuperChip | Family::XoChip)
+    }
+
+    // The variant an opcode belongs to, if only that variant (or a later one) has it
+    fn of_opcode(opcode: u16, hires: bool) -> Option<Family> {
+        match opcode {
+            // Zero padding, not a call to address 0
+            0x0000 => None,
+            0x00FF => Some(Family::SuperChip),
+            0xF000 => Some(Family::XoChip),
+            _ => match (opcode >> 12, opcode & 0x000F, opcode & 0x00FF) {
+                (0xD, 0x0, _) => Some(Family::SuperChip),
+                (0xF, _, 0x30) => Some(Family::SuperChip),
+                (0x5, 0x2, _) => Some(Family::XoChip),
+                // 00E0, 00EE and the SCHIP 00CN - 00FF are interpreter opcodes,
+                // and on CHIP-8 hires 0230 is its clear screen
+                (0x0, _, _) if opcode & 0x0F00 == 0 && opcode & 0x00F0 >= 0xC0 => None,
+                (0x0, _, _) if hires && opcode as usize == HIRES_CLEAR_SCREEN => None,
+                (0x0, _, _) => Some(Family::HybridVip),
+                _ => None,
+            },
+        }
+    }
+}
+
+/**
+ * @brief An opcode that points at a variant
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Evidence {
+    pub addr: usize,
+    pub opcode: u16,
+    pub family: Family,
+    pub reachable: bool, // found by the code walk rather than only in the disassembly
+}
+
+/**
+ * @brief The likeliest variant, how sure the guess is, and why
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Detection {
+    pub family: Family,
+    pub confidence: f64, // 0.0 - 1.0
+    pub hires_header: bool,
+    pub evidence: Vec<Evidence>,
+}
+
+impl Detection {
+    // Quirks to run the ROM with, the ones its variant's interpreter had.
+    // Variants the Cpu lacks get CHIP-8 with their interpreter's speed and
+    // quirks, which is the best it can do for them; see warning.
+    pub fn quirks(&self) -> Quirks {
+        let platform = if self.hires_header {
+            Platform::Chip8Hires
+        } else {
+            Platform::Chip8
+        };
+        let mut quirks = Quirks {
+            platform,
+            ..Quirks::default()
+        };
+        match self.family {
+            Family::Chip8 | Family::Chip8Hires => {}
+            Family::HybridVip => {
+                quirks.machine_code = true;
+                quirks.memory_layout = MemoryLayout::Vip;
+                quirks.stack_depth = Some(VIP_STACK_DEPTH);
+                quirks.shift_vy = true;
+                quirks.index_increment = IndexIncrement::ByXPlusOne;
+                quirks.clip_sprites = true;
+                quirks.logic_resets_vf = true;
+            }
+            // SUPER-CHIP 1.1
+            Family::SuperChip => {
+                quirks.timing = Timing::Instructions {
+                    per_frame: SUPERCHIP_TICKRATE,
+                };
+                quirks.clip_sprites = true;
+                quirks.jump_vx = true;
+            }
+            Family::XoChip => {
+                quirks.timing = Timing::Instructions {
+                    per_frame: XOCHIP_TICKRATE,
+                };
+                quirks.shift_vy = true;
+                quirks.index_increment = IndexIncrement::ByXPlusOne;
+            }
+        }
+        quirks
+    }
+
+    // What to tell the user when the ROM needs a variant the Cpu lacks
+    pub fn warning(&self) -> Option<String> {
+        (!self.family.supported()).then(|| {
+            format!(
+                "{} is not supported: running as {} with its quirks and speed, \
+                 where its own opcodes do nothing",
+                self.family.name(),
+                self.quirks().platform.name()
+            )
+        })
+    }
+}
+
+pub fn detect(program: &[u8]) -> Detection {
+    let hires_header = program.starts_with(&HIRES_HEADER);
+    let entry = if hires_header {
+        HIRES_ENTRY_POINT
+    } else {
+        PROGRAM_START
+    };
+    let word = |addr: usize| -> Option<u16> {
+        let offset = addr.checked_sub(PROGRAM_START)?;
+        let bytes = program.get(offset..offset + 2)?;
+        Some(((bytes[0] as u16) << 8) | bytes[1] as u16)
+    };
+
+    // Walk the reachable code
+    let mut reachable = HashSet::new();
+    let mut pending = vec![entry];
+    let (mut walked, mut unknown) = (0, 0);
+    while let Some(addr) = pending.pop() {
+        let Some(opcode) = word(addr) else {
+            continue;
+        };
+        if !reachable.insert(addr) {
+            continue;
+        }
+        walked += 1;
+        let next = addr + 2;
+        match opcode >> 12 {
+            0x0 if matches!(opcode, 0x0000 | 0x00EE | 0x00FD) => {} // padding, return, SCHIP exit
+            0x1 => pending.push((opcode & 0x0FFF) as usize),
+            0x2 => pending.extend([(opcode & 0x0FFF) as usize, next]),
+            0xB => {} // computed jump: target unknown
+            0x3 | 0x4 | 0x5 | 0x9 => pending.extend([next, next + 2]),
+            0xE if matches!(opcode & 0x00FF, 0x9E | 0xA1) => pending.extend([next, next + 2]),
+            0xF if opcode == 0xF000 => pending.push(next + 2), // XO-CHIP long I
+            _ => pending.push(next),
+        }
+        if matches!(decode(opcode), Instruction::Unknown(_))
+            && Family::of_opcode(opcode, hires_header).is_none()
+        {
+            unknown += 1;
+        }
+    }
+
+    let mut evidence = Vec::new();
+    for (addr, opcode, _) in disassemble(program, PROGRAM_START) {
+        let reachable = reachable.contains(&addr);
+        match Family::of_opcode(opcode, hires_header) {
+            // Too many data bytes look like 0NNN for those to count
+            Some(Family::HybridVip) if !reachable => {}
+            Some(family) => evidence.push(Evidence {
+                addr,
+                opcode,
+                family,
+                reachable,
+            }),
+            None => {}
+        }
+    }
+    // Reachable code at odd addresses is missed by the disassembly
+    let mut odd: Vec<usize> = reachable
+        .iter()
+        .copied()
+        .filter(|addr| (addr - PROGRAM_START) % 2 == 1)
+        .collect();
+    odd.sort();
+    for addr in odd {
+        let opcode = word(addr).unwrap_or_default();
+        if let Some(family) = Family::of_opcode(opcode, hires_header) {
+            evidence.push(Evidence {
+                addr,
+                opcode,
+                family,
+                reachable: true,
+            });
+        }
+    }
+
+    // Later variants include the earlier ones' opcodes, so the latest one
+    // with any evidence wins. Evidence only in the data counts only when the
+    // walk found none at all: a stray DXY0 in sprite data shouldn't turn a
+    // ROM whose code is plainly hybrid VIP into SUPER-CHIP.
+    let score = |family: Family| -> u32 {
+        evidence
+            .iter()
+            .filter(|evidence| evidence.family == family)
+            .map(|evidence| {
+                if evidence.reachable {
+                    REACHABLE_WEIGHT
+                } else {
+                    DATA_WEIGHT
+                }
+            })
+            .sum()
+    };
+    let any_reachable = evidence.iter().any(|evidence| evidence.reachable);
+    let found = [Family::XoChip, Family::SuperChip, Family::HybridVip]
+        .into_iter()
+        .filter(|&family| {
+            !any_reachable
+                || evidence
+                    .iter()
+                    .any(|evidence| evidence.family == family && evidence.reachable)
+        })
+        .map(|family| (family, score(family)))
+        .find(|&(_, score)| score > 0);
+    let (family, confidence) = match found {
+        Some((family, score)) => (family, score as f64 / (score as f64 + 1.0)),
+        None if hires_header => (Family::Chip8Hires, HIRES_HEADER_CONFIDENCE),
+        // Plain CHIP-8 is as likely as the reachable code is valid CHIP-8
+        None if walked > 0 => (Family::Chip8, (walked - unknown) as f64 / walked as f64),
+        None => (Family::Chip8, 0.0),
+    };
+
+    Detection {
+        family,
+        confidence,
+        hires_header,
+        evidence,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_chip8() {
+        // LD V0, 1; JP 0x202
+        let detection = detect(&[0x60, 0x01, 0x12, 0x02]);
+        assert_eq!(detection.family, Family::Chip8);
+        assert_eq!(detection.confidence, 1.0);
+        assert_eq!(detection.quirks(), Quirks::default());
+        assert_eq!(detection.warning(), None);
+    }
+
+    #[test]
+    fn hybrid_vip() {
+        // SYS 0x300; JP 0x202
+        let detection = detect(&[0x03, 0x00, 0x12, 0x02]);
+        assert_eq!(detection.family, Family::HybridVip);
+        let quirks = detection.quirks();
+        assert!(quirks.machine_code && quirks.shift_vy && quirks.logic_resets_vf);
+        assert_eq!(detection.warning(), None);
+    }
+
+    #[test]
+    fn superchip_is_reported() {
+        // HIGH; JP 0x202
+        let detection = detect(&[0x00, 0xFF, 0x12, 0x02]);
+        assert_eq!(detection.family, Family::SuperChip);
+        assert!(detection.evidence[0].reachable);
+        let quirks = detection.quirks();
+        assert!(quirks.jump_vx && quirks.clip_sprites);
+        assert_eq!(quirks.timing.budget(), SUPERCHIP_TICKRATE);
+        assert!(detection.warning().is_some());
+    }
+
+    #[test]
+    fn data_evidence_does_not_override_code() {
+        // SYS 0x300; JP 0x202; then a sprite row pair that reads as DXY0
+        let detection = detect(&[0x03, 0x00, 0x12, 0x02, 0xD1, 0x20]);
+        assert_eq!(detection.family, Family::HybridVip);
+        assert_eq!(detection.evidence.len(), 2);
+
+        // With no code evidence at all, the data is all there is to go on
+        let detection = detect(&[0x12, 0x00, 0xD1, 0x20]);
+        assert_eq!(detection.family, Family::SuperChip);
+        assert!(!detection.evidence[0].reachable);
+    }
+
+    #[test]
+    fn hires_header() {
+        // JP 0x260, then padding up to the hires entry point
+        let mut program = vec![0x12, 0x60];
+        program.resize(0x60, 0);
+        program.extend_from_slice(&[0x02, 0x30, 0x12, 0x62]);
+        let detection = detect(&program);
+        assert_eq!(detection.family, Family::Chip8Hires);
+        assert_eq!(detection.quirks().platform, Platform::Chip8Hires);
+    }
+}
@@ -1,73 +1,551 @@
-/**
+/*!
  * @file display.rs
  * @brief Display module to draw whatever is in memmory to the CLI
  */
 
+// (red, green, blue)
+pub type Rgb = (u8, u8, u8);
+
+/**
+ * @brief The eight colours of the VP-590 colour board used by CHIP-8X
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Black,
+    Red,
+    Blue,
+    Violet,
+    Green,
+    Yellow,
+    Aqua,
+    White,
+}
+
+// Background colours in the order 02A0 steps through them
+pub const BACKGROUND_COLORS: [Color; 4] = [Color::Blue, Color::Black, Color::Green, Color::Red];
+
+impl Color {
+    // Colour from the low three bits of a CHIP-8X colour code
+    pub fn from_code(code: u8) -> Color {
+        match code & 0x7 {
+            0 => Color::Black,
+            1 => Color::Red,
+            2 => Color::Blue,
+            3 => Color::Violet,
+            4 => Color::Green,
+            5 => Color::Yellow,
+            6 => Color::Aqua,
+            _ => Color::White,
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn rgb(&self) -> Rgb {
+        match self {
+            Color::Black => (0x00, 0x00, 0x00),
+            Color::Red => (0xFF, 0x00, 0x00),
+            Color::Blue => (0x00, 0x00, 0xFF),
+            Color::Violet => (0xFF, 0x00, 0xFF),
+            Color::Green => (0x00, 0xFF, 0x00),
+            Color::Yellow => (0xFF, 0xFF, 0x00),
+            Color::Aqua => (0x00, 0xFF, 0xFF),
+            Color::White => (0xFF, 0xFF, 0xFF),
+        }
+    }
+
+    // ANSI SGR foreground colour code
+    fn ansi(&self) -> u8 {
+        match self {
+            Color::Black => 30,
+            Color::Red => 31,
+            Color::Green => 32,
+            Color::Yellow => 33,
+            Color::Blue => 34,
+            Color::Violet => 35,
+            Color::Aqua => 36,
+            Color::White => 37,
+        }
+    }
+}
+
+/**
+ * @brief CHIP-8X colour attributes: one foreground colour per 8x1 pixel cell
+ * and a single background colour
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColorLayer {
+    pub background: usize, // index into BACKGROUND_COLORS
+    pub cells: Vec<Color>, // (width / 8) * height foreground colours, row-major
+}
+
+// Foreground colour of every cell at power-on
+pub const DEFAULT_FOREGROUND: Color = Color::Red;
+
+/**
+ * @brief MegaChip truecolor layer: the palette index drawn at each pixel and
+ * the colour it shows once sprites are blended
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Truecolor {
+    pub indices: Vec<u8>, // palette index per pixel, row-major; 0 is unlit
+    pub pixels: Vec<u32>, // 0xRRGGBB per pixel, row-major
+    pub alpha: u8,        // opacity of the whole screen (05NN); 255 is fully shown
+}
+
+// Pixels are packed into words, MSB first: pixel x of a row is bit 63 - x % 64
+// of word x / 64, so an 8-pixel sprite row is a shift and an XOR
+const WORD_BITS: usize = u64::BITS as usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Display {
     pub width: usize,
     pub height: usize,
-    pub buffer: Vec<u8>,
+    words_per_row: usize,
+    words: Vec<u64>, // lit pixels, words_per_row words per row
+    pub colors: Option<ColorLayer>,
+    pub truecolor: Option<Truecolor>,
 }
 
 impl Display {
     pub fn new(width: usize, height: usize) -> Display {
+        let words_per_row = width.div_ceil(WORD_BITS);
         Display {
             width,
             height,
-            buffer: vec![0; width * height],
+            words_per_row,
+            words: vec![0; words_per_row * height],
+            colors: None,
+            truecolor: None,
+        }
+    }
+
+    // A display with a CHIP-8X colour attribute layer
+    pub fn with_colors(width: usize, height: usize) -> Display {
+        Display {
+            colors: Some(ColorLayer {
+                background: 0,
+                cells: vec![DEFAULT_FOREGROUND; width.div_ceil(8) * height],
+            }),
+            ..Display::new(width, height)
         }
     }
 
+    // A MegaChip display: palette indices and colours in a truecolor layer
+    pub fn with_truecolor(width: usize, height: usize) -> Display {
+        Display {
+            truecolor: Some(Truecolor {
+                indices: vec![0; width * height],
+                pixels: vec![0; width * height],
+                alpha: 0xFF,
+            }),
+            ..Display::new(width, height)
+        }
+    }
+
+    // Whether frames need a colour image rather than a bitmap
+    pub fn has_color(&self) -> bool {
+        self.colors.is_some() || self.truecolor.is_some()
+    }
+
+    // Colour the pixel shows, for any kind of display
+    pub fn rgb_at(&self, x: usize, y: usize) -> Rgb {
+        match &self.truecolor {
+            Some(truecolor) => {
+                let pixel = truecolor.pixels[self.pixel_index(x, y)];
+                let fade =
+                    |shift: u32| ((pixel >> shift & 0xFF) * truecolor.alpha as u32 / 0xFF) as u8;
+                (fade(16), fade(8), fade(0))
+            }
+            None => self.color_at(x, y).rgb(),
+        }
+    }
+
+    // Colour the pixel shows: its cell's foreground colour if lit, the
+    // background colour if not; plain displays are white on black
+    pub fn color_at(&self, x: usize, y: usize) -> Color {
+        let lit = self.get_pixel(x, y) == 1;
+        match &self.colors {
+            None if lit => Color::White,
+            None => Color::Black,
+            Some(colors) if lit => {
+                colors.cells[(y % self.height) * self.width.div_ceil(8) + (x % self.width) / 8]
+            }
+            Some(colors) => BACKGROUND_COLORS[colors.background],
+        }
+    }
+
+    // Set the foreground colour of the 8x1 cell holding pixel (x, y); wraps like pixels
+    pub fn set_cell_color(&mut self, x: usize, y: usize, color: Color) {
+        let (width, height) = (self.width, self.height);
+        if let Some(colors) = &mut self.colors {
+            colors.cells[(y % height) * width.div_ceil(8) + (x % width) / 8] = color;
+        }
+    }
+
+    // Row-major index of a pixel in the truecolor layer; wraps like pixels
+    fn pixel_index(&self, x: usize, y: usize) -> usize {
+        (y % self.height) * self.width + x % self.width
+    }
+
+    // Word holding pixel (x, y), and the pixel's bit in it; wraps around both edges
+    fn bit(&self, x: usize, y: usize) -> (usize, u64) {
+        let (x, y) = (x % self.width, y % self.height);
+        (
+            y * self.words_per_row + x / WORD_BITS,
+            1 << (WORD_BITS - 1 - x % WORD_BITS),
+        )
+    }
+
+    // Packed pixels of row y, leftmost pixel in the top bit of the first word
+    pub fn row_words(&self, y: usize) -> &[u64] {
+        let start = y * self.words_per_row;
+        &self.words[start..start + self.words_per_row]
+    }
+
+    // Pixels of row y, 0 or 1 each
+    pub fn row(&self, y: usize) -> impl Iterator<Item = u8> + '_ {
+        (0..self.width).map(move |x| self.get_pixel(x, y))
+    }
+
+    // Pixel rows from top to bottom
+    pub fn rows(&self) -> impl Iterator<Item = impl Iterator<Item = u8> + '_> + '_ {
+        (0..self.height).map(move |y| self.row(y))
+    }
+
     pub fn clear(&mut self) {
-        for i in 0..self.buffer.len() {
-            self.buffer[i] = 0;
+        self.words.fill(0);
+        if let Some(truecolor) = &mut self.truecolor {
+            truecolor.indices.fill(0);
+            truecolor.pixels.fill(0);
+        }
+    }
+
+    // Copy a row-major frame of 0/1 pixels into the display
+    pub fn blit<R: AsRef<[u8]>>(&mut self, rows: &[R]) {
+        for (y, row) in rows.iter().enumerate().take(self.height) {
+            for (x, &pixel) in row.as_ref().iter().enumerate().take(self.width) {
+                self.set_pixel(x, y, pixel);
+            }
+        }
+    }
+
+    // The pixels as bytes, 8 to a byte MSB first, as the VIP keeps its
+    // display in memory; widths are whole bytes
+    pub fn to_bytes(&self, bytes: &mut [u8]) {
+        let bytes_per_row = self.width / 8;
+        for (y, row) in bytes
+            .chunks_mut(bytes_per_row)
+            .take(self.height)
+            .enumerate()
+        {
+            let words = self.row_words(y);
+            for (i, byte) in row.iter_mut().enumerate() {
+                *byte = (words[i / 8] >> (WORD_BITS - 8 - 8 * (i % 8))) as u8;
+            }
+        }
+    }
+
+    // Replace the pixels with bytes laid out as to_bytes writes them
+    pub fn load_bytes(&mut self, bytes: &[u8]) {
+        let bytes_per_row = self.width / 8;
+        for (y, row) in bytes.chunks(bytes_per_row).take(self.height).enumerate() {
+            let start = y * self.words_per_row;
+            let words = &mut self.words[start..start + self.words_per_row];
+            words.fill(0);
+            for (i, &byte) in row.iter().enumerate() {
+                words[i / 8] |= (byte as u64) << (WORD_BITS - 8 - 8 * (i % 8));
+            }
         }
     }
 
     pub fn get_pixel(&self, x: usize, y: usize) -> u8 {
-        let index = (x + (y * self.width)) % self.buffer.len();
-        self.buffer[index]
+        let (word, bit) = self.bit(x, y);
+        (self.words[word] & bit != 0) as u8
     }
 
     pub fn set_pixel(&mut self, x: usize, y: usize, value: u8) {
-        let index = (x + (y * self.width)) % self.buffer.len();
-        self.buffer[index] = value;
+        let (word, bit) = self.bit(x, y);
+        if value & 1 == 1 {
+            self.words[word] |= bit;
+        } else {
+            self.words[word] &= !bit;
+        }
     }
 
     pub fn toggle_pixel(&mut self, x: usize, y: usize) -> bool {
-        let index = (x + (y * self.width)) % self.buffer.len();
-        let old_pixel = self.buffer[index];
-        self.buffer[index] ^= 1;
-        old_pixel == 1
-    }
-
-    pub fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
-        let mut collision = false;
-        for (i, row) in sprite.iter().enumerate() {
-            for j in 0..8 {
-                let pixel = (row >> (7 - j)) & 0x1;
-                let index = (x + j + ((y + i) * self.width)) % self.buffer.len();
-                if pixel == 1 && self.buffer[index] == 1 {
-                    collision = true;
+        let (word, bit) = self.bit(x, y);
+        self.words[word] ^= bit;
+        self.words[word] & bit == 0
+    }
+
+    // Palette index at a pixel of a truecolor display, 0 elsewhere
+    pub fn index_at(&self, x: usize, y: usize) -> u8 {
+        match &self.truecolor {
+            Some(truecolor) => truecolor.indices[self.pixel_index(x, y)],
+            None => 0,
+        }
+    }
+
+    // 0xRRGGBB at a pixel of a truecolor display before alpha, 0 elsewhere
+    pub fn truecolor_at(&self, x: usize, y: usize) -> u32 {
+        match &self.truecolor {
+            Some(truecolor) => truecolor.pixels[self.pixel_index(x, y)],
+            None => 0,
+        }
+    }
+
+    // Draw palette index `index` in colour `rgb` on a truecolor display
+    pub fn set_index(&mut self, x: usize, y: usize, index: u8, rgb: u32) {
+        let offset = self.pixel_index(x, y);
+        if let Some(truecolor) = &mut self.truecolor {
+            truecolor.indices[offset] = index;
+            truecolor.pixels[offset] = rgb;
+        }
+        self.set_pixel(x, y, (index != 0) as u8);
+    }
+
+    // XOR an 8-pixel-wide sprite onto the display at (x, y), wrapping around
+    // both edges, or with `wrap` false cutting off whatever is past them. The
+    // position itself always wraps. Returns whether any lit pixel was turned off.
+    pub fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[u8], wrap: bool) -> bool {
+        let (x, y) = (x % self.width, y % self.height);
+        let rows = if wrap {
+            sprite.len()
+        } else {
+            sprite.len().min(self.height - y)
+        };
+        if !self.width.is_multiple_of(WORD_BITS) {
+            // Rows don't fill whole words: no word-at-a-time wrapping
+            let columns = if wrap { 8 } else { 8.min(self.width - x) };
+            let mut collision = false;
+            for (i, &row) in sprite[..rows].iter().enumerate() {
+                for j in 0..columns {
+                    if (row >> (7 - j)) & 1 == 1 {
+                        collision |= self.toggle_pixel(x + j, y + i);
+                    }
                 }
-                self.buffer[index] ^= pixel;
             }
+            return collision;
         }
-        collision
+
+        let (first, shift) = (x / WORD_BITS, x % WORD_BITS);
+        // The word after the last one in a row is the row's first word
+        let second = (first + 1) % self.words_per_row;
+        let spills = wrap || first + 1 < self.words_per_row;
+        let mut collision = 0;
+        for (i, &row) in sprite[..rows].iter().enumerate() {
+            let start = (y + i) % self.height * self.words_per_row;
+            let bits = (row as u64) << (WORD_BITS - 8);
+            // Whatever doesn't fit in the first word spills into the next
+            let spill = if spills && shift > WORD_BITS - 8 {
+                bits << (WORD_BITS - shift)
+            } else {
+                0
+            };
+            for (word, bits) in [(first, bits >> shift), (second, spill)] {
+                collision |= self.words[start + word] & bits;
+                self.words[start + word] ^= bits;
+            }
+        }
+        collision != 0
     }
 
     pub fn render(&self) {
+        if self.truecolor.is_some() {
+            self.render_truecolor();
+            return;
+        }
+        if self.colors.is_some() {
+            self.render_colors();
+            return;
+        }
         print!("{esc}[0m{esc}[32m{esc}[2J{esc}[1;1H", esc = 27 as char);
 
-        for i in 0..self.buffer.len() {
-            if i % self.width == 0 {
-                println!();
+        for row in self.rows() {
+            println!();
+            for pixel in row {
+                if pixel == 1 {
+                    print!("█");
+                } else {
+                    print!("░");
+                }
             }
-            if self.buffer[i] == 1 {
-                print!("█");
-            } else {
-                print!("░");
+        }
+    }
+
+    // Every pixel as a full block in its colour
+    fn render_colors(&self) {
+        print!("{esc}[0m{esc}[2J{esc}[1;1H", esc = 27 as char);
+
+        for y in 0..self.height {
+            println!();
+            for x in 0..self.width {
+                print!("{}[{}m█", 27 as char, self.color_at(x, y).ansi());
+            }
+        }
+        print!("{}[0m", 27 as char);
+    }
+
+    // A monochrome display in the given [background, foreground] colours
+    pub fn render_mono(&self, colors: [Rgb; 2]) {
+        print!("{esc}[0m{esc}[2J{esc}[1;1H", esc = 27 as char);
+
+        for row in self.rows() {
+            println!();
+            for pixel in row {
+                let (r, g, b) = colors[pixel as usize];
+                print!("{}[38;2;{};{};{}m█", 27 as char, r, g, b);
             }
         }
+        print!("{}[0m", 27 as char);
+    }
+
+    // Two pixel rows per character: a half block in the upper pixel's colour
+    // over a background in the lower one's, with 24-bit ANSI colours
+    fn render_truecolor(&self) {
+        print!("{esc}[0m{esc}[2J{esc}[1;1H", esc = 27 as char);
+
+        for y in (0..self.height).step_by(2) {
+            println!();
+            for x in 0..self.width {
+                let (r, g, b) = self.rgb_at(x, y);
+                let (br, bg, bb) = if y + 1 < self.height {
+                    self.rgb_at(x, y + 1)
+                } else {
+                    (0, 0, 0)
+                };
+                print!(
+                    "{esc}[38;2;{};{};{}m{esc}[48;2;{};{};{}m▀",
+                    r,
+                    g,
+                    b,
+                    br,
+                    bg,
+                    bb,
+                    esc = 27 as char
+                );
+            }
+            print!("{}[0m", 27 as char);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One byte per pixel, drawn a pixel at a time
+    struct Reference {
+        width: usize,
+        height: usize,
+        pixels: Vec<Vec<u8>>,
+    }
+
+    impl Reference {
+        fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[u8], wrap: bool) -> bool {
+            let (x, y) = (x % self.width, y % self.height);
+            let mut collision = false;
+            for (i, &row) in sprite.iter().enumerate() {
+                for j in 0..8 {
+                    let (px, py) = (x + j, y + i);
+                    if !wrap && (px >= self.width || py >= self.height) {
+                        continue;
+                    }
+                    let pixel = &mut self.pixels[py % self.height][px % self.width];
+                    let bit = (row >> (7 - j)) & 1;
+                    collision |= bit == 1 && *pixel == 1;
+                    *pixel ^= bit;
+                }
+            }
+            collision
+        }
+    }
+
+    // Draw sprites at pseudo-random places, edges included, on both and
+    // compare after every one
+    fn matches_reference(width: usize, height: usize) {
+        let mut display = Display::new(width, height);
+        let mut reference = Reference {
+            width,
+            height,
+            pixels: vec![vec![0; width]; height],
+        };
+        let mut seed = 0x2545_F491_u64;
+        let mut next = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize
+        };
+        for draw in 0..500 {
+            // Every other sprite sits across the right or bottom edge
+            let (x, y) = match draw % 4 {
+                0 => (width - 1 - next() % 7, next()),
+                1 => (next(), height - 1 - next() % 15),
+                _ => (next(), next()),
+            };
+            let sprite: Vec<u8> = (0..1 + next() % 15).map(|_| next() as u8).collect();
+            let wrap = draw % 3 != 0;
+            assert_eq!(
+                display.draw_sprite(x, y, &sprite, wrap),
+                reference.draw_sprite(x, y, &sprite, wrap),
+                "collision of draw {draw} at ({x}, {y})"
+            );
+            let rows: Vec<Vec<u8>> = display.rows().map(Iterator::collect).collect();
+            assert!(rows == reference.pixels, "pixels after draw {draw}");
+        }
+    }
+
+    #[test]
+    fn draw_lores() {
+        matches_reference(64, 32);
+    }
+
+    #[test]
+    fn draw_hires() {
+        matches_reference(64, 64);
+    }
+
+    #[test]
+    fn draw_rows_of_several_words() {
+        matches_reference(128, 64);
+        matches_reference(256, 192);
+    }
+
+    #[test]
+    fn draw_rows_of_part_words() {
+        matches_reference(40, 24);
+    }
+
+    #[test]
+    fn collision_only_on_overlap() {
+        let mut display = Display::new(64, 32);
+        assert!(!display.draw_sprite(60, 0, &[0xF0], true));
+        // Shares no lit pixel with the first sprite
+        assert!(!display.draw_sprite(56, 0, &[0xF0], true));
+        assert!(display.draw_sprite(62, 0, &[0xC0], true));
+        assert_eq!(
+            display.row(0).collect::<Vec<_>>()[56..],
+            [1, 1, 1, 1, 1, 1, 0, 0]
+        );
+    }
+
+    #[test]
+    fn wrap_and_clip_at_edges() {
+        let mut display = Display::new(64, 32);
+        display.draw_sprite(62, 31, &[0xFF, 0xFF], true);
+        for (x, y) in [(62, 31), (63, 31), (0, 31), (5, 31), (62, 0), (5, 0)] {
+            assert_eq!(display.get_pixel(x, y), 1, "({x}, {y})");
+        }
+        let mut display = Display::new(64, 32);
+        display.draw_sprite(62, 31, &[0xFF, 0xFF], false);
+        let lit: usize = display.rows().flatten().map(usize::from).sum();
+        assert_eq!(lit, 2);
+        // The position itself wraps, clipping or not
+        display.draw_sprite(64 + 1, 32 + 1, &[0x80], false);
+        assert_eq!(display.get_pixel(1, 1), 1);
     }
 }
@@ -0,0 +1,539 @@
+/*!
+ * @file env.rs
+ * @brief Reinforcement-learning environment over a Cpu, in the style of Gym
+ *
+ * An episode starts from the state the Cpu is in right after the ROM is
+ * loaded: reset restores a save state taken then and reseeds CXKK. Each step
+ * holds one action for `frame_skip` frames. Actions are 0 for no key and
+ * 1 - 16 for keys 0 - F; observations are the display, one byte per pixel.
+ *
+ * What counts as scoring and when an episode is over depends on the game, so
+ * it comes from a config in the input script's operand and condition syntax:
+ *
+ * ```text
+ * frame skip 4
+ * reward bcd[0x2F0]        # reward is how much this went up during the step
+ * done mem[0x2F3] == 0     # any number of these; the episode ends when one holds
+ * max frames 18000
+ * ```
+ *
+ * VecEnv steps many Envs in lockstep across threads for training, writing
+ * into buffers the caller owns and starting a new episode wherever one ends.
+ */
+use crate::cpu::Cpu;
+use crate::quirks::Quirks;
+use crate::script::{parse_condition, parse_number, parse_operand, Condition, Operand, ParseError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_FRAME_SKIP: u32 = 4;
+
+// No key, then each of the 16 keys on its own
+pub const ACTIONS: usize = 17;
+
+/**
+ * @brief Per-game settings: how long actions last, and where reward and the end of an episode come from
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvConfig {
+    pub frame_skip: u32,
+    pub reward: Option<Operand>, // None: reward is always 0
+    pub done: Vec<Condition>,
+    pub max_frames: Option<u64>, // per episode
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        EnvConfig {
+            frame_skip: DEFAULT_FRAME_SKIP,
+            reward: None,
+            done: Vec::new(),
+            max_frames: None,
+        }
+    }
+}
+
+fn parse_setting(words: &[&str], config: &mut EnvConfig) -> Result<(), String> {
+    match words {
+        ["frame", "skip", frames] => match parse_number(frames)? {
+            0 => return Err("frame skip must be at least 1".to_string()),
+            frames => config.frame_skip = frames as u32,
+        },
+        ["reward", operand] => config.reward = Some(parse_operand(operand)?),
+        ["done", rest @ ..] => match parse_condition(rest)? {
+            (condition, []) => config.done.push(condition),
+            (_, rest) => return Err(format!("unexpected `{}`", rest.join(" "))),
+        },
+        ["max", "frames", frames] => config.max_frames = Some(parse_number(frames)? as u64),
+        _ => return Err(format!("unknown setting `{}`", words.join(" "))),
+    }
+    Ok(())
+}
+
+impl EnvConfig {
+    // One setting per line, `#` starts a comment; see the top of this file
+    pub fn parse(source: &str) -> Result<EnvConfig, ParseError> {
+        let mut config = EnvConfig::default();
+        for (index, line) in source.lines().enumerate() {
+            let code = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = code.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            parse_setting(&words, &mut config).map_err(|message| ParseError {
+                line: index + 1,
+                message,
+            })?;
+        }
+        Ok(config)
+    }
+}
+
+/**
+ * @brief One game, stepped an action at a time
+ */
+pub struct Env {
+    cpu: Cpu,
+    start: Vec<u8>, // save state right after loading the ROM
+    config: EnvConfig,
+    width: usize,
+    height: usize,
+    seed: u64,    // of the current episode
+    score: usize, // reward operand after the last step
+    frames: u64,  // into the episode
+    done: bool,
+}
+
+impl Env {
+    pub fn new(program: &[u8], quirks: Quirks, config: EnvConfig) -> Self {
+        let mut cpu = Cpu::with_quirks(quirks);
+        cpu.load_program(program);
+        let (width, height) = quirks.platform.display_size();
+        Env {
+            start: cpu.save_state(),
+            cpu,
+            config,
+            width,
+            height,
+            seed: 0,
+            score: 0,
+            frames: 0,
+            done: false,
+        }
+    }
+
+    pub fn action_space(&self) -> usize {
+        ACTIONS
+    }
+
+    // Bytes in an observation: width * height
+    pub fn observation_size(&self) -> usize {
+        self.width * self.height
+    }
+
+    // (width, height) of the display an observation holds
+    pub fn observation_shape(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    // Mutable access, e.g. to turn on the JIT; reset keeps host-side settings
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn done(&self) -> bool {
+        self.done
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Start a new episode; CXKK's numbers follow from `seed`
+    pub fn reset(&mut self, seed: u64) -> Vec<u8> {
+        self.restart(seed);
+        self.observation()
+    }
+
+    pub fn step(&mut self, action: usize) -> (Vec<u8>, f32, bool) {
+        let (reward, done) = self.advance(action);
+        (self.observation(), reward, done)
+    }
+
+    // reset without building an observation
+    pub fn restart(&mut self, seed: u64) {
+        self.cpu
+            .load_state(&self.start)
+            .expect("the Cpu restores its own save state");
+        self.cpu.seed_random(seed);
+        self.seed = seed;
+        self.score = self.read_score();
+        self.frames = 0;
+        self.done = false;
+    }
+
+    // step without building an observation: hold `action` for up to
+    // frame_skip frames, stopping early if the episode ends. Once it has
+    // ended, steps do nothing until the next reset.
+    pub fn advance(&mut self, action: usize) -> (f32, bool) {
+        assert!(action < ACTIONS, "action {} out of range", action);
+        if self.done {
+            return (0.0, true);
+        }
+        let mut keypad = [false; 16];
+        if action > 0 {
+            keypad[action - 1] = true;
+        }
+
+        for _ in 0..self.config.frame_skip {
+            self.run_frame(keypad);
+            self.done = self.cpu.fault().is_some()
+                || self.config.done.iter().any(|done| done.holds(&self.cpu))
+                || self.config.max_frames.is_some_and(|max| self.frames >= max);
+            if self.done {
+                break;
+            }
+        }
+
+        let score = self.read_score();
+        let reward = score as f32 - self.score as f32;
+        self.score = score;
+        (reward, self.done)
+    }
+
+    fn run_frame(&mut self, keypad: [bool; 16]) {
+        while !self.cpu.cycle(keypad).vblank && self.cpu.fault().is_none() {}
+        self.frames += 1;
+    }
+
+    fn read_score(&self) -> usize {
+        self.config
+            .reward
+            .map_or(0, |reward| reward.read(&self.cpu))
+    }
+
+    pub fn observation(&self) -> Vec<u8> {
+        let mut observation = vec![0; self.observation_size()];
+        self.observe(&mut observation);
+        observation
+    }
+
+    // Write the display into `out`, row by row, 1 for a lit pixel and 0 for
+    // an unlit one. MegaChip's 256x192 mode is scaled down to fit.
+    pub fn observe(&self, out: &mut [u8]) {
+        assert_eq!(
+            out.len(),
+            self.observation_size(),
+            "observation buffer size"
+        );
+        let display = self.cpu.display();
+        for (y, row) in out.chunks_exact_mut(self.width).enumerate() {
+            let source_y = y * display.height / self.height;
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let source_x = x * display.width / self.width;
+                *pixel = match display.truecolor {
+                    Some(_) => (display.index_at(source_x, source_y) != 0) as u8,
+                    None => display.get_pixel(source_x, source_y),
+                };
+            }
+        }
+    }
+}
+
+/**
+ * @brief Envs stepped together, with observations, rewards and dones in caller buffers
+ */
+pub struct VecEnv {
+    envs: Vec<Env>,
+    threads: usize,
+    frames: u64,       // run by step, all envs together
+    elapsed: Duration, // in step
+}
+
+// Step each env in `envs` with its action, starting the next episode where
+// one ended; returns the frames run
+fn step_envs(
+    envs: &mut [Env],
+    stride: u64,
+    actions: &[usize],
+    observations: &mut [u8],
+    rewards: &mut [f32],
+    dones: &mut [bool],
+) -> u64 {
+    let mut frames = 0;
+    let size = observations.len() / envs.len();
+    for (i, env) in envs.iter_mut().enumerate() {
+        let start = env.frames();
+        let (reward, done) = env.advance(actions[i]);
+        frames += env.frames() - start;
+        if done {
+            env.restart(env.seed().wrapping_add(stride));
+        }
+        env.observe(&mut observations[i * size..(i + 1) * size]);
+        rewards[i] = reward;
+        dones[i] = done;
+    }
+    frames
+}
+
+impl VecEnv {
+    // The envs can run different games, but their observations must be the
+    // same size. Steps use every core until set_threads says otherwise.
+    pub fn new(envs: Vec<Env>) -> Self {
+        if let Some(first) = envs.first() {
+            assert!(
+                envs.iter()
+                    .all(|env| env.observation_size() == first.observation_size()),
+                "envs with different observation sizes"
+            );
+        }
+        VecEnv {
+            envs,
+            threads: thread::available_parallelism().map_or(1, |threads| threads.get()),
+            frames: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.envs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.envs.is_empty()
+    }
+
+    pub fn envs(&self) -> &[Env] {
+        &self.envs
+    }
+
+    pub fn envs_mut(&mut self) -> &mut [Env] {
+        &mut self.envs
+    }
+
+    // Of one env; buffers hold len() of them back to back
+    pub fn observation_size(&self) -> usize {
+        self.envs.first().map_or(0, Env::observation_size)
+    }
+
+    // Start env i's episode with seed `seed + i`. Later episodes of env i get
+    // that plus multiples of len(), so no two episodes share a seed.
+    pub fn reset(&mut self, seed: u64, observations: &mut [u8]) {
+        let size = self.observation_size();
+        assert_eq!(
+            observations.len(),
+            self.len() * size,
+            "observation buffer size"
+        );
+        for (i, env) in self.envs.iter_mut().enumerate() {
+            env.restart(seed.wrapping_add(i as u64));
+            env.observe(&mut observations[i * size..(i + 1) * size]);
+        }
+    }
+
+    // Step every env with its action. An env whose episode ended reports the
+    // final reward and done, and the first observation of its next episode.
+    pub fn step(
+        &mut self,
+        actions: &[usize],
+        observations: &mut [u8],
+        rewards: &mut [f32],
+        dones: &mut [bool],
+    ) {
+        let (count, size) = (self.len(), self.observation_size());
+        assert_eq!(actions.len(), count, "action count");
+        assert_eq!(observations.len(), count * size, "observation buffer size");
+        assert_eq!(rewards.len(), count, "reward buffer size");
+        assert_eq!(dones.len(), count, "done buffer size");
+        if count == 0 {
+            return;
+        }
+
+        let start = Instant::now();
+        let stride = count as u64;
+        let chunk = count.div_ceil(self.threads);
+        self.frames += if chunk == count {
+            step_envs(
+                &mut self.envs,
+                stride,
+                actions,
+                observations,
+                rewards,
+                dones,
+            )
+        } else {
+            thread::scope(|scope| {
+                let workers: Vec<_> = self
+                    .envs
+                    .chunks_mut(chunk)
+                    .zip(actions.chunks(chunk))
+                    .zip(observations.chunks_mut(chunk * size))
+                    .zip(rewards.chunks_mut(chunk).zip(dones.chunks_mut(chunk)))
+                    .map(|(((envs, actions), observations), (rewards, dones))| {
+                        scope.spawn(move || {
+                            step_envs(envs, stride, actions, observations, rewards, dones)
+                        })
+                    })
+                    .collect();
+                workers
+                    .into_iter()
+                    .map(|worker| worker.join().expect("env step panicked"))
+                    .sum::<u64>()
+            })
+        };
+        self.elapsed += start.elapsed();
+    }
+
+    // Frames run by step so far, over all envs
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Frames run by step per second of wall-clock time spent in it
+    pub fn fps(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::Comparison;
+    use crate::timing::Timing;
+
+    const PROGRAM: [u8; 10] = [
+        0xD0, 0x05, // DRW V0, V0, 5: the 0 glyph at the top left
+        0x60, 0x03, // LD V0, 3
+        0xE0, 0xA1, // loop: SKNP V0
+        0x75, 0x01, // ADD V5, 1
+        0x12, 0x04, // JP loop
+    ];
+
+    const CONFIG: &str = "\
+frame skip 2
+reward v5           # key 3 scores
+done v5 >= 6
+max frames 40
+";
+
+    fn env() -> Env {
+        let quirks = Quirks {
+            timing: Timing::Instructions { per_frame: 3 },
+            ..Quirks::default()
+        };
+        Env::new(&PROGRAM, quirks, EnvConfig::parse(CONFIG).unwrap())
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = EnvConfig::parse(CONFIG).unwrap();
+        assert_eq!(
+            config,
+            EnvConfig {
+                frame_skip: 2,
+                reward: Some(Operand::V(5)),
+                done: vec![Condition::Compare(Operand::V(5), Comparison::Ge, 6)],
+                max_frames: Some(40),
+            }
+        );
+        assert_eq!(EnvConfig::parse("").unwrap(), EnvConfig::default());
+
+        let error = |source: &str| EnvConfig::parse(source).unwrap_err();
+        assert_eq!(error("\nframe skip 0").line, 2);
+        assert_eq!(
+            error("frame skip 0").message,
+            "frame skip must be at least 1"
+        );
+        assert_eq!(error("done v5 >= 6 now").message, "unexpected `now`");
+        assert_eq!(error("speed 3").message, "unknown setting `speed 3`");
+    }
+
+    #[test]
+    fn episode() {
+        let mut env = env();
+        assert_eq!(env.observation_shape(), (64, 32));
+        let observation = env.reset(1);
+        assert!(observation.iter().all(|&pixel| pixel == 0));
+
+        let (observation, reward, done) = env.step(0);
+        assert_eq!((reward, done), (0.0, false));
+        assert_eq!(env.frames(), 2);
+        assert_eq!(observation[..4], [1, 1, 1, 1]);
+        assert_eq!(observation[64..68], [1, 0, 0, 1]);
+
+        let mut total = 0.0;
+        while !env.done() {
+            let (_, reward, _) = env.step(4);
+            assert!(reward > 0.0);
+            total += reward;
+        }
+        assert_eq!(total, env.cpu().v_registers()[5] as f32);
+        assert_eq!(env.step(4), (env.observation(), 0.0, true));
+
+        env.reset(2);
+        assert_eq!((env.frames(), env.done(), env.seed()), (0, false, 2));
+        assert_eq!(env.cpu().v_registers()[5], 0);
+    }
+
+    #[test]
+    fn max_frames() {
+        let mut env = env();
+        env.reset(0);
+        let mut steps = 0;
+        while !env.advance(0).1 {
+            steps += 1;
+        }
+        assert_eq!((steps, env.frames()), (19, 40));
+    }
+
+    // Observations, rewards and dones after a step
+    type Step = (Vec<u8>, Vec<f32>, Vec<bool>);
+
+    // Run `steps` steps of three envs on `threads` threads, pressing key 3 in
+    // all but the first; returns everything the buffers held after each step
+    fn run_vec(threads: usize, steps: usize) -> (Vec<Step>, u64) {
+        let mut envs = VecEnv::new(vec![env(), env(), env()]);
+        envs.set_threads(threads);
+        let size = envs.observation_size();
+        let mut observations = vec![0; 3 * size];
+        let mut rewards = vec![0.0; 3];
+        let mut dones = vec![false; 3];
+        envs.reset(10, &mut observations);
+        assert_eq!(
+            envs.envs().iter().map(Env::seed).collect::<Vec<_>>(),
+            [10, 11, 12]
+        );
+        let mut history = Vec::new();
+        for _ in 0..steps {
+            envs.step(&[0, 4, 4], &mut observations, &mut rewards, &mut dones);
+            history.push((observations.clone(), rewards.clone(), dones.clone()));
+        }
+        (history, envs.frames())
+    }
+
+    #[test]
+    fn vec_env() {
+        let (history, frames) = run_vec(1, 10);
+        assert_eq!((history, frames), run_vec(2, 10));
+
+        // The scoring envs end an episode and show the first frame of the next
+        let (history, _) = run_vec(3, 10);
+        let ended = history.iter().position(|(_, _, dones)| dones[1]).unwrap();
+        let (observations, _, dones) = &history[ended];
+        assert_eq!(dones, &[false, true, true]);
+        let size = observations.len() / 3;
+        assert!(observations[size..].iter().all(|&pixel| pixel == 0));
+    }
+}
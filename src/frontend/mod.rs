@@ -0,0 +1,152 @@
+/*!
+ * @file frontend/mod.rs
+ * @brief Pluggable video, audio and input backends, and the loop that drives them
+ *
+ * The emulation loop only talks to the three traits below, so a new frontend
+ * (SDL window, web canvas, ...) is a new implementation, not a new loop.
+ */
+use crate::cpu::{Cpu, OutputState};
+
+mod movie;
+mod null;
+mod recording;
+mod scheduler;
+mod scripted;
+mod terminal;
+
+pub use movie::{MovieInput, MovieRecorder};
+pub use null::{NullAudio, NullInput, NullVideo};
+pub use recording::{RecordingAudio, RecordingVideo};
+pub use scheduler::{Scheduler, Tick, DEFAULT_FRAME_SKIP, MIN_SPEED};
+pub use scripted::ScriptedInput;
+pub use terminal::{TerminalAudio, TerminalInput, TerminalVideo};
+
+pub type Keypad = [bool; 16];
+
+/**
+ * @brief Runtime speed (and cheat) requests from the user, handled by the main loop
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpeedControl {
+    // Toggle running unthrottled, showing one frame in frame_skip
+    FastForward,
+    // Halve the frame rate, down to MIN_SPEED
+    Slower,
+    // Back to 60 Hz, fast-forward off
+    NormalSpeed,
+    // Toggle pause
+    Pause,
+    // Pause after running one more frame
+    Step,
+    // Double / halve the instructions run per frame
+    MoreCycles,
+    FewerCycles,
+    // Turn the nth cheat in the ROM's cheat file on or off, counting from 0
+    ToggleCheat(usize),
+}
+
+/**
+ * @brief Receives frames to show; only called when the display changed
+ */
+pub trait VideoSink {
+    fn present(&mut self, output: &OutputState<'_>);
+}
+
+/**
+ * @brief Receives the state of the beeper once per frame
+ */
+pub trait AudioSink {
+    fn update(&mut self, beep: bool);
+}
+
+/**
+ * @brief Produces the keypad state for the next frame, or None to stop the run
+ */
+pub trait InputSource {
+    fn poll(&mut self, cpu: &Cpu) -> Option<Keypad>;
+
+    // CHIP-8X second keypad, read after poll; sources without one leave it up
+    fn second_keypad(&self) -> Keypad {
+        [false; 16]
+    }
+
+    // Speed controls pressed since the last call, or None to stop the run.
+    // Called every host frame, also while paused, when poll isn't.
+    fn speed_controls(&mut self) -> Option<Vec<SpeedControl>> {
+        Some(Vec::new())
+    }
+
+    // Why the source stopped the run unsuccessfully, e.g. a failed script assertion
+    fn failure(&self) -> Option<&str> {
+        None
+    }
+}
+
+/**
+ * @brief A set of backends driven one frame at a time from Cpu::cycle
+ */
+pub struct Frontend {
+    pub video: Box<dyn VideoSink>,
+    pub audio: Box<dyn AudioSink>,
+    pub input: Box<dyn InputSource>,
+    frame: u64,
+    unpresented: bool, // the display changed in a frame that wasn't presented
+}
+
+impl Frontend {
+    pub fn new(
+        video: Box<dyn VideoSink>,
+        audio: Box<dyn AudioSink>,
+        input: Box<dyn InputSource>,
+    ) -> Self {
+        Frontend {
+            video,
+            audio,
+            input,
+            frame: 0,
+            unpresented: false,
+        }
+    }
+
+    // Number of frames completed so far
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // Run one frame: poll input, cycle the Cpu until it reaches vertical blank
+    // (how many instructions that takes depends on Quirks::timing), then hand
+    // the result to the video and audio sinks.
+    // Returns false once the input source asks to stop or the Cpu faults.
+    pub fn run_frame(&mut self, cpu: &mut Cpu) -> bool {
+        self.run_frame_presenting(cpu, true)
+    }
+
+    // run_frame, but with `present` false the video sink isn't called (frame
+    // skipping); the change is presented with the next presented frame
+    pub fn run_frame_presenting(&mut self, cpu: &mut Cpu, present: bool) -> bool {
+        let keypad = match self.input.poll(cpu) {
+            Some(keypad) => keypad,
+            None => return false,
+        };
+        cpu.set_second_keypad(self.input.second_keypad());
+
+        let mut display_changed = false;
+        let mut output_state = cpu.cycle(keypad);
+        display_changed |= output_state.display_changed;
+        while !output_state.vblank && output_state.fault.is_none() {
+            output_state = cpu.cycle(keypad);
+            display_changed |= output_state.display_changed;
+        }
+
+        display_changed |= self.unpresented;
+        if display_changed && present {
+            output_state.display_changed = true;
+            self.video.present(&output_state);
+        }
+        self.unpresented = display_changed && !present;
+        self.audio.update(output_state.beep);
+
+        self.frame += 1;
+        output_state.fault.is_none()
+    }
+}
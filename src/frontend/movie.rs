@@ -0,0 +1,288 @@
+/*!
+ * @file frontend/movie.rs
+ * @brief Input source that plays back an input movie, and one that records
+ *        another source into one (see movie.rs)
+ */
+use super::{InputSource, Keypad, SpeedControl};
+use crate::cheats::Cheats;
+use crate::cpu::Cpu;
+use crate::movie::{Event, Movie};
+use std::io::Write;
+
+// Plays the movie's keys and cheat toggles; the run should start with its
+// seed and cheat set. Stops at the movie's end.
+pub struct MovieInput {
+    movie: Movie,
+    // Next event for poll and for speed_controls; each skips the other's events
+    keys_cursor: usize,
+    toggles_cursor: usize,
+    frame: u64,
+    keypad: Keypad,
+    second_keypad: Keypad,
+}
+
+impl MovieInput {
+    pub fn new(movie: Movie) -> Self {
+        MovieInput {
+            movie,
+            keys_cursor: 0,
+            toggles_cursor: 0,
+            frame: 0,
+            keypad: [false; 16],
+            second_keypad: [false; 16],
+        }
+    }
+
+    // The event at `cursor` if it is due by this frame
+    fn due(&self, cursor: usize) -> Option<&Event> {
+        match self.movie.events.get(cursor) {
+            Some((frame, event)) if *frame <= self.frame => Some(event),
+            _ => None,
+        }
+    }
+}
+
+impl InputSource for MovieInput {
+    fn poll(&mut self, _cpu: &Cpu) -> Option<Keypad> {
+        if self.movie.length.is_some_and(|length| self.frame >= length) {
+            return None;
+        }
+        while let Some(event) = self.due(self.keys_cursor) {
+            match event {
+                Event::Keys(keypad) => self.keypad = *keypad,
+                Event::SecondKeys(keypad) => self.second_keypad = *keypad,
+                Event::Toggle(_) => {}
+            }
+            self.keys_cursor += 1;
+        }
+        self.frame += 1;
+        Some(self.keypad)
+    }
+
+    fn second_keypad(&self) -> Keypad {
+        self.second_keypad
+    }
+
+    fn speed_controls(&mut self) -> Option<Vec<SpeedControl>> {
+        // Called before the frame's poll, like the toggles were when recorded
+        let mut controls = Vec::new();
+        while let Some(event) = self.due(self.toggles_cursor) {
+            if let Event::Toggle(name) = event {
+                let cheats = &self.movie.cheats;
+                if let Some(index) = cheats.iter().position(|cheat| cheat.name == *name) {
+                    controls.push(SpeedControl::ToggleCheat(index));
+                }
+            }
+            self.toggles_cursor += 1;
+        }
+        Some(controls)
+    }
+}
+
+// Passes another source through, writing what it does as a movie: the seed
+// and cheats given up front, then every change of keys and each cheat toggle.
+// The movie's end is written when the recorder is dropped.
+pub struct MovieRecorder<W: Write> {
+    input: Box<dyn InputSource>,
+    writer: W,
+    cheats: Vec<String>, // names, for the indices in SpeedControl::ToggleCheat
+    frame: u64,
+    keypad: Keypad,
+    second_keypad: Keypad,
+    failed: bool,
+}
+
+impl<W: Write> MovieRecorder<W> {
+    pub fn new(input: Box<dyn InputSource>, writer: W, seed: u64, cheats: &Cheats) -> Self {
+        let mut recorder = MovieRecorder {
+            input,
+            writer,
+            cheats: cheats.iter().map(|cheat| cheat.name.clone()).collect(),
+            frame: 0,
+            keypad: [false; 16],
+            second_keypad: [false; 16],
+            failed: false,
+        };
+        let header = Movie {
+            seed: Some(seed),
+            cheats: cheats.clone(),
+            ..Movie::default()
+        };
+        recorder.write(&header.to_string());
+        recorder
+    }
+
+    fn write(&mut self, text: &str) {
+        if self.failed {
+            return;
+        }
+        if let Err(err) = self.writer.write_all(text.as_bytes()) {
+            eprintln!("movie recording stopped: {}", err);
+            self.failed = true;
+        }
+    }
+
+    fn record(&mut self, event: Event) {
+        let line = format!("{} {}\n", self.frame, event);
+        self.write(&line);
+    }
+}
+
+impl<W: Write> InputSource for MovieRecorder<W> {
+    fn poll(&mut self, cpu: &Cpu) -> Option<Keypad> {
+        let keypad = self.input.poll(cpu)?;
+        if keypad != self.keypad {
+            self.record(Event::Keys(keypad));
+            self.keypad = keypad;
+        }
+        let second_keypad = self.input.second_keypad();
+        if second_keypad != self.second_keypad {
+            self.record(Event::SecondKeys(second_keypad));
+            self.second_keypad = second_keypad;
+        }
+        self.frame += 1;
+        Some(keypad)
+    }
+
+    fn second_keypad(&self) -> Keypad {
+        self.second_keypad
+    }
+
+    fn speed_controls(&mut self) -> Option<Vec<SpeedControl>> {
+        let controls = self.input.speed_controls()?;
+        for control in &controls {
+            if let SpeedControl::ToggleCheat(index) = *control {
+                if let Some(name) = self.cheats.get(index).cloned() {
+                    self.record(Event::Toggle(name));
+                }
+            }
+        }
+        Some(controls)
+    }
+
+    fn failure(&self) -> Option<&str> {
+        self.input.failure()
+    }
+}
+
+impl<W: Write> Drop for MovieRecorder<W> {
+    fn drop(&mut self) {
+        let end = format!("end {}\n", self.frame);
+        self.write(&end);
+        if !self.failed {
+            let _ = self.writer.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cheats::Effect;
+    use crate::frontend::{Frontend, NullAudio, NullVideo};
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    // A buffer the test can read while the recorder still owns a handle to it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Presses key 5 on frame 2, turns cheat 1 on at frame 3, and stops after 5 frames
+    struct Player {
+        frame: u64,
+    }
+
+    impl InputSource for Player {
+        fn poll(&mut self, _cpu: &Cpu) -> Option<Keypad> {
+            if self.frame == 5 {
+                return None;
+            }
+            let mut keypad = [false; 16];
+            keypad[5] = self.frame == 2;
+            self.frame += 1;
+            Some(keypad)
+        }
+
+        fn speed_controls(&mut self) -> Option<Vec<SpeedControl>> {
+            match self.frame {
+                3 => Some(vec![SpeedControl::ToggleCheat(1), SpeedControl::Pause]),
+                _ => Some(Vec::new()),
+            }
+        }
+    }
+
+    const CHEATS: &str = "\
+lives freeze mem[0x300] = 3
+speed set v5 = 0x10 off
+";
+
+    // JP 0x200
+    const PROGRAM: [u8; 2] = [0x12, 0x00];
+
+    // Run `input` to the end like the runner does, returning the cheats that
+    // were on at each frame
+    fn run(input: Box<dyn InputSource>, cheats: &mut Cheats) -> Vec<Vec<Effect>> {
+        let mut cpu = Cpu::new();
+        cpu.load_program(&PROGRAM);
+        cpu.set_cheats(&cheats.enabled());
+        let mut frontend = Frontend::new(Box::new(NullVideo), Box::new(NullAudio), input);
+        let mut active = Vec::new();
+        while let Some(controls) = frontend.input.speed_controls() {
+            for control in controls {
+                if let SpeedControl::ToggleCheat(index) = control {
+                    let name = cheats.iter().nth(index).unwrap().name.clone();
+                    cheats.toggle(&name);
+                    cpu.set_cheats(&cheats.enabled());
+                }
+            }
+            if !frontend.run_frame(&mut cpu) {
+                break;
+            }
+            active.push(cpu.cheats());
+        }
+        active
+    }
+
+    #[test]
+    fn record_and_play_back() {
+        let mut cheats = Cheats::parse(CHEATS).unwrap();
+        let written = Shared::default();
+        let recorded = {
+            let recorder =
+                MovieRecorder::new(Box::new(Player { frame: 0 }), written.clone(), 42, &cheats);
+            run(Box::new(recorder), &mut cheats)
+        };
+        let movie = Movie::parse(std::str::from_utf8(&written.0.borrow()).unwrap()).unwrap();
+        assert_eq!(movie.seed, Some(42));
+        assert_eq!(movie.cheats, Cheats::parse(CHEATS).unwrap());
+        let mut key5 = [false; 16];
+        key5[5] = true;
+        assert_eq!(
+            movie.events,
+            [
+                (2, Event::Keys(key5)),
+                (3, Event::Toggle("speed".to_string())),
+                (3, Event::Keys([false; 16])),
+            ]
+        );
+        assert_eq!(movie.length, Some(5));
+
+        let mut cheats = movie.cheats.clone();
+        let played = run(Box::new(MovieInput::new(movie)), &mut cheats);
+        assert_eq!(played, recorded);
+        assert_eq!(played.len(), 5);
+        assert_eq!(played[2].len(), 1);
+        assert_eq!(played[3].len(), 2);
+    }
+}
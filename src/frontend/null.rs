@@ -0,0 +1,54 @@
+/*!
+ * @file frontend/null.rs
+ * @brief Headless backends that discard output and never press a key
+ */
+use super::{AudioSink, InputSource, Keypad, VideoSink};
+use crate::cpu::{Cpu, OutputState};
+
+pub struct NullVideo;
+
+impl VideoSink for NullVideo {
+    fn present(&mut self, _output: &OutputState<'_>) {}
+}
+
+pub struct NullAudio;
+
+impl AudioSink for NullAudio {
+    fn update(&mut self, _beep: bool) {}
+}
+
+// Keeps every key released; optionally stops after a fixed number of frames
+pub struct NullInput {
+    frames_left: Option<u64>,
+}
+
+impl NullInput {
+    pub fn new() -> Self {
+        NullInput { frames_left: None }
+    }
+
+    pub fn with_frame_limit(frames: u64) -> Self {
+        NullInput {
+            frames_left: Some(frames),
+        }
+    }
+}
+
+impl Default for NullInput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InputSource for NullInput {
+    fn poll(&mut self, _cpu: &Cpu) -> Option<Keypad> {
+        match self.frames_left {
+            Some(0) => None,
+            Some(ref mut frames) => {
+                *frames -= 1;
+                Some([false; 16])
+            }
+            None => Some([false; 16]),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+/*!
+ * @file frontend/recording.rs
+ * @brief Sinks that record video frames and beeper changes to a file
+ */
+use super::{AudioSink, VideoSink};
+use crate::cpu::OutputState;
+use crate::display::Rgb;
+use std::io::{self, Write};
+
+// Writes every presented frame as a plain PBM image, or a plain PPM in the
+// display's colours when it has them (CHIP-8X, MegaChip); the result is a
+// multi-image netpbm stream that `pnmsplit` or ffmpeg can read back.
+// Given colours, monochrome displays are written as PPM in those too.
+pub struct RecordingVideo<W: Write> {
+    writer: W,
+    colors: Option<[Rgb; 2]>, // background, foreground
+    frames: u64,
+    failed: bool,
+}
+
+impl<W: Write> RecordingVideo<W> {
+    pub fn new(writer: W) -> Self {
+        RecordingVideo {
+            writer,
+            colors: None,
+            frames: 0,
+            failed: false,
+        }
+    }
+
+    pub fn with_colors(writer: W, colors: [Rgb; 2]) -> Self {
+        RecordingVideo {
+            colors: Some(colors),
+            ..Self::new(writer)
+        }
+    }
+
+    fn write_frame(&mut self, output: &OutputState<'_>) -> io::Result<()> {
+        let display = &output.display;
+        let (width, height) = (display.width, display.height);
+        if display.has_color() || self.colors.is_some() {
+            writeln!(
+                self.writer,
+                "P3\n# frame {}\n{} {}\n255",
+                self.frames, width, height
+            )?;
+            for y in 0..height {
+                let line: Vec<String> = (0..width)
+                    .map(|x| {
+                        let (r, g, b) = match self.colors {
+                            Some(colors) if !display.has_color() => {
+                                colors[display.get_pixel(x, y) as usize]
+                            }
+                            _ => display.rgb_at(x, y),
+                        };
+                        format!("{} {} {}", r, g, b)
+                    })
+                    .collect();
+                writeln!(self.writer, "{}", line.join("  "))?;
+            }
+            return self.writer.flush();
+        }
+
+        writeln!(
+            self.writer,
+            "P1\n# frame {}\n{} {}",
+            self.frames, width, height
+        )?;
+        for row in output.display.rows() {
+            let line: Vec<&str> = row
+                .map(|pixel| if pixel == 1 { "1" } else { "0" })
+                .collect();
+            writeln!(self.writer, "{}", line.join(" "))?;
+        }
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> VideoSink for RecordingVideo<W> {
+    fn present(&mut self, output: &OutputState<'_>) {
+        if self.failed {
+            return;
+        }
+        if let Err(err) = self.write_frame(output) {
+            eprintln!("video recording stopped: {}", err);
+            self.failed = true;
+        }
+        self.frames += 1;
+    }
+}
+
+// Logs the beeper as "<frame> on" / "<frame> off" lines, one per change
+pub struct RecordingAudio<W: Write> {
+    writer: W,
+    frame: u64,
+    beep: bool,
+    failed: bool,
+}
+
+impl<W: Write> RecordingAudio<W> {
+    pub fn new(writer: W) -> Self {
+        RecordingAudio {
+            writer,
+            frame: 0,
+            beep: false,
+            failed: false,
+        }
+    }
+}
+
+impl<W: Write> AudioSink for RecordingAudio<W> {
+    fn update(&mut self, beep: bool) {
+        if beep != self.beep && !self.failed {
+            let state = if beep { "on" } else { "off" };
+            if let Err(err) = writeln!(self.writer, "{} {}", self.frame, state) {
+                eprintln!("audio recording stopped: {}", err);
+                self.failed = true;
+            }
+        }
+        self.beep = beep;
+        self.frame += 1;
+    }
+}
@@ -0,0 +1,246 @@
+/*!
+ * @file frontend/scheduler.rs
+ * @brief Paces emulated frames against the host clock: 60 Hz, slow motion,
+ *        fast-forward with frame skipping, pause and single-frame advance
+ *
+ * Deadlines advance by exactly one frame period from the previous deadline,
+ * not from when the host woke up, so sleep overshoot doesn't accumulate into
+ * drift. After a stall of more than a few frames the schedule restarts from
+ * now instead of bursting through the backlog.
+ */
+use super::SpeedControl;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const FRAMES_PER_SECOND: f64 = 60.0;
+
+// Slowest slow-motion rate reached by repeated SpeedControl::Slower
+pub const MIN_SPEED: f64 = 0.125;
+
+// Fast-forward shows one frame in this many unless set otherwise
+pub const DEFAULT_FRAME_SKIP: u32 = 5;
+
+// How far behind schedule the host may fall before it stops catching up
+const MAX_LAG_FRAMES: u32 = 4;
+
+/**
+ * @brief Where the scheduler gets the time and how it waits
+ */
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&mut self, duration: Duration);
+}
+
+/**
+ * @brief The host's monotonic clock
+ */
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/**
+ * @brief What the loop should do for the next host frame
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tick {
+    // Run one emulated frame; present it only if `present`
+    Run { present: bool },
+    // Paused: run nothing, just poll the controls again
+    Paused,
+}
+
+pub struct Scheduler<C = SystemClock> {
+    throttled: bool, // false: run as fast as possible (headless runs)
+    speed: f64,      // 1.0 is 60 Hz; below 1 is slow motion
+    fast_forward: bool,
+    frame_skip: u32,
+    paused: bool,
+    steps: u32, // frames to run while paused
+    skipped: u32,
+    deadline: Option<Instant>,
+    clock: C,
+}
+
+impl Scheduler {
+    pub fn new(speed: f64, frame_skip: u32) -> Self {
+        Self::with_clock(speed, frame_skip, SystemClock)
+    }
+
+    // Never sleeps and presents every frame
+    pub fn unthrottled() -> Self {
+        Scheduler {
+            throttled: false,
+            ..Self::new(1.0, 1)
+        }
+    }
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn with_clock(speed: f64, frame_skip: u32, clock: C) -> Self {
+        Scheduler {
+            throttled: true,
+            speed,
+            fast_forward: false,
+            frame_skip: frame_skip.max(1),
+            paused: false,
+            steps: 0,
+            skipped: 0,
+            deadline: None,
+            clock,
+        }
+    }
+
+    pub fn apply(&mut self, control: SpeedControl) {
+        match control {
+            SpeedControl::FastForward => self.fast_forward = !self.fast_forward,
+            SpeedControl::Slower => {
+                self.fast_forward = false;
+                self.speed = (self.speed / 2.0).max(MIN_SPEED);
+            }
+            SpeedControl::NormalSpeed => {
+                self.fast_forward = false;
+                self.speed = 1.0;
+            }
+            SpeedControl::Pause => {
+                self.paused = !self.paused;
+                self.steps = 0;
+            }
+            SpeedControl::Step => {
+                self.paused = true;
+                self.steps += 1;
+            }
+            // Instructions per frame and cheats are the Cpu's business, see
+            // Cpu::set_timing and Cpu::set_cheats
+            SpeedControl::MoreCycles | SpeedControl::FewerCycles | SpeedControl::ToggleCheat(_) => {
+            }
+        }
+        // Pacing starts over after any change of rate
+        self.deadline = None;
+    }
+
+    // Wait until the next frame is due and say what to do with it
+    pub fn tick(&mut self) -> Tick {
+        if self.paused && self.steps == 0 {
+            self.wait(Duration::from_secs_f64(1.0 / FRAMES_PER_SECOND));
+            return Tick::Paused;
+        }
+        let stepping = self.paused;
+        if stepping {
+            self.steps -= 1;
+        }
+
+        if !self.throttled {
+            return Tick::Run { present: true };
+        }
+        if self.fast_forward && !stepping {
+            self.skipped = (self.skipped + 1) % self.frame_skip;
+            return Tick::Run {
+                present: self.skipped == 0,
+            };
+        }
+
+        self.wait(Duration::from_secs_f64(
+            1.0 / (FRAMES_PER_SECOND * self.speed),
+        ));
+        Tick::Run { present: true }
+    }
+
+    fn wait(&mut self, period: Duration) {
+        let now = self.clock.now();
+        let deadline = match self.deadline {
+            Some(deadline) if now < deadline + period * MAX_LAG_FRAMES => deadline + period,
+            _ => now + period,
+        };
+        if let Some(delay) = deadline.checked_duration_since(now) {
+            self.clock.sleep(delay);
+        }
+        self.deadline = Some(deadline);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Time only moves when the scheduler sleeps or a test stalls it
+    struct FakeClock {
+        now: Instant,
+        overshoot: Duration, // added to every sleep, like a late wakeup
+        sleeps: usize,       // sleeps that actually waited
+    }
+
+    fn scheduler(overshoot: Duration) -> Scheduler<FakeClock> {
+        let clock = FakeClock {
+            now: Instant::now(),
+            overshoot,
+            sleeps: 0,
+        };
+        Scheduler::with_clock(1.0, DEFAULT_FRAME_SKIP, clock)
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.now
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            if !duration.is_zero() {
+                self.sleeps += 1;
+            }
+            self.now += duration + self.overshoot;
+        }
+    }
+
+    fn period() -> Duration {
+        Duration::from_secs_f64(1.0 / FRAMES_PER_SECOND)
+    }
+
+    #[test]
+    fn late_wakeups_dont_drift() {
+        let overshoot = Duration::from_millis(3);
+        let mut scheduler = scheduler(overshoot);
+        let start = scheduler.clock.now;
+        for _ in 0..600 {
+            assert_eq!(scheduler.tick(), Tick::Run { present: true });
+        }
+        // Only the last frame's overshoot is still outstanding
+        assert_eq!(scheduler.clock.now - start, period() * 600 + overshoot);
+    }
+
+    #[test]
+    fn stalls_catch_up_at_most_max_lag_frames() {
+        for stalled_frames in [1.5, 2.0, 3.5, 3.99, 4.0, 4.5, 10.0, 600.0] {
+            let mut scheduler = scheduler(Duration::ZERO);
+            for _ in 0..10 {
+                scheduler.tick();
+            }
+            scheduler.clock.now += period().mul_f64(stalled_frames);
+
+            // Frames that ran straight away, without waiting for their deadline
+            let sleeps = scheduler.clock.sleeps;
+            let mut catch_up = 0;
+            loop {
+                scheduler.tick();
+                if scheduler.clock.sleeps > sleeps {
+                    break;
+                }
+                catch_up += 1;
+            }
+            let expected = if stalled_frames < MAX_LAG_FRAMES as f64 {
+                stalled_frames.floor() as u32
+            } else {
+                0
+            };
+            assert_eq!(catch_up, expected, "stall of {stalled_frames} frames");
+            assert!(catch_up <= MAX_LAG_FRAMES);
+        }
+    }
+}
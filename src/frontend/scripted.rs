@@ -0,0 +1,143 @@
+/*!
+ * @file frontend/scripted.rs
+ * @brief Input source that plays back an input script (see script.rs)
+ */
+use super::{InputSource, Keypad};
+use crate::cpu::Cpu;
+use crate::script::{Command, Script};
+
+enum Step {
+    Done,
+    Blocked,
+    Exit,
+    Fail(String),
+}
+
+pub struct ScriptedInput {
+    script: Script,
+    cursor: usize,
+    frame: u64,
+    // frame at which the current wait command started
+    wait_started: Option<u64>,
+    // frame until which each key stays held (exclusive)
+    held_until: [u64; 16],
+    finished: bool,
+    failure: Option<String>,
+}
+
+impl ScriptedInput {
+    pub fn new(script: Script) -> Self {
+        ScriptedInput {
+            script,
+            cursor: 0,
+            frame: 0,
+            wait_started: None,
+            held_until: [0; 16],
+            finished: false,
+            failure: None,
+        }
+    }
+
+    fn step(&mut self, line: usize, command: &Command, cpu: &Cpu) -> Step {
+        let frame = self.frame;
+        match command {
+            Command::AtFrame(at, command) => {
+                if frame < *at {
+                    Step::Blocked
+                } else {
+                    self.step(line, command, cpu)
+                }
+            }
+            Command::Press { key, frames } => {
+                self.held_until[*key] = self.held_until[*key].max(frame + frames);
+                Step::Done
+            }
+            Command::Wait(frames) => {
+                let started = *self.wait_started.get_or_insert(frame);
+                if frame >= started + frames {
+                    Step::Done
+                } else {
+                    Step::Blocked
+                }
+            }
+            Command::WaitUntil { condition, within } => {
+                let started = *self.wait_started.get_or_insert(frame);
+                if condition.holds(cpu) {
+                    Step::Done
+                } else if within.is_some_and(|within| frame >= started + within) {
+                    Step::Fail(format!(
+                        "line {}: timed out at frame {} waiting until {} ({})",
+                        line,
+                        frame,
+                        condition,
+                        condition.describe(cpu)
+                    ))
+                } else {
+                    Step::Blocked
+                }
+            }
+            Command::Assert(condition) => {
+                if condition.holds(cpu) {
+                    Step::Done
+                } else {
+                    Step::Fail(format!(
+                        "line {}: assertion failed at frame {}: {} ({})",
+                        line,
+                        frame,
+                        condition,
+                        condition.describe(cpu)
+                    ))
+                }
+            }
+            Command::Exit => Step::Exit,
+        }
+    }
+}
+
+impl InputSource for ScriptedInput {
+    fn poll(&mut self, cpu: &Cpu) -> Option<Keypad> {
+        if self.finished {
+            return None;
+        }
+
+        while self.cursor < self.script.commands.len() {
+            let (line, command) = self.script.commands[self.cursor].clone();
+            match self.step(line, &command, cpu) {
+                Step::Done => {
+                    self.cursor += 1;
+                    self.wait_started = None;
+                }
+                Step::Blocked => break,
+                Step::Exit => {
+                    self.finished = true;
+                    return None;
+                }
+                Step::Fail(message) => {
+                    self.failure = Some(message);
+                    self.finished = true;
+                    return None;
+                }
+            }
+        }
+
+        // Once the script has run out, stop as soon as every key is released
+        let frame = self.frame;
+        if self.cursor == self.script.commands.len()
+            && self.held_until.iter().all(|&until| until <= frame)
+        {
+            self.finished = true;
+            return None;
+        }
+
+        let mut keypad = [false; 16];
+        for (pressed, &until) in keypad.iter_mut().zip(self.held_until.iter()) {
+            *pressed = until > frame;
+        }
+        self.frame += 1;
+        Some(keypad)
+    }
+
+    fn failure(&self) -> Option<&str> {
+        self.failure.as_deref()
+    }
+}
@@ -0,0 +1,255 @@
+/*!
+ * @file frontend/terminal.rs
+ * @brief ANSI terminal backends: block-character video, bell audio, line-buffered keys
+ */
+use super::{AudioSink, InputSource, Keypad, SpeedControl, VideoSink};
+use crate::cpu::{Cpu, OutputState};
+use crate::display::Rgb;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// How long a typed key stays held down; stdin gives us no key-up events
+const KEY_HOLD_FRAMES: u8 = 6;
+
+// Conventional layout: the 4x4 block 1234/qwer/asdf/zxcv maps onto the hex keypad
+//   1 2 3 C
+//   4 5 6 D
+//   7 8 9 E
+//   A 0 B F
+const KEY_MAP: [(u8, usize); 16] = [
+    (b'1', 0x1),
+    (b'2', 0x2),
+    (b'3', 0x3),
+    (b'4', 0xC),
+    (b'q', 0x4),
+    (b'w', 0x5),
+    (b'e', 0x6),
+    (b'r', 0xD),
+    (b'a', 0x7),
+    (b's', 0x8),
+    (b'd', 0x9),
+    (b'f', 0xE),
+    (b'z', 0xA),
+    (b'x', 0x0),
+    (b'c', 0xB),
+    (b'v', 0xF),
+];
+
+// CHIP-8X second keypad on the 4x4 block to the right: 7890/uiop/jkl;/m,./
+const KEY_MAP_2: [(u8, usize); 16] = [
+    (b'7', 0x1),
+    (b'8', 0x2),
+    (b'9', 0x3),
+    (b'0', 0xC),
+    (b'u', 0x4),
+    (b'i', 0x5),
+    (b'o', 0x6),
+    (b'p', 0xD),
+    (b'j', 0x7),
+    (b'k', 0x8),
+    (b'l', 0x9),
+    (b';', 0xE),
+    (b'm', 0xA),
+    (b',', 0x0),
+    (b'.', 0xB),
+    (b'/', 0xF),
+];
+
+// Keys for the game actions a ROM database entry binds (see with_actions). The
+// directions share keys with KEY_MAP_2, so CHIP-8X ROMs get no actions.
+const ACTION_KEYS: [(&str, u8); 6] = [
+    ("up", b'i'),
+    ("down", b'k'),
+    ("left", b'j'),
+    ("right", b'l'),
+    ("a", b' '),
+    ("b", b'b'),
+];
+
+// Speed controls, on keys no keypad or action uses; shifted 1 - 9 toggle the
+// first nine cheats
+const SPEED_KEYS: [(u8, SpeedControl); 16] = [
+    (b'\t', SpeedControl::FastForward),
+    (b'-', SpeedControl::Slower),
+    (b'=', SpeedControl::NormalSpeed),
+    (b'h', SpeedControl::Pause),
+    (b'n', SpeedControl::Step),
+    (b']', SpeedControl::MoreCycles),
+    (b'[', SpeedControl::FewerCycles),
+    (b'!', SpeedControl::ToggleCheat(0)),
+    (b'@', SpeedControl::ToggleCheat(1)),
+    (b'#', SpeedControl::ToggleCheat(2)),
+    (b'$', SpeedControl::ToggleCheat(3)),
+    (b'%', SpeedControl::ToggleCheat(4)),
+    (b'^', SpeedControl::ToggleCheat(5)),
+    (b'&', SpeedControl::ToggleCheat(6)),
+    (b'*', SpeedControl::ToggleCheat(7)),
+    (b'(', SpeedControl::ToggleCheat(8)),
+];
+
+const ESCAPE: u8 = 0x1b;
+
+#[derive(Default)]
+pub struct TerminalVideo {
+    colors: Option<[Rgb; 2]>, // background, foreground for monochrome displays
+}
+
+impl TerminalVideo {
+    pub fn new() -> Self {
+        TerminalVideo { colors: None }
+    }
+
+    pub fn with_colors(colors: [Rgb; 2]) -> Self {
+        TerminalVideo {
+            colors: Some(colors),
+        }
+    }
+}
+
+impl VideoSink for TerminalVideo {
+    fn present(&mut self, output: &OutputState<'_>) {
+        match self.colors {
+            Some(colors) if !output.display.has_color() => output.display.render_mono(colors),
+            _ => output.display.render(),
+        }
+        io::stdout().flush().ok();
+    }
+}
+
+// Rings the terminal bell when the sound timer starts
+pub struct TerminalAudio {
+    beep: bool,
+}
+
+impl TerminalAudio {
+    pub fn new() -> Self {
+        TerminalAudio { beep: false }
+    }
+}
+
+impl Default for TerminalAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioSink for TerminalAudio {
+    fn update(&mut self, beep: bool) {
+        if beep && !self.beep {
+            print!("\x07");
+            io::stdout().flush().ok();
+        }
+        self.beep = beep;
+    }
+}
+
+// Reads stdin on a background thread. In a cooked terminal keys arrive after
+// Enter; put the terminal in raw mode (`stty raw -echo`) for immediate input.
+// Escape or end of input stops the run.
+pub struct TerminalInput {
+    bytes: Receiver<u8>,
+    held: [u8; 16],
+    held2: [u8; 16],
+    actions: Vec<(u8, usize)>, // extra bindings from with_actions
+    controls: Vec<SpeedControl>,
+    stopped: bool, // Escape or end of input seen
+}
+
+impl TerminalInput {
+    pub fn new() -> Self {
+        let (sender, bytes) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                match byte {
+                    Ok(byte) if sender.send(byte).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+        TerminalInput {
+            bytes,
+            held: [0; 16],
+            held2: [0; 16],
+            actions: Vec::new(),
+            controls: Vec::new(),
+            stopped: false,
+        }
+    }
+
+    // Also bind game actions ("up", "a", ...) to CHIP-8 keys: ijkl for the
+    // directions, space for a and b for b
+    pub fn with_actions(actions: &[(String, usize)]) -> Self {
+        let mut input = Self::new();
+        for (action, key) in actions {
+            if let Some(&(_, byte)) = ACTION_KEYS.iter().find(|(name, _)| name == action) {
+                input.actions.push((byte, *key));
+            }
+        }
+        input
+    }
+}
+
+impl Default for TerminalInput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TerminalInput {
+    // Take the bytes typed so far: press keys and queue speed controls
+    fn read_bytes(&mut self) {
+        loop {
+            match self.bytes.try_recv() {
+                Ok(ESCAPE) | Err(TryRecvError::Disconnected) => {
+                    self.stopped = true;
+                    break;
+                }
+                Ok(byte) => {
+                    let byte = byte.to_ascii_lowercase();
+                    let mut bindings = KEY_MAP.iter().chain(self.actions.iter());
+                    if let Some(&(_, key)) = bindings.find(|(c, _)| *c == byte) {
+                        self.held[key] = KEY_HOLD_FRAMES;
+                    } else if let Some(&(_, key)) = KEY_MAP_2.iter().find(|(c, _)| *c == byte) {
+                        self.held2[key] = KEY_HOLD_FRAMES;
+                    } else if let Some(&(_, control)) = SPEED_KEYS.iter().find(|(c, _)| *c == byte)
+                    {
+                        self.controls.push(control);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+    }
+}
+
+impl InputSource for TerminalInput {
+    fn poll(&mut self, _cpu: &Cpu) -> Option<Keypad> {
+        for frames in self.held.iter_mut().chain(self.held2.iter_mut()) {
+            *frames = frames.saturating_sub(1);
+        }
+
+        self.read_bytes();
+        if self.stopped {
+            return None;
+        }
+
+        let mut keypad = [false; 16];
+        for (pressed, &frames) in keypad.iter_mut().zip(self.held.iter()) {
+            *pressed = frames > 0;
+        }
+        Some(keypad)
+    }
+
+    fn second_keypad(&self) -> Keypad {
+        self.held2.map(|frames| frames > 0)
+    }
+
+    fn speed_controls(&mut self) -> Option<Vec<SpeedControl>> {
+        self.read_bytes();
+        if self.stopped {
+            return None;
+        }
+        Some(std::mem::take(&mut self.controls))
+    }
+}
@@ -0,0 +1,230 @@
+/*!
+ * @file instruction.rs
+ * @brief Instruction decoder and disassembler for CHIP-8 opcodes
+ */
+use crate::platform::Platform;
+use std::fmt;
+
+/**
+ * @brief A decoded CHIP-8 instruction with its operands broken out
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Cls,                                  // 00E0
+    Ret,                                  // 00EE
+    Sys { nnn: usize },                   // 0NNN
+    Jp { nnn: usize },                    // 1NNN
+    Call { nnn: usize },                  // 2NNN
+    SeImm { x: usize, kk: u8 },           // 3XKK
+    SneImm { x: usize, kk: u8 },          // 4XKK
+    SeReg { x: usize, y: usize },         // 5XY0
+    LdImm { x: usize, kk: u8 },           // 6XKK
+    AddImm { x: usize, kk: u8 },          // 7XKK
+    LdReg { x: usize, y: usize },         // 8XY0
+    Or { x: usize, y: usize },            // 8XY1
+    And { x: usize, y: usize },           // 8XY2
+    Xor { x: usize, y: usize },           // 8XY3
+    AddReg { x: usize, y: usize },        // 8XY4
+    Sub { x: usize, y: usize },           // 8XY5
+    Shr { x: usize, y: usize },           // 8XY6
+    Subn { x: usize, y: usize },          // 8XY7
+    Shl { x: usize, y: usize },           // 8XYE
+    SneReg { x: usize, y: usize },        // 9XY0
+    LdI { nnn: usize },                   // ANNN
+    JpV0 { nnn: usize },                  // BNNN
+    Rnd { x: usize, kk: u8 },             // CXKK
+    Drw { x: usize, y: usize, n: usize }, // DXYN
+    Skp { x: usize },                     // EX9E
+    Sknp { x: usize },                    // EXA1
+    LdVxDt { x: usize },                  // FX07
+    LdVxK { x: usize },                   // FX0A
+    LdDtVx { x: usize },                  // FX15
+    LdStVx { x: usize },                  // FX18
+    AddIVx { x: usize },                  // FX1E
+    LdFVx { x: usize },                   // FX29
+    LdBVx { x: usize },                   // FX33
+    LdIVx { x: usize },                   // FX55
+    LdVxI { x: usize },                   // FX65
+    // CHIP-8X
+    Col { x: usize, y: usize, n: usize }, // BXYN, BXY0
+    Bgc,                                  // 02A0
+    Skp2 { x: usize },                    // EXF2
+    Sknp2 { x: usize },                   // EXF5
+    // MegaChip
+    MegaOff,              // 0010
+    MegaOn,               // 0011
+    LdIHi { nn: u8 },     // 01NN NNNN, the low 16 bits in the next word
+    LdPal { nn: u8 },     // 02NN
+    SprW { nn: u8 },      // 03NN
+    SprH { nn: u8 },      // 04NN
+    Alpha { nn: u8 },     // 05NN
+    DigiSnd { n: usize }, // 060N
+    StopSnd,              // 0700
+    BMode { nn: u8 },     // 08NN
+    Unknown(u16),
+}
+
+// Decode a raw 2-byte opcode into an Instruction
+pub fn decode(opcode: u16) -> Instruction {
+    // nibbles = HEX Digits of the opcode
+    let nibbles = (
+        (opcode & 0xF000) >> 12,
+        (opcode & 0x0F00) >> 8,
+        (opcode & 0x00F0) >> 4,
+        opcode & 0x000F,
+    );
+
+    // break apart parameters o the instruction
+    let nnn = (opcode & 0x0FFF) as usize;
+    let kk = (opcode & 0x00FF) as u8;
+    let x = nibbles.1 as usize;
+    let y = nibbles.2 as usize;
+    let n = nibbles.3 as usize;
+
+    match nibbles {
+        (0x00, 0x00, 0x0e, 0x00) => Instruction::Cls,
+        (0x00, 0x00, 0x0e, 0x0e) => Instruction::Ret,
+        (0x00, _, _, _) => Instruction::Sys { nnn },
+        (0x01, _, _, _) => Instruction::Jp { nnn },
+        (0x02, _, _, _) => Instruction::Call { nnn },
+        (0x03, _, _, _) => Instruction::SeImm { x, kk },
+        (0x04, _, _, _) => Instruction::SneImm { x, kk },
+        (0x05, _, _, 0x00) => Instruction::SeReg { x, y },
+        (0x06, _, _, _) => Instruction::LdImm { x, kk },
+        (0x07, _, _, _) => Instruction::AddImm { x, kk },
+        (0x08, _, _, 0x00) => Instruction::LdReg { x, y },
+        (0x08, _, _, 0x01) => Instruction::Or { x, y },
+        (0x08, _, _, 0x02) => Instruction::And { x, y },
+        (0x08, _, _, 0x03) => Instruction::Xor { x, y },
+        (0x08, _, _, 0x04) => Instruction::AddReg { x, y },
+        (0x08, _, _, 0x05) => Instruction::Sub { x, y },
+        (0x08, _, _, 0x06) => Instruction::Shr { x, y },
+        (0x08, _, _, 0x07) => Instruction::Subn { x, y },
+        (0x08, _, _, 0x0e) => Instruction::Shl { x, y },
+        (0x09, _, _, 0x00) => Instruction::SneReg { x, y },
+        (0x0a, _, _, _) => Instruction::LdI { nnn },
+        (0x0b, _, _, _) => Instruction::JpV0 { nnn },
+        (0x0c, _, _, _) => Instruction::Rnd { x, kk },
+        (0x0d, _, _, _) => Instruction::Drw { x, y, n },
+        (0x0e, _, 0x09, 0x0e) => Instruction::Skp { x },
+        (0x0e, _, 0x0a, 0x01) => Instruction::Sknp { x },
+        (0x0f, _, 0x00, 0x07) => Instruction::LdVxDt { x },
+        (0x0f, _, 0x00, 0x0a) => Instruction::LdVxK { x },
+        (0x0f, _, 0x01, 0x05) => Instruction::LdDtVx { x },
+        (0x0f, _, 0x01, 0x08) => Instruction::LdStVx { x },
+        (0x0f, _, 0x01, 0x0e) => Instruction::AddIVx { x },
+        (0x0f, _, 0x02, 0x09) => Instruction::LdFVx { x },
+        (0x0f, _, 0x03, 0x03) => Instruction::LdBVx { x },
+        (0x0f, _, 0x05, 0x05) => Instruction::LdIVx { x },
+        (0x0f, _, 0x06, 0x05) => Instruction::LdVxI { x },
+        _ => Instruction::Unknown(opcode),
+    }
+}
+
+// Decode an opcode as `platform` reads it; variants reuse CHIP-8 opcodes
+pub fn decode_for(platform: Platform, opcode: u16) -> Instruction {
+    let x = ((opcode & 0x0F00) >> 8) as usize;
+    let y = ((opcode & 0x00F0) >> 4) as usize;
+    let n = (opcode & 0x000F) as usize;
+    let nn = (opcode & 0x00FF) as u8;
+
+    match (platform, opcode & 0xF000, opcode & 0x00FF) {
+        (Platform::Chip8X, 0x0000, _) if opcode == 0x02A0 => Instruction::Bgc,
+        (Platform::Chip8X, 0xB000, _) => Instruction::Col { x, y, n },
+        (Platform::Chip8X, 0xE000, 0xF2) => Instruction::Skp2 { x },
+        (Platform::Chip8X, 0xE000, 0xF5) => Instruction::Sknp2 { x },
+        (Platform::MegaChip, 0x0000, _) => match opcode & 0x0F00 {
+            0x0000 if opcode == 0x0010 => Instruction::MegaOff,
+            0x0000 if opcode == 0x0011 => Instruction::MegaOn,
+            0x0100 => Instruction::LdIHi { nn },
+            0x0200 => Instruction::LdPal { nn },
+            0x0300 => Instruction::SprW { nn },
+            0x0400 => Instruction::SprH { nn },
+            0x0500 => Instruction::Alpha { nn },
+            0x0600 if opcode & 0x00F0 == 0 => Instruction::DigiSnd { n },
+            0x0700 if opcode == 0x0700 => Instruction::StopSnd,
+            0x0800 => Instruction::BMode { nn },
+            _ => decode(opcode),
+        },
+        _ => decode(opcode),
+    }
+}
+
+// Decode every opcode of a program, starting at address `origin`.
+// Returns (address, raw opcode, instruction) triples; a trailing odd byte is ignored.
+pub fn disassemble(program: &[u8], origin: usize) -> Vec<(usize, u16, Instruction)> {
+    disassemble_for(Platform::Chip8, program, origin)
+}
+
+pub fn disassemble_for(
+    platform: Platform,
+    program: &[u8],
+    origin: usize,
+) -> Vec<(usize, u16, Instruction)> {
+    program
+        .chunks_exact(2)
+        .enumerate()
+        .map(|(i, bytes)| {
+            let opcode = ((bytes[0] as u16) << 8) | bytes[1] as u16;
+            (origin + i * 2, opcode, decode_for(platform, opcode))
+        })
+        .collect()
+}
+
+// Mnemonics follow Cowgod's Chip-8 Technical Reference
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Sys { nnn } => write!(f, "SYS {:#05x}", nnn),
+            Instruction::Jp { nnn } => write!(f, "JP {:#05x}", nnn),
+            Instruction::Call { nnn } => write!(f, "CALL {:#05x}", nnn),
+            Instruction::SeImm { x, kk } => write!(f, "SE V{:X}, {:#04x}", x, kk),
+            Instruction::SneImm { x, kk } => write!(f, "SNE V{:X}, {:#04x}", x, kk),
+            Instruction::SeReg { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::LdImm { x, kk } => write!(f, "LD V{:X}, {:#04x}", x, kk),
+            Instruction::AddImm { x, kk } => write!(f, "ADD V{:X}, {:#04x}", x, kk),
+            Instruction::LdReg { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneReg { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI { nnn } => write!(f, "LD I, {:#05x}", nnn),
+            Instruction::JpV0 { nnn } => write!(f, "JP V0, {:#05x}", nnn),
+            Instruction::Rnd { x, kk } => write!(f, "RND V{:X}, {:#04x}", x, kk),
+            Instruction::Drw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp { x } => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp { x } => write!(f, "SKNP V{:X}", x),
+            Instruction::LdVxDt { x } => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdVxK { x } => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDtVx { x } => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdStVx { x } => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddIVx { x } => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdFVx { x } => write!(f, "LD F, V{:X}", x),
+            Instruction::LdBVx { x } => write!(f, "LD B, V{:X}", x),
+            Instruction::LdIVx { x } => write!(f, "LD [I], V{:X}", x),
+            Instruction::LdVxI { x } => write!(f, "LD V{:X}, [I]", x),
+            Instruction::Col { x, y, n } => write!(f, "COL V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Bgc => write!(f, "BGC"),
+            Instruction::Skp2 { x } => write!(f, "SKP2 V{:X}", x),
+            Instruction::Sknp2 { x } => write!(f, "SKNP2 V{:X}", x),
+            Instruction::MegaOff => write!(f, "MEGAOFF"),
+            Instruction::MegaOn => write!(f, "MEGAON"),
+            Instruction::LdIHi { nn } => write!(f, "LDHI I, {:#04x}....", nn),
+            Instruction::LdPal { nn } => write!(f, "LDPAL {}", nn),
+            Instruction::SprW { nn } => write!(f, "SPRW {}", nn),
+            Instruction::SprH { nn } => write!(f, "SPRH {}", nn),
+            Instruction::Alpha { nn } => write!(f, "ALPHA {:#04x}", nn),
+            Instruction::DigiSnd { n } => write!(f, "DIGISND {}", n),
+            Instruction::StopSnd => write!(f, "STOPSND"),
+            Instruction::BMode { nn } => write!(f, "BMODE {}", nn),
+            Instruction::Unknown(opcode) => write!(f, "DW {:#06x}", opcode),
+        }
+    }
+}
@@ -0,0 +1,731 @@
+/*!
+ * @file jit.rs
+ * @brief Compiles hot straight-line CHIP-8 code to native code with Cranelift
+ *
+ * A block is a run of register-only instructions, optionally ended by a jump
+ * or a conditional skip. Anything that touches the display, the keypad, memory
+ * or the call stack (DXYN, FX0A, FX55, 2NNN ...) ends a block and is left to
+ * the interpreter, so blocks never write memory themselves. A block only runs
+ * when it ends inside the current frame; otherwise the interpreter takes it an
+ * instruction at a time, so the timers tick exactly where they would have.
+ *
+ * Every write to memory has to be reported through invalidate. A block whose
+ * bytes change is thrown away, and code rewritten REWRITE_LIMIT times is taken
+ * to be self-modifying and interpreted from then on. Code of dropped blocks is
+ * only freed with the Jit.
+ */
+use crate::instruction::{decode_for, Instruction};
+use crate::quirks::Quirks;
+use crate::timing::Timing;
+use cranelift_codegen::entity::EntityRef;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, Type, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::Module;
+use std::collections::HashMap;
+use std::mem::offset_of;
+use std::ops::Range;
+
+const OPCODE_SIZE: usize = 2;
+
+const PAGE_BITS: usize = 8;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+
+// Times the code at an address is reached before it is compiled
+const HOT_THRESHOLD: u16 = 8;
+
+// Times a block may be invalidated before its address is left to the interpreter
+const REWRITE_LIMIT: u16 = 2;
+
+const MAX_BLOCK_LENGTH: usize = 64;
+
+/**
+ * @brief Whether the Cpu runs compiled blocks
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JitMode {
+    #[default]
+    Off,
+    On,
+    // Run each block compiled, then again in the interpreter from the same
+    // state, and panic if the two disagree
+    Differential,
+}
+
+/**
+ * @brief The machine state a block reads and writes, laid out for generated code
+ */
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub index: u64,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+/**
+ * @brief What running a block did, besides changing the registers
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ran {
+    pub start: usize,
+    pub next: usize, // the next program counter, not yet wrapped around memory
+    pub instructions: usize,
+    pub cost: u32, // of all the instructions, see timing.rs
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JitStats {
+    pub blocks_compiled: u64,
+    pub blocks_invalidated: u64,
+    pub instructions: u64, // run as native code
+}
+
+type BlockFn = unsafe extern "C" fn(*mut Registers) -> u64;
+
+struct Block {
+    code: BlockFn,
+    start: usize,
+    bytes: Box<[u8]>, // as compiled, to check memory against after load_state
+    instructions: Box<[Instruction]>,
+    lead_cost: Option<(Timing, u32)>, // all but the last instruction, per cost model
+}
+
+impl Block {
+    fn end(&self) -> usize {
+        self.start + self.bytes.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Cold(u16),       // reached this many times
+    Compiled(usize), // index into Jit::blocks
+    Interpreted,     // nothing to compile here, or rewritten too often
+}
+
+// How an instruction fits into a block
+enum Compiles {
+    Straight,
+    Branch, // ends the block, setting the program counter
+    No,
+}
+
+fn compiles(instruction: Instruction) -> Compiles {
+    match instruction {
+        Instruction::LdImm { .. }
+        | Instruction::AddImm { .. }
+        | Instruction::LdReg { .. }
+        | Instruction::Or { .. }
+        | Instruction::And { .. }
+        | Instruction::Xor { .. }
+        | Instruction::AddReg { .. }
+        | Instruction::Sub { .. }
+        | Instruction::Shr { .. }
+        | Instruction::Subn { .. }
+        | Instruction::Shl { .. }
+        | Instruction::LdI { .. }
+        | Instruction::LdVxDt { .. }
+        | Instruction::LdDtVx { .. }
+        | Instruction::LdStVx { .. }
+        | Instruction::AddIVx { .. }
+        | Instruction::LdFVx { .. } => Compiles::Straight,
+        Instruction::Jp { .. } | Instruction::JpV0 { .. } => Compiles::Branch,
+        instruction if is_skip(instruction) => Compiles::Branch,
+        _ => Compiles::No,
+    }
+}
+
+fn is_skip(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::SeImm { .. }
+            | Instruction::SneImm { .. }
+            | Instruction::SeReg { .. }
+            | Instruction::SneReg { .. }
+    )
+}
+
+// Generated code keeps V0 - VF, I, DT and ST in these variables
+const I: usize = 16;
+const DT: usize = 17;
+const ST: usize = 18;
+const FIELDS: usize = 19;
+
+fn field(var: usize) -> (Type, i32) {
+    match var {
+        I => (types::I64, offset_of!(Registers, index) as i32),
+        DT => (types::I8, offset_of!(Registers, delay_timer) as i32),
+        ST => (types::I8, offset_of!(Registers, sound_timer) as i32),
+        x => (types::I8, (offset_of!(Registers, v) + x) as i32),
+    }
+}
+
+// Emit `instruction`, found at `addr`, the way the interpreter runs it with
+// `quirks`. Returns the next program counter if it branches; `written`
+// collects the variables that have to be stored back.
+fn translate(
+    builder: &mut FunctionBuilder,
+    instruction: Instruction,
+    addr: usize,
+    quirks: &Quirks,
+    written: &mut [bool; FIELDS],
+) -> Option<Value> {
+    let mut set = |builder: &mut FunctionBuilder, var: usize, value: Value| {
+        builder.def_var(Variable::new(var), value);
+        written[var] = true;
+    };
+    let get = |builder: &mut FunctionBuilder, var: usize| builder.use_var(Variable::new(var));
+    let byte =
+        |builder: &mut FunctionBuilder, value: u8| builder.ins().iconst(types::I8, value as i64);
+    let address =
+        |builder: &mut FunctionBuilder, addr: usize| builder.ins().iconst(types::I64, addr as i64);
+
+    match instruction {
+        Instruction::LdImm { x, kk } => {
+            let value = byte(builder, kk);
+            set(builder, x, value);
+        }
+        Instruction::AddImm { x, kk } => {
+            let (vx, kk) = (get(builder, x), byte(builder, kk));
+            let sum = builder.ins().iadd(vx, kk);
+            set(builder, x, sum);
+        }
+        Instruction::LdReg { x, y } => {
+            let vy = get(builder, y);
+            set(builder, x, vy);
+        }
+        Instruction::Or { x, y } | Instruction::And { x, y } | Instruction::Xor { x, y } => {
+            let (vx, vy) = (get(builder, x), get(builder, y));
+            let value = match instruction {
+                Instruction::Or { .. } => builder.ins().bor(vx, vy),
+                Instruction::And { .. } => builder.ins().band(vx, vy),
+                _ => builder.ins().bxor(vx, vy),
+            };
+            set(builder, x, value);
+            if quirks.logic_resets_vf {
+                let zero = byte(builder, 0);
+                set(builder, 0xF, zero);
+            }
+        }
+        Instruction::AddReg { x, y } => {
+            let (vx, vy) = (get(builder, x), get(builder, y));
+            let sum = builder.ins().iadd(vx, vy);
+            set(builder, x, sum);
+            let carry = builder.ins().icmp(IntCC::UnsignedLessThan, sum, vx);
+            set(builder, 0xF, carry);
+        }
+        // The flag is written first, so the subtraction sees it if x or y is F
+        Instruction::Sub { x, y } | Instruction::Subn { x, y } => {
+            let (from, by) = match instruction {
+                Instruction::Sub { .. } => (x, y),
+                _ => (y, x),
+            };
+            let (a, b) = (get(builder, from), get(builder, by));
+            let no_borrow = builder.ins().icmp(IntCC::UnsignedGreaterThan, a, b);
+            set(builder, 0xF, no_borrow);
+            let (a, b) = (get(builder, from), get(builder, by));
+            let difference = builder.ins().isub(a, b);
+            set(builder, x, difference);
+        }
+        // As with subtraction, the flag goes first
+        Instruction::Shr { x, y } | Instruction::Shl { x, y } => {
+            let source = if quirks.shift_vy { y } else { x };
+            let value = get(builder, source);
+            let bit = match instruction {
+                Instruction::Shr { .. } => builder.ins().band_imm(value, 1),
+                _ => builder.ins().ushr_imm(value, 7),
+            };
+            set(builder, 0xF, bit);
+            let value = get(builder, source);
+            let shifted = match instruction {
+                Instruction::Shr { .. } => builder.ins().ushr_imm(value, 1),
+                _ => builder.ins().ishl_imm(value, 1),
+            };
+            set(builder, x, shifted);
+        }
+        Instruction::LdI { nnn } => {
+            let value = address(builder, nnn);
+            set(builder, I, value);
+        }
+        Instruction::LdVxDt { x } => {
+            let dt = get(builder, DT);
+            set(builder, x, dt);
+        }
+        Instruction::LdDtVx { x } => {
+            let vx = get(builder, x);
+            set(builder, DT, vx);
+        }
+        Instruction::LdStVx { x } => {
+            let vx = get(builder, x);
+            set(builder, ST, vx);
+        }
+        Instruction::AddIVx { x } => {
+            let (index, vx) = (get(builder, I), get(builder, x));
+            let vx = builder.ins().uextend(types::I64, vx);
+            let sum = builder.ins().iadd(index, vx);
+            set(builder, I, sum);
+            let overflow = builder
+                .ins()
+                .icmp_imm(IntCC::UnsignedGreaterThan, sum, 0x0F00);
+            set(builder, 0xF, overflow);
+        }
+        Instruction::LdFVx { x } => {
+            let vx = get(builder, x);
+            let vx = builder.ins().uextend(types::I64, vx);
+            let glyph = builder.ins().imul_imm(vx, 5);
+            set(builder, I, glyph);
+        }
+        Instruction::Jp { nnn } => return Some(address(builder, nnn)),
+        Instruction::JpV0 { nnn } => {
+            let x = if quirks.jump_vx { nnn >> 8 } else { 0 };
+            let vx = get(builder, x);
+            let vx = builder.ins().uextend(types::I64, vx);
+            return Some(builder.ins().iadd_imm(vx, nnn as i64));
+        }
+        Instruction::SeImm { x, kk } | Instruction::SneImm { x, kk } => {
+            let (vx, kk) = (get(builder, x), byte(builder, kk));
+            return Some(skip_if(builder, instruction, vx, kk, addr));
+        }
+        Instruction::SeReg { x, y } | Instruction::SneReg { x, y } => {
+            let (vx, vy) = (get(builder, x), get(builder, y));
+            return Some(skip_if(builder, instruction, vx, vy, addr));
+        }
+        _ => unreachable!("{:?} is never compiled", instruction),
+    }
+    None
+}
+
+// The program counter after the skip instruction at `addr` compared `a` and `b`
+fn skip_if(
+    builder: &mut FunctionBuilder,
+    instruction: Instruction,
+    a: Value,
+    b: Value,
+    addr: usize,
+) -> Value {
+    let condition = match instruction {
+        Instruction::SeImm { .. } | Instruction::SeReg { .. } => IntCC::Equal,
+        _ => IntCC::NotEqual,
+    };
+    let skip = builder.ins().icmp(condition, a, b);
+    let skipped = builder
+        .ins()
+        .iconst(types::I64, (addr + 2 * OPCODE_SIZE) as i64);
+    let next = builder
+        .ins()
+        .iconst(types::I64, (addr + OPCODE_SIZE) as i64);
+    builder.ins().select(skip, skipped, next)
+}
+
+pub struct Jit {
+    module: Option<JITModule>, // taken only when dropped
+    context: cranelift_codegen::Context,
+    builder_context: FunctionBuilderContext,
+    quirks: Quirks,                  // what the code is generated for
+    slots: Vec<Option<Box<[Slot]>>>, // by page, allocated as pages are first run
+    blocks: Vec<Option<Block>>,
+    page_blocks: Vec<Vec<usize>>, // blocks with code on each page
+    rewrites: HashMap<usize, u16>,
+    stats: JitStats,
+}
+
+impl Jit {
+    pub fn new(quirks: &Quirks) -> Self {
+        let mut flags = settings::builder();
+        flags
+            .set("opt_level", "speed")
+            .expect("cranelift knows opt_level");
+        let isa = cranelift_native::builder()
+            .expect("the host is supported by cranelift")
+            .finish(settings::Flags::new(flags))
+            .expect("the host ISA builds");
+        let module = JITModule::new(JITBuilder::with_isa(
+            isa,
+            cranelift_module::default_libcall_names(),
+        ));
+
+        Jit {
+            context: module.make_context(),
+            module: Some(module),
+            builder_context: FunctionBuilderContext::new(),
+            quirks: *quirks,
+            slots: Vec::new(),
+            blocks: Vec::new(),
+            page_blocks: Vec::new(),
+            rewrites: HashMap::new(),
+            stats: JitStats::default(),
+        }
+    }
+
+    // Whether the blocks compiled so far run the way a Cpu with `quirks` would;
+    // timing is passed to each run, so it doesn't matter
+    pub fn compiled_for(&self, quirks: &Quirks) -> bool {
+        Quirks {
+            timing: self.quirks.timing,
+            ..*quirks
+        } == self.quirks
+    }
+
+    pub fn stats(&self) -> JitStats {
+        self.stats
+    }
+
+    fn slot_mut(&mut self, addr: usize) -> &mut Slot {
+        let page = addr >> PAGE_BITS;
+        if page >= self.slots.len() {
+            self.slots.resize_with(page + 1, || None);
+        }
+        let slots = self.slots[page].get_or_insert_with(|| vec![Slot::Cold(0); PAGE_SIZE].into());
+        &mut slots[addr & (PAGE_SIZE - 1)]
+    }
+
+    // Run the block starting at `pc` on `registers`, if there is one and its
+    // instructions before the last cost less than `remaining`, so the frame
+    // can't end inside it. Counts towards compiling the code at `pc` otherwise.
+    pub fn run(
+        &mut self,
+        memory: &[u8],
+        pc: usize,
+        timing: Timing,
+        remaining: u32,
+        registers: &mut Registers,
+    ) -> Option<Ran> {
+        let id = match *self.slot_mut(pc) {
+            Slot::Compiled(id) => id,
+            Slot::Interpreted => return None,
+            Slot::Cold(hits) if hits + 1 < HOT_THRESHOLD => {
+                *self.slot_mut(pc) = Slot::Cold(hits + 1);
+                return None;
+            }
+            Slot::Cold(_) => {
+                let compiled = self.compile(memory, pc);
+                *self.slot_mut(pc) = compiled.map_or(Slot::Interpreted, Slot::Compiled);
+                compiled?
+            }
+        };
+
+        let block = self.blocks[id]
+            .as_mut()
+            .expect("compiled slots point at live blocks");
+        let (&last, lead) = block
+            .instructions
+            .split_last()
+            .expect("blocks aren't empty");
+        let lead_cost = match block.lead_cost {
+            Some((cached, cost)) if cached == timing => cost,
+            _ => {
+                let cost = lead
+                    .iter()
+                    .map(|&instruction| timing.cost(instruction, &registers.v, false))
+                    .sum();
+                block.lead_cost = Some((timing, cost));
+                cost
+            }
+        };
+        if lead_cost >= remaining {
+            return None;
+        }
+
+        // Safety: the code lives as long as the module, which outlives self.blocks
+        let next = unsafe { (block.code)(registers) } as usize;
+        let last_addr = block.end() - OPCODE_SIZE;
+        let skipped = is_skip(last) && next == last_addr + 2 * OPCODE_SIZE;
+        let instructions = block.instructions.len();
+        self.stats.instructions += instructions as u64;
+        Some(Ran {
+            start: pc,
+            next,
+            instructions,
+            cost: lead_cost + timing.cost(last, &registers.v, skipped),
+        })
+    }
+
+    // Compile the block starting at `start`; None if it would be too short to be worth it
+    fn compile(&mut self, memory: &[u8], start: usize) -> Option<usize> {
+        let mut instructions = Vec::new();
+        let mut addr = start;
+        while instructions.len() < MAX_BLOCK_LENGTH && addr + 1 < memory.len() {
+            let opcode = ((memory[addr] as u16) << 8) | memory[addr + 1] as u16;
+            let instruction = decode_for(self.quirks.platform, opcode);
+            let compiles = compiles(instruction);
+            if matches!(compiles, Compiles::No) {
+                break;
+            }
+            instructions.push(instruction);
+            addr += OPCODE_SIZE;
+            if matches!(compiles, Compiles::Branch) {
+                break;
+            }
+        }
+        if instructions.len() < 2 {
+            return None;
+        }
+
+        let code = self.generate(start, &instructions)?;
+        let id = self.blocks.len();
+        self.blocks.push(Some(Block {
+            code,
+            start,
+            bytes: memory[start..addr].into(),
+            instructions: instructions.into(),
+            lead_cost: None,
+        }));
+        let last_page = (addr - 1) >> PAGE_BITS;
+        if last_page >= self.page_blocks.len() {
+            self.page_blocks.resize_with(last_page + 1, Vec::new);
+        }
+        for page in (start >> PAGE_BITS)..=last_page {
+            self.page_blocks[page].push(id);
+        }
+        self.stats.blocks_compiled += 1;
+        Some(id)
+    }
+
+    // Native code for `instructions`, found from `start` on; None if Cranelift fails
+    fn generate(&mut self, start: usize, instructions: &[Instruction]) -> Option<BlockFn> {
+        let module = self.module.as_mut().expect("the module lives until drop");
+        let signature = &mut self.context.func.signature;
+        signature
+            .params
+            .push(AbiParam::new(module.target_config().pointer_type()));
+        signature.returns.push(AbiParam::new(types::I64));
+
+        let mut builder = FunctionBuilder::new(&mut self.context.func, &mut self.builder_context);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+        let registers = builder.block_params(entry)[0];
+
+        let flags = MemFlags::trusted();
+        for var in 0..FIELDS {
+            let (ty, offset) = field(var);
+            builder.declare_var(Variable::new(var), ty);
+            let value = builder.ins().load(ty, flags, registers, offset);
+            builder.def_var(Variable::new(var), value);
+        }
+
+        let mut written = [false; FIELDS];
+        let mut next = None;
+        for (i, &instruction) in instructions.iter().enumerate() {
+            next = translate(
+                &mut builder,
+                instruction,
+                start + i * OPCODE_SIZE,
+                &self.quirks,
+                &mut written,
+            );
+        }
+        let next = next.unwrap_or_else(|| {
+            let end = start + instructions.len() * OPCODE_SIZE;
+            builder.ins().iconst(types::I64, end as i64)
+        });
+
+        for var in (0..FIELDS).filter(|&var| written[var]) {
+            let value = builder.use_var(Variable::new(var));
+            builder.ins().store(flags, value, registers, field(var).1);
+        }
+        builder.ins().return_(&[next]);
+        builder.finalize();
+
+        let defined = module
+            .declare_anonymous_function(&self.context.func.signature)
+            .ok()
+            .filter(|&id| module.define_function(id, &mut self.context).is_ok());
+        module.clear_context(&mut self.context);
+        let id = defined?;
+        module.finalize_definitions().ok()?;
+        let code = module.get_finalized_function(id);
+        // Safety: the function was built with BlockFn's signature above
+        Some(unsafe { std::mem::transmute::<*const u8, BlockFn>(code) })
+    }
+
+    // memory[range] was just written: drop the blocks compiled from it
+    pub fn invalidate(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        for page in (range.start >> PAGE_BITS)..=((range.end - 1) >> PAGE_BITS) {
+            if page >= self.page_blocks.len() {
+                break;
+            }
+            let mut i = 0;
+            while let Some(&id) = self.page_blocks[page].get(i) {
+                let block = self.blocks[id].as_ref().expect("pages list live blocks");
+                if block.start < range.end && range.start < block.end() {
+                    // Takes id off this page's list, moving the next one up to i
+                    self.remove(id, true);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    // After memory was replaced wholesale (load_state): drop the blocks whose
+    // code changed, and look again at addresses where nothing compiled
+    pub fn revalidate(&mut self, memory: &[u8]) {
+        for id in 0..self.blocks.len() {
+            let stale = self.blocks[id]
+                .as_ref()
+                .is_some_and(|block| memory.get(block.start..block.end()) != Some(&block.bytes));
+            if stale {
+                self.remove(id, false);
+            }
+        }
+        for (page, slots) in self.slots.iter_mut().enumerate() {
+            for (offset, slot) in slots.iter_mut().flatten().enumerate() {
+                let addr = (page << PAGE_BITS) + offset;
+                let rewrites = self.rewrites.get(&addr).copied().unwrap_or(0);
+                if *slot == Slot::Interpreted && rewrites < REWRITE_LIMIT {
+                    *slot = Slot::Cold(0);
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, id: usize, rewritten: bool) {
+        let Some(block) = self.blocks[id].take() else {
+            return;
+        };
+        for page in (block.start >> PAGE_BITS)..=((block.end() - 1) >> PAGE_BITS) {
+            self.page_blocks[page].retain(|&other| other != id);
+        }
+        let rewrites = self.rewrites.entry(block.start).or_insert(0);
+        if rewritten {
+            *rewrites += 1;
+        }
+        let rewrites = *rewrites;
+        *self.slot_mut(block.start) = if rewrites >= REWRITE_LIMIT {
+            Slot::Interpreted
+        } else {
+            Slot::Cold(0)
+        };
+        self.stats.blocks_invalidated += 1;
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        // The blocks go with self, so nothing can call into the code afterwards
+        if let Some(module) = self.module.take() {
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    fn quirks() -> Quirks {
+        Quirks {
+            timing: Timing::Instructions { per_frame: 100 },
+            ..Quirks::default()
+        }
+    }
+
+    fn frame(cpu: &mut Cpu) {
+        while !cpu.cycle([false; 16]).vblank {}
+        assert_eq!(cpu.fault(), None);
+    }
+
+    // Run `program` frame by frame compiled and interpreted, checking the two
+    // agree after every frame; returns the compiled one
+    fn run_both(quirks: Quirks, program: &[u8], frames: usize) -> Cpu {
+        let [mut compiled, mut interpreted] = [JitMode::On, JitMode::Off].map(|mode| {
+            let mut cpu = Cpu::with_quirks(quirks);
+            cpu.set_idle_skip(false);
+            cpu.set_jit(mode);
+            cpu.load_program(program);
+            cpu
+        });
+        for n in 0..frames {
+            frame(&mut compiled);
+            frame(&mut interpreted);
+            assert_eq!(
+                (
+                    compiled.v_registers(),
+                    compiled.index_register(),
+                    compiled.program_counter()
+                ),
+                (
+                    interpreted.v_registers(),
+                    interpreted.index_register(),
+                    interpreted.program_counter()
+                ),
+                "after frame {n}"
+            );
+            assert!(compiled.memory() == interpreted.memory(), "after frame {n}");
+        }
+        let stats = compiled.jit_stats().expect("the jit is on");
+        assert!(stats.instructions > 0, "nothing ran compiled");
+        compiled
+    }
+
+    #[test]
+    fn arithmetic_into_vf() {
+        let program = [
+            0x6F, 0x30, // LD VF, 0x30
+            0x61, 0x40, // LD V1, 0x40
+            0x8F, 0x15, // SUB VF, V1: borrows
+            0x82, 0xF0, // LD V2, VF
+            0x6F, 0x30, // LD VF, 0x30
+            0x8F, 0x17, // SUBN VF, V1: doesn't borrow
+            0x83, 0xF0, // LD V3, VF
+            0x6F, 0x81, // LD VF, 0x81
+            0x8F, 0xF6, // SHR VF
+            0x84, 0xF0, // LD V4, VF
+            0x6F, 0x81, // LD VF, 0x81
+            0x8F, 0xFE, // SHL VF
+            0x85, 0xF0, // LD V5, VF
+            0x76, 0x01, // ADD V6, 1
+            0x12, 0x00, // JP 0x200
+        ];
+        for shift_vy in [false, true] {
+            let quirks = Quirks {
+                shift_vy,
+                ..quirks()
+            };
+            let cpu = run_both(quirks, &program, 10);
+            // The flag goes first, so the operation then works on it
+            assert_eq!(cpu.v_registers()[2..6], [0xC0, 0x3F, 0, 2]);
+        }
+    }
+
+    #[test]
+    fn index_overflow() {
+        let program = [
+            0x60, 0x80, // LD V0, 0x80
+            0xF0, 0x1E, // ADD I, V0
+            0x12, 0x02, // JP 0x202
+        ];
+        let cpu = run_both(quirks(), &program, 10);
+        assert!(cpu.index_register() > 0xFFF);
+    }
+
+    #[test]
+    fn fx55_into_a_compiled_block() {
+        let program = [
+            0x71, 0x01, // ADD V1, 1: becomes ADD V3, 1
+            0x72, 0x01, // ADD V2, 1
+            0x32, 0x10, // SE V2, 16
+            0x12, 0x00, // JP 0x200
+            0xA2, 0x00, // LD I, 0x200
+            0x60, 0x73, // LD V0, 0x73
+            0xF0, 0x55, // LD [I], V0
+            0x62, 0x00, // LD V2, 0
+            0x12, 0x00, // JP 0x200
+        ];
+        let cpu = run_both(quirks(), &program, 10);
+        assert_eq!(cpu.v_registers()[1], 16);
+        assert!(cpu.v_registers()[3] > 0);
+        let stats = cpu.jit_stats().expect("the jit is on");
+        assert!(stats.blocks_invalidated > 0);
+    }
+}
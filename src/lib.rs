@@ -0,0 +1,51 @@
+/*!
+ * @file lib.rs
+ * @brief CHIP-8 emulator core, shared by the runner, debugger and tools binaries
+ */
+pub mod batch;
+pub mod cdp1802;
+pub mod cheats;
+pub mod cpu;
+pub mod crc32;
+pub mod decode_cache;
+pub mod detect;
+pub mod display;
+pub mod env;
+pub mod font;
+pub mod frontend;
+pub mod instruction;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod json;
+pub mod megachip;
+pub mod memory_search;
+pub mod movie;
+pub mod patch;
+pub mod platform;
+pub mod quirks;
+pub mod recompile;
+pub mod romdb;
+pub mod savestate;
+pub mod script;
+pub mod sha1;
+pub mod stack;
+pub mod symbols;
+pub mod timing;
+pub mod vip;
+
+pub use cdp1802::Cdp1802;
+pub use cpu::{
+    CallFrame, Cpu, Fault, OutputState, DISPLAY_HEIGHT, DISPLAY_WIDTH, MEMORY_SIZE, PROGRAM_START,
+};
+pub use display::{Color, Display};
+pub use font::FONT_SET;
+pub use instruction::{decode, decode_for, disassemble, disassemble_for, Instruction};
+pub use megachip::MegaChip;
+pub use platform::Platform;
+pub use quirks::{
+    IndexIncrement, KeyWait, MemoryLayout, Quirks, SCHIP_STACK_DEPTH, VIP_STACK_DEPTH,
+};
+pub use romdb::RomDatabase;
+pub use savestate::StateError;
+pub use timing::Timing;
+pub use vip::Vip;
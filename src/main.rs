@@ -1,22 +1,668 @@
-mod cpu;
-mod display;
-mod font;
+use chip8_emulator::cheats::Cheats;
+use chip8_emulator::detect::{detect, Family};
+use chip8_emulator::display::Rgb;
+use chip8_emulator::frontend::{
+    AudioSink, Frontend, InputSource, MovieInput, MovieRecorder, NullAudio, NullInput, NullVideo,
+    RecordingAudio, RecordingVideo, Scheduler, ScriptedInput, SpeedControl, TerminalAudio,
+    TerminalInput, TerminalVideo, Tick, VideoSink, DEFAULT_FRAME_SKIP, MIN_SPEED,
+};
+#[cfg(feature = "jit")]
+use chip8_emulator::jit::JitMode;
+use chip8_emulator::movie::Movie;
+use chip8_emulator::patch::apply_patch;
+use chip8_emulator::romdb::RomSettings;
+use chip8_emulator::script::Script;
+use chip8_emulator::{
+    Cpu, IndexIncrement, KeyWait, MemoryLayout, Platform, Quirks, RomDatabase, Timing,
+};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+use std::{env, fs, process};
 
-use cpu::Cpu;
-use std::{thread, time};
+const USAGE: &str = "usage: chip8-emulator <rom> [options]
+
+options:
+    --video <terminal|null|record:FILE>   where frames go (default terminal)
+    --audio <terminal|null|record:FILE>   where the beeper goes (default terminal)
+    --input <terminal|null>               where keys come from (default terminal)
+    --script <file>                       take keys from an input script; exit 1 if
+                                          one of its assertions fails
+    --frames <n>                          stop after n frames
+    --record-movie <file>                 write the keys pressed and cheats toggled,
+                                          with the seed and cheats, to an input movie
+    --play-movie <file>                   replay an input movie instead of reading
+                                          keys; its seed and cheats replace --seed
+                                          and the ROM's cheats
+    --patch <file>                        apply an IPS or BPS patch to the ROM before
+                                          loading it; repeat to apply several in order
+    --platform <auto|chip8|hires|chip8x|megachip>
+                                          display, memory, load address and opcodes;
+                                          auto guesses the variant from the opcodes
+                                          the ROM uses (see chip8-tools detect)
+    --database <dir>                      ROM database files that override the
+                                          bundled and per-user ones
+    --no-database                         don't look the ROM up; quirks come only
+                                          from detection and the options
+    --cheats <file>                       cheats to use instead of the ROM's cheat file
+    --key-wait <release|press>            when FX0A resumes (default release)
+    --memory-layout <separate|vip>        vip keeps the stack and display in RAM
+    --stack-depth <n|unlimited>           nested calls before a stack fault (default 16)
+    --cycles <n>                          instructions per frame (default 1)
+    --speed <x>                           frame rate as a multiple of 60 Hz; below 1
+                                          is slow motion (default 1, at least 0.125)
+    --frame-skip <n>                      fast-forward shows one frame in n (default 5)
+    --timing vip                          charge VIP machine cycles per instruction
+    --display-wait                        DXYN waits for the next frame (VIP)
+    --no-display-wait                     DXYN draws at once, whatever the database says
+    --machine-code                        0NNN runs 1802 machine code (VIP hybrids)
+    --no-machine-code                     0NNN does nothing, whatever the database says
+    --shift <vx|vy>                       8XY6 / 8XYE shift VX in place, or put VY
+                                          shifted into VX (VIP)
+    --index <keep|x|x+1>                  how far FX55 / FX65 move I on: not at
+                                          all, X (SCHIP 1.0) or X + 1 (VIP)
+    --sprites <wrap|clip>                 what DXYN does at the screen edges
+    --jump <v0|vx>                        BNNN adds V0, or BXNN adds VX (SCHIP)
+    --logic-vf <keep|reset>               whether 8XY1 - 8XY3 reset VF (VIP)
+    --seed <n>                            make CXKK repeatable, e.g. to replay a run
+                                          from chip8-tools batch (default: random)
+    --headless                            default to null backends, no frame delay
+    --no-idle-skip                        run delay timer polling loops instruction
+                                          by instruction instead of skipping ahead
+    --jit                                 compile hot code to native code (needs a
+                                          build with --features jit)
+    --jit-check                           like --jit, but also interpret every compiled
+                                          block and stop if the results differ
+    --stats                               print frame and skipped cycle counts at exit
+
+Keys while running in the terminal: Tab toggles fast-forward, - halves the
+speed, = restores normal speed, h pauses, n advances one frame, ] and [
+double and halve the instructions per frame, and ! @ # ... ( (shifted 1 - 9)
+turn the first nine cheats on and off. Escape quits.
+
+ROMs found in the ROM database (by SHA-1) get its platform, quirks, speed, key
+bindings and colours; options given here still win. Per-user database files go
+in $XDG_CONFIG_HOME/chip8-emulator/database (~/.config by default). Cheats
+come from the ROM's file in the cheats directory there, named after its SHA-1
+(chip8-debugger's cheat command writes it); the ones not marked off start on.";
+
+struct Options {
+    rom_path: String,
+    patches: Vec<String>,
+    video: Option<String>,
+    audio: Option<String>,
+    input: Option<String>,
+    script: Option<String>,
+    record_movie: Option<String>,
+    play_movie: Option<String>,
+    frames: Option<u64>,
+    platform: Option<Platform>, // None to take it from the database or detect it
+    quirks: QuirkOptions,
+    databases: Vec<PathBuf>,
+    use_database: bool,
+    cheats: Option<String>, // None for the ROM's own cheat file
+    headless: bool,
+    speed: f64,
+    frame_skip: u32,
+    seed: Option<u64>,
+    idle_skip: bool,
+    jit: bool,
+    jit_check: bool,
+    stats: bool,
+}
+
+// Quirks set on the command line, applied over whatever the database picked
+#[derive(Default)]
+struct QuirkOptions {
+    key_wait: Option<KeyWait>,
+    memory_layout: Option<MemoryLayout>,
+    stack_depth: Option<Option<usize>>,
+    timing: Option<Timing>,
+    display_wait: Option<bool>,
+    machine_code: Option<bool>,
+    shift_vy: Option<bool>,
+    index_increment: Option<IndexIncrement>,
+    clip_sprites: Option<bool>,
+    jump_vx: Option<bool>,
+    logic_resets_vf: Option<bool>,
+}
+
+impl QuirkOptions {
+    fn apply(&self, quirks: &mut Quirks) {
+        if let Some(key_wait) = self.key_wait {
+            quirks.key_wait = key_wait;
+        }
+        if let Some(memory_layout) = self.memory_layout {
+            quirks.memory_layout = memory_layout;
+        }
+        if let Some(stack_depth) = self.stack_depth {
+            quirks.stack_depth = stack_depth;
+        }
+        if let Some(timing) = self.timing {
+            quirks.timing = timing;
+        }
+        if let Some(display_wait) = self.display_wait {
+            quirks.display_wait = display_wait;
+        }
+        if let Some(machine_code) = self.machine_code {
+            quirks.machine_code = machine_code;
+        }
+        if let Some(shift_vy) = self.shift_vy {
+            quirks.shift_vy = shift_vy;
+        }
+        if let Some(index_increment) = self.index_increment {
+            quirks.index_increment = index_increment;
+        }
+        if let Some(clip_sprites) = self.clip_sprites {
+            quirks.clip_sprites = clip_sprites;
+        }
+        if let Some(jump_vx) = self.jump_vx {
+            quirks.jump_vx = jump_vx;
+        }
+        if let Some(logic_resets_vf) = self.logic_resets_vf {
+            quirks.logic_resets_vf = logic_resets_vf;
+        }
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn parse_args() -> Options {
+    let mut args = env::args().skip(1);
+    let mut options = Options {
+        rom_path: String::new(),
+        patches: Vec::new(),
+        video: None,
+        audio: None,
+        input: None,
+        script: None,
+        record_movie: None,
+        play_movie: None,
+        frames: None,
+        platform: None,
+        quirks: QuirkOptions::default(),
+        databases: Vec::new(),
+        use_database: true,
+        cheats: None,
+        headless: false,
+        speed: 1.0,
+        frame_skip: DEFAULT_FRAME_SKIP,
+        seed: None,
+        idle_skip: true,
+        jit: false,
+        jit_check: false,
+        stats: false,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| usage_error(&format!("{} needs a value", arg)))
+        };
+        match arg.as_str() {
+            "--video" => options.video = Some(value()),
+            "--audio" => options.audio = Some(value()),
+            "--input" => options.input = Some(value()),
+            "--script" => options.script = Some(value()),
+            "--record-movie" => options.record_movie = Some(value()),
+            "--play-movie" => options.play_movie = Some(value()),
+            "--patch" => options.patches.push(value()),
+            "--frames" => {
+                let frames = value();
+                options.frames =
+                    Some(frames.parse().unwrap_or_else(|_| {
+                        usage_error(&format!("invalid frame count {}", frames))
+                    }));
+            }
+            "--platform" => {
+                let name = value();
+                options.platform = match name.as_str() {
+                    "auto" => None,
+                    _ => Some(
+                        Platform::from_name(&name)
+                            .unwrap_or_else(|| usage_error(&format!("unknown platform {}", name))),
+                    ),
+                }
+            }
+            "--cycles" => {
+                let cycles = value();
+                let per_frame = cycles
+                    .parse()
+                    .unwrap_or_else(|_| usage_error(&format!("invalid cycle count {}", cycles)));
+                options.quirks.timing = Some(Timing::Instructions { per_frame });
+            }
+            "--speed" => {
+                let speed = value();
+                options.speed = speed
+                    .parse()
+                    .ok()
+                    .filter(|&speed| speed >= MIN_SPEED)
+                    .unwrap_or_else(|| usage_error(&format!("invalid speed {}", speed)));
+            }
+            "--frame-skip" => {
+                let skip = value();
+                options.frame_skip = skip
+                    .parse()
+                    .ok()
+                    .filter(|&skip| skip > 0)
+                    .unwrap_or_else(|| usage_error(&format!("invalid frame skip {}", skip)));
+            }
+            "--timing" => {
+                options.quirks.timing = match value().as_str() {
+                    "vip" => Some(Timing::Vip),
+                    other => usage_error(&format!("unknown timing model {}", other)),
+                }
+            }
+            "--display-wait" => options.quirks.display_wait = Some(true),
+            "--no-display-wait" => options.quirks.display_wait = Some(false),
+            "--machine-code" => options.quirks.machine_code = Some(true),
+            "--no-machine-code" => options.quirks.machine_code = Some(false),
+            "--shift" => {
+                options.quirks.shift_vy = match value().as_str() {
+                    "vx" => Some(false),
+                    "vy" => Some(true),
+                    other => usage_error(&format!("unknown shift mode {}", other)),
+                }
+            }
+            "--index" => {
+                options.quirks.index_increment = match value().as_str() {
+                    "keep" => Some(IndexIncrement::Unchanged),
+                    "x" => Some(IndexIncrement::ByX),
+                    "x+1" => Some(IndexIncrement::ByXPlusOne),
+                    other => usage_error(&format!("unknown index mode {}", other)),
+                }
+            }
+            "--sprites" => {
+                options.quirks.clip_sprites = match value().as_str() {
+                    "wrap" => Some(false),
+                    "clip" => Some(true),
+                    other => usage_error(&format!("unknown sprite mode {}", other)),
+                }
+            }
+            "--jump" => {
+                options.quirks.jump_vx = match value().as_str() {
+                    "v0" => Some(false),
+                    "vx" => Some(true),
+                    other => usage_error(&format!("unknown jump mode {}", other)),
+                }
+            }
+            "--logic-vf" => {
+                options.quirks.logic_resets_vf = match value().as_str() {
+                    "keep" => Some(false),
+                    "reset" => Some(true),
+                    other => usage_error(&format!("unknown logic mode {}", other)),
+                }
+            }
+            "--key-wait" => {
+                options.quirks.key_wait = match value().as_str() {
+                    "release" => Some(KeyWait::PressAndRelease),
+                    "press" => Some(KeyWait::Press),
+                    other => usage_error(&format!("unknown key wait mode {}", other)),
+                }
+            }
+            "--memory-layout" => {
+                options.quirks.memory_layout = match value().as_str() {
+                    "separate" => Some(MemoryLayout::Separate),
+                    "vip" => Some(MemoryLayout::Vip),
+                    other => usage_error(&format!("unknown memory layout {}", other)),
+                }
+            }
+            "--stack-depth" => {
+                let depth = value();
+                options.quirks.stack_depth = match depth.as_str() {
+                    "unlimited" => Some(None),
+                    _ => Some(Some(depth.parse().unwrap_or_else(|_| {
+                        usage_error(&format!("invalid stack depth {}", depth))
+                    }))),
+                }
+            }
+            "--database" => options.databases.push(PathBuf::from(value())),
+            "--no-database" => options.use_database = false,
+            "--cheats" => options.cheats = Some(value()),
+            "--seed" => {
+                let seed = value();
+                options.seed = Some(
+                    seed.parse()
+                        .unwrap_or_else(|_| usage_error(&format!("invalid seed {}", seed))),
+                );
+            }
+            "--headless" => options.headless = true,
+            "--no-idle-skip" => options.idle_skip = false,
+            "--jit" => options.jit = true,
+            "--jit-check" => options.jit_check = true,
+            "--stats" => options.stats = true,
+            _ if arg.starts_with("--") => usage_error(&format!("unknown option {}", arg)),
+            _ if options.rom_path.is_empty() => options.rom_path = arg,
+            _ => usage_error(&format!("unexpected argument {}", arg)),
+        }
+    }
+
+    if options.rom_path.is_empty() {
+        usage_error("missing ROM path");
+    }
+    if options.script.is_some() && options.play_movie.is_some() {
+        usage_error("--script and --play-movie both give the keys; pick one");
+    }
+    options
+}
+
+fn create_file(path: &str) -> BufWriter<File> {
+    match File::create(path) {
+        Ok(file) => BufWriter::new(file),
+        Err(err) => {
+            eprintln!("cannot create {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn video_sink(name: &str, colors: Option<[Rgb; 2]>) -> Box<dyn VideoSink> {
+    match (name, colors) {
+        ("terminal", Some(colors)) => Box::new(TerminalVideo::with_colors(colors)),
+        ("terminal", None) => Box::new(TerminalVideo::new()),
+        ("null", _) => Box::new(NullVideo),
+        _ => match name.strip_prefix("record:") {
+            Some(path) => match colors {
+                Some(colors) => Box::new(RecordingVideo::with_colors(create_file(path), colors)),
+                None => Box::new(RecordingVideo::new(create_file(path))),
+            },
+            None => usage_error(&format!("unknown video backend {}", name)),
+        },
+    }
+}
+
+fn audio_sink(name: &str) -> Box<dyn AudioSink> {
+    match name {
+        "terminal" => Box::new(TerminalAudio::new()),
+        "null" => Box::new(NullAudio),
+        _ => match name.strip_prefix("record:") {
+            Some(path) => Box::new(RecordingAudio::new(create_file(path))),
+            None => usage_error(&format!("unknown audio backend {}", name)),
+        },
+    }
+}
+
+fn input_source(
+    name: &str,
+    frames: Option<u64>,
+    actions: &[(String, usize)],
+) -> Box<dyn InputSource> {
+    match (name, frames) {
+        ("terminal", _) => Box::new(TerminalInput::with_actions(actions)),
+        ("null", Some(frames)) => Box::new(NullInput::with_frame_limit(frames)),
+        ("null", None) => Box::new(NullInput::new()),
+        _ => usage_error(&format!("unknown input backend {}", name)),
+    }
+}
+
+fn script_input(path: &str) -> Box<dyn InputSource> {
+    let source = fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("cannot read {}: {}", path, err);
+        process::exit(1);
+    });
+    match Script::parse(&source) {
+        Ok(script) => Box::new(ScriptedInput::new(script)),
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(2);
+        }
+    }
+}
+
+fn load_movie(path: &str) -> Movie {
+    let source = fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("cannot read {}: {}", path, err);
+        process::exit(1);
+    });
+    Movie::parse(&source).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        process::exit(2);
+    })
+}
+
+// Bundled database, then the per-user files, then --database directories
+fn load_database(options: &Options) -> RomDatabase {
+    let mut database = RomDatabase::bundled();
+    let user_dir = RomDatabase::user_dir().into_iter();
+    for dir in user_dir.chain(options.databases.iter().cloned()) {
+        if let Err(err) = database.load_dir(&dir) {
+            eprintln!("ROM database: {}", err);
+            process::exit(1);
+        }
+    }
+    database
+}
+
+// The ROM with the --patch files applied
+fn patch_program(options: &Options, original: &[u8]) -> Vec<u8> {
+    let mut program = original.to_vec();
+    for path in &options.patches {
+        let patch = fs::read(path).unwrap_or_else(|err| {
+            eprintln!("cannot read {}: {}", path, err);
+            process::exit(1);
+        });
+        program = apply_patch(&program, &patch).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        });
+    }
+    program
+}
+
+// Settings for the ROM from the database, or the quirks detection picks for
+// it. A patched ROM not in the database gets the settings of its original.
+fn rom_settings(options: &Options, program: &[u8], original: &[u8]) -> RomSettings {
+    if options.use_database {
+        let database = load_database(options);
+        if let Some(entry) = database
+            .lookup(program)
+            .or_else(|| database.lookup(original))
+        {
+            match entry.authors.as_slice() {
+                [] => println!("{}", entry.title),
+                authors => println!("{} by {}", entry.title, authors.join(", ")),
+            }
+            let settings = database.settings(entry, program);
+            for note in settings.notes.iter() {
+                eprintln!("ROM database: {}", note);
+            }
+            return settings;
+        }
+    }
+
+    let mut settings = RomSettings::default();
+    if options.platform.is_none() {
+        let detection = detect(program);
+        settings.quirks = detection.quirks();
+        if detection.family != Family::Chip8 {
+            eprintln!(
+                "detected {} ({:.0}% confidence)",
+                detection.family.name(),
+                detection.confidence * 100.0
+            );
+        }
+        if let Some(warning) = detection.warning() {
+            eprintln!("warning: {}", warning);
+        }
+    }
+    settings
+}
+
+// --cheats, or the ROM's cheat file if it has one
+fn load_cheats(options: &Options, program: &[u8]) -> Cheats {
+    let Some(path) = options
+        .cheats
+        .as_ref()
+        .map(PathBuf::from)
+        .or_else(|| Cheats::path(program))
+    else {
+        return Cheats::new();
+    };
+    match fs::read_to_string(&path) {
+        Ok(source) => Cheats::parse(&source).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path.display(), err);
+            process::exit(2);
+        }),
+        Err(err) if options.cheats.is_none() && err.kind() == io::ErrorKind::NotFound => {
+            Cheats::new()
+        }
+        Err(err) => {
+            eprintln!("cannot read {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    }
+}
+
+fn toggle_cheat(cpu: &mut Cpu, cheats: &mut Cheats, index: usize) {
+    let name = cheats.iter().nth(index).map(|cheat| cheat.name.clone());
+    if let Some(name) = name {
+        cheats.toggle(&name);
+        cpu.set_cheats(&cheats.enabled());
+    }
+}
+
+// Double or halve the instructions per frame; VIP timing has no such knob
+fn change_cycles(cpu: &mut Cpu, control: SpeedControl) {
+    if let Timing::Instructions { per_frame } = cpu.quirks().timing {
+        let per_frame = match control {
+            SpeedControl::MoreCycles => per_frame.saturating_mul(2),
+            _ => (per_frame / 2).max(1),
+        };
+        cpu.set_timing(Timing::Instructions { per_frame });
+    }
+}
 
 fn main() {
-    let mut cpu =  Cpu::new();
-    let keypad = [true; 16];
-   // TODO: load rom 
-    // TODO: poll keyboard
+    let options = parse_args();
+    let original = fs::read(&options.rom_path).unwrap_or_else(|err| {
+        eprintln!("cannot read {}: {}", options.rom_path, err);
+        process::exit(1);
+    });
+    let program = patch_program(&options, &original);
+
+    let settings = rom_settings(&options, &program, &original);
+    let mut quirks = settings.quirks;
+    if let Some(platform) = options.platform {
+        quirks.platform = platform;
+    }
+    options.quirks.apply(&mut quirks);
+    let mut cpu = Cpu::with_quirks(quirks);
+    let movie = options.play_movie.as_deref().map(load_movie);
+    // A recorded movie needs the seed to replay CXKK
+    let seed = match &movie {
+        Some(movie) => movie.seed,
+        None => options.seed,
+    }
+    .or_else(|| options.record_movie.is_some().then(rand::random));
+    if let Some(seed) = seed {
+        cpu.seed_random(seed);
+    }
+    cpu.load_program(&program);
+    let mut cheats = match &movie {
+        Some(movie) => movie.cheats.clone(),
+        None => load_cheats(&options, &program),
+    };
+    cpu.set_cheats(&cheats.enabled());
+    cpu.set_idle_skip(options.idle_skip);
+    #[cfg(feature = "jit")]
+    if options.jit_check {
+        cpu.set_jit(JitMode::Differential);
+    } else if options.jit {
+        cpu.set_jit(JitMode::On);
+    }
+    #[cfg(not(feature = "jit"))]
+    if options.jit || options.jit_check {
+        eprintln!("this build has no JIT; rebuild with --features jit");
+        process::exit(2);
+    }
+
+    // The action keys overlap the terminal's CHIP-8X second keypad
+    let actions = match cpu.quirks().platform {
+        Platform::Chip8X => &[][..],
+        _ => &settings.keys[..],
+    };
+    let default_backend = if options.headless { "null" } else { "terminal" };
+    let backend =
+        |choice: &Option<String>| choice.as_deref().unwrap_or(default_backend).to_string();
+    let mut input = match (movie, &options.script) {
+        (Some(movie), _) => Box::new(MovieInput::new(movie)),
+        (None, Some(path)) => script_input(path),
+        (None, None) => input_source(&backend(&options.input), options.frames, actions),
+    };
+    if let (Some(path), Some(seed)) = (&options.record_movie, seed) {
+        input = Box::new(MovieRecorder::new(input, create_file(path), seed, &cheats));
+    }
+    let mut frontend = Frontend::new(
+        video_sink(&backend(&options.video), settings.colors),
+        audio_sink(&backend(&options.audio)),
+        input,
+    );
+
+    let mut scheduler = if options.headless {
+        Scheduler::unthrottled()
+    } else {
+        Scheduler::new(options.speed, options.frame_skip)
+    };
+    while let Some(controls) = frontend.input.speed_controls() {
+        for control in controls {
+            match control {
+                SpeedControl::MoreCycles | SpeedControl::FewerCycles => {
+                    change_cycles(&mut cpu, control)
+                }
+                SpeedControl::ToggleCheat(index) => toggle_cheat(&mut cpu, &mut cheats, index),
+                _ => scheduler.apply(control),
+            }
+        }
+
+        match scheduler.tick() {
+            Tick::Paused => continue,
+            Tick::Run { present } => {
+                if !frontend.run_frame_presenting(&mut cpu, present) {
+                    break;
+                }
+            }
+        }
+        if options
+            .frames
+            .is_some_and(|frames| frontend.frame() >= frames)
+        {
+            break;
+        }
+    }
+
+    // Finish the recordings before exiting
+    let frames = frontend.frame();
+    let failure = frontend.input.failure().map(str::to_string);
+    drop(frontend);
 
-    let outputState = cpu.cycle(keypad);
+    if options.stats {
+        eprintln!("frames: {}", frames);
+        eprintln!("idle cycles skipped: {}", cpu.idle_cycles_skipped());
+        #[cfg(feature = "jit")]
+        if let Some(stats) = cpu.jit_stats() {
+            eprintln!(
+                "jit: {} blocks compiled, {} invalidated, {} instructions run compiled",
+                stats.blocks_compiled, stats.blocks_invalidated, stats.instructions
+            );
+        }
+    }
 
-    //TODO: update display
-    
+    if let Some(fault) = cpu.fault() {
+        eprintln!("fault: {}", fault);
+        for frame in cpu.backtrace() {
+            eprintln!(
+                "    called from {:03X} -> {:03X}",
+                frame.caller, frame.callee
+            );
+        }
+        process::exit(1);
+    }
 
-    thread::sleep(time::Duration::from_millis(200));
- 
-    
+    if let Some(failure) = failure {
+        eprintln!("{}", failure);
+        process::exit(1);
+    }
 }
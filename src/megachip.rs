@@ -0,0 +1,166 @@
+/*!
+ * @file megachip.rs
+ * @brief MegaChip state: 256x192 mode, palette, sprite size, blending and digitised sound
+ *
+ * In MegaChip mode (0011) sprites are sprite_width x sprite_height bytes, one
+ * palette index per pixel, with index 0 transparent. The display's truecolor
+ * layer keeps the indices (for collisions) and what is shown after blending.
+ * 00E0 presents the finished frame and starts drawing the next one.
+ */
+use crate::display::Display;
+
+pub const MEGACHIP_WIDTH: usize = 256;
+pub const MEGACHIP_HEIGHT: usize = 192;
+
+// 01NN NNNN loads a 24-bit I, so ROMs and their data can fill 16 MB
+pub const MEGACHIP_MEMORY_SIZE: usize = 0x100_0000;
+
+// Palette entry the 1-bit font sprites are drawn in
+pub const FONT_COLOR: u8 = 0xFF;
+
+// A digitised sound starts with its rate (2 bytes), length (3 bytes) and a zero byte
+const SOUND_HEADER_SIZE: usize = 6;
+
+const FRAMES_PER_SECOND: u64 = 60;
+
+/**
+ * @brief How a sprite pixel is combined with the colour already on screen (08NN)
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    // Sprite drawn at 25% / 50% opacity
+    Quarter,
+    Half,
+    // Channels added / multiplied
+    Add,
+    Multiply,
+}
+
+impl BlendMode {
+    pub fn from_code(code: u8) -> Option<BlendMode> {
+        match code {
+            0 => Some(BlendMode::Normal),
+            1 => Some(BlendMode::Quarter),
+            2 => Some(BlendMode::Half),
+            3 => Some(BlendMode::Add),
+            4 => Some(BlendMode::Multiply),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            BlendMode::Normal => 0,
+            BlendMode::Quarter => 1,
+            BlendMode::Half => 2,
+            BlendMode::Add => 3,
+            BlendMode::Multiply => 4,
+        }
+    }
+
+    // Combine a 0xRRGGBB sprite colour with the 0xRRGGBB colour under it
+    pub fn blend(&self, source: u32, destination: u32) -> u32 {
+        let mut result = 0;
+        for shift in [16, 8, 0] {
+            let s = (source >> shift) & 0xFF;
+            let d = (destination >> shift) & 0xFF;
+            let channel = match self {
+                BlendMode::Normal => s,
+                BlendMode::Quarter => (s + 3 * d) / 4,
+                BlendMode::Half => (s + d) / 2,
+                BlendMode::Add => (s + d).min(0xFF),
+                BlendMode::Multiply => s * d / 0xFF,
+            };
+            result |= channel << shift;
+        }
+        result
+    }
+}
+
+/**
+ * @brief A digitised sound started by 060N: 8-bit unsigned samples in memory
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DigitisedSound {
+    pub start: usize,  // address of the first sample
+    pub length: usize, // in samples
+    pub rate: u32,     // samples per second
+    pub looping: bool,
+    pub elapsed: u64,          // samples played, times 60
+    pub frame: (usize, usize), // addresses of the samples played in the last frame
+}
+
+impl DigitisedSound {
+    // Read the header at `addr`; None if it runs past the end of memory
+    pub fn from_memory(memory: &[u8], addr: usize, looping: bool) -> Option<DigitisedSound> {
+        let header = memory.get(addr..addr + SOUND_HEADER_SIZE)?;
+        let rate = ((header[0] as u32) << 8) | header[1] as u32;
+        let length =
+            ((header[2] as usize) << 16) | ((header[3] as usize) << 8) | header[4] as usize;
+        let start = addr + SOUND_HEADER_SIZE;
+        Some(DigitisedSound {
+            start,
+            length: length.min(memory.len() - start),
+            rate,
+            looping,
+            elapsed: 0,
+            frame: (start, start),
+        })
+    }
+
+    // Play the next 60 Hz frame's worth of samples; false once a one-shot
+    // sound has played to the end
+    pub fn advance(&mut self) -> bool {
+        let total = self.length as u64 * FRAMES_PER_SECOND;
+        if self.elapsed >= total {
+            if !self.looping || total == 0 {
+                return false;
+            }
+            self.elapsed %= total;
+        }
+
+        let first = self.elapsed / FRAMES_PER_SECOND;
+        self.elapsed += self.rate as u64;
+        let last = (self.elapsed / FRAMES_PER_SECOND).min(self.length as u64);
+        self.frame = (self.start + first as usize, self.start + last as usize);
+        true
+    }
+}
+
+/**
+ * @brief Everything MegaChip adds to the Cpu
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MegaChip {
+    pub enabled: bool,        // 0011 / 0010
+    pub palette: [u32; 256],  // 0xAARRGGBB, loaded by 02NN into entries 1 - NN
+    pub sprite_width: usize,  // 03NN
+    pub sprite_height: usize, // 04NN
+    pub blend: BlendMode,
+    pub sound: Option<DigitisedSound>,
+    pub frame: Display, // the frame 00E0 last presented
+}
+
+impl Default for MegaChip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MegaChip {
+    pub fn new() -> Self {
+        let mut palette = [0; 256];
+        palette[FONT_COLOR as usize] = 0xFFFF_FFFF;
+        MegaChip {
+            enabled: false,
+            palette,
+            sprite_width: 0,
+            sprite_height: 0,
+            blend: BlendMode::Normal,
+            sound: None,
+            frame: Display::with_truecolor(MEGACHIP_WIDTH, MEGACHIP_HEIGHT),
+        }
+    }
+}
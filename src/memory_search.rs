@@ -0,0 +1,187 @@
+/*!
+ * @file memory_search.rs
+ * @brief Cheat finder: narrow memory and V registers down to the few that
+ *        behave like a game's lives, score or level counter
+ *
+ * A search starts with every byte of memory and every V register as a
+ * candidate and a snapshot of their values. Each filter compares the values
+ * now with the snapshot, keeps the candidates that match and takes a new
+ * snapshot, so "decreased" after losing a life and "unchanged" after a quiet
+ * few frames soon leave only the lives counter.
+ */
+use crate::cpu::{Cpu, REGISTER_COUNT};
+use crate::script::Operand;
+use std::fmt;
+
+/**
+ * @brief How a candidate's value has to compare with the snapshot to stay one
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    Equals(u8),
+}
+
+impl Filter {
+    pub fn parse(words: &[&str]) -> Option<Filter> {
+        match words {
+            ["changed"] => Some(Filter::Changed),
+            ["unchanged"] => Some(Filter::Unchanged),
+            ["increased"] => Some(Filter::Increased),
+            ["decreased"] => Some(Filter::Decreased),
+            ["=" | "==" | "equals", value] => {
+                let value = match value.strip_prefix("0x") {
+                    Some(hex) => u8::from_str_radix(hex, 16).ok(),
+                    None => value.parse().ok(),
+                };
+                value.map(Filter::Equals)
+            }
+            _ => None,
+        }
+    }
+
+    fn keeps(&self, before: u8, now: u8) -> bool {
+        match *self {
+            Filter::Changed => now != before,
+            Filter::Unchanged => now == before,
+            Filter::Increased => now > before,
+            Filter::Decreased => now < before,
+            Filter::Equals(value) => now == value,
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Changed => write!(f, "changed"),
+            Filter::Unchanged => write!(f, "unchanged"),
+            Filter::Increased => write!(f, "increased"),
+            Filter::Decreased => write!(f, "decreased"),
+            Filter::Equals(value) => write!(f, "equals {}", value),
+        }
+    }
+}
+
+/**
+ * @brief Candidates left in a search, and the values they had at the last snapshot
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemorySearch {
+    snapshot: Vec<u8>,     // V0 - VF, then memory
+    candidates: Vec<bool>, // per snapshot entry
+    remaining: usize,
+}
+
+// V registers and memory side by side, the way a search indexes them
+fn values(cpu: &Cpu) -> Vec<u8> {
+    let mut values = cpu.v_registers().to_vec();
+    values.extend_from_slice(cpu.memory());
+    values
+}
+
+fn location(index: usize) -> Operand {
+    match index {
+        x if x < REGISTER_COUNT => Operand::V(x),
+        addr => Operand::Mem(addr - REGISTER_COUNT),
+    }
+}
+
+impl MemorySearch {
+    pub fn start(cpu: &Cpu) -> Self {
+        let snapshot = values(cpu);
+        MemorySearch {
+            candidates: vec![true; snapshot.len()],
+            remaining: snapshot.len(),
+            snapshot,
+        }
+    }
+
+    // Keep the candidates whose value now passes `filter`, then snapshot again
+    pub fn filter(&mut self, cpu: &Cpu, filter: Filter) {
+        let now = values(cpu);
+        self.remaining = 0;
+        for ((candidate, &before), &now) in self
+            .candidates
+            .iter_mut()
+            .zip(self.snapshot.iter())
+            .zip(now.iter())
+        {
+            *candidate = *candidate && filter.keeps(before, now);
+            self.remaining += *candidate as usize;
+        }
+        self.snapshot = now;
+    }
+
+    pub fn len(&self) -> usize {
+        self.remaining
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+
+    // Each candidate left, V registers first, with its value at the last snapshot
+    pub fn candidates(&self) -> impl Iterator<Item = (Operand, u8)> + '_ {
+        self.candidates
+            .iter()
+            .zip(self.snapshot.iter())
+            .enumerate()
+            .filter(|(_, (&candidate, _))| candidate)
+            .map(|(index, (_, &value))| (location(index), value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_filters() {
+        assert_eq!(Filter::parse(&["decreased"]), Some(Filter::Decreased));
+        assert_eq!(Filter::parse(&["=", "3"]), Some(Filter::Equals(3)));
+        assert_eq!(
+            Filter::parse(&["equals", "0x1F"]),
+            Some(Filter::Equals(0x1F))
+        );
+        assert_eq!(Filter::parse(&["==", "256"]), None);
+        assert_eq!(Filter::parse(&["smaller"]), None);
+        assert_eq!(Filter::Equals(3).to_string(), "equals 3");
+    }
+
+    #[test]
+    fn finds_a_counter() {
+        let program = [
+            0x65, 0x03, // LD V5, 3
+            0xA3, 0x00, // LD I, 0x300
+            0x75, 0xFF, // loop: ADD V5, -1
+            0xF5, 0x55, // LD [I], V5
+            0x12, 0x04, // JP loop
+        ];
+        let mut cpu = Cpu::new();
+        cpu.set_idle_skip(false);
+        cpu.load_program(&program);
+        let run = |cpu: &mut Cpu, steps| {
+            for _ in 0..steps {
+                cpu.cycle([false; 16]);
+            }
+        };
+        run(&mut cpu, 4);
+
+        let mut search = MemorySearch::start(&cpu);
+        assert_eq!(search.len(), REGISTER_COUNT + cpu.memory().len());
+        run(&mut cpu, 3);
+        search.filter(&cpu, Filter::Decreased);
+        let counter = [(Operand::V(5), 1), (Operand::Mem(0x305), 1)];
+        assert_eq!(search.candidates().collect::<Vec<_>>(), counter);
+        search.filter(&cpu, Filter::Unchanged);
+        search.filter(&cpu, Filter::Equals(1));
+        assert_eq!(search.len(), 2);
+        run(&mut cpu, 3);
+        search.filter(&cpu, Filter::Increased);
+        assert!(search.is_empty());
+    }
+}
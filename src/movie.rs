@@ -0,0 +1,237 @@
+/*!
+ * @file movie.rs
+ * @brief Input movie format: what a run's player did, frame by frame
+ *
+ * A movie replays a run given the same ROM and quirks. It holds the CXKK seed,
+ * the cheats the run started with (as in a cheat file, after `cheat`), and at
+ * each frame where something changed the keys held from then on and the
+ * cheats turned on or off. `#` starts a comment:
+ *
+ * ```text
+ * seed 1234
+ * cheat lives freeze mem[0x2f3] = 0x03
+ * cheat no-deaths replace 0x2a4 = 0x1300 off
+ * 120 keys 5 a      # 5 and A held from frame 120
+ * 123 keys          # all released
+ * 300 toggle lives
+ * 300 keys2 1       # CHIP-8X second keypad
+ * end 900           # the run stopped after 900 frames
+ * ```
+ *
+ * Events are in frame order; a movie without `end` (the run was cut short)
+ * plays on with the last keys held.
+ */
+use crate::cheats::{Cheat, Cheats};
+use crate::frontend::Keypad;
+use crate::script::{parse_key, parse_number, ParseError};
+use std::fmt;
+
+/**
+ * @brief Something that changed at the start of a frame
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    // The keys held from this frame on
+    Keys(Keypad),
+    // The same, for the CHIP-8X second keypad
+    SecondKeys(Keypad),
+    // The named cheat was turned on or off
+    Toggle(String),
+}
+
+fn parse_keypad(words: &[&str]) -> Result<Keypad, String> {
+    let mut keypad = [false; 16];
+    for word in words {
+        keypad[parse_key(word)?] = true;
+    }
+    Ok(keypad)
+}
+
+fn write_keypad(f: &mut fmt::Formatter<'_>, name: &str, keypad: &Keypad) -> fmt::Result {
+    write!(f, "{}", name)?;
+    for key in (0..keypad.len()).filter(|&key| keypad[key]) {
+        write!(f, " {:x}", key)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Keys(keypad) => write_keypad(f, "keys", keypad),
+            Event::SecondKeys(keypad) => write_keypad(f, "keys2", keypad),
+            Event::Toggle(name) => write!(f, "toggle {}", name),
+        }
+    }
+}
+
+/**
+ * @brief A recorded run
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Movie {
+    pub seed: Option<u64>,
+    pub cheats: Cheats,            // enabled as they were at frame 0
+    pub events: Vec<(u64, Event)>, // in frame order
+    pub length: Option<u64>,       // frames run; None if the movie has no end
+}
+
+impl Movie {
+    pub fn parse(source: &str) -> Result<Movie, ParseError> {
+        let mut movie = Movie::default();
+        for (index, line) in source.lines().enumerate() {
+            let code = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = code.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            movie.parse_line(&words).map_err(|message| ParseError {
+                line: index + 1,
+                message,
+            })?;
+        }
+        Ok(movie)
+    }
+
+    fn parse_line(&mut self, words: &[&str]) -> Result<(), String> {
+        if self.length.is_some() {
+            return Err("nothing can follow `end`".to_string());
+        }
+        match words {
+            ["seed", seed] => {
+                let seed = seed
+                    .parse()
+                    .map_err(|_| format!("expected a seed, found `{}`", seed))?;
+                self.seed = Some(seed);
+            }
+            ["cheat", cheat @ ..] => {
+                if !self.events.is_empty() {
+                    return Err("cheats must come before the first frame".to_string());
+                }
+                self.cheats.insert(Cheat::parse(cheat)?);
+            }
+            ["end", frame] => {
+                let frame = self.parse_frame(frame)?;
+                self.length = Some(frame);
+            }
+            [frame, event @ ..] => {
+                let frame = self.parse_frame(frame)?;
+                let event = match event {
+                    ["keys", keys @ ..] => Event::Keys(parse_keypad(keys)?),
+                    ["keys2", keys @ ..] => Event::SecondKeys(parse_keypad(keys)?),
+                    ["toggle", name] => {
+                        if !self.cheats.iter().any(|cheat| cheat.name == *name) {
+                            return Err(format!("no cheat named `{}`", name));
+                        }
+                        Event::Toggle(name.to_string())
+                    }
+                    _ => return Err(format!("unknown event `{}`", event.join(" "))),
+                };
+                self.events.push((frame, event));
+            }
+            [] => {}
+        }
+        Ok(())
+    }
+
+    // A frame number no earlier than the last event's
+    fn parse_frame(&self, word: &str) -> Result<u64, String> {
+        let frame = parse_number(word)? as u64;
+        match self.events.last() {
+            Some(&(last, _)) if frame < last => {
+                Err(format!("frame {} comes before frame {}", frame, last))
+            }
+            _ => Ok(frame),
+        }
+    }
+}
+
+// In the format parse reads
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(seed) = self.seed {
+            writeln!(f, "seed {}", seed)?;
+        }
+        for cheat in self.cheats.iter() {
+            writeln!(f, "cheat {}", cheat)?;
+        }
+        for (frame, event) in &self.events {
+            writeln!(f, "{} {}", frame, event)?;
+        }
+        if let Some(length) = self.length {
+            writeln!(f, "end {}", length)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cheats::Effect;
+
+    const MOVIE: &str = "\
+seed 1234
+cheat lives freeze mem[0x2F3] = 3
+cheat no-deaths replace 0x2A4 = 0x1300 off
+120 keys 5 a      # 5 and A held from frame 120
+123 keys
+300 toggle lives
+300 keys2 1
+end 900
+";
+
+    #[test]
+    fn parse_movie() {
+        let movie = Movie::parse(MOVIE).unwrap();
+        assert_eq!(movie.seed, Some(1234));
+        assert_eq!(
+            movie.cheats.enabled(),
+            [Effect::Freeze {
+                addr: 0x2F3,
+                value: 3
+            }]
+        );
+        assert_eq!(movie.cheats.len(), 2);
+
+        let mut keys = [false; 16];
+        keys[5] = true;
+        keys[0xA] = true;
+        let mut second = [false; 16];
+        second[1] = true;
+        assert_eq!(
+            movie.events,
+            [
+                (120, Event::Keys(keys)),
+                (123, Event::Keys([false; 16])),
+                (300, Event::Toggle("lives".to_string())),
+                (300, Event::SecondKeys(second)),
+            ]
+        );
+        assert_eq!(movie.length, Some(900));
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let movie = Movie::parse(MOVIE).unwrap();
+        let written = movie.to_string();
+        assert!(written.contains("120 keys 5 a\n"));
+        assert!(written.contains("cheat lives freeze mem[0x2f3] = 0x03\n"));
+        assert_eq!(Movie::parse(&written).unwrap(), movie);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |source: &str| Movie::parse(source).unwrap_err();
+        assert_eq!(error("10 keys\n5 keys").line, 2);
+        assert!(error("10 keys g").message.contains("expected a key"));
+        assert!(error("10 toggle lives")
+            .message
+            .contains("no cheat named `lives`"));
+        assert!(error("1 keys\ncheat a set v1 = 2")
+            .message
+            .contains("before the first frame"));
+        assert!(error("end 5\n6 keys").message.contains("follow `end`"));
+        assert!(error("10 jump").message.contains("unknown event"));
+    }
+}
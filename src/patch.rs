@@ -0,0 +1,590 @@
+/*!
+ * @file patch.rs
+ * @brief IPS and BPS patches: applying them to a ROM before it is loaded, and
+ *        making them from an original ROM and a modified one
+ *
+ * IPS writes bytes at offsets and nothing else, so it applies to any file,
+ * right or wrong. BPS carries CRC-32s of the ROM it was made from and of the
+ * ROM it makes, so applying one to the wrong ROM is an error rather than a
+ * broken game. Translations and bug fixes usually come as one or the other;
+ * when there are several, each applies to the result of the one before.
+ */
+use crate::crc32::crc32;
+use std::collections::HashMap;
+use std::fmt;
+
+const IPS_MAGIC: &[u8; 5] = b"PATCH";
+const IPS_END: &[u8; 3] = b"EOF";
+const IPS_MAX_OFFSET: usize = 0xFF_FFFF;
+const IPS_MAX_RECORD: usize = 0xFFFF;
+// A record costs 5 bytes of header, so unchanged gaps shorter than this are
+// cheaper to repeat inside one record than to start another
+const IPS_MERGE_GAP: usize = 6;
+
+const BPS_MAGIC: &[u8; 4] = b"BPS1";
+const BPS_FOOTER: usize = 12; // source, target and patch CRC-32
+const SOURCE_READ: usize = 0;
+const TARGET_READ: usize = 1;
+const SOURCE_COPY: usize = 2;
+const TARGET_COPY: usize = 3;
+// Runs shorter than these are cheaper written out as new bytes
+const BPS_MIN_READ: usize = 4;
+const BPS_MIN_COPY: usize = 8;
+const BPS_MAX_CANDIDATES: usize = 32; // source offsets tried per 4-byte key
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Bps,
+}
+
+impl PatchFormat {
+    // From the patch's magic bytes
+    pub fn detect(patch: &[u8]) -> Option<PatchFormat> {
+        if patch.starts_with(IPS_MAGIC) {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(BPS_MAGIC) {
+            Some(PatchFormat::Bps)
+        } else {
+            None
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PatchFormat> {
+        match name {
+            "ips" => Some(PatchFormat::Ips),
+            "bps" => Some(PatchFormat::Bps),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    UnknownFormat,
+    Truncated,
+    Corrupt(&'static str),
+    // The ROM isn't the one a BPS patch was made from
+    WrongSource { expected: u32, actual: u32 },
+    // Applying the patch didn't give the ROM it was made to give
+    WrongTarget { expected: u32, actual: u32 },
+    // More than IPS can address
+    TooLarge,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS or BPS patch"),
+            PatchError::Truncated => write!(f, "patch is truncated"),
+            PatchError::Corrupt(what) => write!(f, "patch is corrupt: {}", what),
+            PatchError::WrongSource { expected, actual } => write!(
+                f,
+                "patch is for a different ROM (CRC-32 {:08x}, this one is {:08x})",
+                expected, actual
+            ),
+            PatchError::WrongTarget { expected, actual } => write!(
+                f,
+                "patched ROM has CRC-32 {:08x}, the patch promised {:08x}",
+                actual, expected
+            ),
+            PatchError::TooLarge => write!(f, "ROM is too large for an IPS patch"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match PatchFormat::detect(patch) {
+        Some(PatchFormat::Ips) => apply_ips(rom, patch),
+        Some(PatchFormat::Bps) => apply_bps(rom, patch),
+        None => Err(PatchError::UnknownFormat),
+    }
+}
+
+// A patch that turns `source` into `target`
+pub fn create_patch(
+    format: PatchFormat,
+    source: &[u8],
+    target: &[u8],
+) -> Result<Vec<u8>, PatchError> {
+    match format {
+        PatchFormat::Ips => create_ips(source, target),
+        PatchFormat::Bps => Ok(create_bps(source, target)),
+    }
+}
+
+/**
+ * @brief Reads a patch front to back
+ */
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let end = self
+            .position
+            .checked_add(len)
+            .ok_or(PatchError::Corrupt("length out of range"))?;
+        let bytes = self
+            .data
+            .get(self.position..end)
+            .ok_or(PatchError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn big_endian(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |value, &byte| (value << 8) | byte as usize))
+    }
+
+    // BPS's variable-length number: 7 bits a byte, the last byte flagged
+    // with the top bit, each continuation adding one to avoid two encodings
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.bytes(1)?[0] as usize;
+            value = (byte & 0x7F)
+                .checked_mul(shift)
+                .and_then(|add| value.checked_add(add))
+                .ok_or(PatchError::Corrupt("number out of range"))?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift
+                .checked_mul(0x80)
+                .ok_or(PatchError::Corrupt("number out of range"))?;
+            value = value
+                .checked_add(shift)
+                .ok_or(PatchError::Corrupt("number out of range"))?;
+        }
+    }
+
+    // A relative offset: magnitude, then the sign in the low bit
+    fn offset(&mut self) -> Result<isize, PatchError> {
+        let number = self.number()?;
+        let magnitude = (number >> 1) as isize;
+        Ok(if number & 1 != 0 {
+            -magnitude
+        } else {
+            magnitude
+        })
+    }
+}
+
+fn write_number(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let low = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(0x80 | low);
+            return;
+        }
+        out.push(low);
+        value -= 1;
+    }
+}
+
+fn write_offset(out: &mut Vec<u8>, offset: isize) {
+    write_number(out, (offset.unsigned_abs() << 1) | (offset < 0) as usize);
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut out = rom.to_vec();
+    let mut reader = Reader {
+        data: patch,
+        position: IPS_MAGIC.len(),
+    };
+    while !patch
+        .get(reader.position..)
+        .is_some_and(|rest| rest.starts_with(IPS_END))
+    {
+        let offset = reader.big_endian(3)?;
+        let bytes = match reader.big_endian(2)? {
+            // Run-length record: a count, then the byte to repeat
+            0 => {
+                let len = reader.big_endian(2)?;
+                vec![reader.bytes(1)?[0]; len]
+            }
+            len => reader.bytes(len)?.to_vec(),
+        };
+        if out.len() < offset + bytes.len() {
+            out.resize(offset + bytes.len(), 0);
+        }
+        out[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+    reader.position += IPS_END.len();
+    // An extension some tools write: the size to cut the file down to
+    if let Ok(size) = reader.big_endian(3) {
+        out.truncate(size);
+    }
+    Ok(out)
+}
+
+fn create_ips(source: &[u8], target: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if target.len() > IPS_MAX_OFFSET {
+        return Err(PatchError::TooLarge);
+    }
+    let differs = |i: usize| source.get(i) != Some(&target[i]);
+    let mut patch = IPS_MAGIC.to_vec();
+    let mut i = 0;
+    while i < target.len() {
+        if !differs(i) {
+            i += 1;
+            continue;
+        }
+        // An offset that reads as "EOF" would end the patch; start a byte early
+        let start = if i == 0x454F46 { i - 1 } else { i };
+        let mut end = i + 1;
+        let mut gap = 0;
+        while end < target.len() && end - start < IPS_MAX_RECORD && gap < IPS_MERGE_GAP {
+            gap = if differs(end) { 0 } else { gap + 1 };
+            end += 1;
+        }
+        end -= gap;
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&target[start..end]);
+        i = end;
+    }
+    patch.extend_from_slice(IPS_END);
+    if target.len() < source.len() {
+        patch.extend_from_slice(&(target.len() as u32).to_be_bytes()[1..]);
+    }
+    Ok(patch)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER {
+        return Err(PatchError::Truncated);
+    }
+    let (body, footer) = patch.split_at(patch.len() - BPS_FOOTER);
+    let crc = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let (source_crc, target_crc, patch_crc) = (crc(footer), crc(&footer[4..]), crc(&footer[8..]));
+    if crc32(&patch[..patch.len() - 4]) != patch_crc {
+        return Err(PatchError::Corrupt("checksum mismatch"));
+    }
+    let actual = crc32(rom);
+    if actual != source_crc {
+        return Err(PatchError::WrongSource {
+            expected: source_crc,
+            actual,
+        });
+    }
+
+    let mut reader = Reader {
+        data: body,
+        position: BPS_MAGIC.len(),
+    };
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(PatchError::Corrupt("source size"));
+    }
+
+    let mut out = Vec::new(); // not target_size up front: a bad patch could ask for anything
+    let (mut source_at, mut target_at) = (0isize, 0isize);
+    let out_of_range = PatchError::Corrupt("copy out of range");
+    // rom[start..start + len], for a start and length read from the patch
+    let source_run = |start: usize, len: usize| {
+        start
+            .checked_add(len)
+            .and_then(|end| rom.get(start..end))
+            .ok_or(PatchError::Corrupt("copy out of range"))
+    };
+    // Moves a relative copy position, which a bad patch could send anywhere
+    let seek = |at: isize, by: isize| {
+        at.checked_add(by)
+            .ok_or(PatchError::Corrupt("copy out of range"))
+    };
+    while reader.position < body.len() {
+        let action = reader.number()?;
+        let len = (action >> 2) + 1;
+        if len > target_size - out.len() {
+            return Err(PatchError::Corrupt("writes past the target size"));
+        }
+        match action & 3 {
+            SOURCE_READ => {
+                out.extend_from_slice(source_run(out.len(), len)?);
+            }
+            TARGET_READ => out.extend_from_slice(reader.bytes(len)?),
+            SOURCE_COPY => {
+                source_at = seek(source_at, reader.offset()?)?;
+                let start = usize::try_from(source_at).map_err(|_| out_of_range.clone())?;
+                out.extend_from_slice(source_run(start, len)?);
+                source_at = seek(source_at, len as isize)?;
+            }
+            TARGET_COPY => {
+                // From what is already written, byte by byte since the copy
+                // can overlap its own output
+                target_at = seek(target_at, reader.offset()?)?;
+                for _ in 0..len {
+                    let at = usize::try_from(target_at).map_err(|_| out_of_range.clone())?;
+                    let byte = *out.get(at).ok_or(out_of_range.clone())?;
+                    out.push(byte);
+                    target_at += 1;
+                }
+            }
+            _ => unreachable!("actions are two bits"),
+        }
+    }
+
+    if out.len() != target_size {
+        return Err(PatchError::Corrupt("target size"));
+    }
+    let actual = crc32(&out);
+    if actual != target_crc {
+        return Err(PatchError::WrongTarget {
+            expected: target_crc,
+            actual,
+        });
+    }
+    Ok(out)
+}
+
+// The longest run of `target` that appears anywhere in `source`, found
+// through the source offsets of its first four bytes
+fn longest_match(
+    source: &[u8],
+    index: &HashMap<[u8; 4], Vec<usize>>,
+    target: &[u8],
+) -> Option<(usize, usize)> {
+    let key: [u8; 4] = target.get(..4)?.try_into().ok()?;
+    index
+        .get(&key)?
+        .iter()
+        .map(|&start| {
+            let len = source[start..]
+                .iter()
+                .zip(target)
+                .take_while(|(a, b)| a == b)
+                .count();
+            (start, len)
+        })
+        .max_by_key(|&(_, len)| len)
+}
+
+fn create_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut index: HashMap<[u8; 4], Vec<usize>> = HashMap::new();
+    for (start, window) in source.windows(4).enumerate() {
+        let starts = index.entry(window.try_into().unwrap()).or_default();
+        if starts.len() < BPS_MAX_CANDIDATES {
+            starts.push(start);
+        }
+    }
+
+    let mut patch = BPS_MAGIC.to_vec();
+    write_number(&mut patch, source.len());
+    write_number(&mut patch, target.len());
+    write_number(&mut patch, 0); // no metadata
+
+    let action = |patch: &mut Vec<u8>, kind: usize, len: usize| {
+        write_number(patch, ((len - 1) << 2) | kind);
+    };
+    let flush = |patch: &mut Vec<u8>, literal: &mut Vec<u8>| {
+        if !literal.is_empty() {
+            action(patch, TARGET_READ, literal.len());
+            patch.append(literal);
+        }
+    };
+    let mut literal = Vec::new();
+    let mut source_at = 0;
+    let mut i = 0;
+    while i < target.len() {
+        // Unchanged in place
+        let same = (i..target.len())
+            .take_while(|&j| source.get(j) == Some(&target[j]))
+            .count();
+        if same >= BPS_MIN_READ || (same > 0 && i + same == target.len()) {
+            flush(&mut patch, &mut literal);
+            action(&mut patch, SOURCE_READ, same);
+            i += same;
+            continue;
+        }
+        // Moved from elsewhere in the source
+        if let Some((start, len)) = longest_match(source, &index, &target[i..]) {
+            if len >= BPS_MIN_COPY {
+                flush(&mut patch, &mut literal);
+                action(&mut patch, SOURCE_COPY, len);
+                write_offset(&mut patch, start as isize - source_at as isize);
+                source_at = start + len;
+                i += len;
+                continue;
+            }
+        }
+        literal.push(target[i]);
+        i += 1;
+    }
+    flush(&mut patch, &mut literal);
+
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let patch_crc = crc32(&patch);
+    patch.extend_from_slice(&patch_crc.to_le_bytes());
+    patch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ROM-sized buffer of not-very-random bytes
+    fn rom(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    // Edits that exercise every kind of record: changed bytes, a long run of
+    // one value, a block moved from elsewhere and a new tail
+    fn modified(source: &[u8]) -> Vec<u8> {
+        let mut target = source.to_vec();
+        target[0x10] ^= 0xFF;
+        target[0x40..0x80].fill(0xAA);
+        target.copy_within(0x200..0x280, 0x100);
+        target.extend_from_slice(b"new code at the end");
+        target
+    }
+
+    fn round_trip(format: PatchFormat, source: &[u8], target: &[u8]) {
+        let patch = create_patch(format, source, target).unwrap();
+        assert_eq!(PatchFormat::detect(&patch), Some(format));
+        assert_eq!(apply_patch(source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn ips_round_trip() {
+        let source = rom(0x1000, 1);
+        round_trip(PatchFormat::Ips, &source, &modified(&source));
+        round_trip(PatchFormat::Ips, &source, &source);
+    }
+
+    #[test]
+    fn bps_round_trip() {
+        let source = rom(0x1000, 2);
+        round_trip(PatchFormat::Bps, &source, &modified(&source));
+        round_trip(PatchFormat::Bps, &source, &source);
+        round_trip(PatchFormat::Bps, &source, &rom(0x800, 3));
+        round_trip(PatchFormat::Bps, &[], &source);
+    }
+
+    #[test]
+    fn ips_truncate_extension() {
+        let source = rom(0x400, 4);
+        let mut target = source[..0x300].to_vec();
+        target[0x20] ^= 1;
+        let patch = create_patch(PatchFormat::Ips, &source, &target).unwrap();
+        assert!(patch.ends_with(&[b'E', b'O', b'F', 0x00, 0x03, 0x00]));
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn ips_offset_that_reads_as_eof() {
+        let source = vec![0; 0x454F50];
+        let mut target = source.clone();
+        target[0x454F46] = 1;
+        let patch = create_patch(PatchFormat::Ips, &source, &target).unwrap();
+        // The record starts a byte early instead of at offset "EOF"
+        assert_eq!(&patch[5..8], &[0x45, 0x4F, 0x45]);
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn ips_run_length_record() {
+        let patch = [b"PATCH".as_slice(), &[0, 0, 2, 0, 0, 0, 3, 0x7F], b"EOF"].concat();
+        assert_eq!(
+            apply_patch(&[0; 4], &patch).unwrap(),
+            [0, 0, 0x7F, 0x7F, 0x7F]
+        );
+    }
+
+    #[test]
+    fn bps_wrong_source() {
+        let source = rom(0x400, 5);
+        let patch = create_patch(PatchFormat::Bps, &source, &modified(&source)).unwrap();
+        let other = rom(0x400, 6);
+        assert_eq!(
+            apply_patch(&other, &patch),
+            Err(PatchError::WrongSource {
+                expected: crc32(&source),
+                actual: crc32(&other),
+            })
+        );
+    }
+
+    #[test]
+    fn bps_corrupt_checksum() {
+        let source = rom(0x400, 7);
+        let mut patch = create_patch(PatchFormat::Bps, &source, &modified(&source)).unwrap();
+        patch[6] ^= 0x01;
+        assert_eq!(
+            apply_patch(&source, &patch),
+            Err(PatchError::Corrupt("checksum mismatch"))
+        );
+    }
+
+    // A BPS patch around `body`, with correct checksums for `source`
+    fn bps(source: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut patch = [BPS_MAGIC.as_slice(), body].concat();
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&0u32.to_le_bytes());
+        let patch_crc = crc32(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn bps_lengths_out_of_range() {
+        let source = rom(0x10, 8);
+        // Metadata as long as a usize can say
+        let mut body = Vec::new();
+        write_number(&mut body, source.len());
+        write_number(&mut body, 4);
+        write_number(&mut body, usize::MAX);
+        assert_eq!(
+            apply_patch(&source, &bps(&source, &body)),
+            Err(PatchError::Corrupt("length out of range"))
+        );
+
+        // A source copy to the very end of the address space
+        let mut body = Vec::new();
+        write_number(&mut body, source.len());
+        write_number(&mut body, 4);
+        write_number(&mut body, 0);
+        write_number(&mut body, (3 << 2) | SOURCE_COPY);
+        write_offset(&mut body, isize::MAX);
+        write_number(&mut body, (3 << 2) | SOURCE_COPY);
+        write_offset(&mut body, isize::MAX);
+        assert_eq!(
+            apply_patch(&source, &bps(&source, &body)),
+            Err(PatchError::Corrupt("copy out of range"))
+        );
+    }
+
+    #[test]
+    fn not_a_patch() {
+        assert_eq!(
+            apply_patch(&[0; 4], b"hello"),
+            Err(PatchError::UnknownFormat)
+        );
+        assert_eq!(
+            apply_patch(&[0; 4], b"PATCH\0\0"),
+            Err(PatchError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,105 @@
+/*!
+ * @file platform.rs
+ * @brief CHIP-8 variants that differ in display geometry and where programs start
+ */
+use crate::cpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH, MEMORY_SIZE, PROGRAM_START};
+use crate::display::Display;
+use crate::megachip::MEGACHIP_MEMORY_SIZE;
+
+// Every CHIP-8 hires ROM opens with 1260, jumping over the interpreter patch
+// that shares its first 0x60 bytes
+pub const HIRES_HEADER: [u8; 2] = [0x12, 0x60];
+pub const HIRES_ENTRY_POINT: usize = 0x260;
+// 0230 calls the patch's routine that clears all 64 rows
+pub const HIRES_CLEAR_SCREEN: usize = 0x230;
+
+// The CHIP-8X interpreter is bigger and pushes programs up to 0x300
+pub const CHIP8X_PROGRAM_START: usize = 0x300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Platform {
+    // COSMAC VIP CHIP-8: 64x32, programs start at 0x200
+    #[default]
+    Chip8,
+    // 1978 two-page "CHIP-8 hires": 64x64, programs start at 0x260
+    Chip8Hires,
+    // 1980 CHIP-8X for the VP-590 colour board and a second keypad: 64x32 in
+    // eight colours, programs start at 0x300
+    Chip8X,
+    // 2007 MegaChip: 64x32 until 0011 switches to 256x192 in 256 colours, 16 MB
+    MegaChip,
+}
+
+impl Platform {
+    // Guess the platform from the first bytes of a ROM
+    pub fn detect(program: &[u8]) -> Platform {
+        if program.starts_with(&HIRES_HEADER) {
+            Platform::Chip8Hires
+        } else {
+            Platform::Chip8
+        }
+    }
+
+    // (width, height) of the display in pixels
+    pub fn display_size(&self) -> (usize, usize) {
+        match self {
+            Platform::Chip8 => (DISPLAY_WIDTH, DISPLAY_HEIGHT),
+            Platform::Chip8Hires => (DISPLAY_WIDTH, 2 * DISPLAY_HEIGHT),
+            Platform::Chip8X => (DISPLAY_WIDTH, DISPLAY_HEIGHT),
+            Platform::MegaChip => (DISPLAY_WIDTH, DISPLAY_HEIGHT),
+        }
+    }
+
+    // A blank display of the right size, with a colour layer where the platform has one
+    pub fn new_display(&self) -> Display {
+        let (width, height) = self.display_size();
+        match self {
+            Platform::Chip8X => Display::with_colors(width, height),
+            _ => Display::new(width, height),
+        }
+    }
+
+    // Bytes of memory the Cpu gets
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Platform::MegaChip => MEGACHIP_MEMORY_SIZE,
+            _ => MEMORY_SIZE,
+        }
+    }
+
+    // Where ROMs are loaded
+    pub fn load_address(&self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::Chip8Hires | Platform::MegaChip => PROGRAM_START,
+            Platform::Chip8X => CHIP8X_PROGRAM_START,
+        }
+    }
+
+    // Where the first instruction is executed
+    pub fn entry_point(&self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::MegaChip => PROGRAM_START,
+            Platform::Chip8Hires => HIRES_ENTRY_POINT,
+            Platform::Chip8X => CHIP8X_PROGRAM_START,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::Chip8 => "chip8",
+            Platform::Chip8Hires => "hires",
+            Platform::Chip8X => "chip8x",
+            Platform::MegaChip => "megachip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Platform> {
+        match name {
+            "chip8" => Some(Platform::Chip8),
+            "hires" => Some(Platform::Chip8Hires),
+            "chip8x" => Some(Platform::Chip8X),
+            "megachip" => Some(Platform::MegaChip),
+            _ => None,
+        }
+    }
+}
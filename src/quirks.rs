@@ -0,0 +1,93 @@
+/*!
+ * @file quirks.rs
+ * @brief Behaviour switches where CHIP-8 interpreters historically disagree
+ */
+use crate::platform::Platform;
+use crate::timing::Timing;
+
+/**
+ * @brief When FX0A (LD Vx, K) stops waiting
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyWait {
+    // COSMAC VIP: a key must go down and come back up; Vx gets it on release
+    #[default]
+    PressAndRelease,
+    // Resume as soon as a key goes down. Keys already held when FX0A ran don't count.
+    Press,
+}
+
+/**
+ * @brief Where the call stack and framebuffer live
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemoryLayout {
+    // Stack and display are kept outside the 4 KB address space
+    #[default]
+    Separate,
+    // COSMAC VIP: the stack is at 0xEA0 and the display at 0xF00-0xFFF in `memory`.
+    // Programs can read and overwrite both. With an unlimited stack_depth, deep
+    // recursion runs over into whatever follows the 12 stack slots, as on the
+    // original hardware.
+    Vip,
+}
+
+/**
+ * @brief What FX55 and FX65 leave in I
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexIncrement {
+    // SUPER-CHIP 1.1 and most modern interpreters: I stays where it was
+    #[default]
+    Unchanged,
+    // SUPER-CHIP 1.0: I ends up X past where it was
+    ByX,
+    // COSMAC VIP: I ends up just past the last register stored or loaded
+    ByXPlusOne,
+}
+
+// Call stack depths of the original interpreters
+pub const VIP_STACK_DEPTH: usize = 12;
+pub const SCHIP_STACK_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    pub platform: Platform,
+    pub key_wait: KeyWait,
+    pub memory_layout: MemoryLayout,
+    // Nested 2NNN calls allowed before a stack overflow fault; None for no limit
+    pub stack_depth: Option<usize>,
+    pub timing: Timing,
+    // VIP: DXYN waits for the vertical blank interrupt before drawing
+    pub display_wait: bool,
+    // VIP: 0NNN runs the 1802 machine-code routine at NNN instead of being ignored
+    pub machine_code: bool,
+    // VIP: 8XY6 / 8XYE put VY shifted into VX instead of shifting VX in place
+    pub shift_vy: bool,
+    pub index_increment: IndexIncrement,
+    // VIP: DXYN clips sprites at the screen edges instead of wrapping them around
+    pub clip_sprites: bool,
+    // SUPER-CHIP: BXNN jumps to XNN + VX instead of NNN + V0
+    pub jump_vx: bool,
+    // VIP: 8XY1 - 8XY3 reset VF to 0
+    pub logic_resets_vf: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            platform: Platform::default(),
+            key_wait: KeyWait::default(),
+            memory_layout: MemoryLayout::default(),
+            stack_depth: Some(SCHIP_STACK_DEPTH),
+            timing: Timing::default(),
+            display_wait: false,
+            machine_code: false,
+            shift_vy: false,
+            index_increment: IndexIncrement::default(),
+            clip_sprites: false,
+            jump_vx: false,
+            logic_resets_vf: false,
+        }
+    }
+}
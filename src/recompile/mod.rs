@@ -0,0 +1,631 @@
+/*!
+ * @file recompile/mod.rs
+ * @brief Ahead-of-time recompiler from a CHIP-8 ROM to a standalone Rust module
+ *
+ * Control-flow analysis walks the code reachable from the entry point,
+ * following jumps, calls, returns and both ways out of every skip, and splits
+ * it into basic blocks. Each block becomes a Rust function on the module's
+ * State, the machine state of the emulator's Cpu. Whatever the analysis can't
+ * see is left to a small interpreter in the module (runtime.rs): targets of
+ * computed BNNN jumps, code outside the ROM, and compiled code the program
+ * has since overwritten.
+ *
+ * The module needs nothing but std. Quirks are fixed when it is generated;
+ * only what the Cpu does with MemoryLayout::Separate, Timing::Instructions and
+ * neither display_wait nor machine_code can be recompiled. CXKK draws from the
+ * module's own xorshift64* generator, so its numbers don't match a Cpu's for
+ * the same seed.
+ */
+use crate::font::FONT_SET;
+use crate::instruction::{decode_for, Instruction};
+use crate::platform::Platform;
+use crate::quirks::{IndexIncrement, KeyWait, MemoryLayout, Quirks};
+use crate::timing::Timing;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+
+const OPCODE_SIZE: usize = 2;
+const MEMORY_SIZE: usize = 4096;
+
+// Shared by every generated module, after the ROM-specific constants
+const RUNTIME: &str = include_str!("runtime.rs");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecompileError {
+    Platform(Platform),
+    Quirk(&'static str),
+    TooLarge,
+}
+
+impl fmt::Display for RecompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecompileError::Platform(platform) => write!(
+                f,
+                "can't recompile {} ROMs, only chip8 and hires",
+                platform.name()
+            ),
+            RecompileError::Quirk(quirk) => write!(f, "can't recompile with {}", quirk),
+            RecompileError::TooLarge => write!(f, "program too large to fit in memory"),
+        }
+    }
+}
+
+impl std::error::Error for RecompileError {}
+
+/**
+ * @brief A generated module and what went into it
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recompiled {
+    pub source: String,
+    pub blocks: usize,
+    pub instructions: usize,
+    pub computed_jumps: usize, // BNNN, left to the interpreter to follow
+}
+
+/**
+ * @brief A basic block: entered only at `start`, left only after its last instruction
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Block {
+    start: usize,
+    instructions: Vec<(usize, u16, Instruction)>,
+}
+
+impl Block {
+    fn end(&self) -> usize {
+        self.start + self.instructions.len() * OPCODE_SIZE
+    }
+}
+
+// Where control can go after `instruction` at `addr`; computed jumps and
+// returns lead nowhere the analysis can see
+fn successors(addr: usize, instruction: Instruction) -> Vec<usize> {
+    let next = addr + OPCODE_SIZE;
+    match instruction {
+        Instruction::Jp { nnn } => vec![nnn],
+        Instruction::Call { nnn } => vec![nnn, next],
+        Instruction::Ret | Instruction::JpV0 { .. } => vec![],
+        Instruction::SeImm { .. }
+        | Instruction::SneImm { .. }
+        | Instruction::SeReg { .. }
+        | Instruction::SneReg { .. }
+        | Instruction::Skp { .. }
+        | Instruction::Sknp { .. } => vec![next, next + OPCODE_SIZE],
+        _ => vec![next],
+    }
+}
+
+// Whether a block has to end after `instruction`: it leaves straight-line
+// code, or (FX0A) the next instruction runs only after a wait
+fn ends_block(instruction: Instruction) -> bool {
+    successors(0, instruction) != [OPCODE_SIZE] || matches!(instruction, Instruction::LdVxK { .. })
+}
+
+// Split the code reachable from `entry` into basic blocks. Only the program's
+// own bytes, from `origin` on, are compiled.
+fn find_blocks(program: &[u8], origin: usize, entry: usize, platform: Platform) -> Vec<Block> {
+    let rom = origin..origin + program.len();
+    let fetch = |addr: usize| {
+        let offset = addr - origin;
+        let opcode = ((program[offset] as u16) << 8) | program[offset + 1] as u16;
+        (opcode, decode_for(platform, opcode))
+    };
+    let in_rom = |addr: usize| rom.contains(&addr) && rom.contains(&(addr + 1));
+
+    // Every instruction reachable, and the addresses blocks must start at
+    let mut reachable = BTreeMap::new();
+    let mut leaders = BTreeSet::from([entry]);
+    let mut pending = vec![entry];
+    while let Some(addr) = pending.pop() {
+        if !in_rom(addr) || reachable.contains_key(&addr) {
+            continue;
+        }
+        let (opcode, instruction) = fetch(addr);
+        reachable.insert(addr, (opcode, instruction));
+        let targets = successors(addr, instruction);
+        if ends_block(instruction) {
+            leaders.extend(targets.iter().copied());
+        }
+        pending.extend(targets);
+    }
+
+    leaders
+        .iter()
+        .filter(|addr| reachable.contains_key(addr))
+        .map(|&start| {
+            let mut instructions = Vec::new();
+            let mut addr = start;
+            while let Some(&(opcode, instruction)) = reachable.get(&addr) {
+                instructions.push((addr, opcode, instruction));
+                addr += OPCODE_SIZE;
+                if ends_block(instruction) || leaders.contains(&addr) {
+                    break;
+                }
+            }
+            Block {
+                start,
+                instructions,
+            }
+        })
+        .collect()
+}
+
+fn check_quirks(quirks: &Quirks) -> Result<u32, RecompileError> {
+    if !matches!(quirks.platform, Platform::Chip8 | Platform::Chip8Hires) {
+        return Err(RecompileError::Platform(quirks.platform));
+    }
+    if quirks.memory_layout != MemoryLayout::Separate {
+        return Err(RecompileError::Quirk("the VIP memory layout"));
+    }
+    if quirks.display_wait {
+        return Err(RecompileError::Quirk("display wait"));
+    }
+    if quirks.machine_code {
+        return Err(RecompileError::Quirk("machine code"));
+    }
+    match quirks.timing {
+        Timing::Instructions { .. } => Ok(quirks.timing.budget()),
+        Timing::Vip => Err(RecompileError::Quirk("VIP timing")),
+    }
+}
+
+fn wrap(addr: usize) -> usize {
+    addr % MEMORY_SIZE
+}
+
+// Rust for one instruction of a block, the `count`th. Instructions that end
+// the block also return how many ran.
+fn emit_instruction(
+    out: &mut String,
+    addr: usize,
+    instruction: Instruction,
+    count: usize,
+    quirks: &Quirks,
+) -> fmt::Result {
+    let platform = quirks.platform;
+    let next = wrap(addr + OPCODE_SIZE);
+    let skip = |out: &mut String, condition: String| {
+        writeln!(
+            out,
+            "    s.pc = if {} {{ {:#05x} }} else {{ {:#05x} }};\n    {}",
+            condition,
+            wrap(addr + 2 * OPCODE_SIZE),
+            next,
+            count
+        )
+    };
+    match instruction {
+        Instruction::Cls => writeln!(out, "    s.clear();"),
+        Instruction::Ret => writeln!(
+            out,
+            "    let Some(addr) = s.ret({:#05x}) else {{\n        s.pc = {:#05x};\n        return {};\n    }};\n    s.pc = addr;\n    {}",
+            addr,
+            addr,
+            count - 1,
+            count
+        ),
+        Instruction::Sys { nnn } if platform == Platform::Chip8Hires && nnn == 0x230 => {
+            writeln!(out, "    s.clear();")
+        }
+        Instruction::Sys { .. } | Instruction::Unknown(_) => Ok(()),
+        Instruction::Jp { nnn } => writeln!(out, "    s.pc = {:#05x};\n    {}", nnn, count),
+        Instruction::Call { nnn } => writeln!(
+            out,
+            "    if !s.call({:#05x}) {{\n        s.pc = {:#05x};\n        return {};\n    }}\n    s.pc = {:#05x};\n    {}",
+            addr,
+            addr,
+            count - 1,
+            nnn,
+            count
+        ),
+        Instruction::SeImm { x, kk } => skip(out, format!("s.v[{:#x}] == {:#04x}", x, kk)),
+        Instruction::SneImm { x, kk } => skip(out, format!("s.v[{:#x}] != {:#04x}", x, kk)),
+        Instruction::SeReg { x, y } => skip(out, format!("s.v[{:#x}] == s.v[{:#x}]", x, y)),
+        Instruction::SneReg { x, y } => skip(out, format!("s.v[{:#x}] != s.v[{:#x}]", x, y)),
+        Instruction::Skp { x } => skip(out, format!("s.key_down(s.v[{:#x}])", x)),
+        Instruction::Sknp { x } => skip(out, format!("!s.key_down(s.v[{:#x}])", x)),
+        Instruction::LdImm { x, kk } => writeln!(out, "    s.v[{:#x}] = {:#04x};", x, kk),
+        Instruction::AddImm { x, kk } => writeln!(
+            out,
+            "    s.v[{x:#x}] = s.v[{x:#x}].wrapping_add({kk:#04x});"
+        ),
+        Instruction::LdReg { x, y } => writeln!(out, "    s.v[{:#x}] = s.v[{:#x}];", x, y),
+        Instruction::Or { x, y } | Instruction::And { x, y } | Instruction::Xor { x, y } => {
+            let operator = match instruction {
+                Instruction::Or { .. } => "|",
+                Instruction::And { .. } => "&",
+                _ => "^",
+            };
+            writeln!(out, "    s.v[{:#x}] {}= s.v[{:#x}];", x, operator, y)?;
+            if quirks.logic_resets_vf {
+                writeln!(out, "    s.v[0xf] = 0;")?;
+            }
+            Ok(())
+        }
+        Instruction::AddReg { x, y } => writeln!(
+            out,
+            "    let (sum, carry) = s.v[{x:#x}].overflowing_add(s.v[{y:#x}]);\n    s.v[{x:#x}] = sum;\n    s.v[0xf] = carry as u8;"
+        ),
+        Instruction::Sub { x, y } => writeln!(
+            out,
+            "    s.v[0xf] = (s.v[{x:#x}] > s.v[{y:#x}]) as u8;\n    s.v[{x:#x}] = s.v[{x:#x}].wrapping_sub(s.v[{y:#x}]);"
+        ),
+        Instruction::Shr { x, y } => {
+            let source = if quirks.shift_vy { y } else { x };
+            writeln!(
+                out,
+                "    s.v[0xf] = s.v[{source:#x}] & 1;\n    s.v[{x:#x}] = s.v[{source:#x}] >> 1;"
+            )
+        }
+        Instruction::Subn { x, y } => writeln!(
+            out,
+            "    s.v[0xf] = (s.v[{y:#x}] > s.v[{x:#x}]) as u8;\n    s.v[{x:#x}] = s.v[{y:#x}].wrapping_sub(s.v[{x:#x}]);"
+        ),
+        Instruction::Shl { x, y } => {
+            let source = if quirks.shift_vy { y } else { x };
+            writeln!(
+                out,
+                "    s.v[0xf] = s.v[{source:#x}] >> 7;\n    s.v[{x:#x}] = s.v[{source:#x}] << 1;"
+            )
+        }
+        Instruction::LdI { nnn } => writeln!(out, "    s.i = {:#05x};", nnn),
+        Instruction::JpV0 { nnn } => writeln!(
+            out,
+            "    s.pc = ({:#05x} + s.v[{:#x}] as usize) % MEMORY_SIZE;\n    {}",
+            nnn,
+            if quirks.jump_vx { nnn >> 8 } else { 0 },
+            count
+        ),
+        Instruction::Rnd { x, kk } => {
+            writeln!(out, "    s.v[{:#x}] = s.random() & {:#04x};", x, kk)
+        }
+        Instruction::Drw { x, y, n } => writeln!(out, "    s.draw({:#x}, {:#x}, {});", x, y, n),
+        Instruction::LdVxDt { x } => writeln!(out, "    s.v[{:#x}] = s.delay_timer;", x),
+        Instruction::LdVxK { x } => writeln!(
+            out,
+            "    s.wait_key({:#x});\n    s.pc = {:#05x};\n    {}",
+            x, next, count
+        ),
+        Instruction::LdDtVx { x } => writeln!(out, "    s.delay_timer = s.v[{:#x}];", x),
+        Instruction::LdStVx { x } => writeln!(out, "    s.sound_timer = s.v[{:#x}];", x),
+        Instruction::AddIVx { x } => writeln!(
+            out,
+            "    s.i += s.v[{:#x}] as usize;\n    s.v[0xf] = (s.i > 0x0f00) as u8;",
+            x
+        ),
+        Instruction::LdFVx { x } => writeln!(out, "    s.i = s.v[{:#x}] as usize * 5;", x),
+        Instruction::LdBVx { x } => writeln!(out, "    s.bcd({:#x});", x),
+        Instruction::LdIVx { x } => writeln!(out, "    s.store({:#x});", x),
+        Instruction::LdVxI { x } => writeln!(out, "    s.load({:#x});", x),
+        // Only decoded on CHIP-8X and MegaChip, which aren't recompiled
+        _ => unreachable!("{:?} on {}", instruction, platform.name()),
+    }
+}
+
+fn emit_block(out: &mut String, block: &Block, quirks: &Quirks) -> fmt::Result {
+    writeln!(
+        out,
+        "\nfn block_{:03x}(s: &mut State, limit: u32) -> u32 {{",
+        block.start
+    )?;
+    let last = block.instructions.len();
+    for (i, &(addr, opcode, instruction)) in block.instructions.iter().enumerate() {
+        let count = i + 1;
+        writeln!(out, "    // {:03X}: {:04X}  {}", addr, opcode, instruction)?;
+        emit_instruction(out, addr, instruction, count, quirks)?;
+        if ends_block(instruction) {
+            break;
+        }
+        let next = wrap(addr + OPCODE_SIZE);
+        if count == last {
+            writeln!(out, "    s.pc = {:#05x};\n    {}", next, count)?;
+            break;
+        }
+        // Stop where the frame ends, or once the program overwrote compiled code
+        let writes = matches!(
+            instruction,
+            Instruction::LdBVx { .. } | Instruction::LdIVx { .. }
+        );
+        writeln!(
+            out,
+            "    if {}limit == {} {{\n        s.pc = {:#05x};\n        return {};\n    }}",
+            if writes { "s.code_modified || " } else { "" },
+            count,
+            next,
+            count
+        )?;
+    }
+    writeln!(out, "}}")
+}
+
+fn emit_bytes(out: &mut String, bytes: &[u8]) -> fmt::Result {
+    for line in bytes.chunks(16) {
+        let bytes: Vec<String> = line.iter().map(|byte| format!("{:#04x},", byte)).collect();
+        writeln!(out, "    {}", bytes.join(" "))?;
+    }
+    Ok(())
+}
+
+// The whole module: constants, the runtime, then the blocks and their dispatch
+fn emit_module(
+    out: &mut String,
+    name: &str,
+    program: &[u8],
+    quirks: &Quirks,
+    budget: u32,
+    blocks: &[Block],
+    code: &[(usize, usize)],
+) -> fmt::Result {
+    let platform = quirks.platform;
+    let origin = platform.load_address();
+    let (width, height) = platform.display_size();
+    writeln!(
+        out,
+        "//! {} recompiled by chip8-tools recompile; regenerate it rather than edit it.",
+        name
+    )?;
+    writeln!(out, "//!")?;
+    writeln!(
+        out,
+        "//! `State::new(seed)` powers on with the ROM loaded. Call `run_frame(keypad)`"
+    )?;
+    writeln!(
+        out,
+        "//! 60 times a second and show `display` when `display_changed`; `beep()` is"
+    )?;
+    writeln!(
+        out,
+        "//! the sound timer. Include it with `mod`, it has inner attributes."
+    )?;
+    writeln!(out, "#![allow(dead_code, unused_variables, clippy::all)]\n")?;
+
+    writeln!(out, "pub const WIDTH: usize = {};", width)?;
+    writeln!(out, "pub const HEIGHT: usize = {};", height)?;
+    writeln!(out, "const LOAD_ADDRESS: usize = {:#05x};", origin)?;
+    writeln!(
+        out,
+        "const ENTRY_POINT: usize = {:#05x};",
+        platform.entry_point()
+    )?;
+    writeln!(out, "const INSTRUCTIONS_PER_FRAME: u32 = {};", budget)?;
+    writeln!(
+        out,
+        "const STACK_DEPTH: usize = {};",
+        quirks
+            .stack_depth
+            .map_or("usize::MAX".to_string(), |depth| depth.to_string())
+    )?;
+    writeln!(
+        out,
+        "const KEY_WAIT_RELEASE: bool = {};",
+        quirks.key_wait == KeyWait::PressAndRelease
+    )?;
+    writeln!(
+        out,
+        "const HIRES_CLEAR_SCREEN: bool = {};",
+        platform == Platform::Chip8Hires
+    )?;
+    writeln!(out, "const SHIFT_VY: bool = {};", quirks.shift_vy)?;
+    writeln!(
+        out,
+        "const INDEX_STEP: Option<usize> = {};",
+        match quirks.index_increment {
+            IndexIncrement::Unchanged => "None",
+            IndexIncrement::ByX => "Some(0)",
+            IndexIncrement::ByXPlusOne => "Some(1)",
+        }
+    )?;
+    writeln!(out, "const WRAP_SPRITES: bool = {};", !quirks.clip_sprites)?;
+    writeln!(out, "const JUMP_VX: bool = {};", quirks.jump_vx)?;
+    writeln!(
+        out,
+        "const LOGIC_RESETS_VF: bool = {};",
+        quirks.logic_resets_vf
+    )?;
+    writeln!(out, "\nconst FONT: [u8; {}] = [", FONT_SET.len())?;
+    emit_bytes(out, &FONT_SET)?;
+    writeln!(out, "];\n\npub const ROM: &[u8] = &[")?;
+    emit_bytes(out, program)?;
+    writeln!(
+        out,
+        "];\n\n// Memory holding compiled code, as sorted start..end ranges"
+    )?;
+    writeln!(out, "const CODE: &[(usize, usize)] = &[")?;
+    for (start, end) in code {
+        writeln!(out, "    ({:#05x}, {:#05x}),", start, end)?;
+    }
+    writeln!(out, "];\n")?;
+    out.push_str(RUNTIME);
+
+    writeln!(out, "\n// ---- Compiled blocks ----\n")?;
+    writeln!(
+        out,
+        "// Run the block at the program counter for at most `limit` instructions;"
+    )?;
+    writeln!(out, "// None if there is none, or its code was overwritten")?;
+    writeln!(
+        out,
+        "fn run_block(s: &mut State, limit: u32) -> Option<u32> {{"
+    )?;
+    writeln!(
+        out,
+        "    let (block, end): (fn(&mut State, u32) -> u32, usize) = match s.pc {{"
+    )?;
+    for block in blocks {
+        writeln!(
+            out,
+            "        {:#05x} => (block_{:03x}, {:#05x}),",
+            block.start,
+            block.start,
+            block.end()
+        )?;
+    }
+    writeln!(out, "        _ => return None,\n    }};")?;
+    writeln!(
+        out,
+        "    if !s.intact(s.pc, end) {{\n        return None;\n    }}"
+    )?;
+    writeln!(
+        out,
+        "    s.code_modified = false;\n    Some(block(s, limit))\n}}"
+    )?;
+    for block in blocks {
+        emit_block(out, block, quirks)?;
+    }
+    Ok(())
+}
+
+// Turn `program` into a Rust module that runs it the way a Cpu with `quirks`
+// would. `name` only goes into the module's doc comment.
+pub fn recompile(
+    program: &[u8],
+    quirks: &Quirks,
+    name: &str,
+) -> Result<Recompiled, RecompileError> {
+    let budget = check_quirks(quirks)?;
+    let platform = quirks.platform;
+    let origin = platform.load_address();
+    if origin + program.len() > MEMORY_SIZE {
+        return Err(RecompileError::TooLarge);
+    }
+    let blocks = find_blocks(program, origin, platform.entry_point(), platform);
+
+    let mut code: Vec<(usize, usize)> = Vec::new();
+    let mut ranges: Vec<(usize, usize)> = blocks
+        .iter()
+        .map(|block| (block.start, block.end()))
+        .collect();
+    ranges.sort();
+    for (start, end) in ranges {
+        match code.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => code.push((start, end)),
+        }
+    }
+
+    let mut out = String::new();
+    emit_module(&mut out, name, program, quirks, budget, &blocks, &code)
+        .expect("writing to a String can't fail");
+
+    Ok(Recompiled {
+        source: out,
+        blocks: blocks.len(),
+        instructions: blocks.iter().map(|block| block.instructions.len()).sum(),
+        computed_jumps: blocks
+            .iter()
+            .flat_map(|block| &block.instructions)
+            .filter(|(_, _, instruction)| matches!(instruction, Instruction::JpV0 { .. }))
+            .count(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use std::fs;
+    use std::process::Command;
+
+    const FRAMES: usize = 30;
+
+    #[rustfmt::skip]
+    const PROGRAM: [u8; 0x44] = [
+        0x00, 0xE0, // 200: CLS
+        0xA2, 0x40, // 202: LD I, 0x240
+        0x60, 0x7B, // 204: LD V0, 123
+        0xF0, 0x33, // 206: LD B, V0
+        0xF2, 0x65, // 208: LD V2, [I]
+        0x22, 0x30, // 20A: CALL 0x230
+        0x75, 0x01, // 20C: ADD V5, 1
+        0xF5, 0x29, // 20E: LD F, V5
+        0x8A, 0x54, // 210: ADD VA, V5
+        0xDA, 0xB5, // 212: DRW VA, VB, 5
+        0x7B, 0x03, // 214: ADD VB, 3
+        0x86, 0x5E, // 216: SHL V6, V5
+        0x35, 0x20, // 218: SE V5, 0x20
+        0x12, 0x02, // 21A: JP 0x202
+        0x60, 0x00, // 21C: LD V0, 0
+        0xB2, 0x22, // 21E: JP V0, 0x222
+        0x12, 0x20, // 220: JP 0x220
+        0xF5, 0x1E, // 222: ADD I, V5
+        0xF2, 0x55, // 224: LD [I], V2
+        0x65, 0x00, // 226: LD V5, 0
+        0x12, 0x02, // 228: JP 0x202
+        0x00, 0x00,
+        0x00, 0x00,
+        0x00, 0x00,
+        0x86, 0x54, // 230: ADD V6, V5
+        0x00, 0xEE, // 232: RET
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, // 240: data
+    ];
+
+    const HARNESS: &str = r#"mod rom;
+
+fn main() {
+    let mut state = rom::State::new(0);
+    for _ in 0..FRAMES {
+        assert!(state.run_frame([false; 16]));
+    }
+    println!("{:?} {} {}", state.v, state.i, state.pc);
+    println!("{:?}", state.memory);
+    println!("{:?}", state.display);
+}
+"#;
+
+    // What the harness prints, from a Cpu run for the same frames
+    fn interpreted(quirks: Quirks) -> String {
+        let mut cpu = Cpu::with_quirks(quirks);
+        cpu.set_idle_skip(false);
+        cpu.load_program(&PROGRAM);
+        for _ in 0..FRAMES {
+            while !cpu.cycle([false; 16]).vblank {}
+            assert_eq!(cpu.fault(), None);
+        }
+        let pixels: Vec<u8> = cpu.display().rows().flatten().collect();
+        format!(
+            "{:?} {} {}\n{:?}\n{:?}\n",
+            cpu.v_registers(),
+            cpu.index_register(),
+            cpu.program_counter(),
+            cpu.memory(),
+            pixels
+        )
+    }
+
+    #[test]
+    fn compiled_module_matches_the_cpu() {
+        let quirks = Quirks {
+            timing: Timing::Instructions { per_frame: 20 },
+            ..Quirks::default()
+        };
+        let recompiled = recompile(&PROGRAM, &quirks, "fixture").expect("chip8 recompiles");
+        assert_eq!(recompiled.computed_jumps, 1);
+
+        let dir = std::env::temp_dir().join(format!("chip8-recompile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("rom.rs"), &recompiled.source).unwrap();
+        let harness = HARNESS.replace("FRAMES", &FRAMES.to_string());
+        fs::write(dir.join("main.rs"), harness).unwrap();
+        let binary = dir.join("harness");
+        let rustc = Command::new("rustc")
+            .args(["--edition", "2021", "-o"])
+            .arg(&binary)
+            .arg(dir.join("main.rs"))
+            .output()
+            .expect("rustc runs");
+        assert!(
+            rustc.status.success(),
+            "{}",
+            String::from_utf8_lossy(&rustc.stderr)
+        );
+        let run = Command::new(&binary).output().expect("the harness runs");
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(run.status.success());
+
+        assert!(String::from_utf8(run.stdout).unwrap() == interpreted(quirks));
+    }
+}
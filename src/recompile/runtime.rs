@@ -0,0 +1,415 @@
+// ---- Runtime, the same in every recompiled module ----
+
+pub const MEMORY_SIZE: usize = 4096;
+
+/**
+ * @brief Why the program stopped; `pc` is the address of the faulting instruction
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    StackOverflow { pc: usize },
+    StackUnderflow { pc: usize },
+}
+
+/**
+ * @brief Machine state. The fields mean what they do in the emulator's Cpu, and
+ * a frame here ends in the state a Cpu with the same quirks would be in.
+ */
+pub struct State {
+    pub memory: Vec<u8>,
+    pub v: [u8; 16],
+    pub i: usize,
+    pub pc: usize,
+    pub stack: Vec<usize>, // return addresses
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub keypad: [bool; 16],
+    pub display: Vec<u8>, // WIDTH * HEIGHT pixels, 0 or 1, row by row
+    pub display_changed: bool,
+    pub fault: Option<Fault>,
+    key_wait: Option<usize>, // FX0A's register while it waits
+    key_pressed: Option<usize>,
+    frame_cycles: u32,
+    rng: u64,
+    modified: Vec<bool>, // compiled code bytes overwritten with something else
+    any_modified: bool,
+    code_modified: bool, // by the current block, which then stops
+}
+
+impl State {
+    // Power on with the ROM loaded; `seed` drives CXKK
+    pub fn new(seed: u64) -> Self {
+        let mut memory = vec![0; MEMORY_SIZE];
+        memory[..FONT.len()].copy_from_slice(&FONT);
+        memory[LOAD_ADDRESS..LOAD_ADDRESS + ROM.len()].copy_from_slice(ROM);
+        State {
+            memory,
+            v: [0; 16],
+            i: 0,
+            pc: ENTRY_POINT,
+            stack: Vec::new(),
+            delay_timer: 0,
+            sound_timer: 0,
+            keypad: [false; 16],
+            display: vec![0; WIDTH * HEIGHT],
+            display_changed: false,
+            fault: None,
+            key_wait: None,
+            key_pressed: None,
+            frame_cycles: 0,
+            rng: seed ^ 0x9E37_79B9_7F4A_7C15,
+            modified: vec![false; MEMORY_SIZE],
+            any_modified: false,
+            code_modified: false,
+        }
+    }
+
+    pub fn beep(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    // Run until the current 60 Hz frame ends with `keypad` held. Returns false
+    // once the program has faulted.
+    pub fn run_frame(&mut self, keypad: [bool; 16]) -> bool {
+        let mut previous = self.keypad;
+        self.keypad = keypad;
+        self.display_changed = false;
+        while self.fault.is_none() {
+            if self.cycle(previous) {
+                return true;
+            }
+            previous = keypad;
+        }
+        false
+    }
+
+    // One step: a compiled block, or a single interpreted instruction where
+    // there is none. Returns whether the frame ended.
+    fn cycle(&mut self, previous: [bool; 16]) -> bool {
+        if self.key_wait.is_some() {
+            self.poll_key_wait(previous);
+            return self.spend(1);
+        }
+        let limit = INSTRUCTIONS_PER_FRAME - self.frame_cycles;
+        let executed = match run_block(self, limit) {
+            Some(executed) => executed,
+            None => self.step(),
+        };
+        self.spend(executed)
+    }
+
+    fn spend(&mut self, cost: u32) -> bool {
+        self.frame_cycles += cost;
+        let mut vblank = false;
+        while self.frame_cycles >= INSTRUCTIONS_PER_FRAME {
+            self.frame_cycles -= INSTRUCTIONS_PER_FRAME;
+            self.delay_timer = self.delay_timer.saturating_sub(1);
+            self.sound_timer = self.sound_timer.saturating_sub(1);
+            vblank = true;
+        }
+        vblank
+    }
+
+    fn poll_key_wait(&mut self, previous: [bool; 16]) {
+        let key = match self.key_pressed {
+            Some(key) if self.keypad[key] => return,
+            Some(key) => key,
+            None => match (0..16).find(|&key| self.keypad[key] && !previous[key]) {
+                None => return,
+                Some(key) if KEY_WAIT_RELEASE => {
+                    self.key_pressed = Some(key);
+                    return;
+                }
+                Some(key) => key,
+            },
+        };
+        if let Some(x) = self.key_wait.take() {
+            self.v[x] = key as u8;
+        }
+        self.key_pressed = None;
+    }
+
+    // Whether none of memory[start..end] was overwritten since it was compiled
+    fn intact(&self, start: usize, end: usize) -> bool {
+        !self.any_modified || !self.modified[start..end].contains(&true)
+    }
+
+    fn write(&mut self, addr: usize, value: u8) {
+        if self.memory[addr] != value && is_code(addr) {
+            self.modified[addr] = true;
+            self.any_modified = true;
+            self.code_modified = true;
+        }
+        self.memory[addr] = value;
+    }
+
+    fn random(&mut self) -> u8 {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        (self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+
+    fn key_down(&self, key: u8) -> bool {
+        self.keypad.get(key as usize).copied().unwrap_or(false)
+    }
+
+    fn clear(&mut self) {
+        self.display.fill(0);
+        self.display_changed = true;
+    }
+
+    // DXYN: XOR n rows from I onto the display, wrapping around both edges
+    // or cut off at them
+    fn draw(&mut self, x: usize, y: usize, n: usize) {
+        let (left, top) = (self.v[x] as usize % WIDTH, self.v[y] as usize % HEIGHT);
+        let mut collision = 0;
+        for row in 0..n {
+            let bits = self.memory[(self.i + row) % MEMORY_SIZE];
+            for column in 0..8 {
+                if !WRAP_SPRITES && (left + column >= WIDTH || top + row >= HEIGHT) {
+                    continue;
+                }
+                if (bits >> (7 - column)) & 1 == 1 {
+                    let offset = (top + row) % HEIGHT * WIDTH + (left + column) % WIDTH;
+                    collision |= self.display[offset];
+                    self.display[offset] ^= 1;
+                }
+            }
+        }
+        self.v[0xF] = collision;
+        self.display_changed = true;
+    }
+
+    fn bcd(&mut self, x: usize) {
+        let value = self.v[x];
+        self.write(self.i % MEMORY_SIZE, value / 100);
+        self.write((self.i + 1) % MEMORY_SIZE, (value / 10) % 10);
+        self.write((self.i + 2) % MEMORY_SIZE, value % 10);
+    }
+
+    fn store(&mut self, x: usize) {
+        for r in 0..=x {
+            self.write((self.i + r) % MEMORY_SIZE, self.v[r]);
+        }
+        self.increment_index(x);
+    }
+
+    fn load(&mut self, x: usize) {
+        for r in 0..=x {
+            self.v[r] = self.memory[(self.i + r) % MEMORY_SIZE];
+        }
+        self.increment_index(x);
+    }
+
+    fn increment_index(&mut self, x: usize) {
+        if let Some(step) = INDEX_STEP {
+            self.i += x + step;
+        }
+    }
+
+    // 8XY1 - 8XY3
+    fn logic(&mut self) {
+        if LOGIC_RESETS_VF {
+            self.v[0xF] = 0;
+        }
+    }
+
+    // The register 8XY6 and 8XYE shift
+    fn shift_source(x: usize, y: usize) -> usize {
+        if SHIFT_VY {
+            y
+        } else {
+            x
+        }
+    }
+
+    // 2NNN at `pc`: false if the stack is full
+    fn call(&mut self, pc: usize) -> bool {
+        if self.stack.len() >= STACK_DEPTH {
+            self.fault = Some(Fault::StackOverflow { pc });
+            return false;
+        }
+        self.stack.push(pc + 2);
+        true
+    }
+
+    // 00EE at `pc`: where to return to, None if the stack is empty
+    fn ret(&mut self, pc: usize) -> Option<usize> {
+        let addr = self.stack.pop();
+        if addr.is_none() {
+            self.fault = Some(Fault::StackUnderflow { pc });
+        }
+        addr
+    }
+
+    fn wait_key(&mut self, x: usize) {
+        self.key_wait = Some(x);
+        self.key_pressed = None;
+    }
+
+    // The dynamic fallback: interpret the instruction at pc. Returns how many
+    // instructions ran, 0 if it faulted.
+    fn step(&mut self) -> u32 {
+        let pc = self.pc;
+        let opcode = ((self.memory[pc] as u16) << 8) | self.memory[(pc + 1) % MEMORY_SIZE] as u16;
+        let x = ((opcode >> 8) & 0xF) as usize;
+        let y = ((opcode >> 4) & 0xF) as usize;
+        let n = (opcode & 0xF) as usize;
+        let kk = opcode as u8;
+        let nnn = (opcode & 0xFFF) as usize;
+        let skip = |condition: bool| if condition { pc + 4 } else { pc + 2 };
+
+        let next = match (opcode >> 12, kk, n) {
+            (0x0, 0xE0, _) if x == 0 => {
+                self.clear();
+                pc + 2
+            }
+            (0x0, 0xEE, _) if x == 0 => match self.ret(pc) {
+                Some(addr) => addr,
+                None => return 0,
+            },
+            (0x0, _, _) => {
+                if HIRES_CLEAR_SCREEN && nnn == 0x230 {
+                    self.clear();
+                }
+                pc + 2
+            }
+            (0x1, _, _) => nnn,
+            (0x2, _, _) => {
+                if !self.call(pc) {
+                    return 0;
+                }
+                nnn
+            }
+            (0x3, _, _) => skip(self.v[x] == kk),
+            (0x4, _, _) => skip(self.v[x] != kk),
+            (0x5, _, 0x0) => skip(self.v[x] == self.v[y]),
+            (0x6, _, _) => {
+                self.v[x] = kk;
+                pc + 2
+            }
+            (0x7, _, _) => {
+                self.v[x] = self.v[x].wrapping_add(kk);
+                pc + 2
+            }
+            (0x8, _, 0x0) => {
+                self.v[x] = self.v[y];
+                pc + 2
+            }
+            (0x8, _, 0x1) => {
+                self.v[x] |= self.v[y];
+                self.logic();
+                pc + 2
+            }
+            (0x8, _, 0x2) => {
+                self.v[x] &= self.v[y];
+                self.logic();
+                pc + 2
+            }
+            (0x8, _, 0x3) => {
+                self.v[x] ^= self.v[y];
+                self.logic();
+                pc + 2
+            }
+            (0x8, _, 0x4) => {
+                let (sum, carry) = self.v[x].overflowing_add(self.v[y]);
+                self.v[x] = sum;
+                self.v[0xF] = carry as u8;
+                pc + 2
+            }
+            (0x8, _, 0x5) => {
+                self.v[0xF] = (self.v[x] > self.v[y]) as u8;
+                self.v[x] = self.v[x].wrapping_sub(self.v[y]);
+                pc + 2
+            }
+            (0x8, _, 0x6) => {
+                let source = Self::shift_source(x, y);
+                self.v[0xF] = self.v[source] & 1;
+                self.v[x] = self.v[source] >> 1;
+                pc + 2
+            }
+            (0x8, _, 0x7) => {
+                self.v[0xF] = (self.v[y] > self.v[x]) as u8;
+                self.v[x] = self.v[y].wrapping_sub(self.v[x]);
+                pc + 2
+            }
+            (0x8, _, 0xE) => {
+                let source = Self::shift_source(x, y);
+                self.v[0xF] = self.v[source] >> 7;
+                self.v[x] = self.v[source] << 1;
+                pc + 2
+            }
+            (0x9, _, 0x0) => skip(self.v[x] != self.v[y]),
+            (0xA, _, _) => {
+                self.i = nnn;
+                pc + 2
+            }
+            (0xB, _, _) => nnn + self.v[if JUMP_VX { x } else { 0 }] as usize,
+            (0xC, _, _) => {
+                self.v[x] = self.random() & kk;
+                pc + 2
+            }
+            (0xD, _, _) => {
+                self.draw(x, y, n);
+                pc + 2
+            }
+            (0xE, 0x9E, _) => skip(self.key_down(self.v[x])),
+            (0xE, 0xA1, _) => skip(!self.key_down(self.v[x])),
+            (0xF, 0x07, _) => {
+                self.v[x] = self.delay_timer;
+                pc + 2
+            }
+            (0xF, 0x0A, _) => {
+                self.wait_key(x);
+                pc + 2
+            }
+            (0xF, 0x15, _) => {
+                self.delay_timer = self.v[x];
+                pc + 2
+            }
+            (0xF, 0x18, _) => {
+                self.sound_timer = self.v[x];
+                pc + 2
+            }
+            (0xF, 0x1E, _) => {
+                self.i += self.v[x] as usize;
+                self.v[0xF] = (self.i > 0x0F00) as u8;
+                pc + 2
+            }
+            (0xF, 0x29, _) => {
+                self.i = self.v[x] as usize * 5;
+                pc + 2
+            }
+            (0xF, 0x33, _) => {
+                self.bcd(x);
+                pc + 2
+            }
+            (0xF, 0x55, _) => {
+                self.store(x);
+                pc + 2
+            }
+            (0xF, 0x65, _) => {
+                self.load(x);
+                pc + 2
+            }
+            _ => pc + 2,
+        };
+        self.pc = next % MEMORY_SIZE;
+        1
+    }
+}
+
+fn is_code(addr: usize) -> bool {
+    CODE.binary_search_by(|&(start, end)| {
+        if end <= addr {
+            std::cmp::Ordering::Less
+        } else if start > addr {
+            std::cmp::Ordering::Greater
+        } else {
+            std::cmp::Ordering::Equal
+        }
+    })
+    .is_ok()
+}
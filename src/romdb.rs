@@ -0,0 +1,488 @@
+/*!
+ * @file romdb.rs
+ * @brief ROM database: per-ROM platform, quirks, speed, keys and colours, keyed by SHA-1
+ *
+ * Reads the files of the community chip-8-database (programs.json and
+ * platforms.json). Its platforms ship in data/chip-8-database and are compiled
+ * in, next to a programs.json with no ROMs of its own yet; directories with
+ * files of the same schema can be layered on top, later ones replacing
+ * earlier entries for the same ROM or platform. Drop the upstream release
+ * into a user directory to get its full ROM list.
+ */
+use crate::display::Rgb;
+use crate::json::{Json, JsonError};
+use crate::platform::Platform;
+use crate::quirks::{IndexIncrement, MemoryLayout, Quirks, VIP_STACK_DEPTH};
+use crate::sha1::sha1_hex;
+use crate::timing::Timing;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{env, fmt, fs, io};
+
+const BUNDLED_PROGRAMS: &str = include_str!("../data/chip-8-database/programs.json");
+const BUNDLED_PLATFORMS: &str = include_str!("../data/chip-8-database/platforms.json");
+
+const PROGRAMS_FILE: &str = "programs.json";
+const PLATFORMS_FILE: &str = "platforms.json";
+
+// Per-user files: $XDG_CONFIG_HOME/chip8-emulator, falling back to ~/.config
+pub fn config_dir() -> Option<PathBuf> {
+    let config = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("chip8-emulator"))
+}
+
+/**
+ * @brief One ROM's entry, as recorded under its program in programs.json
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RomEntry {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub platforms: Vec<String>, // platform ids, most suitable first
+    pub quirky_platforms: Vec<(String, Vec<(String, bool)>)>, // per-platform quirk overrides
+    pub tickrate: Option<u32>,
+    pub start_address: Option<usize>,
+    pub keys: Vec<(String, usize)>, // game action ("up", "a", ...) to CHIP-8 key
+    pub colors: Vec<Rgb>,           // pixel colours, background first
+}
+
+/**
+ * @brief A platform from platforms.json
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PlatformEntry {
+    pub id: String,
+    pub name: String,
+    pub default_tickrate: Option<u32>,
+    pub quirks: Vec<(String, bool)>,
+}
+
+/**
+ * @brief What an entry asks of the emulator, ready to apply
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RomSettings {
+    pub quirks: Quirks,
+    pub keys: Vec<(String, usize)>,
+    pub colors: Option<[Rgb; 2]>, // background, foreground
+    pub notes: Vec<String>,       // recorded settings that can't be honoured
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    Io(PathBuf, io::Error),
+    Json(PathBuf, JsonError),
+    Schema(PathBuf, String),
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            DatabaseError::Json(path, err) => write!(f, "{}: {}", path.display(), err),
+            DatabaseError::Schema(path, message) => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+#[derive(Debug, Clone, Default)]
+pub struct RomDatabase {
+    roms: HashMap<String, RomEntry>, // by lowercase SHA-1
+    platforms: HashMap<String, PlatformEntry>,
+}
+
+impl RomDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The database compiled into the binary
+    pub fn bundled() -> Self {
+        let mut database = Self::new();
+        let path = Path::new("data/chip-8-database");
+        database
+            .add_platforms(path, BUNDLED_PLATFORMS)
+            .and_then(|()| database.add_programs(path, BUNDLED_PROGRAMS))
+            .expect("bundled ROM database is valid");
+        database
+    }
+
+    // Per-user override directory: database in config_dir
+    pub fn user_dir() -> Option<PathBuf> {
+        Some(config_dir()?.join("database"))
+    }
+
+    // Layer the files found in `dir` over what is loaded; missing files are skipped
+    pub fn load_dir(&mut self, dir: &Path) -> Result<(), DatabaseError> {
+        let platforms = dir.join(PLATFORMS_FILE);
+        if let Some(source) = read_optional(&platforms)? {
+            self.add_platforms(&platforms, &source)?;
+        }
+        let programs = dir.join(PROGRAMS_FILE);
+        if let Some(source) = read_optional(&programs)? {
+            self.add_programs(&programs, &source)?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.roms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roms.is_empty()
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomEntry> {
+        self.roms.get(&sha1_hex(rom))
+    }
+
+    pub fn platform(&self, id: &str) -> Option<&PlatformEntry> {
+        self.platforms.get(id)
+    }
+
+    fn add_platforms(&mut self, path: &Path, source: &str) -> Result<(), DatabaseError> {
+        let json = Json::parse(source).map_err(|err| DatabaseError::Json(path.into(), err))?;
+        let schema = |message: &str| DatabaseError::Schema(path.into(), message.to_string());
+
+        for platform in json.as_array().ok_or_else(|| schema("expected an array"))? {
+            let id = platform
+                .get("id")
+                .and_then(Json::as_str)
+                .ok_or_else(|| schema("platform without an id"))?;
+            let entry = PlatformEntry {
+                id: id.to_string(),
+                name: string_field(platform, "name").unwrap_or_else(|| id.to_string()),
+                default_tickrate: u32_field(platform, "defaultTickrate"),
+                quirks: platform.get("quirks").map(flags).unwrap_or_default(),
+            };
+            self.platforms.insert(entry.id.clone(), entry);
+        }
+        Ok(())
+    }
+
+    fn add_programs(&mut self, path: &Path, source: &str) -> Result<(), DatabaseError> {
+        let json = Json::parse(source).map_err(|err| DatabaseError::Json(path.into(), err))?;
+        let schema = |message: &str| DatabaseError::Schema(path.into(), message.to_string());
+
+        for program in json.as_array().ok_or_else(|| schema("expected an array"))? {
+            let title = string_field(program, "title").unwrap_or_default();
+            let authors: Vec<String> = program
+                .get("authors")
+                .and_then(Json::as_array)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|author| author.as_str().map(str::to_string))
+                .collect();
+            let roms = program
+                .get("roms")
+                .and_then(Json::as_object)
+                .ok_or_else(|| schema(&format!("program \"{}\" has no roms", title)))?;
+
+            for (hash, rom) in roms {
+                let entry = RomEntry {
+                    title: title.clone(),
+                    authors: authors.clone(),
+                    platforms: rom
+                        .get("platforms")
+                        .and_then(Json::as_array)
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(|id| id.as_str().map(str::to_string))
+                        .collect(),
+                    quirky_platforms: rom
+                        .get("quirkyPlatforms")
+                        .and_then(Json::as_object)
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|(id, quirks)| (id.clone(), flags(quirks)))
+                        .collect(),
+                    tickrate: u32_field(rom, "tickrate"),
+                    start_address: u32_field(rom, "startAddress").map(|addr| addr as usize),
+                    keys: rom
+                        .get("keys")
+                        .and_then(Json::as_object)
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(|(action, key)| match key.as_u64() {
+                            Some(key) if key < 16 => Some((action.clone(), key as usize)),
+                            _ => None,
+                        })
+                        .collect(),
+                    colors: rom
+                        .get("colors")
+                        .and_then(|colors| colors.get("pixels"))
+                        .and_then(Json::as_array)
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(|color| color.as_str().and_then(parse_color))
+                        .collect(),
+                };
+                self.roms.insert(hash.to_ascii_lowercase(), entry);
+            }
+        }
+        Ok(())
+    }
+
+    // Turn an entry into quirks and frontend settings for `program`. The
+    // first of the entry's platforms this emulator runs is used; its quirks
+    // come from platforms.json, then the ROM's own overrides.
+    pub fn settings(&self, entry: &RomEntry, program: &[u8]) -> RomSettings {
+        let mut settings = RomSettings::default();
+
+        let chosen = entry
+            .platforms
+            .iter()
+            .find_map(|id| platform_for(id, program).map(|platform| (id.as_str(), platform)));
+        let (id, (platform, hybrid)) = match chosen {
+            Some(chosen) => chosen,
+            None => {
+                if let Some(id) = entry.platforms.first() {
+                    settings.notes.push(format!(
+                        "platform {} is not supported, running as chip8",
+                        id
+                    ));
+                }
+                ("", (Platform::detect(program), false))
+            }
+        };
+        settings.quirks.platform = platform;
+        if hybrid {
+            settings.quirks.machine_code = true;
+            settings.quirks.memory_layout = MemoryLayout::Vip;
+            settings.quirks.stack_depth = Some(VIP_STACK_DEPTH);
+        }
+
+        let base = self.platforms.get(id);
+        let overrides = entry
+            .quirky_platforms
+            .iter()
+            .find(|(quirky, _)| quirky == id)
+            .map(|(_, quirks)| quirks.as_slice())
+            .unwrap_or_default();
+        let mut quirks: Vec<(String, bool)> =
+            base.map(|base| base.quirks.clone()).unwrap_or_default();
+        for (name, value) in overrides {
+            match quirks.iter_mut().find(|(quirk, _)| quirk == name) {
+                Some(quirk) => quirk.1 = *value,
+                None => quirks.push((name.clone(), *value)),
+            }
+        }
+        let mut unsupported = Vec::new();
+        for (name, value) in &quirks {
+            let value = *value;
+            match name.as_str() {
+                "vblank" => settings.quirks.display_wait = value,
+                "shift" => settings.quirks.shift_vy = !value,
+                "wrap" => settings.quirks.clip_sprites = !value,
+                "jump" => settings.quirks.jump_vx = value,
+                "logic" => settings.quirks.logic_resets_vf = value,
+                // Two switches for one setting, taken together below
+                "memoryIncrementByX" | "memoryLeaveIUnchanged" => {}
+                _ => unsupported.push(format!("{} = {}", name, value)),
+            }
+        }
+        let flag = |name: &str| quirks.iter().find(|(quirk, _)| quirk == name).map(|q| q.1);
+        match (flag("memoryLeaveIUnchanged"), flag("memoryIncrementByX")) {
+            (None, None) => {}
+            (Some(true), _) => settings.quirks.index_increment = IndexIncrement::Unchanged,
+            (_, Some(true)) => settings.quirks.index_increment = IndexIncrement::ByX,
+            _ => settings.quirks.index_increment = IndexIncrement::ByXPlusOne,
+        }
+        if !unsupported.is_empty() {
+            settings.notes.push(format!(
+                "unsupported quirks ignored: {}",
+                unsupported.join(", ")
+            ));
+        }
+
+        if let Some(per_frame) = entry
+            .tickrate
+            .or_else(|| base.and_then(|base| base.default_tickrate))
+        {
+            settings.quirks.timing = Timing::Instructions { per_frame };
+        }
+        if let Some(addr) = entry.start_address {
+            if addr != platform.load_address() {
+                settings.notes.push(format!(
+                    "start address {:#05x} is not supported, loading at {:#05x}",
+                    addr,
+                    platform.load_address()
+                ));
+            }
+        }
+        settings.keys = entry.keys.clone();
+        if let [background, foreground, ..] = entry.colors[..] {
+            settings.colors = Some([background, foreground]);
+        }
+        settings
+    }
+}
+
+// Our platform for a database platform id, and whether 0NNN machine code is expected
+fn platform_for(id: &str, program: &[u8]) -> Option<(Platform, bool)> {
+    match id {
+        "originalChip8" | "modernChip8" => Some((Platform::detect(program), false)),
+        "hybridVIP" => Some((Platform::detect(program), true)),
+        "chip8x" => Some((Platform::Chip8X, false)),
+        "megachip8" => Some((Platform::MegaChip, false)),
+        _ => None,
+    }
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>, DatabaseError> {
+    match fs::read_to_string(path) {
+        Ok(source) => Ok(Some(source)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(DatabaseError::Io(path.into(), err)),
+    }
+}
+
+fn string_field(json: &Json, key: &str) -> Option<String> {
+    json.get(key).and_then(Json::as_str).map(str::to_string)
+}
+
+fn u32_field(json: &Json, key: &str) -> Option<u32> {
+    json.get(key)
+        .and_then(Json::as_u64)
+        .and_then(|value| u32::try_from(value).ok())
+}
+
+// {"name": true, ...}; non-boolean members are ignored
+fn flags(json: &Json) -> Vec<(String, bool)> {
+    json.as_object()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|(name, value)| value.as_bool().map(|value| (name.clone(), value)))
+        .collect()
+}
+
+// "#rrggbb"
+fn parse_color(text: &str) -> Option<Rgb> {
+    let hex = text.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 00E0; JP 0x202
+    const ROM: &[u8] = &[0x00, 0xE0, 0x12, 0x02];
+
+    // A database with the bundled platforms and one program holding ROM
+    fn database(rom: &str) -> RomDatabase {
+        let programs = format!(
+            r#"[{{"title": "Test", "authors": ["A", "B"], "roms": {{"{}": {}}}}}]"#,
+            sha1_hex(ROM).to_ascii_uppercase(),
+            rom
+        );
+        let mut database = RomDatabase::bundled();
+        database
+            .add_programs(Path::new("programs.json"), &programs)
+            .unwrap();
+        database
+    }
+
+    fn settings(rom: &str) -> RomSettings {
+        let database = database(rom);
+        let entry = database.lookup(ROM).expect("the ROM is in the database");
+        database.settings(entry, ROM)
+    }
+
+    // Whatever programs.json ships with has to be usable as it stands: keyed by
+    // a full SHA-1 and pointing at platforms the bundled platforms.json knows
+    #[test]
+    fn bundled_programs_are_consistent() {
+        let database = RomDatabase::bundled();
+        for (hash, entry) in &database.roms {
+            assert!(
+                hash.len() == 40 && hash.bytes().all(|b| b.is_ascii_hexdigit()),
+                "{} has hash {}",
+                entry.title,
+                hash
+            );
+            for id in &entry.platforms {
+                assert!(database.platform(id).is_some(), "{}: {}", entry.title, id);
+            }
+        }
+    }
+
+    #[test]
+    fn lookup_by_hash() {
+        let database = database(r#"{"platforms": ["originalChip8"], "tickrate": 20}"#);
+        let entry = database.lookup(ROM).unwrap();
+        assert_eq!(entry.title, "Test");
+        assert_eq!(entry.authors, ["A", "B"]);
+        assert_eq!(entry.tickrate, Some(20));
+        assert_eq!(database.lookup(&ROM[..2]), None);
+    }
+
+    #[test]
+    fn original_chip8_quirks() {
+        let settings = settings(r#"{"platforms": ["originalChip8"]}"#);
+        assert_eq!(settings.notes, Vec::<String>::new());
+        let quirks = settings.quirks;
+        assert!(quirks.shift_vy && quirks.clip_sprites && quirks.logic_resets_vf);
+        assert!(quirks.display_wait && !quirks.jump_vx);
+        assert_eq!(quirks.index_increment, IndexIncrement::ByXPlusOne);
+        assert_eq!(quirks.timing, Timing::Instructions { per_frame: 15 });
+    }
+
+    #[test]
+    fn megachip_quirks() {
+        let quirks = settings(r#"{"platforms": ["megachip8"]}"#).quirks;
+        assert_eq!(quirks.platform, Platform::MegaChip);
+        assert!(!quirks.shift_vy && quirks.clip_sprites && quirks.jump_vx);
+        assert_eq!(quirks.index_increment, IndexIncrement::Unchanged);
+    }
+
+    #[test]
+    fn rom_overrides_platform_quirks() {
+        let settings = settings(
+            r#"{"platforms": ["originalChip8"],
+                "quirkyPlatforms": {"originalChip8": {"shift": true, "memoryIncrementByX": true,
+                                                      "vblank": false, "sprites": true}}}"#,
+        );
+        assert!(!settings.quirks.shift_vy && !settings.quirks.display_wait);
+        assert_eq!(settings.quirks.index_increment, IndexIncrement::ByX);
+        assert_eq!(
+            settings.notes,
+            ["unsupported quirks ignored: sprites = true"]
+        );
+    }
+
+    #[test]
+    fn first_supported_platform() {
+        let chip8x = settings(r#"{"platforms": ["superchip", "chip8x"]}"#);
+        assert_eq!(chip8x.quirks.platform, Platform::Chip8X);
+        let settings = settings(r#"{"platforms": ["superchip"]}"#);
+        assert_eq!(settings.quirks.platform, Platform::Chip8);
+        assert_eq!(
+            settings.notes[0],
+            "platform superchip is not supported, running as chip8"
+        );
+    }
+
+    #[test]
+    fn keys_and_colors() {
+        let settings = settings(
+            r##"{"keys": {"up": 5, "a": 6, "bad": 16},
+                 "colors": {"pixels": ["#102030", "#ffcc00"]}}"##,
+        );
+        assert_eq!(settings.keys, [("up".to_string(), 5), ("a".to_string(), 6)]);
+        assert_eq!(
+            settings.colors,
+            Some([(0x10, 0x20, 0x30), (0xFF, 0xCC, 0x00)])
+        );
+    }
+}
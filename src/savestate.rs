@@ -0,0 +1,202 @@
+/*!
+ * @file savestate.rs
+ * @brief Little-endian binary encoding used by Cpu::save_state / Cpu::load_state
+ */
+use std::fmt;
+
+pub const MAGIC: &[u8; 4] = b"C8ST";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a CHIP-8 save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::InvalidValue(field) => write!(f, "invalid value for {}", field),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(version: u8) -> Self {
+        let mut data = MAGIC.to_vec();
+        data.push(version);
+        StateWriter { data }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pub version: u8,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, StateError> {
+        match data {
+            [m0, m1, m2, m3, version, rest @ ..] if [*m0, *m1, *m2, *m3] == *MAGIC => {
+                Ok(StateReader {
+                    data: rest,
+                    version: *version,
+                })
+            }
+            _ if data.len() < MAGIC.len() + 1 => Err(StateError::Truncated),
+            _ => Err(StateError::BadMagic),
+        }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    #[test]
+    fn round_trip() {
+        let mut writer = StateWriter::new(7);
+        writer.u8(0xAB);
+        writer.bool(true);
+        writer.u16(0x1234);
+        writer.u32(0xDEAD_BEEF);
+        writer.u64(u64::MAX - 1);
+        writer.bytes(b"tail");
+        let data = writer.finish();
+        assert_eq!(&data[..6], b"C8ST\x07\xAB");
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.version, 7);
+        assert_eq!(reader.u8(), Ok(0xAB));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.u16(), Ok(0x1234));
+        assert_eq!(reader.u32(), Ok(0xDEAD_BEEF));
+        assert_eq!(reader.u64(), Ok(u64::MAX - 1));
+        assert_eq!(reader.bytes(4), Ok(&b"tail"[..]));
+        assert_eq!(reader.u8(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn bad_headers() {
+        assert!(matches!(
+            StateReader::new(b"C8S"),
+            Err(StateError::Truncated)
+        ));
+        assert!(matches!(
+            StateReader::new(b"PNG\x00\x01"),
+            Err(StateError::BadMagic)
+        ));
+        let mut reader = StateReader::new(b"C8ST\x01\x02").unwrap();
+        assert_eq!(reader.u16(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn cpu_round_trip() {
+        // LD I, 0x300; loop: ADD V0, 3; LD [I], V0; ADD I, V0; JP loop
+        let program = [0xA3, 0x00, 0x70, 0x03, 0xF0, 0x55, 0xF0, 0x1E, 0x12, 0x02];
+        let mut cpu = Cpu::new();
+        cpu.set_idle_skip(false);
+        cpu.load_program(&program);
+        for _ in 0..10 {
+            cpu.cycle([false; 16]);
+        }
+        let state = cpu.save_state();
+
+        let mut restored = Cpu::new();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        for _ in 0..10 {
+            cpu.cycle([false; 16]);
+            restored.cycle([false; 16]);
+        }
+        assert_eq!(restored.save_state(), cpu.save_state());
+
+        let mut old = state.clone();
+        old[MAGIC.len()] -= 1;
+        let version = old[MAGIC.len()];
+        assert_eq!(
+            restored.load_state(&old),
+            Err(StateError::UnsupportedVersion(version))
+        );
+        assert_eq!(
+            restored.load_state(&state[..state.len() - 1]),
+            Err(StateError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,467 @@
+/*!
+ * @file script.rs
+ * @brief Input script format for headless automated runs
+ *
+ * One command per line, `#` starts a comment:
+ *
+ * ```text
+ * at frame 120 press 5 for 3 frames
+ * press a                          # held for 1 frame
+ * wait 30 frames
+ * wait until pc == 0x2F0 within 600 frames
+ * wait until display contains font digit 3
+ * assert v3 >= 10
+ * assert mem[0x300] != 0
+ * assert bcd[0x2F0] >= 100         # 3 digits from FX33, read as a decimal number
+ * exit
+ * ```
+ *
+ * `at frame N` delays the rest of the line until frame N. Key presses don't
+ * block: the script moves on while the key is held, so presses can overlap.
+ * Operands are decimal or 0x-prefixed hex; key names are a single hex digit.
+ */
+use crate::cpu::Cpu;
+use crate::font::FONT_SET;
+use std::fmt;
+
+/**
+ * @brief A value read from the machine state
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Pc,
+    I,
+    Dt,
+    St,
+    V(usize),
+    Mem(usize),
+    Bcd(usize), // the three digits FX33 stores from here on, as a number
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Compare(Operand, Comparison, usize),
+    DisplayContainsDigit(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    AtFrame(u64, Box<Command>),
+    Press {
+        key: usize,
+        frames: u64,
+    },
+    Wait(u64),
+    WaitUntil {
+        condition: Condition,
+        within: Option<u64>,
+    },
+    Assert(Condition),
+    Exit,
+}
+
+/**
+ * @brief A parsed script; each command keeps its source line for error messages
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    pub commands: Vec<(usize, Command)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub(crate) fn parse_number(word: &str) -> Result<usize, String> {
+    let parsed = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+    parsed.map_err(|_| format!("expected a number, found `{}`", word))
+}
+
+pub(crate) fn parse_key(word: &str) -> Result<usize, String> {
+    match usize::from_str_radix(word, 16) {
+        Ok(key) if key < 16 => Ok(key),
+        _ => Err(format!("expected a key 0-F, found `{}`", word)),
+    }
+}
+
+pub(crate) fn parse_operand(word: &str) -> Result<Operand, String> {
+    let lower = word.to_ascii_lowercase();
+    match lower.as_str() {
+        "pc" => Ok(Operand::Pc),
+        "i" => Ok(Operand::I),
+        "dt" => Ok(Operand::Dt),
+        "st" => Ok(Operand::St),
+        _ => {
+            if let Some(register) = lower.strip_prefix('v') {
+                return parse_key(register).map(Operand::V);
+            }
+            if let Some(addr) = lower.strip_prefix("mem[").and_then(|s| s.strip_suffix(']')) {
+                return parse_number(addr).map(Operand::Mem);
+            }
+            if let Some(addr) = lower.strip_prefix("bcd[").and_then(|s| s.strip_suffix(']')) {
+                return parse_number(addr).map(Operand::Bcd);
+            }
+            Err(format!("unknown operand `{}`", word))
+        }
+    }
+}
+
+fn parse_comparison(word: &str) -> Result<Comparison, String> {
+    match word {
+        "==" => Ok(Comparison::Eq),
+        "!=" => Ok(Comparison::Ne),
+        "<" => Ok(Comparison::Lt),
+        "<=" => Ok(Comparison::Le),
+        ">" => Ok(Comparison::Gt),
+        ">=" => Ok(Comparison::Ge),
+        _ => Err(format!("unknown comparison `{}`", word)),
+    }
+}
+
+// Parses a condition at the start of `words`, returning it and the words left over
+pub(crate) fn parse_condition<'a>(
+    words: &'a [&'a str],
+) -> Result<(Condition, &'a [&'a str]), String> {
+    match words {
+        ["display", "contains", "font", "digit", digit, rest @ ..] => {
+            Ok((Condition::DisplayContainsDigit(parse_key(digit)?), rest))
+        }
+        [operand, comparison, value, rest @ ..] => Ok((
+            Condition::Compare(
+                parse_operand(operand)?,
+                parse_comparison(comparison)?,
+                parse_number(value)?,
+            ),
+            rest,
+        )),
+        _ => Err("expected a condition".to_string()),
+    }
+}
+
+fn parse_frames(words: &[&str]) -> Result<u64, String> {
+    match words {
+        [count, "frame"] | [count, "frames"] => Ok(parse_number(count)? as u64),
+        _ => Err(format!(
+            "expected `<n> frames`, found `{}`",
+            words.join(" ")
+        )),
+    }
+}
+
+fn parse_command(words: &[&str]) -> Result<Command, String> {
+    match words {
+        ["at", "frame", frame, rest @ ..] => {
+            let command = parse_command(rest)?;
+            Ok(Command::AtFrame(
+                parse_number(frame)? as u64,
+                Box::new(command),
+            ))
+        }
+        ["press", key] => Ok(Command::Press {
+            key: parse_key(key)?,
+            frames: 1,
+        }),
+        ["press", key, "for", rest @ ..] => Ok(Command::Press {
+            key: parse_key(key)?,
+            frames: parse_frames(rest)?,
+        }),
+        ["wait", "until", rest @ ..] => {
+            let (condition, rest) = parse_condition(rest)?;
+            let within = match rest {
+                [] => None,
+                ["within", frames @ ..] => Some(parse_frames(frames)?),
+                _ => return Err(format!("unexpected `{}`", rest.join(" "))),
+            };
+            Ok(Command::WaitUntil { condition, within })
+        }
+        ["wait", rest @ ..] => Ok(Command::Wait(parse_frames(rest)?)),
+        ["assert", rest @ ..] => match parse_condition(rest)? {
+            (condition, []) => Ok(Command::Assert(condition)),
+            (_, rest) => Err(format!("unexpected `{}`", rest.join(" "))),
+        },
+        ["exit"] => Ok(Command::Exit),
+        _ => Err(format!("unknown command `{}`", words.join(" "))),
+    }
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Script, ParseError> {
+        let mut commands = Vec::new();
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let code = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = code.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            let command = parse_command(&words).map_err(|message| ParseError {
+                line: line_number,
+                message,
+            })?;
+            commands.push((line_number, command));
+        }
+        Ok(Script { commands })
+    }
+}
+
+impl Operand {
+    // One word in script syntax: pc, i, dt, st, v3, mem[0x2F0] or bcd[0x2F0]
+    pub fn parse(word: &str) -> Result<Operand, String> {
+        parse_operand(word)
+    }
+
+    pub fn read(&self, cpu: &Cpu) -> usize {
+        match *self {
+            Operand::Pc => cpu.program_counter(),
+            Operand::I => cpu.index_register(),
+            Operand::Dt => cpu.delay_timer() as usize,
+            Operand::St => cpu.sound_timer() as usize,
+            Operand::V(x) => cpu.v_registers()[x] as usize,
+            Operand::Mem(addr) => cpu.memory().get(addr).copied().unwrap_or(0) as usize,
+            Operand::Bcd(addr) => {
+                (addr..addr + 3).fold(0, |number, addr| 10 * number + Operand::Mem(addr).read(cpu))
+            }
+        }
+    }
+}
+
+impl Comparison {
+    fn holds(&self, left: usize, right: usize) -> bool {
+        match self {
+            Comparison::Eq => left == right,
+            Comparison::Ne => left != right,
+            Comparison::Lt => left < right,
+            Comparison::Le => left <= right,
+            Comparison::Gt => left > right,
+            Comparison::Ge => left >= right,
+        }
+    }
+}
+
+// True if the 4x5 font glyph for `digit` appears anywhere on screen, pixel for pixel
+fn display_contains_digit(cpu: &Cpu, digit: usize) -> bool {
+    let glyph = &FONT_SET[digit * 5..digit * 5 + 5];
+    let display = cpu.display();
+    let (width, height) = (display.width, display.height);
+
+    (0..=height - 5).any(|top| {
+        (0..=width - 4).any(|left| {
+            glyph.iter().enumerate().all(|(row, bits)| {
+                (0..4)
+                    .all(|col| display.get_pixel(left + col, top + row) == (bits >> (7 - col)) & 1)
+            })
+        })
+    })
+}
+
+impl Condition {
+    pub fn holds(&self, cpu: &Cpu) -> bool {
+        match *self {
+            Condition::Compare(operand, comparison, value) => {
+                comparison.holds(operand.read(cpu), value)
+            }
+            Condition::DisplayContainsDigit(digit) => display_contains_digit(cpu, digit),
+        }
+    }
+
+    // Human-readable explanation of the current value, for failure messages
+    pub fn describe(&self, cpu: &Cpu) -> String {
+        match *self {
+            // BCD scores are decimal numbers; everything else reads best in hex
+            Condition::Compare(operand @ Operand::Bcd(_), _, _) => {
+                format!("{} is {}", operand, operand.read(cpu))
+            }
+            Condition::Compare(operand, _, _) => {
+                format!("{} is {:#x}", operand, operand.read(cpu))
+            }
+            Condition::DisplayContainsDigit(digit) => {
+                format!("font digit {:X} is not on screen", digit)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Pc => write!(f, "pc"),
+            Operand::I => write!(f, "i"),
+            Operand::Dt => write!(f, "dt"),
+            Operand::St => write!(f, "st"),
+            Operand::V(x) => write!(f, "v{:X}", x),
+            Operand::Mem(addr) => write!(f, "mem[{:#05x}]", addr),
+            Operand::Bcd(addr) => write!(f, "bcd[{:#05x}]", addr),
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Compare(operand @ Operand::Bcd(_), comparison, value) => {
+                write!(f, "{} {} {}", operand, comparison, value)
+            }
+            Condition::Compare(operand, comparison, value) => {
+                write!(f, "{} {} {:#x}", operand, comparison, value)
+            }
+            Condition::DisplayContainsDigit(digit) => {
+                write!(f, "display contains font digit {:X}", digit)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        let script = Script::parse(
+            "\
+at frame 120 press 5 for 3 frames
+press a                          # held for 1 frame
+
+wait 30 frames
+wait until pc == 0x2F0 within 600 frames
+wait until display contains font digit 3
+assert v3 >= 10
+assert bcd[0x2F0] >= 100
+exit
+",
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            [
+                (
+                    1,
+                    Command::AtFrame(120, Box::new(Command::Press { key: 5, frames: 3 }))
+                ),
+                (2, Command::Press { key: 10, frames: 1 }),
+                (4, Command::Wait(30)),
+                (
+                    5,
+                    Command::WaitUntil {
+                        condition: Condition::Compare(Operand::Pc, Comparison::Eq, 0x2F0),
+                        within: Some(600),
+                    }
+                ),
+                (
+                    6,
+                    Command::WaitUntil {
+                        condition: Condition::DisplayContainsDigit(3),
+                        within: None,
+                    }
+                ),
+                (
+                    7,
+                    Command::Assert(Condition::Compare(Operand::V(3), Comparison::Ge, 10))
+                ),
+                (
+                    8,
+                    Command::Assert(Condition::Compare(Operand::Bcd(0x2F0), Comparison::Ge, 100))
+                ),
+                (9, Command::Exit),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |source: &str| Script::parse(source).unwrap_err();
+        assert_eq!(
+            error("wait 3 frames\npress g").to_string(),
+            "line 2: expected a key 0-F, found `g`"
+        );
+        assert_eq!(
+            error("assert v3 >= 10 frames").message,
+            "unexpected `frames`"
+        );
+        assert_eq!(error("assert w3 == 1").message, "unknown operand `w3`");
+        assert_eq!(error("assert v3 =< 1").message, "unknown comparison `=<`");
+        assert_eq!(
+            error("wait until pc == 0x200 within 3").message,
+            "expected `<n> frames`, found `3`"
+        );
+        assert_eq!(error("jump").message, "unknown command `jump`");
+    }
+
+    #[test]
+    fn operands_round_trip() {
+        for word in ["pc", "i", "dt", "st", "vA", "mem[0x2f0]", "bcd[0x300]"] {
+            let operand = Operand::parse(word).unwrap();
+            assert_eq!(Operand::parse(&operand.to_string()), Ok(operand));
+        }
+        assert_eq!(Operand::parse("MEM[768]"), Ok(Operand::Mem(0x300)));
+    }
+
+    #[test]
+    fn conditions_on_the_cpu() {
+        let program = [
+            0x63, 0x03, // LD V3, 3
+            0xF3, 0x29, // LD F, V3
+            0xD0, 0x05, // DRW V0, V0, 5
+            0x63, 0xEA, // LD V3, 234
+            0xA3, 0x00, // LD I, 0x300
+            0xF3, 0x33, // LD B, V3
+            0x12, 0x0C, // JP 0x20C
+        ];
+        let mut cpu = Cpu::new();
+        cpu.set_idle_skip(false);
+        cpu.load_program(&program);
+        for _ in 0..7 {
+            cpu.cycle([false; 16]);
+        }
+
+        let condition = |source: &str| {
+            let words: Vec<&str> = source.split_whitespace().collect();
+            parse_condition(&words).unwrap().0
+        };
+        assert!(condition("display contains font digit 3").holds(&cpu));
+        assert!(!condition("display contains font digit 8").holds(&cpu));
+        assert!(condition("pc == 0x20C").holds(&cpu));
+        assert!(condition("mem[0x301] == 3").holds(&cpu));
+        let bcd = condition("bcd[0x300] > 233");
+        assert!(bcd.holds(&cpu));
+        assert_eq!(bcd.describe(&cpu), "bcd[0x300] is 234");
+        assert_eq!(condition("v3 < 0x10").describe(&cpu), "v3 is 0xea");
+    }
+}
@@ -1,18 +1,73 @@
+/*!
+ * @file stack.rs
+ * @brief Generic stack with an optional capacity, used as the CHIP-8 call stack
+ */
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    Overflow { capacity: usize },
+    Underflow,
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackError::Overflow { capacity } => {
+                write!(f, "stack overflow (capacity {})", capacity)
+            }
+            StackError::Underflow => write!(f, "stack underflow"),
+        }
+    }
+}
+
+impl std::error::Error for StackError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Stack<T> {
     data: Vec<T>,
+    capacity: Option<usize>,
+}
+
+impl<T> Default for Stack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T> Stack<T> {
+    // An unbounded stack
     pub fn new() -> Stack<T> {
-        Stack { data: Vec::new() }
+        Stack {
+            data: Vec::new(),
+            capacity: None,
+        }
+    }
+
+    // A stack that refuses to grow past `capacity` items
+    pub fn with_capacity(capacity: usize) -> Stack<T> {
+        Stack {
+            data: Vec::with_capacity(capacity),
+            capacity: Some(capacity),
+        }
     }
 
-    pub fn push(&mut self, item: T) {
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    pub fn push(&mut self, item: T) -> Result<(), StackError> {
+        if let Some(capacity) = self.capacity {
+            if self.data.len() >= capacity {
+                return Err(StackError::Overflow { capacity });
+            }
+        }
         self.data.push(item);
+        Ok(())
     }
 
-    pub fn pop(&mut self) -> Option<T> {
-        self.data.pop()
+    pub fn pop(&mut self) -> Result<T, StackError> {
+        self.data.pop().ok_or(StackError::Underflow)
     }
 
     pub fn is_empty(&self) -> bool {
@@ -26,4 +81,95 @@ impl<T> Stack<T> {
     pub fn peek(&self) -> Option<&T> {
         self.data.last()
     }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    // Items from the bottom of the stack to the top
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.data.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Cpu, Fault};
+    use crate::quirks::{Quirks, VIP_STACK_DEPTH};
+
+    // A Cpu with `stack_depth` after running the first `steps` instructions of `program`
+    fn run(stack_depth: Option<usize>, program: &[u8], steps: usize) -> Cpu {
+        let mut cpu = Cpu::with_quirks(Quirks {
+            stack_depth,
+            ..Quirks::default()
+        });
+        cpu.set_idle_skip(false);
+        cpu.load_program(program);
+        for _ in 0..steps {
+            cpu.cycle([false; 16]);
+        }
+        cpu
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let mut stack = Stack::with_capacity(2);
+        assert_eq!(stack.capacity(), Some(2));
+        assert_eq!(stack.push(1), Ok(()));
+        assert_eq!(stack.push(2), Ok(()));
+        assert_eq!(stack.push(3), Err(StackError::Overflow { capacity: 2 }));
+        assert_eq!(stack.iter().copied().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(stack.peek(), Some(&2));
+        assert_eq!(stack.pop(), Ok(2));
+        assert_eq!(stack.pop(), Ok(1));
+        assert_eq!(stack.pop(), Err(StackError::Underflow));
+        assert!(stack.is_empty());
+
+        let mut unbounded = Stack::new();
+        for i in 0..1000 {
+            assert_eq!(unbounded.push(i), Ok(()));
+        }
+        assert_eq!(unbounded.length(), 1000);
+    }
+
+    #[test]
+    fn depth_limit() {
+        // CALL 0x200, without end
+        let program = [0x22, 0x00];
+        for depth in [16, VIP_STACK_DEPTH, 1] {
+            let cpu = run(Some(depth), &program, depth);
+            assert_eq!((cpu.fault(), cpu.stack().length()), (None, depth));
+            let cpu = run(Some(depth), &program, depth + 1);
+            let overflow = Fault::Stack {
+                pc: 0x200,
+                error: StackError::Overflow { capacity: depth },
+            };
+            assert_eq!(cpu.fault(), Some(overflow));
+            assert_eq!(cpu.stack().length(), depth);
+            assert_eq!(
+                overflow.to_string(),
+                format!("stack overflow (capacity {}) at 0x200", depth)
+            );
+        }
+        let cpu = run(None, &program, 1000);
+        assert_eq!((cpu.fault(), cpu.stack().length()), (None, 1000));
+    }
+
+    #[test]
+    fn underflow() {
+        // CALL 0x204; RET; RET
+        let program = [0x22, 0x04, 0x00, 0xEE, 0x00, 0xEE];
+        let cpu = run(Some(16), &program, 2);
+        assert_eq!((cpu.fault(), cpu.program_counter()), (None, 0x202));
+        let cpu = run(Some(16), &program, 3);
+        assert_eq!(
+            cpu.fault(),
+            Some(Fault::Stack {
+                pc: 0x202,
+                error: StackError::Underflow
+            })
+        );
+        assert_eq!(cpu.program_counter(), 0x202);
+    }
 }
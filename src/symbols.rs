@@ -0,0 +1,161 @@
+/*!
+ * @file symbols.rs
+ * @brief Named locations in a ROM's state: where its lives, score or level live
+ *
+ * A symbol file has one name per line after the location, in the input
+ * script's operand syntax; `#` starts a comment:
+ *
+ * ```text
+ * mem[0x2F3] lives
+ * bcd[0x2F0] score
+ * v5 level
+ * ```
+ *
+ * Each ROM has its own file in the user config directory, keyed by the ROM's
+ * SHA-1 like the ROM database, so it follows the ROM through renames.
+ */
+use crate::romdb::config_dir;
+use crate::script::{parse_operand, Operand, ParseError};
+use crate::sha1::sha1_hex;
+use std::fmt;
+use std::path::PathBuf;
+
+/**
+ * @brief Names for locations, in the order they were first named
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Symbols {
+    entries: Vec<(String, Operand)>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(source: &str) -> Result<Symbols, ParseError> {
+        let mut symbols = Symbols::new();
+        for (index, line) in source.lines().enumerate() {
+            let code = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = code.split_whitespace().collect();
+            let parsed = match words[..] {
+                [] => continue,
+                [operand, name] => parse_operand(operand).map(|operand| (name, operand)),
+                _ => Err(format!(
+                    "expected a location and a name, found `{}`",
+                    code.trim()
+                )),
+            };
+            let (name, operand) = parsed.map_err(|message| ParseError {
+                line: index + 1,
+                message,
+            })?;
+            symbols.insert(name, operand);
+        }
+        Ok(symbols)
+    }
+
+    // symbols/<SHA-1 of the ROM>.sym in the user config directory
+    pub fn path(program: &[u8]) -> Option<PathBuf> {
+        Some(
+            config_dir()?
+                .join("symbols")
+                .join(format!("{}.sym", sha1_hex(program))),
+        )
+    }
+
+    // Name `operand`, moving the name if it was already in use
+    pub fn insert(&mut self, name: &str, operand: Operand) {
+        match self.entries.iter_mut().find(|(known, _)| known == name) {
+            Some(entry) => entry.1 = operand,
+            None => self.entries.push((name.to_string(), operand)),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Operand> {
+        let index = self.entries.iter().position(|(known, _)| known == name)?;
+        Some(self.entries.remove(index).1)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Operand> {
+        self.entries
+            .iter()
+            .find(|(known, _)| known == name)
+            .map(|&(_, operand)| operand)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Operand)> + '_ {
+        self.entries
+            .iter()
+            .map(|(name, operand)| (name.as_str(), *operand))
+    }
+}
+
+// In the file format parse reads
+impl fmt::Display for Symbols {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, operand) in &self.entries {
+            writeln!(f, "{} {}", operand, name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "\
+mem[0x2F3] lives
+bcd[0x2F0] score # FX33 digits
+
+v5 level
+";
+
+    #[test]
+    fn parse_file() {
+        let symbols = Symbols::parse(FILE).unwrap();
+        assert_eq!(
+            symbols.iter().collect::<Vec<_>>(),
+            [
+                ("lives", Operand::Mem(0x2F3)),
+                ("score", Operand::Bcd(0x2F0)),
+                ("level", Operand::V(5)),
+            ]
+        );
+        assert_eq!(Symbols::parse(&symbols.to_string()).unwrap(), symbols);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = Symbols::parse("v5 level\nmem[0x2F3]").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.message,
+            "expected a location and a name, found `mem[0x2F3]`"
+        );
+        let error = Symbols::parse("w5 level").unwrap_err();
+        assert_eq!(error.message, "unknown operand `w5`");
+    }
+
+    #[test]
+    fn insert_moves_a_name() {
+        let mut symbols = Symbols::parse(FILE).unwrap();
+        symbols.insert("lives", Operand::Mem(0x2F4));
+        symbols.insert("time", Operand::Dt);
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.get("lives"), Some(Operand::Mem(0x2F4)));
+        assert_eq!(symbols.iter().next(), Some(("lives", Operand::Mem(0x2F4))));
+        assert_eq!(symbols.remove("score"), Some(Operand::Bcd(0x2F0)));
+        assert_eq!(symbols.remove("score"), None);
+        assert_eq!(symbols.get("score"), None);
+    }
+}
@@ -0,0 +1,202 @@
+/*!
+ * @file timing.rs
+ * @brief How much of a 60 Hz frame each instruction uses up
+ *
+ * The Cpu charges every cycle against a per-frame budget. When the budget
+ * runs out the frame ends: the timers tick and OutputState::vblank is set.
+ */
+use crate::instruction::Instruction;
+
+/**
+ * @brief Cost model for instructions
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    // Every instruction costs the same; `per_frame` of them fit in a frame
+    Instructions { per_frame: u32 },
+    // COSMAC VIP: instructions cost 1802 machine cycles, see vip_machine_cycles
+    Vip,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing::Instructions { per_frame: 1 }
+    }
+}
+
+// The VIP's 1802 runs at 1.7609 MHz with 8 clocks per machine cycle: 3668 per frame.
+// The 1861 video chip takes 1024 of those for display DMA (128 lines of 8 bytes)
+// and the interpreter's interrupt routine, which counts down the timers, a few more.
+pub const VIP_CYCLES_PER_FRAME: u32 = 3668;
+pub const VIP_DMA_CYCLES: u32 = 1024;
+pub const VIP_INTERRUPT_CYCLES: u32 = 46;
+
+// Fetching, decoding and dispatching an instruction in the VIP interpreter
+const VIP_DISPATCH_CYCLES: u32 = 68;
+
+// One pass of the interpreter's FX0A keypad polling loop
+pub const VIP_KEY_WAIT_CYCLES: u32 = 40;
+
+impl Timing {
+    // Budget available to the interpreter in one frame
+    pub fn budget(&self) -> u32 {
+        match *self {
+            Timing::Instructions { per_frame } => per_frame.max(1),
+            Timing::Vip => VIP_CYCLES_PER_FRAME - VIP_DMA_CYCLES - VIP_INTERRUPT_CYCLES,
+        }
+    }
+
+    // Cost of executing `instruction` with registers `v`; `skipped` tells whether
+    // a conditional skip was taken
+    pub fn cost(&self, instruction: Instruction, v: &[u8; 16], skipped: bool) -> u32 {
+        match self {
+            Timing::Instructions { .. } => 1,
+            Timing::Vip => VIP_DISPATCH_CYCLES + vip_machine_cycles(instruction, v, skipped),
+        }
+    }
+
+    // Cost of an 0NNN machine-code routine that ran for `machine_cycles`, on top
+    // of the 0NNN itself
+    pub fn machine_code_cost(&self, machine_cycles: u32) -> u32 {
+        match self {
+            Timing::Instructions { .. } => 0,
+            Timing::Vip => machine_cycles,
+        }
+    }
+
+    // Cost of one cycle spent waiting in FX0A
+    pub fn key_wait_cost(&self) -> u32 {
+        match self {
+            Timing::Instructions { .. } => 1,
+            Timing::Vip => VIP_KEY_WAIT_CYCLES,
+        }
+    }
+}
+
+// Execution cost in machine cycles on the VIP, on top of dispatch. These are
+// approximations of the original interpreter: register and immediate ops are
+// cheap, the 8XYN ALU ops go through a generated subroutine, and CLS, DXYN,
+// FX33 and the FX55/FX65 loops scale with the work they do.
+pub fn vip_machine_cycles(instruction: Instruction, v: &[u8; 16], skipped: bool) -> u32 {
+    let skip = if skipped { 4 } else { 0 };
+    match instruction {
+        Instruction::Cls => 3078,
+        Instruction::Ret => 23,
+        Instruction::Sys { .. } => 23,
+        Instruction::Jp { .. } => 23,
+        Instruction::Call { .. } => 26,
+        Instruction::SeImm { .. } | Instruction::SneImm { .. } => 12 + skip,
+        Instruction::SeReg { .. } | Instruction::SneReg { .. } => 16 + skip,
+        Instruction::LdImm { .. } => 6,
+        Instruction::AddImm { .. } => 10,
+        Instruction::LdReg { .. }
+        | Instruction::Or { .. }
+        | Instruction::And { .. }
+        | Instruction::Xor { .. }
+        | Instruction::AddReg { .. }
+        | Instruction::Sub { .. }
+        | Instruction::Shr { .. }
+        | Instruction::Subn { .. }
+        | Instruction::Shl { .. } => 44,
+        Instruction::LdI { .. } => 12,
+        Instruction::JpV0 { .. } => 26,
+        Instruction::Rnd { .. } => 36,
+        Instruction::Drw { x, n, .. } => {
+            // Sprites that don't start on a byte boundary are shifted across two bytes
+            let per_row = if v[x].is_multiple_of(8) { 46 } else { 66 };
+            26 + n as u32 * per_row
+        }
+        Instruction::Skp { .. }
+        | Instruction::Sknp { .. }
+        | Instruction::Skp2 { .. }
+        | Instruction::Sknp2 { .. } => 16 + skip,
+        Instruction::Col { .. } => 48,
+        Instruction::Bgc => 12,
+        // MegaChip never ran on the VIP; its mode opcodes are charged like LD I
+        Instruction::MegaOff
+        | Instruction::MegaOn
+        | Instruction::LdIHi { .. }
+        | Instruction::LdPal { .. }
+        | Instruction::SprW { .. }
+        | Instruction::SprH { .. }
+        | Instruction::Alpha { .. }
+        | Instruction::DigiSnd { .. }
+        | Instruction::StopSnd
+        | Instruction::BMode { .. } => 12,
+        Instruction::LdVxDt { .. } | Instruction::LdDtVx { .. } | Instruction::LdStVx { .. } => 10,
+        Instruction::LdVxK { .. } => 20,
+        Instruction::AddIVx { .. } => 19,
+        Instruction::LdFVx { .. } => 20,
+        Instruction::LdBVx { x } => {
+            // Repeated subtraction: one pass per unit of each digit
+            let value = v[x] as u32;
+            80 + 6 * (value / 100 + (value / 10) % 10 + value % 10)
+        }
+        Instruction::LdIVx { x } | Instruction::LdVxI { x } => 14 + 8 * (x as u32 + 1),
+        Instruction::Unknown(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::instruction::decode_for;
+    use crate::platform::Platform;
+
+    #[test]
+    fn budgets() {
+        assert_eq!(Timing::default(), Timing::Instructions { per_frame: 1 });
+        assert_eq!(Timing::default().budget(), 1);
+        assert_eq!(Timing::Instructions { per_frame: 0 }.budget(), 1);
+        assert_eq!(Timing::Instructions { per_frame: 700 }.budget(), 700);
+        assert_eq!(Timing::Vip.budget(), 3668 - 1024 - 46);
+    }
+
+    #[test]
+    fn vip_costs() {
+        // V0 = 8 (sprites on a byte boundary), V1 = 3, V2 = 123
+        let mut v = [0; 16];
+        (v[0], v[1], v[2]) = (8, 3, 123);
+        for (opcode, skipped, cycles) in [
+            (0x00E0, false, 3078), // CLS
+            (0x00EE, false, 23),   // RET
+            (0x2300, false, 26),   // CALL
+            (0x6005, false, 6),    // LD V0, 5
+            (0x8014, false, 44),   // ADD V0, V1
+            (0x3000, false, 12),   // SE V0, 0
+            (0x3008, true, 16),    // SE V0, 8
+            (0xD015, false, 26 + 5 * 46),
+            (0xD105, false, 26 + 5 * 66),
+            (0xF233, false, 80 + 6 * (1 + 2 + 3)),
+            (0xF355, false, 14 + 8 * 4),
+            (0xF065, false, 14 + 8),
+        ] {
+            let instruction = decode_for(Platform::Chip8, opcode);
+            assert_eq!(
+                Timing::Vip.cost(instruction, &v, skipped),
+                VIP_DISPATCH_CYCLES + cycles,
+                "{:04X}",
+                opcode
+            );
+            let per_instruction = Timing::Instructions { per_frame: 10 };
+            assert_eq!(per_instruction.cost(instruction, &v, skipped), 1);
+        }
+        assert_eq!(Timing::Vip.key_wait_cost(), VIP_KEY_WAIT_CYCLES);
+        assert_eq!(Timing::Vip.machine_code_cost(100), 100);
+        assert_eq!(Timing::default().machine_code_cost(100), 0);
+    }
+
+    #[test]
+    fn one_instruction_per_frame_by_default() {
+        // ADD V0, 1; JP 0x200
+        let mut cpu = Cpu::new();
+        cpu.set_idle_skip(false);
+        cpu.load_program(&[0x70, 0x01, 0x12, 0x00]);
+        for _ in 0..10 {
+            assert!(cpu.cycle([false; 16]).vblank);
+        }
+        assert_eq!(cpu.v_registers()[0], 5);
+        assert_eq!(cpu.cycles(), 10);
+    }
+}
@@ -0,0 +1,251 @@
+/*!
+ * @file vip.rs
+ * @brief COSMAC VIP system: 1802 CPU, 4 KB RAM, CDP1861 video and hex keypad
+ *
+ * Runs the original CHIP-8 interpreter image instead of our high-level Cpu,
+ * as a reference for hybrid ROMs and for cross-checking the Cpu. The
+ * interpreter (and optionally the monitor ROM) are not bundled; callers load
+ * their own dumps.
+ *
+ * Each frame is 262 lines of 14 machine cycles. When the display is on, the
+ * 1861 raises an interrupt two lines before the 128 display lines, asserts EF1
+ * for the four lines before and the last four lines of the display, and takes
+ * 8 of every display line's 14 cycles for DMA. Interrupts and DMA are serviced
+ * at the first instruction boundary after they are requested, which is what
+ * keeps the interpreter's display routine in step with the DMA: it rewinds R0
+ * between lines so each 8-byte row is shown four times, and every fourth DMA
+ * line becomes one row of the 64x32 framebuffer.
+ */
+use crate::cdp1802::{Bus, Cdp1802};
+use crate::cpu::{
+    OutputState, DISPLAY_HEIGHT, DISPLAY_WIDTH, MEMORY_SIZE, PROGRAM_START, REGISTER_COUNT,
+    VIP_VARIABLES_START,
+};
+use crate::display::Display;
+use crate::frontend::Keypad;
+use crate::timing::VIP_CYCLES_PER_FRAME;
+
+// The interpreter occupies the first 512 bytes, below the CHIP-8 program
+pub const VIP_INTERPRETER_SIZE: usize = PROGRAM_START;
+
+// The monitor ROM answers at 0x8000
+pub const VIP_MONITOR_START: u16 = 0x8000;
+pub const VIP_MONITOR_SIZE: usize = 0x200;
+
+const LINES_PER_FRAME: u32 = 262;
+const CYCLES_PER_LINE: u32 = 14;
+const DMA_BYTES_PER_LINE: u32 = 8;
+const INTERRUPT_LINE: u32 = 78;
+const DISPLAY_LINES: std::ops::Range<u32> = 80..208;
+// DMA starts this far into a display line: 30 cycles after the interrupt
+const DMA_DELAY_CYCLES: u32 = 2;
+const DISPLAY_LINES_PER_ROW: u32 = 4;
+const EF1_LEAD_LINES: u32 = 4;
+
+/**
+ * @brief Memory and I/O as the VIP wires them to the 1802
+ */
+struct VipBus {
+    memory: [u8; MEMORY_SIZE],
+    monitor: Option<Vec<u8>>,
+    keypad: Keypad,
+    key_latch: usize,
+    display_enabled: bool,
+    ef1: bool,
+}
+
+impl Bus for VipBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        match &self.monitor {
+            Some(monitor) if addr >= VIP_MONITOR_START => monitor
+                .get((addr - VIP_MONITOR_START) as usize % VIP_MONITOR_SIZE)
+                .copied()
+                .unwrap_or(0),
+            // 4 KB of RAM mirrored across the address space
+            _ => self.memory[addr as usize % MEMORY_SIZE],
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if addr < VIP_MONITOR_START || self.monitor.is_none() {
+            self.memory[addr as usize % MEMORY_SIZE] = value;
+        }
+    }
+
+    // INP 1 turns the 1861 on
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 {
+            self.display_enabled = true;
+        }
+        0
+    }
+
+    // OUT 1 turns the 1861 off, OUT 2 latches the key EF3 reports on
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.display_enabled = false,
+            2 => self.key_latch = (value & 0x0F) as usize,
+            _ => {}
+        }
+    }
+
+    fn flag(&self, line: u8) -> bool {
+        match line {
+            1 => self.ef1,
+            3 => self.keypad[self.key_latch],
+            _ => false,
+        }
+    }
+}
+
+pub struct Vip {
+    processor: Cdp1802,
+    bus: VipBus,
+    display: Display,
+    cycles: u64, // machine cycles since reset
+    frame: u64,
+}
+
+impl Vip {
+    // A VIP with `interpreter` at 0x000, about to run it from reset. R1's high
+    // byte holds the top RAM page, as the monitor leaves it.
+    pub fn new(interpreter: &[u8]) -> Self {
+        if interpreter.len() > VIP_INTERPRETER_SIZE {
+            panic!("Interpreter image too large to fit below the program");
+        }
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..interpreter.len()].copy_from_slice(interpreter);
+
+        let mut processor = Cdp1802::new();
+        processor.r[1] = (MEMORY_SIZE - 1) as u16;
+
+        Vip {
+            processor,
+            bus: VipBus {
+                memory,
+                monitor: None,
+                keypad: [false; 16],
+                key_latch: 0,
+                display_enabled: false,
+                ef1: false,
+            },
+            display: Display::new(DISPLAY_WIDTH, DISPLAY_HEIGHT),
+            cycles: 0,
+            frame: 0,
+        }
+    }
+
+    // Map a monitor ROM dump at 0x8000, for interpreters that call into it
+    pub fn with_monitor(mut self, monitor: &[u8]) -> Self {
+        if monitor.len() > VIP_MONITOR_SIZE {
+            panic!("Monitor image too large");
+        }
+        self.bus.monitor = Some(monitor.to_vec());
+        self
+    }
+
+    pub fn load_program(&mut self, program: &[u8]) {
+        if program.len() > MEMORY_SIZE - PROGRAM_START {
+            panic!("Program too large to fit in memory");
+        }
+        self.bus.memory[PROGRAM_START..PROGRAM_START + program.len()].copy_from_slice(program);
+    }
+
+    /*
+     * Read-only accessors. The CHIP-8 state is read from where the interpreter
+     * keeps it: V0 - VF in memory, I in RA and the CHIP-8 PC in R5.
+     */
+
+    pub fn processor(&self) -> &Cdp1802 {
+        &self.processor
+    }
+
+    pub fn memory(&self) -> &[u8; MEMORY_SIZE] {
+        &self.bus.memory
+    }
+
+    pub fn v_registers(&self) -> [u8; REGISTER_COUNT] {
+        let mut v = [0; REGISTER_COUNT];
+        v.copy_from_slice(
+            &self.bus.memory[VIP_VARIABLES_START..VIP_VARIABLES_START + REGISTER_COUNT],
+        );
+        v
+    }
+
+    pub fn index_register(&self) -> usize {
+        self.processor.r[0xA] as usize % MEMORY_SIZE
+    }
+
+    pub fn program_counter(&self) -> usize {
+        self.processor.r[5] as usize % MEMORY_SIZE
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // Run the 1802 up to the first instruction boundary at or after `time`
+    fn run_until(&mut self, time: u64) {
+        while self.cycles < time {
+            self.cycles += self.processor.step(&mut self.bus) as u64;
+        }
+    }
+
+    // Run one 60 Hz frame with `keypad` held down
+    pub fn run_frame(&mut self, keypad: Keypad) -> OutputState<'_> {
+        self.bus.keypad = keypad;
+        let previous = self.display.clone();
+
+        let frame_start = self.frame * VIP_CYCLES_PER_FRAME as u64;
+        for line in 0..LINES_PER_FRAME {
+            let line_start = frame_start + (line * CYCLES_PER_LINE) as u64;
+            self.run_until(line_start);
+
+            let enabled = self.bus.display_enabled;
+            let display_start = DISPLAY_LINES.start;
+            let display_end = DISPLAY_LINES.end;
+            self.bus.ef1 = enabled
+                && ((display_start - EF1_LEAD_LINES..display_start).contains(&line)
+                    || (display_end - EF1_LEAD_LINES..display_end).contains(&line));
+
+            if enabled && line == INTERRUPT_LINE && self.processor.interrupt() {
+                // Acknowledging the interrupt takes one machine cycle
+                self.cycles += 1;
+            }
+
+            if enabled && DISPLAY_LINES.contains(&line) {
+                self.run_until(line_start + DMA_DELAY_CYCLES as u64);
+                let scanline = (line - display_start) as usize;
+                for column in 0..DMA_BYTES_PER_LINE as usize {
+                    let byte = self.processor.dma_out(&mut self.bus);
+                    if scanline.is_multiple_of(DISPLAY_LINES_PER_ROW as usize) {
+                        let y = scanline / DISPLAY_LINES_PER_ROW as usize;
+                        for bit in 0..8 {
+                            self.display
+                                .set_pixel(column * 8 + bit, y, (byte >> (7 - bit)) & 1);
+                        }
+                    }
+                }
+                self.cycles += DMA_BYTES_PER_LINE as u64;
+            }
+        }
+        self.run_until(frame_start + VIP_CYCLES_PER_FRAME as u64);
+
+        if !self.bus.display_enabled {
+            self.display.clear();
+        }
+        self.frame += 1;
+
+        OutputState {
+            display_changed: self.display != previous,
+            display: &self.display,
+            beep: self.processor.q,
+            fault: None,
+            vblank: true,
+        }
+    }
+}